@@ -17,7 +17,7 @@ mkdir -p images
 
 for ident in $(cd configs/; echo *.yaml)
 do
-    RS_AS_ASSOCIATION=configs/$ident cargo +nightly build --release --target-dir=target
+    RS_AS_ASSOCIATION=configs/$ident cargo build --release --target-dir=target
     objcopy -O ihex target/thumbv7em-none-eabihf/release/coap-ace-poc-firmware "$OURTMP"/firmware.hex
     # Observations regarding what needs to be in here:
     # * If a record 05 (Start Linear Address) is present, it refuses to process the
@@ -4,32 +4,294 @@
 use std::io::Write;
 use std::path::Path;
 
+/// Provisioning data of a device
+///
+/// Besides the security setup, this can describe the board (in the optional fields), making the
+/// file the single source of truth about a device.
 #[derive(Debug, serde::Deserialize)]
-struct Config<'a> {
-    issuer: &'a str,
-    audience: &'a str,
-    as_uri: &'a str,
-    key: Option<&'a str>,
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[allow(dead_code)] // Only relevant to the AS
+    issuer: String,
+    audience: String,
+    /// Further audiences the device accepts symmetric tokens for
+    group_audiences: Option<Vec<String>>,
+    as_uri: String,
+    key: Option<String>,
 
-    edhoc_x: &'a str,
-    edhoc_y: &'a str,
-    edhoc_q: &'a str,
+    edhoc_x: String,
+    edhoc_y: String,
+    edhoc_q: String,
 
-    as_pub_x: Option<&'a str>,
-    as_pub_y: Option<&'a str>,
+    as_pub_x: Option<String>,
+    as_pub_y: Option<String>,
+
+    /// GAP device name
+    device_name: Option<String>,
+    /// GAP appearance value
+    appearance: Option<u16>,
+    /// Pin numbers of the 4 LEDs (P1 pins counting from 32)
+    led_pins: Option<[u8; 4]>,
+    /// Default advertising interval in milliseconds
+    advertising_interval: Option<u16>,
+    /// Seconds after which the resolvable private address is replaced by a new one (15 minutes by
+    /// default), or 0 to advertise with the static address
+    address_rotation: Option<u16>,
+    /// Short form of the AS URI (eg. from a URL shortener) to include in the advertising data, so
+    /// that clients can start their token request before connecting
+    advertised_as_uri: Option<String>,
+
+    /// Derive audience and EDHOC key from the device ID instead of using the ones above
+    derivation: Option<Derivation>,
+}
+
+/// Parameters of the identity derivation (see the library's `derivation` module)
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Derivation {
+    /// 32 bytes, hex encoded
+    batch_secret: String,
+    audience_prefix: String,
+}
+
+/// Longest audience prefix (as `MAX_AUDIENCE_PREFIX_LEN` in the `derivation` module)
+const MAX_AUDIENCE_PREFIX_LEN: usize = 24;
+
+/// Longest AS URI that identities can be derived with (as `MAX_AS_URI_LEN` in the `derivation`
+/// module)
+const MAX_AS_URI_LEN: usize = 120;
+
+/// Longest device name the firmware has room for (as `MAX_DEVICE_NAME_LEN` in main.rs)
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/// UUID of the CoAP GATT service (as in the `gatt_service` of main.rs)
+const COAP_SERVICE_UUID: &str = "8df804b7-3300-496d-9dfa-f8fb40a236bc";
+
+/// Longest advertising or scan response data that legacy advertising allows
+const MAX_ADV_DATA_LEN: usize = 31;
+
+/// Hardware properties of a supported board
+struct Board {
+    /// Name of the chip, for documentation in the linker script
+    chip: &'static str,
+    /// Default pin numbers of the LEDs 1 to 4 (P1 pins counting from 32)
+    led_pins: [u8; 4],
+    /// Pin of button 1 (which `main.rs` takes from the peripherals)
+    button_pin: u8,
+    /// Spare pins set by the `profiling` feature (which `main.rs` takes from the peripherals)
+    profiling_pins: [u8; 3],
+    /// Number of GPIO pins, across all ports
+    pin_count: u8,
+    /// End of the flash usable by softdevice and firmware, in KiB
+    ///
+    /// The settings journal occupies the last two pages before this.
+    flash_end: u32,
+    /// Size of the RAM, in KiB
+    ram: u32,
+}
+
+/// The nRF52-DK (PCA10040)
+const NRF52DK: Board = Board {
+    chip: "nRF52832_xxAA",
+    led_pins: [17, 18, 19, 20],
+    button_pin: 13,
+    // Arduino header A0 to A2
+    profiling_pins: [3, 4, 28],
+    pin_count: 32,
+    flash_end: 512,
+    ram: 64,
+};
+
+/// The nRF52833 DK (PCA10100)
+const NRF52833DK: Board = Board {
+    chip: "nRF52833_xxAA",
+    led_pins: [13, 14, 15, 16],
+    button_pin: 11,
+    // Arduino header A0 to A2
+    profiling_pins: [3, 4, 28],
+    pin_count: 42,
+    flash_end: 512,
+    ram: 128,
+};
+
+/// The nRF52840 Dongle (PCA10059)
+const NRF52840DONGLE: Board = Board {
+    chip: "nRF52840_xxAA",
+    // LD1, and LD2's red, green and blue
+    led_pins: [6, 8, 32 + 9, 12],
+    button_pin: 32 + 6,
+    // Pads on the edge of the board
+    profiling_pins: [2, 29, 31],
+    pin_count: 48,
+    // The preinstalled bootloader starts here, and is kept to allow updates over USB.
+    flash_end: 896,
+    ram: 256,
+};
+
+/// Properties of a softdevice (all in version 7.3.0)
+struct Softdevice {
+    /// Name of the softdevice, for documentation in the linker script and error messages
+    name: &'static str,
+    /// Chips (as in [Board::chip]) the softdevice supports
+    chips: &'static [&'static str],
+    /// Flash occupied by the softdevice at the start of the flash, in KiB
+    flash: u32,
+    /// RAM reserved for the softdevice at the start of the RAM, in KiB
+    ///
+    /// These values are not precise -- if it's too small, the softdevice will complain at startup;
+    /// if it's too large, the linker will complain about insufficient RAM. The room needed by the
+    /// softdevice depends on its initialization parameters.
+    ram: u32,
+}
+
+const S132: Softdevice = Softdevice {
+    name: "S132",
+    chips: &["nRF52832_xxAA"],
+    flash: 152,
+    ram: 27,
+};
+
+const S140: Softdevice = Softdevice {
+    name: "S140",
+    chips: &["nRF52833_xxAA", "nRF52840_xxAA"],
+    flash: 156,
+    ram: 27,
+};
+
+/// The peripheral-only softdevice, which leaves more flash and RAM to the firmware
+const S113: Softdevice = Softdevice {
+    name: "S113",
+    chips: &["nRF52832_xxAA", "nRF52833_xxAA", "nRF52840_xxAA"],
+    flash: 112,
+    ram: 20,
+};
+
+/// Find the one item whose feature is enabled, or the fallback if none is.
+fn select<T>(kind: &str, options: [(&str, &'static T); 3], fallback: &'static T) -> &'static T {
+    let mut selected = options
+        .into_iter()
+        .filter(|(feature, _)| std::env::var_os(feature).is_some());
+    let item = selected.next().map(|(_, item)| item).unwrap_or(fallback);
+    assert!(
+        selected.next().is_none(),
+        "Only one of the `{kind}-*` features can be enabled"
+    );
+    item
+}
+
+/// Find the board selected through the `hardware-*` features.
+///
+/// Builds without any (eg. for the host) get the nRF52-DK's properties; they do not link a
+/// firmware anyway.
+fn board() -> &'static Board {
+    select(
+        "hardware",
+        [
+            ("CARGO_FEATURE_HARDWARE_NRF52DK", &NRF52DK),
+            ("CARGO_FEATURE_HARDWARE_NRF52833DK", &NRF52833DK),
+            ("CARGO_FEATURE_HARDWARE_NRF52840DONGLE", &NRF52840DONGLE),
+        ],
+        &NRF52DK,
+    )
+}
+
+/// Find the softdevice selected through the `softdevice-*` features, and check that it runs on
+/// the board.
+fn softdevice(board: &Board) -> &'static Softdevice {
+    let softdevice = select(
+        "softdevice",
+        [
+            ("CARGO_FEATURE_SOFTDEVICE_S132", &S132),
+            ("CARGO_FEATURE_SOFTDEVICE_S140", &S140),
+            ("CARGO_FEATURE_SOFTDEVICE_S113", &S113),
+        ],
+        &S132,
+    );
+    assert!(
+        softdevice.chips.contains(&board.chip),
+        "The {} softdevice does not support the {}; pick one of the `softdevice-*` features that does",
+        softdevice.name,
+        board.chip,
+    );
+    softdevice
+}
+
+/// Decode a hex encoded field, panicking with a message that points to the field on error.
+///
+/// If `len` is given, the field also needs to decode to exactly that many bytes.
+fn hex_field(name: &str, value: &str, len: Option<usize>) -> Vec<u8> {
+    let bytes = hex::decode(value).unwrap_or_else(|e| {
+        panic!("Config field `{name}` should be hex encoded, but {value:?} is not ({e})")
+    });
+    if let Some(len) = len {
+        assert!(
+            bytes.len() == len,
+            "Config field `{name}` should be {len} bytes ({} hex digits) long, but is {} bytes long",
+            len * 2,
+            bytes.len(),
+        );
+    }
+    bytes
+}
+
+/// Parse the configuration file in the format indicated by its extension.
+fn parse_config(path: &str) -> Config {
+    let text = std::fs::read(path)
+        .unwrap_or_else(|e| panic!("Configuration file {path:?} can not be read ({e})"));
+    let text = String::from_utf8(text).expect("Config file is not UTF-8");
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension {
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).unwrap_or_else(|e| {
+            panic!("Config file {path:?} does not match the config structure: {e}")
+        }),
+        Some("toml") => toml::from_str(&text).unwrap_or_else(|e| {
+            panic!("Config file {path:?} does not match the config structure: {e}")
+        }),
+        Some("json") => serde_json::from_str(&text).unwrap_or_else(|e| {
+            panic!("Config file {path:?} does not match the config structure: {e}")
+        }),
+        _ => panic!("Config file {path:?} needs to have a .yaml, .yml, .toml or .json extension"),
+    }
 }
 
 fn main() {
     println!("cargo:rerun-if-env-changed=RS_AS_ASSOCIATION");
     let config_file = std::env::var("RS_AS_ASSOCIATION").unwrap_or("configs/d00.yaml".to_string());
     println!("cargo:rerun-if-changed={}", config_file);
-    let yaml = std::fs::read(config_file).expect("Configuration file missing");
-    let yaml = String::from_utf8(yaml).expect("Config file is not UTF-8");
-    let config: Config =
-        serde_yaml::from_str(&yaml).expect("Config file needs to match config structure");
-    let key = config
-        .key
-        .map(|k| hex::decode(k).expect("Config key should be hex"));
+    let config = parse_config(&config_file);
+    let key = config.key.as_deref().map(|k| hex_field("key", k, Some(32)));
+    let edhoc_x = hex_field("edhoc_x", &config.edhoc_x, Some(32));
+    let edhoc_y = hex_field("edhoc_y", &config.edhoc_y, Some(32));
+    let edhoc_q = hex_field("edhoc_q", &config.edhoc_q, Some(32));
+    let as_pub = {
+        let x = config
+            .as_pub_x
+            .as_deref()
+            .map(|x| hex_field("as_pub_x", x, Some(32)));
+        let y = config
+            .as_pub_y
+            .as_deref()
+            .map(|y| hex_field("as_pub_y", y, Some(32)));
+        match (x, y) {
+            (Some(x), Some(y)) => Some((x, y)),
+            (None, None) => None,
+            _ => panic!("Configs as_pub_x and as_pub_y have to be given as a pair"),
+        }
+    };
+    let edhoc_credential = edhoc_credential(&edhoc_x, &edhoc_y);
+    let request_creation_hints = request_creation_hints(&config.as_uri, &config.audience);
+    let group_audiences = group_audiences(config.group_audiences.as_deref().unwrap_or_default());
+    let checksum = config_checksum(
+        &config.audience,
+        &group_audiences,
+        &request_creation_hints,
+        &[
+            key.as_deref(),
+            Some(&edhoc_credential[..]),
+            Some(&edhoc_q[..]),
+        ],
+        as_pub.as_ref(),
+    );
     let config_outfile = Path::new(&std::env::var("OUT_DIR").unwrap()).join("rs_as_association.rs");
     let mut config_outfile =
         std::fs::File::create(config_outfile).expect("Config outfile needs to be writable");
@@ -37,45 +299,381 @@ fn main() {
         config_outfile,
         "{{
             let coapcore_config = CoapcoreConfig {{
-                request_creation_hints: &cbor_macro::cbor!({{1 /as/:{:?}, 5 /aud/: {:?}}}),
+                request_creation_hints: &{:?},
                 audience: {:?},
+                group_audiences: &{:?},
                 as_symmetric: {:?},
-                edhoc_x: Some({:?}),
-                edhoc_y: Some({:?}),
+                edhoc_credential: Some(&{:?}),
                 edhoc_q: Some(&{:?}),
                 as_pub: {:?},
+                checksum: {:#x},
             }};
 
             coapcore_config
         }}",
-        config.as_uri,
-        config.audience,
+        request_creation_hints,
         config.audience,
+        group_audiences,
         key,
-        hex::decode(config.edhoc_x).expect("Config edhoc_x should be hex"),
-        hex::decode(config.edhoc_y).expect("Config edhoc_y should be hex"),
-        hex::decode(config.edhoc_q).expect("Config edhoc_q should be hex"),
-        {
-            let x = config
-                .as_pub_x
-                .map(hex::decode)
-                .transpose()
-                .expect("Config as_pub_x should be hex");
-            let y = config
-                .as_pub_y
-                .map(hex::decode)
-                .transpose()
-                .expect("Config as_pub_y should be hex");
-            match (x, y) {
-                (Some(x), Some(y)) => Some((x, y)),
-                (None, None) => None,
-                _ => panic!("Configs as_pub_x and as_pub_y have to be given as a pair"),
-            }
-        },
+        edhoc_credential,
+        edhoc_q,
+        as_pub,
+        checksum,
     )
     .unwrap();
 
+    write_identity_derivation(&config);
+
+    let board = board();
+    write_board_config(&config, board);
+    write_advertising_data(&config);
+    write_memory_layout(board, softdevice(board));
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Validate the derivation parameters, and write them out as an `Option<IdentityDerivation>`.
+fn write_identity_derivation(config: &Config) {
+    let derivation = config.derivation.as_ref().map(|derivation| {
+        let batch_secret = hex_field(
+            "derivation.batch_secret",
+            &derivation.batch_secret,
+            Some(32),
+        );
+        assert!(
+            derivation.audience_prefix.len() <= MAX_AUDIENCE_PREFIX_LEN,
+            "Config field `derivation.audience_prefix` can be at most {MAX_AUDIENCE_PREFIX_LEN} bytes long"
+        );
+        assert!(
+            config.as_uri.len() <= MAX_AS_URI_LEN,
+            "Config field `as_uri` can be at most {MAX_AS_URI_LEN} bytes long with `derivation`"
+        );
+        format!(
+            "IdentityDerivation {{
+                batch_secret: {:?},
+                audience_prefix: {:?},
+                as_uri: {:?},
+            }}",
+            batch_secret, derivation.audience_prefix, config.as_uri,
+        )
+    });
+    let outfile = Path::new(&std::env::var("OUT_DIR").unwrap()).join("identity_derivation.rs");
+    let mut outfile =
+        std::fs::File::create(outfile).expect("Derivation outfile needs to be writable");
+    match derivation {
+        Some(derivation) => write!(outfile, "Some({derivation})"),
+        None => write!(outfile, "None"),
+    }
+    .unwrap();
+}
+
+/// Validate the board related fields, and write them out as a `BoardConfig`.
+fn write_board_config(config: &Config, board: &Board) {
+    if let Some(name) = &config.device_name {
+        assert!(
+            name.len() <= MAX_DEVICE_NAME_LEN,
+            "Config field `device_name` can be at most {MAX_DEVICE_NAME_LEN} bytes long, but {name:?} is {} bytes long",
+            name.len(),
+        );
+    }
+    let led_pins = config.led_pins.unwrap_or(board.led_pins);
+    for (i, pin) in led_pins.iter().enumerate() {
+        assert!(
+            *pin < board.pin_count,
+            "Config field `led_pins` should contain pin numbers of the {} (0 to {}), but contains {pin}",
+            board.chip,
+            board.pin_count - 1,
+        );
+        assert!(
+            !led_pins[..i].contains(pin),
+            "Config field `led_pins` contains pin {pin} twice"
+        );
+    }
+    let mut reserved_pins = vec![(board.button_pin, "button 1")];
+    if std::env::var_os("CARGO_FEATURE_WS2812").is_some() {
+        assert!(
+            std::ptr::eq(board, &NRF52DK),
+            "The `ws2812` feature is only available on the nRF52-DK, where its pins are free"
+        );
+        reserved_pins.extend([(11, "the LED strip's MOSI"), (12, "the LED strip's SCK")]);
+    }
+    if std::env::var_os("CARGO_FEATURE_PROFILING").is_some() {
+        reserved_pins.extend(board.profiling_pins.map(|pin| (pin, "profiling")));
+    }
+    for (pin, usage) in reserved_pins {
+        assert!(
+            !led_pins.contains(&pin),
+            "Config field `led_pins` contains pin {pin}, which is used for {usage}"
+        );
+    }
+    if let Some(interval) = config.advertising_interval {
+        assert!(
+            (20..=10240).contains(&interval),
+            "Config field `advertising_interval` should be between 20 and 10240 (milliseconds), but is {interval}"
+        );
+    }
+
+    let address_rotation = match config.address_rotation.unwrap_or(900) {
+        0 => None,
+        seconds => Some(seconds),
+    };
+    if let Some(seconds) = address_rotation {
+        // Longest cycle the softdevice accepts (11.5 hours)
+        assert!(
+            seconds <= 41400,
+            "Config field `address_rotation` should be at most 41400 (seconds), or 0, but is {seconds}"
+        );
+    }
+
+    let board_outfile = Path::new(&std::env::var("OUT_DIR").unwrap()).join("board_config.rs");
+    let mut board_outfile =
+        std::fs::File::create(board_outfile).expect("Board outfile needs to be writable");
+    write!(
+        board_outfile,
+        "BoardConfig {{
+            device_name: {:?},
+            led_pins: {:?},
+            advertising_interval: {:?},
+            address_rotation: {:?},
+        }}",
+        config.device_name.as_deref(),
+        led_pins,
+        config.advertising_interval,
+        address_rotation,
+    )
+    .unwrap();
+}
+
+/// Encode AD structures (type and value) into advertising or scan response data, checking that
+/// they fit.
+fn ad_structures(kind: &str, structures: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut out = vec![];
+    // Empty values stand for AD structures that are left out.
+    for (ad_type, value) in structures.iter().filter(|(_, value)| !value.is_empty()) {
+        out.push(u8::try_from(1 + value.len()).unwrap());
+        out.push(*ad_type);
+        out.extend(*value);
+    }
+    assert!(
+        out.len() <= MAX_ADV_DATA_LEN,
+        "{kind} is {} bytes long, but may be at most {MAX_ADV_DATA_LEN}",
+        out.len(),
+    );
+    out
+}
+
+/// Encode an `http` or `https` URI as the value of a URI AD structure, in which the scheme is
+/// replaced by its code point from the Bluetooth assigned numbers.
+fn uri_ad_value(uri: &str) -> Vec<u8> {
+    let (scheme, rest) = if let Some(rest) = uri.strip_prefix("https:") {
+        (0x17, rest)
+    } else if let Some(rest) = uri.strip_prefix("http:") {
+        (0x16, rest)
+    } else {
+        panic!("Config field `advertised_as_uri` should be an http or https URI, but is {uri:?}");
+    };
+    let mut out = vec![scheme];
+    out.extend(rest.as_bytes());
+    out
+}
+
+/// Write the advertising and scan response data to `$OUT_DIR/advertising_data.rs`.
+///
+/// The advertising data ends in a placeholder for the number of free connection slots, which the
+/// firmware fills in for every advertisement. The scan response data starts with the device's
+/// shortened name, which contains as much of the audience as fits, followed by the UUID of the
+/// CoAP service.
+fn write_advertising_data(config: &Config) {
+    // Generic thermometer
+    let appearance = config.appearance.unwrap_or(0x0300).to_le_bytes();
+    let advertised_as_uri = config
+        .advertised_as_uri
+        .as_deref()
+        .map(uri_ad_value)
+        .unwrap_or_default();
+    let adv_data = ad_structures(
+        "Advertising data (including `advertised_as_uri`)",
+        &[
+            // Flags (they can't be in the scan data, which is enforced by the softdevice; and
+            // without these, blueman-manager won't show the device): LE General Discoverable
+            // Mode, BR/EDR Not Supported
+            (0x01, &[0x06]),
+            // Appearance
+            (0x19, &appearance),
+            // URI
+            (0x24, &advertised_as_uri[..]),
+            // Manufacturer specific data, with the company ID reserved for testing (0xffff),
+            // followed by the free slots placeholder
+            (0xff, &[0xff, 0xff, 0]),
+        ],
+    );
+
+    // UUIDs are sent little endian.
+    let mut service_uuid =
+        hex::decode(COAP_SERVICE_UUID.replace('-', "")).expect("UUID consists of hex digits");
+    service_uuid.reverse();
+
+    // Two AD structures with their length and type bytes, and the name prefix
+    let room = MAX_ADV_DATA_LEN - 2 - service_uuid.len() - 2 - "CoAP ".len();
+    let mut audience = config.audience.as_str();
+    while audience.len() > room {
+        let mut end = audience.len() - 1;
+        while !audience.is_char_boundary(end) {
+            end -= 1;
+        }
+        audience = &audience[..end];
+    }
+    let name = format!("CoAP {audience}");
+    let scan_data = ad_structures(
+        "Scan response data",
+        &[
+            // Shortened Local Name
+            (0x08, name.as_bytes()),
+            // Incomplete List of 128-bit Service Class UUIDs (we could also send a complete one,
+            // not-sure/not-care at this stage)
+            (0x06, &service_uuid[..]),
+        ],
+    );
+
+    let outfile = Path::new(&std::env::var("OUT_DIR").unwrap()).join("advertising_data.rs");
+    let mut outfile =
+        std::fs::File::create(outfile).expect("Advertising data outfile needs to be writable");
+    write!(
+        outfile,
+        "/// Advertising data; the last byte is to be replaced with the number of free slots
+        const ADV_DATA: [u8; {}] = {:?};
+        /// Scan response data
+        const SCAN_DATA: [u8; {}] = {:?};
+        /// Length of the first AD structure (the name) in [SCAN_DATA]
+        const SCAN_DATA_NAME_LEN: usize = {};",
+        adv_data.len(),
+        adv_data,
+        scan_data.len(),
+        scan_data,
+        2 + name.len(),
+    )
+    .unwrap();
+}
+
+/// Write the linker script's memory layout for the board and softdevice to `$OUT_DIR/memory.x`,
+/// and add it to the linker search path.
+fn write_memory_layout(board: &Board, softdevice: &Softdevice) {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let memory_outfile = Path::new(&out_dir).join("memory.x");
+    let mut memory_outfile =
+        std::fs::File::create(memory_outfile).expect("Memory layout needs to be writable");
+    write!(
+        memory_outfile,
+        "/* Generated by build.rs for the {chip} with SoftDevice {softdevice} 7.3.0 */
+MEMORY
+{{
+  /* The last two pages are reserved for persisted settings (see src/journal.rs), and the one
+     before them for a provisioned identity (see src/serial_provisioning.rs) */
+  FLASH : ORIGIN = 0x00000000 + {softdevice_flash}K, LENGTH = {flash_end}K - {softdevice_flash}K - 12K
+  /* The softdevice's RAM share is estimated (see Softdevice::ram in build.rs) */
+  RAM : ORIGIN = 0x20000000 + {softdevice_ram}K, LENGTH = {ram}K - {softdevice_ram}K
+}}
+",
+        chip = board.chip,
+        softdevice = softdevice.name,
+        softdevice_flash = softdevice.flash,
+        softdevice_ram = softdevice.ram,
+        flash_end = board.flash_end,
+        ram = board.ram,
+    )
+    .unwrap();
+    println!("cargo:rustc-link-search={out_dir}");
+}
+
+/// Encode the device's own EDHOC credential as a CWT Claims Set (CCS) containing the public key.
+///
+/// The CCS is `{2 /sub/: "", 8 /cnf/: {1 /COSE_Key/: {1 /kty/: 2 /EC2/, 2 /kid/: h'63', -1 /crv/: 1
+/// /P-256/, -2 /x/: edhoc_x, -3 /y/: edhoc_y}}}`.
+fn edhoc_credential(x: &[u8], y: &[u8]) -> Vec<u8> {
+    assert!(x.len() == 32, "Config edhoc_x should be 32 bytes long");
+    assert!(y.len() == 32, "Config edhoc_y should be 32 bytes long");
+
+    let mut out = vec![
+        0xa2, // map(2)
+        0x02, 0x60, // sub: ""
+        0x08, 0xa1, // cnf: map(1)
+        0x01, 0xa5, // COSE_Key: map(5)
+        0x01, 0x02, // kty: EC2
+        0x02, 0x41, 0x63, // kid: h'63'
+        0x20, 0x01, // crv: P-256
+        0x21, 0x58, 0x20, // x: bytes(32)
+    ];
+    out.extend(x);
+    out.extend([0x22, 0x58, 0x20]); // y: bytes(32)
+    out.extend(y);
+    out
+}
+
+/// Encode the AS Request Creation Hints as a CBOR map `{1 /as/: as_uri, 5 /aud/: audience}`.
+///
+/// This is done here rather than through `cbor_macro` in the generated code, because the checksum
+/// needs to cover the encoded form.
+fn request_creation_hints(as_uri: &str, audience: &str) -> Vec<u8> {
+    let mut out = vec![];
+    head(5, 2, &mut out);
+    head(0, 1, &mut out);
+    head(3, as_uri.len(), &mut out);
+    out.extend(as_uri.as_bytes());
+    head(0, 5, &mut out);
+    head(3, audience.len(), &mut out);
+    out.extend(audience.as_bytes());
+    out
+}
+
+/// Encode the group audiences as a CBOR array of text strings, or as nothing if there are none.
+fn group_audiences(audiences: &[String]) -> Vec<u8> {
+    let mut out = vec![];
+    if audiences.is_empty() {
+        return out;
+    }
+    head(4, audiences.len(), &mut out);
+    for audience in audiences {
+        head(3, audience.len(), &mut out);
+        out.extend(audience.as_bytes());
+    }
+    out
+}
+
+/// Append a CBOR item head with the given major type and length to `out`.
+fn head(major: u8, len: usize, out: &mut Vec<u8>) {
+    match len {
+        0..=23 => out.push(major << 5 | len as u8),
+        24..=0xff => out.extend([major << 5 | 24, len as u8]),
+        _ => {
+            out.push(major << 5 | 25);
+            out.extend(u16::try_from(len).expect("Text too long").to_be_bytes());
+        }
+    }
+}
+
+/// Calculate the CRC-32 over the configuration.
+///
+/// This needs to match `CoapcoreConfig::calculate_checksum`.
+fn config_checksum(
+    audience: &str,
+    group_audiences: &[u8],
+    request_creation_hints: &[u8],
+    keys: &[Option<&[u8]>],
+    as_pub: Option<&(Vec<u8>, Vec<u8>)>,
+) -> u32 {
+    const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let mut digest = CRC.digest();
+    digest.update(audience.as_bytes());
+    digest.update(group_audiences);
+    digest.update(request_creation_hints);
+    for key in keys.iter().flatten() {
+        digest.update(key);
+    }
+    if let Some((x, y)) = as_pub {
+        digest.update(x);
+        digest.update(y);
+    }
+    digest.finalize()
+}
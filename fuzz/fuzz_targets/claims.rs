@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Feed arbitrary CWT claims sets into the application's claims processing.
+#![no_main]
+
+use coap_ace_poc_firmware::rs_configuration::ApplicationClaims;
+use coset::CborSerializable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that is not a claims set is rejected by coset long before it reaches our code.
+    if let Ok(claims) = coset::cwt::ClaimsSet::from_slice(data) {
+        let _ = ApplicationClaims::try_from(&claims);
+    }
+});
@@ -0,0 +1,20 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Feed arbitrary characteristic writes into a simulated device's CoAP-over-GATT connection.
+#![no_main]
+
+use coap_ace_poc_firmware::sim::Device;
+use libfuzzer_sys::fuzz_target;
+
+thread_local! {
+    // Building the device is costly, and keeping it across runs also lets the fuzzer reach states
+    // that depend on earlier requests (eg. the time being set).
+    static DEVICE: Device = Device::from_build_config();
+}
+
+fuzz_target!(|data: &[u8]| {
+    DEVICE.with(|device| {
+        device.connect().exchange(data);
+    });
+});
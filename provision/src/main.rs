@@ -0,0 +1,350 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Fleet provisioning tool
+//!
+//! This generates the configuration files for a number of devices (`dNN.yaml`, in the format that
+//! the firmware's build script reads through `RS_AS_ASSOCIATION`), each with a fresh EDHOC key
+//! pair and optionally a fresh symmetric key shared with the AS. Alongside, it writes
+//! `as-registration-dNN-dNN.yaml`, which contains what the AS needs to know about the devices
+//! (and nothing secret that the AS does not need).
+//!
+//! When settings are given with `--set`, it also writes an Intel hex image of the settings journal pages
+//! (`dNN-settings.hex`) for each device. That can be merged into the device's firmware image (eg.
+//! in `build-hexfiles.sh`), so that the device starts out with the given settings rather than
+//! the defaults.
+//!
+//! Usage:
+//!
+//! ```sh
+//! cargo run -p coap-ace-poc-provision --target x86_64-unknown-linux-gnu -- \
+//!     --count 5 --first 20 --symmetric --out configs/ --set adv-interval=500
+//! ```
+//!
+//! Existing files are never overwritten, as replacing a device's keys makes the AS's copy stale.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+use coap_ace_poc_firmware::settings::{self, Key};
+
+/// AS used in the demo setup
+const DEFAULT_AS_URI: &str = "https://keycloak.coap.amsuess.com/realms/edf/ace-oauth/token";
+/// Public key of the demo AS, obtained from
+/// <https://keycloak.coap.amsuess.com/realms/edf/ace-oauth/server-public-keys>
+const DEFAULT_AS_PUB: (&str, &str) = (
+    "b4108ad8f21d08a877627aaf3787a91afe75a9886e3bffeb152f9fa42c1dfb50",
+    "6765776379ee0a507e173841669c33fc587bbeac4609b86dfb12af28118baf8a",
+);
+
+/// Header written into every generated file
+const HEADER: &str =
+    "# SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+# SPDX-License-Identifier: BSD-3-Clause
+# See README for all details on copyright, authorship and license.
+";
+
+/// Addresses of the settings journal pages on the boards selected with `--board`; these need to
+/// match the firmware's `journal` module.
+const JOURNAL_PAGES: [(&str, [u32; 2]); 3] = [
+    ("nrf52dk", [0x7e000, 0x7f000]),
+    ("nrf52833dk", [0x7e000, 0x7f000]),
+    ("nrf52840dongle", [0xde000, 0xdf000]),
+];
+/// Size of a flash page on the nRF52
+const PAGE_SIZE: usize = 4096;
+/// Header of a journal page of generation 0
+const JOURNAL_HEADER: [u8; 8] = *b"SET3\0\0\0\0";
+
+/// Command line options
+struct Options {
+    count: usize,
+    first: usize,
+    out: PathBuf,
+    issuer: String,
+    as_uri: String,
+    as_pub: Option<(String, String)>,
+    symmetric: bool,
+    settings: bool,
+    journal_pages: [u32; 2],
+}
+
+/// Device configuration as read by the firmware's build script
+#[derive(serde::Serialize)]
+struct DeviceConfig {
+    issuer: String,
+    audience: String,
+    as_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    edhoc_x: String,
+    edhoc_y: String,
+    edhoc_q: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_pub_x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_pub_y: Option<String>,
+}
+
+/// What the AS needs to know about a device
+#[derive(serde::Serialize)]
+struct Registration {
+    audience: String,
+    /// Symmetric key shared with the device, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// The device's EDHOC credential (a CCS), as it will send it by reference
+    edhoc_credential: String,
+    edhoc_x: String,
+    edhoc_y: String,
+}
+
+#[derive(serde::Serialize)]
+struct Registrations {
+    issuer: String,
+    devices: Vec<Registration>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: coap-ace-poc-provision --count N [--first N] [--out DIR] [--issuer NAME]
+                             [--as-uri URI] [--as-pub X Y | --no-as-pub] [--symmetric]
+                             [--set NAME=VALUE]... [--board BOARD]
+
+Settings given with --set (see the firmware's /config and /config/ble resources for names) are
+written into a settings journal image per device, laid out for the given board (nrf52dk,
+nrf52833dk or nrf52840dongle; default: nrf52dk)."
+    );
+    std::process::exit(1);
+}
+
+/// Parse the command line, applying any `--set` options to the settings right away.
+fn parse_options() -> Options {
+    let mut options = Options {
+        count: 0,
+        first: 0,
+        out: PathBuf::from("."),
+        issuer: "AS".to_string(),
+        as_uri: DEFAULT_AS_URI.to_string(),
+        as_pub: Some((DEFAULT_AS_PUB.0.to_string(), DEFAULT_AS_PUB.1.to_string())),
+        symmetric: false,
+        settings: false,
+        journal_pages: JOURNAL_PAGES[0].1,
+    };
+
+    fn value(args: &mut impl Iterator<Item = String>) -> String {
+        args.next().unwrap_or_else(|| usage())
+    }
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => options.count = value(&mut args).parse().unwrap_or_else(|_| usage()),
+            "--first" => options.first = value(&mut args).parse().unwrap_or_else(|_| usage()),
+            "--out" => options.out = value(&mut args).into(),
+            "--issuer" => options.issuer = value(&mut args),
+            "--as-uri" => options.as_uri = value(&mut args),
+            "--as-pub" => {
+                let x = value(&mut args);
+                let y = value(&mut args);
+                for coordinate in [&x, &y] {
+                    if hex::decode(coordinate).map(|c| c.len()) != Ok(32) {
+                        eprintln!("AS public key coordinates need to be 32 bytes, hex encoded");
+                        std::process::exit(1);
+                    }
+                }
+                options.as_pub = Some((x, y));
+            }
+            "--no-as-pub" => options.as_pub = None,
+            "--symmetric" => options.symmetric = true,
+            "--set" => {
+                let setting = value(&mut args);
+                let Some((name, number)) = setting.split_once('=') else {
+                    usage()
+                };
+                let key = Key::from_name(name)
+                    .filter(|key| key.configurable())
+                    .unwrap_or_else(|| {
+                        eprintln!("Unknown setting {name:?}");
+                        std::process::exit(1);
+                    });
+                let number = number.parse().unwrap_or_else(|_| usage());
+                if settings::set(key, number).is_err() {
+                    eprintln!("Value {number} is out of range for setting {name:?}");
+                    std::process::exit(1);
+                }
+                options.settings = true;
+            }
+            "--board" => {
+                let board = value(&mut args);
+                options.journal_pages = JOURNAL_PAGES
+                    .iter()
+                    .find(|(name, _)| *name == board)
+                    .map(|(_, pages)| *pages)
+                    .unwrap_or_else(|| usage());
+            }
+            _ => usage(),
+        }
+    }
+
+    if options.count == 0 {
+        usage();
+    }
+    if options.first + options.count > 100 {
+        eprintln!("Audiences are numbered d00 to d99");
+        std::process::exit(1);
+    }
+    if !options.symmetric && options.as_pub.is_none() {
+        eprintln!("Devices need either --symmetric keys or an AS public key");
+        std::process::exit(1);
+    }
+    options
+}
+
+/// Encode a device's EDHOC credential; this needs to match the firmware's build script.
+fn edhoc_credential(x: &[u8], y: &[u8]) -> Vec<u8> {
+    let mut out = vec![
+        0xa2, // map(2)
+        0x02, 0x60, // sub: ""
+        0x08, 0xa1, // cnf: map(1)
+        0x01, 0xa5, // COSE_Key: map(5)
+        0x01, 0x02, // kty: EC2
+        0x02, 0x41, 0x63, // kid: h'63'
+        0x20, 0x01, // crv: P-256
+        0x21, 0x58, 0x20, // x: bytes(32)
+    ];
+    out.extend(x);
+    out.extend([0x22, 0x58, 0x20]); // y: bytes(32)
+    out.extend(y);
+    out
+}
+
+/// Encode the current settings as the two journal pages in Intel hex format.
+///
+/// Both pages are included, so that flashing the image also clears any journal left on the device
+/// from earlier use.
+fn settings_image(pages: [u32; 2]) -> String {
+    let mut page = vec![0xff; PAGE_SIZE];
+    page[..JOURNAL_HEADER.len()].copy_from_slice(&JOURNAL_HEADER);
+    let mut offset = JOURNAL_HEADER.len();
+    for (key, value) in settings::all() {
+        offset += settings::encode_record(key, &value, &mut page[offset..]);
+    }
+
+    fn record(out: &mut String, kind: u8, address: u16, data: &[u8]) {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        let checksum = bytes
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+        bytes.push(checksum);
+        out.push(':');
+        out.push_str(&hex::encode_upper(bytes));
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    let empty = vec![0xff; PAGE_SIZE];
+    for (address, content) in pages.into_iter().zip([&page, &empty]) {
+        // Extended linear address
+        record(&mut out, 4, 0, &((address >> 16) as u16).to_be_bytes());
+        for (i, chunk) in content.chunks(16).enumerate() {
+            record(
+                &mut out,
+                0,
+                (address as u16).wrapping_add(i as u16 * 16),
+                chunk,
+            );
+        }
+    }
+    record(&mut out, 1, 0, &[]);
+    out
+}
+
+/// Write a file, refusing to replace an existing one.
+fn create(path: &Path, content: &str) {
+    let mut file = std::fs::File::create_new(path).unwrap_or_else(|e| {
+        eprintln!("Can not create {}: {e}", path.display());
+        std::process::exit(1);
+    });
+    file.write_all(content.as_bytes())
+        .expect("Writing to a freshly created file");
+}
+
+fn main() {
+    let options = parse_options();
+
+    let mut registrations = Registrations {
+        issuer: options.issuer.clone(),
+        devices: vec![],
+    };
+
+    for number in options.first..options.first + options.count {
+        let audience = format!("d{number:02}");
+
+        let private = p256::SecretKey::random(&mut rand_core::OsRng);
+        let public = private.public_key().to_encoded_point(false);
+        let (x, y) = (
+            public.x().expect("Uncompressed point"),
+            public.y().expect("Uncompressed point"),
+        );
+        let key = options.symmetric.then(|| {
+            let mut key = [0; 32];
+            rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut key);
+            hex::encode(key)
+        });
+
+        let config = DeviceConfig {
+            issuer: options.issuer.clone(),
+            audience: audience.clone(),
+            as_uri: options.as_uri.clone(),
+            key: key.clone(),
+            edhoc_x: hex::encode(x),
+            edhoc_y: hex::encode(y),
+            edhoc_q: hex::encode(private.to_bytes()),
+            as_pub_x: options.as_pub.as_ref().map(|(x, _)| x.clone()),
+            as_pub_y: options.as_pub.as_ref().map(|(_, y)| y.clone()),
+        };
+        let config = serde_yaml::to_string(&config).expect("Config is serializable");
+        create(
+            &options.out.join(format!("{audience}.yaml")),
+            &format!("{HEADER}{config}"),
+        );
+
+        if options.settings {
+            create(
+                &options.out.join(format!("{audience}-settings.hex")),
+                &settings_image(options.journal_pages),
+            );
+        }
+
+        registrations.devices.push(Registration {
+            audience,
+            key,
+            edhoc_credential: hex::encode(edhoc_credential(x, y)),
+            edhoc_x: hex::encode(x),
+            edhoc_y: hex::encode(y),
+        });
+    }
+
+    let registrations = serde_yaml::to_string(&registrations).expect("Data is serializable");
+    let last = options.first + options.count - 1;
+    create(
+        &options.out.join(format!(
+            "as-registration-d{:02}-d{last:02}.yaml",
+            options.first
+        )),
+        &format!("{HEADER}{registrations}"),
+    );
+
+    println!(
+        "Generated {} device configurations in {}; register them with the AS using the as-registration file",
+        options.count,
+        options.out.display()
+    );
+}
@@ -0,0 +1,192 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Advertising interval selection, including the low-power profile
+//!
+//! In the low-power profile (enabled through the `low-power` [setting](settings::Key)), the
+//! advertising interval lengthens in steps while no one connects, and returns to the fast interval
+//! when a connection is established or button 1 is pressed. Otherwise, the configured interval
+//! (from the settings, or else from the board configuration) or the softdevice's default interval
+//! is used throughout.
+//!
+//! When no one connected for the time configured in the `beacon-after` setting, the device turns
+//! into a [beacon] until button 1 is pressed.
+//!
+//! Unless the board configuration opts out, the device advertises (and accepts connections) with
+//! a resolvable private address that changes regularly (see [set_privacy]).
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use nrf_softdevice::ble::peripheral;
+use nrf_softdevice::{raw, Softdevice};
+
+use coap_ace_poc_firmware::platform::Thermometer;
+use coap_ace_poc_firmware::{error, info, power, settings};
+
+/// Intervals of the low-power profile in milliseconds, along with how long they are used (in
+/// seconds) before moving on to the next
+const STEPS: [(u16, Option<u16>); 3] = [(100, Some(30)), (1000, Some(120)), (2500, None)];
+
+/// Signaled when the user asks for the device to be found quickly
+pub static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Position in the low-power profile's steps, along with the board's default interval
+pub struct Backoff(usize, Option<u16>);
+
+impl Backoff {
+    pub fn new(default_interval: Option<u16>) -> Self {
+        Self(0, default_interval)
+    }
+
+    /// Return to the fast interval.
+    pub fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    /// Move on to the next longer interval after the current one's time ran out.
+    pub fn next(&mut self) {
+        self.0 = (self.0 + 1).min(STEPS.len() - 1);
+        info!("Advertising interval backs off to {}ms", STEPS[self.0].0);
+    }
+
+    /// Configuration for the next connectable advertisement
+    pub fn config(&self) -> nrf_softdevice::ble::peripheral::Config {
+        let mut config = nrf_softdevice::ble::peripheral::Config::default();
+        let interval = if settings::low_power_advertising() {
+            let (interval, duration) = STEPS[self.0];
+            // in units of 10ms
+            config.timeout = duration.map(|seconds| seconds * 100);
+            Some(interval)
+        } else {
+            settings::advertising_interval().or(self.1)
+        };
+        if let Some(interval) = interval {
+            // in units of 0.625ms
+            config.interval = u32::from(interval) * 8 / 5;
+        }
+        config
+    }
+}
+
+/// Enable LE Privacy, with a resolvable private address that is replaced after `rotation`
+/// seconds, or keep the static address if that is None.
+///
+/// This needs to be called before advertising starts. The softdevice generates the identity
+/// resolving key (IRK) anew at every start, and rotates the address by itself.
+///
+/// Private addresses keep long-term deployments from being tracked by their address; deployments
+/// (like the demo) in which devices are told apart by their address can opt out through the
+/// `address_rotation` field of the board configuration. Peers that want to recognize the device
+/// across rotations need to learn its IRK through bonding, which this firmware does not do;
+/// they can tell devices apart by their name instead. For the same reason, the device has no
+/// identities of peers by which it could resolve their private addresses: Peers are only
+/// identified by the tokens they post, so their addresses are never needed.
+pub fn set_privacy(_sd: &Softdevice, rotation: Option<u16>) {
+    let Some(rotation) = rotation else {
+        info!("Advertising with the static address");
+        return;
+    };
+    let params = raw::ble_gap_privacy_params_t {
+        privacy_mode: raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY as u8,
+        private_addr_type: raw::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE as u8,
+        private_addr_cycle_s: rotation,
+        // Generated by the softdevice
+        p_device_irk: core::ptr::null_mut(),
+    };
+    // SAFETY: The softdevice copies the parameters before returning.
+    let result = unsafe { raw::sd_ble_gap_privacy_set(&params) };
+    if result != raw::NRF_SUCCESS {
+        error!("Failed to enable private addresses: {}", result);
+    }
+}
+
+/// Task waking up the advertisements when button 1 is pressed
+#[embassy_executor::task]
+pub async fn button(mut button: embassy_nrf::gpio::Input<'static>) {
+    loop {
+        // Active low
+        button.wait_for_falling_edge().await;
+        WAKE.signal(());
+    }
+}
+
+/// Advertising interval of the beacon in milliseconds
+const BEACON_INTERVAL: u16 = 10000;
+
+/// Time after which the beacon's telemetry is refreshed, in seconds
+const BEACON_REFRESH: u16 = 60;
+
+/// Build an Eddystone-TLM (unencrypted telemetry) advertisement.
+///
+/// The battery voltage is always sent as 0 (ie. "not supported"), as the boards' supply is not
+/// measured; a temperature of None is sent as -128°C as the format asks for.
+fn telemetry(temperature: Option<fixed::types::I30F2>, advertisements: u32) -> [u8; 25] {
+    // Fixed point 8.8; I30F2 has 2 fractional bits.
+    let temperature = temperature
+        .map(|t| {
+            t.to_bits()
+                .saturating_mul(64)
+                .clamp(i16::MIN.into(), i16::MAX.into()) as i16
+        })
+        .unwrap_or(i16::MIN)
+        .to_be_bytes();
+    let advertisements = advertisements.to_be_bytes();
+    // in units of 0.1s
+    let uptime = ((embassy_time::Instant::now().as_millis() / 100) as u32).to_be_bytes();
+    #[rustfmt::skip]
+    let data = [
+        // Flags
+        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+        // Complete list of 16-bit service UUIDs: Eddystone
+        0x03, 0x03, 0xaa, 0xfe,
+        // Service data of Eddystone: TLM frame, version 0
+        0x11, 0x16, 0xaa, 0xfe, 0x20, 0x00,
+        // Battery voltage
+        0x00, 0x00,
+        temperature[0], temperature[1],
+        advertisements[0], advertisements[1], advertisements[2], advertisements[3],
+        uptime[0], uptime[1], uptime[2], uptime[3],
+    ];
+    data
+}
+
+/// Send non-connectable Eddystone-TLM beacons carrying the device's temperature until button 1 is
+/// pressed.
+///
+/// This is for passive monitoring deployments, where the temperature is read from the air by
+/// anyone in range, and connections are rare: The slow interval uses much less power than
+/// connectable advertisements, at the cost of the device not being connectable until the button
+/// is pressed.
+pub async fn beacon(sd: &Softdevice, thermometer: &impl Thermometer) {
+    info!("No connections for a long time; sending beacons");
+    // Estimated from the interval, as the softdevice does not report the number of advertisements
+    let mut advertisements: u32 = 0;
+    loop {
+        let adv_data = telemetry(thermometer.temperature().ok(), advertisements);
+        let adv = peripheral::NonconnectableAdvertisement::NonscannableUndirected {
+            adv_data: &adv_data,
+        };
+        let config = peripheral::Config {
+            // in units of 0.625ms
+            interval: u32::from(BEACON_INTERVAL) * 8 / 5,
+            // in units of 10ms
+            timeout: Some(BEACON_REFRESH * 100),
+            ..Default::default()
+        };
+        let _advertising = power::track(power::Category::Advertising);
+        match select(peripheral::advertise(sd, adv, &config), WAKE.wait()).await {
+            Either::First(Ok(()) | Err(peripheral::AdvertiseError::Timeout)) => {}
+            Either::First(Err(err)) => {
+                error!("Failed to advertise: {:?}", err);
+                embassy_time::Timer::after_secs(1).await;
+            }
+            Either::Second(()) => {
+                info!("Button pressed, leaving beacon mode");
+                return;
+            }
+        }
+        advertisements = advertisements
+            .wrapping_add(u32::from(BEACON_REFRESH) * 1000 / u32::from(BEACON_INTERVAL));
+    }
+}
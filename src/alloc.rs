@@ -5,13 +5,37 @@
 //!
 //! While this project generally avoids dynamic memory management, dcaf and coset do depend on it
 //! through ciborium.
+//!
+//! Freed memory is wiped: Decoding a token leaves copies of its claims on the heap (in coapcore
+//! as well as in the library's token record), including the OSCORE input material of the ACE
+//! OSCORE profile's `cnf` claim, which would otherwise linger until the memory is reused.
 
 extern crate alloc;
 
+use core::alloc::{GlobalAlloc, Layout};
+
 use embedded_alloc::LlffHeap as Heap;
 
+/// Heap that wipes allocations when they are freed
+struct WipingHeap(Heap);
+
+unsafe impl GlobalAlloc for WipingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.0.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        use zeroize::Zeroize;
+        // SAFETY: The caller guarantees that the allocation is of this layout, and no longer used.
+        unsafe {
+            core::slice::from_raw_parts_mut(ptr, layout.size()).zeroize();
+            self.0.dealloc(ptr, layout)
+        }
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: Heap = Heap::empty();
+static ALLOCATOR: WipingHeap = WipingHeap(Heap::empty());
 
 /// Start the global allocator
 ///
@@ -24,5 +48,10 @@ pub unsafe fn init() {
     // over night. More than 2048 needed when also doing the access token decryption.
     const HEAP_SIZE: usize = 4096;
     static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-    unsafe { ALLOCATOR.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
+    unsafe { ALLOCATOR.0.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
+}
+
+/// Return the number of bytes used and free in the heap.
+pub fn stats() -> (usize, usize) {
+    (ALLOCATOR.0.used(), ALLOCATOR.0.free())
 }
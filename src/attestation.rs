@@ -0,0 +1,178 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Attestation of the running firmware
+//!
+//! Upon a POST of a nonce to the `/attest` resource, the device produces an Entity Attestation
+//! Token (EAT, RFC 9711): a CWT claims set signed as a COSE_Sign1 (ES256) with the device's own
+//! EDHOC private key. A verifier (typically the AS, before it grants sensitive scopes) checks the
+//! signature against the public key it knows from the device's EDHOC credential, and the nonce
+//! against the one it sent, and then decides whether it trusts the code that is running.
+//!
+//! The claims are:
+//!
+//! * `eat_nonce` (10): the nonce as posted.
+//! * `iat` (6): the current time, if the device's clock is set.
+//! * `swname` (270) and `swversion` (271): the firmware's crate name and version.
+//! * Private claims -65537 (the SHA-256 digest of the firmware image in flash, as given in
+//!   [init]), -65538 (the [boot count](crate::settings::boot_count)) and -65539 (the firmware ID
+//!   of the softdevice, as in its info structure).
+//!
+//! The firmware digest covers the application image from its vector table to the end of its
+//! initialized data, but neither the softdevice nor the bootloader. It is calculated when the
+//! first token is requested, which takes a moment.
+//!
+//! The token is only as trustworthy as the key and the code that signs it: Firmware that was
+//! replaced by an attacker who could read the EDHOC key can sign whatever it likes, and there is
+//! no read-out protection keeping an attacker with debug access from doing that. This attests
+//! against accidents (an outdated or wrongly built image), and against attackers who can replace
+//! the firmware without learning the key (eg. through a bootloader).
+//!
+//! Carrying the token in EDHOC's External Authorization Data instead was not possible: coapcore
+//! drives lakers, and offers no hooks for EAD items.
+
+use core::cell::RefCell;
+
+/// Shortest nonce accepted; RFC 9711 requires at least 8 bytes.
+pub const MIN_NONCE_LEN: usize = 8;
+/// Longest nonce accepted; RFC 9711 allows up to 64 bytes.
+pub const MAX_NONCE_LEN: usize = 64;
+
+/// Private claim key for the SHA-256 digest of the firmware image
+const CLAIM_FIRMWARE_DIGEST: i32 = -65537;
+/// Private claim key for the boot count
+const CLAIM_BOOT_COUNT: i32 = -65538;
+/// Private claim key for the softdevice's firmware ID
+const CLAIM_SOFTDEVICE: i32 = -65539;
+
+/// The CBOR encoded protected header: `{1 (alg): -7 (ES256)}`
+const PROTECTED: [u8; 3] = [0xa1, 0x01, 0x26];
+
+struct Platform {
+    image: &'static [u8],
+    /// Calculated from `image` when first needed
+    digest: Option<[u8; 32]>,
+    softdevice: u16,
+}
+
+static PLATFORM: critical_section::Mutex<RefCell<Option<Platform>>> =
+    critical_section::Mutex::new(RefCell::new(None));
+
+/// Provide what is attested about the platform: the firmware image as it is in flash, and the
+/// firmware ID of the softdevice.
+///
+/// Until this is called, attestation is unavailable.
+pub fn init(image: &'static [u8], softdevice: u16) {
+    critical_section::with(|cs| {
+        PLATFORM.borrow_ref_mut(cs).replace(Platform {
+            image,
+            digest: None,
+            softdevice,
+        })
+    });
+}
+
+/// Error type indicating that no token could be produced, because the platform did not call
+/// [init] or the device has no EDHOC key.
+#[derive(Debug)]
+pub struct Unavailable;
+
+/// Return the firmware digest and the softdevice's firmware ID, calculating the former if needed.
+fn platform() -> Result<([u8; 32], u16), Unavailable> {
+    let (image, digest, softdevice) = critical_section::with(|cs| {
+        let platform = PLATFORM.borrow_ref(cs);
+        let platform = platform.as_ref().ok_or(Unavailable)?;
+        Ok((platform.image, platform.digest, platform.softdevice))
+    })?;
+    if let Some(digest) = digest {
+        return Ok((digest, softdevice));
+    }
+    use sha2::Digest;
+    // Hashing the image takes long enough that it is not done in a critical section.
+    let digest: [u8; 32] = sha2::Sha256::digest(image).into();
+    critical_section::with(|cs| {
+        if let Some(platform) = PLATFORM.borrow_ref_mut(cs).as_mut() {
+            platform.digest = Some(digest);
+        }
+    });
+    Ok((digest, softdevice))
+}
+
+/// Longest claims set: the nonce and digest with their heads, and the rest of the claims
+const MAX_CLAIMS_LEN: usize = MAX_NONCE_LEN + 32 + 96;
+
+/// Longest token: the claims along with the COSE_Sign1 structure around them
+pub const MAX_TOKEN_LEN: usize = MAX_CLAIMS_LEN + 64 + 16;
+
+/// Encode the claims set for a nonce, returning its length.
+fn claims(
+    nonce: &[u8],
+    (digest, softdevice): ([u8; 32], u16),
+    out: &mut [u8],
+) -> Result<usize, minicbor::encode::Error<EndOfSlice>> {
+    let now = crate::devicetime::unixtime().ok();
+
+    let mut cursor = minicbor::encode::write::Cursor::new(out);
+    let mut e = minicbor::Encoder::new(&mut cursor);
+    e.map(6 + u64::from(now.is_some()))?;
+    e.u8(10)?.bytes(nonce)?;
+    if let Some(now) = now {
+        e.u8(6)?.u32(now)?;
+    }
+    e.u16(270)?.str(env!("CARGO_PKG_NAME"))?;
+    e.u16(271)?.array(1)?.str(env!("CARGO_PKG_VERSION"))?;
+    e.i32(CLAIM_FIRMWARE_DIGEST)?.bytes(&digest)?;
+    e.i32(CLAIM_BOOT_COUNT)?
+        .u32(crate::settings::boot_count())?;
+    e.i32(CLAIM_SOFTDEVICE)?.u16(softdevice)?;
+    Ok(cursor.position())
+}
+
+type EndOfSlice = minicbor::encode::write::EndOfSlice;
+
+/// Produce an attestation token for a nonce (which the caller ensures to be between
+/// [MIN_NONCE_LEN] and [MAX_NONCE_LEN] bytes long), signed with the EDHOC key of `config`.
+pub fn token(
+    config: &crate::CoapcoreConfig,
+    nonce: &[u8],
+) -> Result<heapless::Vec<u8, MAX_TOKEN_LEN>, Unavailable> {
+    use p256::ecdsa::signature::Signer;
+
+    let key = config.edhoc_q.ok_or(Unavailable)?;
+    let key = p256::ecdsa::SigningKey::from_slice(key).map_err(|_| Unavailable)?;
+    let platform = platform()?;
+
+    let mut payload = [0; MAX_CLAIMS_LEN];
+    let len = claims(nonce, platform, &mut payload).expect("Claims fit the buffer");
+    let payload = &payload[..len];
+
+    // Sig_structure of RFC 9052 Section 4.4, without external AAD
+    let mut to_be_signed = [0; MAX_CLAIMS_LEN + 24];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut to_be_signed[..]);
+    minicbor::Encoder::new(&mut cursor)
+        .array(4)
+        .and_then(|e| {
+            e.str("Signature1")?
+                .bytes(&PROTECTED)?
+                .bytes(&[])?
+                .bytes(payload)
+        })
+        .expect("Structure fits the buffer");
+    let to_be_signed = &to_be_signed[..cursor.position()];
+    let signature: p256::ecdsa::Signature = key.sign(to_be_signed);
+
+    let mut token = [0; MAX_TOKEN_LEN];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut token[..]);
+    minicbor::Encoder::new(&mut cursor)
+        .tag(minicbor::data::Tag::new(18))
+        .and_then(|e| {
+            e.array(4)?
+                .bytes(&PROTECTED)?
+                .map(0)?
+                .bytes(payload)?
+                .bytes(&signature.to_bytes())
+        })
+        .expect("Token fits the buffer");
+    let len = cursor.position();
+    Ok(heapless::Vec::from_slice(&token[..len]).expect("Sizes match"))
+}
@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Record of who changed what on the device
+//!
+//! Every successful request that may change the device's state (a PUT, POST or DELETE that a
+//! resource accepted) is recorded along with the subject of the token it was made with. The most
+//! recent ones are shown in the `/debug/audit` resource.
+//!
+//! Attribution works like the request counting of [crate::tokens]: Before a protected request is
+//! passed to the resource server, [crate::coap_gatt] announces the token posted latest through the
+//! same connection through [set_requester], and the [crate::stats::Metered] wrapper around each
+//! resource calls [record] once the resource accepted the request. Requests that were not
+//! protected (eg. setting the time, which is open to everyone) are recorded without a subject, as
+//! are those made with tokens that carry no `sub` claim.
+//!
+//! The record lives in RAM, and is thus lost at a reset.
+
+use core::cell::{Cell, RefCell};
+
+use crate::tokens::{Recorded, Subject};
+
+/// Number of operations kept (few enough for the report to fit into a response)
+const RECORDED_OPERATIONS: usize = 8;
+
+/// A single state-changing operation
+#[derive(Clone)]
+struct Operation {
+    /// Seconds since boot
+    uptime: u32,
+    /// Subject of the token the request was made with
+    subject: Option<Subject>,
+    /// Request code
+    method: u8,
+    /// Path segments of the resource joined by slashes
+    path: &'static str,
+}
+
+static RECORDED: critical_section::Mutex<RefCell<heapless::Deque<Operation, RECORDED_OPERATIONS>>> =
+    critical_section::Mutex::new(RefCell::new(heapless::Deque::new()));
+
+/// Token of the request that is currently being processed
+static REQUESTER: critical_section::Mutex<Cell<Option<Recorded>>> =
+    critical_section::Mutex::new(Cell::new(None));
+
+/// Set the token to which the request that is processed next is attributed (or None for requests
+/// that were not protected).
+pub fn set_requester(token: Option<Recorded>) {
+    critical_section::with(|cs| REQUESTER.borrow(cs).set(token));
+}
+
+/// Token of the request that is currently being processed, if it was protected
+///
+/// This is the same guess as in [crate::tokens]: It serves attribution, but must not be used to
+/// grant permissions, as a peer can post someone else's token without being able to use it.
+/// Permissions are left to the resource server, which checks them against the scope of the
+/// security context that protected the request.
+pub fn requester() -> Option<Recorded> {
+    critical_section::with(|cs| REQUESTER.borrow(cs).get())
+}
+
+/// Whether requests with that code are recorded
+pub fn changes_state(method: u8) -> bool {
+    use coap_numbers::code::{DELETE, POST, PUT};
+    matches!(method, POST | PUT | DELETE)
+}
+
+/// Record an operation on the resource at `path`, discarding the oldest one if the record is
+/// full.
+pub fn record(method: u8, path: &'static str) {
+    let subject = requester().and_then(crate::tokens::subject);
+    let operation = Operation {
+        uptime: embassy_time::Instant::now().as_secs() as u32,
+        subject,
+        method,
+        path,
+    };
+    critical_section::with(|cs| {
+        let mut recorded = RECORDED.borrow_ref_mut(cs);
+        if recorded.is_full() {
+            recorded.pop_front();
+        }
+        // Can't fail: we just made room
+        let _ = recorded.push_back(operation);
+    });
+}
+
+/// Copy of the recorded operations, oldest first
+///
+/// When encoded into CBOR, this is an array of `[uptime, subject, method, path]` arrays, with the
+/// subject as a text string (or null if unknown) and the method as a CoAP code.
+pub struct Report(heapless::Vec<Operation, RECORDED_OPERATIONS>);
+
+/// Obtain a copy of the currently recorded operations.
+pub fn report() -> Report {
+    critical_section::with(|cs| Report(RECORDED.borrow_ref(cs).iter().cloned().collect()))
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(self.0.len() as u64)?;
+        for operation in self.0.iter() {
+            e.array(4)?.u32(operation.uptime)?;
+            match &operation.subject {
+                Some(subject) => e.str(subject)?,
+                None => e.null()?,
+            };
+            e.u8(operation.method)?.str(operation.path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Battery level, and the power saving that a low one triggers
+//!
+//! The platform measures the supply voltage and passes it in through [measured]; the firmware
+//! does that with the SAADC once a minute. On boards powered from a coin cell (like the nRF52-DK
+//! without USB), the supply voltage is the battery voltage; boards with a regulator (like the
+//! nRF52840 dongle on USB) report the regulated voltage, and thus never appear low.
+//!
+//! Below [LOW_MILLIVOLTS], the device saves power where that costs nothing but looks: the idle LED
+//! level is capped at [SAVING_IDLE_LEVEL], and identify animations are not shown. Power saving
+//! ends only once the voltage recovered by a margin, so that a voltage near the threshold does not
+//! toggle it with every measurement.
+//!
+//! The state is shown in the `/battery` resource.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+/// Supply voltage below which power saving starts, in millivolts
+pub const LOW_MILLIVOLTS: u16 = 2500;
+
+/// Supply voltage above which power saving ends again, in millivolts
+const RECOVERED_MILLIVOLTS: u16 = 2600;
+
+/// Highest idle LED level shown while saving power
+pub const SAVING_IDLE_LEVEL: u8 = 1;
+
+/// Latest measurement in millivolts (0 if none was reported yet)
+static MILLIVOLTS: AtomicU16 = AtomicU16::new(0);
+
+static SAVING: AtomicBool = AtomicBool::new(false);
+
+/// Record a new measurement of the supply voltage.
+///
+/// Returns true if that started or ended power saving, in which case the platform needs to
+/// re-apply the idle LED level.
+pub fn measured(millivolts: u16) -> bool {
+    MILLIVOLTS.store(millivolts, Ordering::Relaxed);
+    let saving = SAVING.load(Ordering::Relaxed);
+    let new_saving = match saving {
+        false => millivolts < LOW_MILLIVOLTS,
+        true => millivolts < RECOVERED_MILLIVOLTS,
+    };
+    if new_saving != saving {
+        SAVING.store(new_saving, Ordering::Relaxed);
+        match new_saving {
+            true => crate::warn!("Battery low ({}mV), saving power", millivolts),
+            false => crate::info!("Battery recovered ({}mV)", millivolts),
+        }
+    }
+    new_saving != saving
+}
+
+/// Latest measurement of the supply voltage in millivolts, if any was made yet
+pub fn millivolts() -> Option<u16> {
+    match MILLIVOLTS.load(Ordering::Relaxed) {
+        0 => None,
+        millivolts => Some(millivolts),
+    }
+}
+
+/// Whether the device is saving power because of a low battery
+pub fn power_saving() -> bool {
+    SAVING.load(Ordering::Relaxed)
+}
+
+/// The idle LED level to show for a configured one, considering power saving
+pub fn idle_level(configured: u8) -> u8 {
+    match power_saving() {
+        true => configured.min(SAVING_IDLE_LEVEL),
+        false => configured,
+    }
+}
+
+/// Snapshot of the battery state
+///
+/// When encoded into CBOR, this is a map containing the supply voltage in millivolts under
+/// `"mv"` (or null if it was not measured yet), and whether power saving is active under
+/// `"saving"`.
+pub struct Report {
+    millivolts: Option<u16>,
+    saving: bool,
+}
+
+/// Obtain the current battery state.
+pub fn report() -> Report {
+    Report {
+        millivolts: millivolts(),
+        saving: power_saving(),
+    }
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(2)?.str("mv")?;
+        match self.millivolts {
+            Some(millivolts) => e.u16(millivolts)?,
+            None => e.null()?,
+        };
+        e.str("saving")?.bool(self.saving)?;
+        Ok(())
+    }
+}
@@ -5,23 +5,44 @@
 
 use core::cell::Cell;
 
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+
+use coap_ace_poc_firmware::battery;
+#[cfg(feature = "ws2812")]
+use coap_ace_poc_firmware::platform::NoColorLeds;
+use coap_ace_poc_firmware::platform::{IdentifyParameters, IdentifyPattern, LedControl, Status};
+
+/// Number of animations that can wait while another one is running
+const QUEUE_LENGTH: usize = 4;
+
+/// Status to show, as set by tasks that run on the interrupt executor
+///
+/// [Leds] can only be used on the thread mode executor, so the Bluetooth tasks signal their
+/// status here instead. It is shown like through [LedControl::show_status].
+pub static STATUS: Signal<CriticalSectionRawMutex, Status> = Signal::new();
+
 /// The collection of device LEDs, along with all it needs to run animations and return to an idle
 /// state again.
 ///
-/// All its operations work on mutable state, allowing LEDs to be accessed from different
-/// components in a system. The actual workhorse implementations are all on [LedPins], on which
-/// they require exclusive access; [core::cell::Cell]s in this struct are used to coordinate
-/// access.
+/// The pins themselves are owned by the [run] task; this struct is the shared front end through
+/// which other components send it animations. All its operations work on shared references,
+/// allowing LEDs to be accessed from different components in a system.
 pub struct Leds {
-    /// GPIO pins representing the LEDs. As these are temporarily handed off to an embassy task
-    /// during identification, they are optional here. (The value being None indicates that the
-    /// task is running.).
-    pins: Cell<Option<LedPins>>,
     /// State that was last set. Mostly merely set in order to be readable again, but this is also
-    /// where the identify task looks up which state to return the LEDs to.
+    /// where the [run] task looks up which state to return the LEDs to after an animation.
     idle_state: Cell<u8>,
-    /// Means to start a task that runs an animation
-    spawner: embassy_executor::Spawner,
+    /// Commands waiting to be processed by the [run] task
+    queue: Channel<NoopRawMutex, Command, QUEUE_LENGTH>,
+    /// The animation the [run] task is currently showing
+    current: Cell<Option<Animation>>,
+    /// Signaled to stop a running identify animation
+    cancel: Signal<NoopRawMutex, ()>,
+    /// Attached RGB LED strip
+    #[cfg(feature = "ws2812")]
+    strip: &'static crate::ws2812::Strip,
 }
 
 pub struct LedPins {
@@ -31,100 +52,320 @@ pub struct LedPins {
     pub l4: embassy_nrf::gpio::Output<'static>,
 }
 
+/// The sequences the [run] task can show
+#[derive(Copy, Clone)]
+enum Animation {
+    Walk,
+    Identify(IdentifyParameters),
+    Failure,
+    Status(Status),
+}
+
+enum Command {
+    /// Apply a changed idle state
+    Idle,
+    Animate(Animation),
+}
+
 impl Leds {
-    pub fn new(spawner: embassy_executor::Spawner, pins: LedPins) -> Self {
+    pub fn new(#[cfg(feature = "ws2812")] strip: &'static crate::ws2812::Strip) -> Self {
         Self {
-            spawner,
-            pins: Cell::new(Some(pins)),
             idle_state: Cell::new(0),
+            queue: Channel::new(),
+            current: Cell::new(None),
+            cancel: Signal::new(),
+            #[cfg(feature = "ws2812")]
+            strip,
         }
     }
 
-    /// Set the number of LEDs to be active when idle.
-    pub fn set_idle(&self, level: u8) {
-        self.idle_state.set(level);
+    /// Light up one LED after the other, for a visual check of the LEDs at startup.
+    pub fn run_walk(&self) {
+        self.animate(Animation::Walk);
+    }
+
+    /// Return the LEDs to the idle level, eg. after [power saving](battery) started or ended.
+    pub fn show_idle(&self) {
+        // If this fails, there are animations queued, and the task returns to the idle state after
+        // them anyway.
+        let _ = self.queue.try_send(Command::Idle);
+    }
 
-        if let Some(mut pins) = self.pins.take() {
-            pins.set_level(level);
-            self.pins.set(Some(pins))
+    fn animate(&self, animation: Animation) {
+        if self.queue.try_send(Command::Animate(animation)).is_err() {
+            coap_ace_poc_firmware::warn!("LED animation queue is full, dropping animation");
         }
     }
+}
+
+impl LedControl for Leds {
+    fn set_idle(&self, level: u8) {
+        self.idle_state.set(level);
+        coap_ace_poc_firmware::settings::set_idle_level(level);
+        self.show_idle();
+    }
 
-    /// Return the number of LEDs active when idle.
-    pub fn idle(&self) -> u8 {
+    fn idle(&self) -> u8 {
         self.idle_state.get()
     }
 
-    /// Run some animation useful for visually identifying a device.
-    ///
-    /// If the animation is already running, this is a no-op.
-    pub fn run_identify(&'static self) {
-        // Discarding result: Either there's a slot free, or we're already identifying.
-        //
-        // Not trying to take the pins out first: If we did, we'd have to return them ourselves
-        // (and a failed spawn doesn't return the token's parts).
-        let _ = defmt::dbg!(self.spawner.spawn(identify(self)));
+    fn run_identify(&'static self, parameters: IdentifyParameters) {
+        if battery::power_saving() {
+            coap_ace_poc_firmware::info!("Saving power, not identifying");
+            return;
+        }
+        self.animate(Animation::Identify(parameters));
+    }
+
+    fn cancel_identify(&self) {
+        if let Some(Animation::Identify(_)) = self.current.get() {
+            self.cancel.signal(());
+        }
+    }
+
+    fn show_failure(&'static self) {
+        self.animate(Animation::Failure);
+    }
+
+    fn show_status(&'static self, status: Status) {
+        // Status patterns are only layered onto an otherwise idle display; they are frequent, and
+        // losing one is not a problem.
+        if self.current.get().is_none() && self.queue.is_empty() {
+            let _ = self
+                .queue
+                .try_send(Command::Animate(Animation::Status(status)));
+        }
+    }
+
+    #[cfg(feature = "ws2812")]
+    fn set_color(&self, color: [u8; 3]) -> Result<(), NoColorLeds> {
+        self.strip.set_color(color);
+        Ok(())
+    }
+
+    #[cfg(feature = "ws2812")]
+    fn color(&self) -> Result<[u8; 3], NoColorLeds> {
+        Ok(self.strip.color())
     }
 }
 
 impl LedPins {
     fn set_level(&mut self, level: u8) {
-        use nrf52832_hal::prelude::OutputPin;
         // `<` rather than `>=`: Pins are active-low.
-        self.l1.set_state((level < 1).into()).unwrap();
-        self.l4.set_state((level < 2).into()).unwrap();
-        self.l3.set_state((level < 3).into()).unwrap();
-        self.l2.set_state((level < 4).into()).unwrap();
+        self.l1.set_level((level < 1).into());
+        self.l4.set_level((level < 2).into());
+        self.l3.set_level((level < 3).into());
+        self.l2.set_level((level < 4).into());
     }
 
-    async fn identify(&mut self) {
-        use embassy_time::Duration;
-        use embassy_time::Timer;
+    async fn identify(&mut self, parameters: IdentifyParameters) {
+        use embassy_time::{Duration, Instant};
 
-        let pause = Duration::from_millis(50);
+        let deadline = parameters
+            .duration
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds.into()));
 
         self.l1.set_high();
         self.l2.set_high();
         self.l3.set_high();
         self.l4.set_high();
 
+        let mut rounds = 0;
+        loop {
+            let done = match deadline {
+                Some(deadline) => Instant::now() >= deadline,
+                None => rounds >= parameters.repeat,
+            };
+            if done {
+                break;
+            }
+            rounds = rounds.saturating_add(1);
+
+            match parameters.pattern {
+                IdentifyPattern::Circle => self.circle().await,
+                IdentifyPattern::Flash => self.flash().await,
+            }
+        }
+    }
+
+    /// Run a single LED around the circle once
+    async fn circle(&mut self) {
+        use embassy_time::Duration;
+        use embassy_time::Timer;
+
+        let pause = Duration::from_millis(50);
+
         // They're numbered line-wise, but we go circular
         let a = &mut self.l1;
         let b = &mut self.l2;
         let c = &mut self.l4;
         let d = &mut self.l3;
 
-        for _ in 0..4 {
-            a.set_low();
-            Timer::after(pause).await;
-            d.set_high();
-            Timer::after(pause).await;
-            b.set_low();
-            Timer::after(pause).await;
-            a.set_high();
-            Timer::after(pause).await;
-            c.set_low();
-            Timer::after(pause).await;
-            b.set_high();
+        a.set_low();
+        Timer::after(pause).await;
+        d.set_high();
+        Timer::after(pause).await;
+        b.set_low();
+        Timer::after(pause).await;
+        a.set_high();
+        Timer::after(pause).await;
+        c.set_low();
+        Timer::after(pause).await;
+        b.set_high();
+        Timer::after(pause).await;
+        d.set_low();
+        Timer::after(pause).await;
+        c.set_high();
+        Timer::after(pause).await;
+    }
+}
+
+impl LedPins {
+    fn set_all(&mut self, on: bool) {
+        for pin in [&mut self.l1, &mut self.l2, &mut self.l3, &mut self.l4] {
+            pin.set_level((!on).into());
+        }
+    }
+
+    /// Flash all LEDs once
+    async fn flash(&mut self) {
+        use embassy_time::{Duration, Timer};
+
+        let pause = Duration::from_millis(200);
+
+        self.set_all(true);
+        Timer::after(pause).await;
+        self.set_all(false);
+        Timer::after(pause).await;
+    }
+
+    /// Blink all LEDs slowly in unison
+    async fn failure(&mut self) {
+        use embassy_time::{Duration, Timer};
+
+        let pause = Duration::from_millis(250);
+
+        for _ in 0..5 {
+            self.set_all(true);
             Timer::after(pause).await;
-            d.set_low();
+            self.set_all(false);
             Timer::after(pause).await;
-            c.set_high();
+        }
+    }
+
+    /// Show a brief pattern that is characteristic of the status
+    ///
+    /// * Advertising: LED 1 flashes once.
+    /// * Connected: LEDs 1 and 2 flash twice.
+    /// * Handshaking: LEDs 3 and 4 flash alternatingly.
+    /// * Authorized: All LEDs are lit for half a second.
+    /// * Error: All LEDs flicker quickly.
+    /// * Expired: All LEDs light up, and go out one after the other from LED 4 to LED 1.
+    async fn status(&mut self, status: Status) {
+        use embassy_time::{Duration, Timer};
+
+        let short = Duration::from_millis(100);
+
+        self.set_all(false);
+        match status {
+            Status::Advertising => {
+                self.l1.set_low();
+                Timer::after(short).await;
+            }
+            Status::Connected => {
+                for _ in 0..2 {
+                    self.l1.set_low();
+                    self.l2.set_low();
+                    Timer::after(short).await;
+                    self.set_all(false);
+                    Timer::after(short).await;
+                }
+            }
+            Status::Handshaking => {
+                for _ in 0..2 {
+                    self.l3.set_low();
+                    Timer::after(short).await;
+                    self.l3.set_high();
+                    self.l4.set_low();
+                    Timer::after(short).await;
+                    self.l4.set_high();
+                }
+            }
+            Status::Authorized => {
+                self.set_all(true);
+                Timer::after(Duration::from_millis(500)).await;
+            }
+            Status::Error => {
+                for _ in 0..3 {
+                    self.set_all(true);
+                    Timer::after(Duration::from_millis(50)).await;
+                    self.set_all(false);
+                    Timer::after(Duration::from_millis(50)).await;
+                }
+            }
+            Status::Expired => {
+                self.set_all(true);
+                for pin in [&mut self.l4, &mut self.l3, &mut self.l2, &mut self.l1] {
+                    Timer::after(Duration::from_millis(300)).await;
+                    pin.set_high();
+                }
+            }
+        }
+    }
+
+    /// Light up each LED once, in the order of their numbering
+    async fn walk(&mut self) {
+        use embassy_time::{Duration, Timer};
+
+        let pause = Duration::from_millis(150);
+
+        self.set_all(false);
+        for pin in [&mut self.l1, &mut self.l2, &mut self.l3, &mut self.l4] {
+            pin.set_low();
             Timer::after(pause).await;
+            pin.set_high();
         }
     }
 }
 
-/// Task for configuring and blinking the board LEDs
+/// Task driving the board LEDs
+///
+/// This owns the pins, and shows the animations sent through `leds` one after the other, returning
+/// to the idle state after each.
 #[embassy_executor::task]
-async fn identify(leds: &'static Leds) {
-    if let Some(mut pins) = leds.pins.take() {
-        pins.identify().await;
-
-        // Pins are not Sync, so we're in a single-threaded setup, which means that we can just set
-        // the level without racing against what happens inside a concurrent set_idle as long as we
-        // don't have an await point between get(), set_level() and returning the pins
-        pins.set_level(leds.idle_state.get());
-        leds.pins.set(Some(pins))
+pub async fn run(leds: &'static Leds, mut pins: LedPins) {
+    let idle_level = || battery::idle_level(leds.idle_state.get());
+    pins.set_level(idle_level());
+
+    loop {
+        let command = match select(leds.queue.receive(), STATUS.wait()).await {
+            Either::First(command) => command,
+            Either::Second(status) => Command::Animate(Animation::Status(status)),
+        };
+        let animation = match command {
+            Command::Idle => {
+                pins.set_level(idle_level());
+                continue;
+            }
+            Command::Animate(animation) => animation,
+        };
+
+        leds.current.set(Some(animation));
+        match animation {
+            Animation::Walk => pins.walk().await,
+            Animation::Identify(parameters) => {
+                // Any earlier cancellation was for an animation that is over by now.
+                leds.cancel.reset();
+                embassy_futures::select::select(pins.identify(parameters), leds.cancel.wait())
+                    .await;
+            }
+            Animation::Failure => pins.failure().await,
+            Animation::Status(status) => pins.status(status).await,
+        }
+        leds.current.set(None);
+        // Statuses are only layered onto an otherwise idle display.
+        STATUS.reset();
+
+        pins.set_level(idle_level());
     }
 }
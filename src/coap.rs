@@ -4,14 +4,47 @@
 //! CoAP handlers for the demo application
 //!
 //! This modules's main entry point is [create_coap_handler], which produces a full handler with
-//! the resources `/time`, `/leds`, `/temp` and `/identify`, all backed by structs of this module,
-//! and `/authz-info`, backed by a resource server.
+//! the resources `/time`, `/time/sync`, `/leds`, `/temp`, `/identify`, `/selftest`, `/attest`,
+//! `/info`, `/config`, `/config/ble`, `/keys/as`, `/stats/resources`, `/stats/power`,
+//! `/stats/crypto`, `/stats/auth`, `/stats/ble`, `/battery`, `/description`, `/debug/loglevel`,
+//! `/debug/log`, `/debug/claims`, `/debug/contexts`, `/debug/echo`, `/debug/sdfault` and
+//! `/debug/audit`, all backed by structs of this module, and `/authz-info`, backed by a resource
+//! server (except for protected GET requests, which [AuthzInfo] answers).
+//!
+//! All resources are wrapped in [crate::stats::Metered] handlers for counting.
+//!
+//! The application resources `/time` (along with `/time/sync`), `/temp`, `/leds` and `/identify`
+//! are only built with their respective `resource-time`, `resource-temp`, `resource-leds` and
+//! `resource-identify` features (all enabled through the default `resources` feature); the other
+//! resources are needed to operate the device and are always present. Resources that are left out
+//! are not listed in `/.well-known/core` either. The application resources carry interface
+//! descriptions (`if`) and resource types (`rt`) there, and are described along with their
+//! methods in `/description`. With the `lwm2m` feature, the LwM2M objects of
+//! [crate::lwm2m] are added.
+//!
+//! All resources respond right away, as [crate::coap_gatt] needs the response before the write
+//! that carried the request is complete.
+//!
+//! Where a resource rejects a request for a reason the client can act on, the error response
+//! carries an RFC 9290 Concise Problem Details payload with a title (through
+//! [Error::with_title]), which the webapp can show instead of the bare code. Other error
+//! responses carry no payload: Those of the resources built on
+//! [coap_handler_implementations::TypeRenderable] only have their code, and the 4.01 Unauthorized
+//! and 4.03 Forbidden responses are produced by coapcore (the former with the request creation
+//! hints that point the client to the AS, which is the actionable part; [crate::coap_gatt] adds a
+//! scope hint for the denied request to them).
+//!
+//! Which of those two a denied request receives is decided in coapcore's `WithPermissions`, before
+//! any handler of this module is involved: Requests without a security context get the 4.01, and
+//! protected requests whose token does not grant the method on the path get the 4.03.
 
 use coap_message::{Code as _, MinimalWritableMessage, MutableWritableMessage, ReadableMessage};
 use coap_message_utils::Error;
 use coap_numbers::code::CHANGED;
 
-pub type CoapHandler = impl coap_handler::Handler;
+#[cfg(feature = "resource-identify")]
+use crate::platform::IdentifyParameters;
+use crate::platform::{LedControl, Thermometer};
 
 /// Resource handler for the [crate::devicetime] UNIX time tracking.
 ///
@@ -23,20 +56,119 @@ pub type CoapHandler = impl coap_handler::Handler;
 ///
 /// As system time is a critical resource in authorization validation, it should not be left
 /// unprotected. It is unprotected in the demo; see the demo's overall documentation for details.
+/// Deployments that share a key with the AS can use [TimeSync] instead, and leave `/time` out of
+/// the unauthenticated scope.
+///
+/// To limit the damage, a PUT can only turn the clock back by a small tolerance (see
+/// [crate::devicetime::advance_unixtime]), and is otherwise rejected with 4.03 Forbidden. Setting
+/// the clock freely takes a POST, which is not in the unauthenticated scope: coapcore only lets it
+/// through if the security context of the request was established with a token that grants POST
+/// on `/time`, which is meant to be in the scope of administrators only. Times the clock can not
+/// express (see [crate::devicetime::LATEST]) are rejected with 4.00 Bad Request.
+#[cfg(feature = "resource-time")]
 struct Time;
 
+#[cfg(feature = "resource-time")]
 impl coap_handler_implementations::TypeRenderable for Time {
     type Get = u32;
     type Put = u32;
-    type Post = ();
+    type Post = u32;
 
     fn get(&mut self) -> Result<Self::Get, u8> {
         crate::devicetime::unixtime().map_err(|_| coap_numbers::code::INTERNAL_SERVER_ERROR)
     }
 
     fn put(&mut self, representation: &Self::Put) -> u8 {
-        crate::devicetime::set_unixtime(*representation);
-        CHANGED
+        use crate::devicetime::Refused;
+        match crate::devicetime::advance_unixtime(*representation) {
+            Ok(()) => CHANGED,
+            Err(Refused::Rewind) => {
+                crate::info!("Refusing to turn the clock back");
+                coap_numbers::code::FORBIDDEN
+            }
+            Err(Refused::OutOfRange) => {
+                crate::info!("Refusing to set the clock to {}", representation);
+                coap_numbers::code::BAD_REQUEST
+            }
+        }
+    }
+
+    fn post(&mut self, representation: &Self::Post) -> u8 {
+        match crate::devicetime::set_unixtime(*representation) {
+            Ok(()) => CHANGED,
+            Err(_) => {
+                crate::info!("Refusing to set the clock to {}", representation);
+                coap_numbers::code::BAD_REQUEST
+            }
+        }
+    }
+}
+
+/// Resource handler for setting the clock through the AS (see [crate::timesync])
+///
+/// A GET produces a new nonce as a CBOR byte string; a PUT of the AS's answer to it (the encoded
+/// COSE_Encrypt0) sets the clock.
+///
+/// ## Security
+///
+/// This needs to be accessible without authorization, as tokens can only be verified once the
+/// clock is set. The protection is in the answer, which only the AS can produce.
+#[cfg(feature = "resource-time")]
+struct TimeSync<R>(R);
+
+#[cfg(feature = "resource-time")]
+impl<R: rand_core::RngCore> coap_handler::Handler for TimeSync<R> {
+    /// The nonce to send, or None if the clock was just set
+    type RequestData = Option<[u8; crate::timesync::NONCE_LEN]>;
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Error> {
+        use coap_message_utils::OptionsExt;
+        use coap_numbers::code::*;
+        request.options().ignore_elective_others()?;
+        match request.code().into() {
+            GET => Ok(Some(crate::timesync::challenge(&mut self.0))),
+            PUT => {
+                crate::timesync::answer(request.payload()).map_err(|_| {
+                    Error::bad_request().with_title("Answer does not match a pending nonce")
+                })?;
+                Ok(None)
+            }
+            _ => Err(Error::method_not_allowed()),
+        }
+    }
+    fn estimate_length(&mut self, _: &Self::RequestData) -> usize {
+        20
+    }
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        nonce: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        use coap_message::OptionNumber;
+        let Some(nonce) = nonce else {
+            response.set_code(M::Code::new(CHANGED)?);
+            return Ok(());
+        };
+        let mut buffer = [0; crate::timesync::NONCE_LEN + 1];
+        let mut cursor = minicbor::encode::write::Cursor::new(&mut buffer[..]);
+        if minicbor::encode(minicbor::bytes::ByteArray::from(nonce), &mut cursor).is_err() {
+            crate::error!("Nonce does not fit its buffer");
+            response.set_code(M::Code::new(coap_numbers::code::INTERNAL_SERVER_ERROR)?);
+            return Ok(());
+        }
+        let length = cursor.position();
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT)?);
+        response.add_option_uint(
+            M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+            60u8,
+        )?;
+        response.set_payload(&buffer[..length])?;
+        Ok(())
     }
 }
 
@@ -45,9 +177,14 @@ impl coap_handler_implementations::TypeRenderable for Time {
 /// Values are read through GET as CBOR bigfloat (through [BigfloatFixedI32]), which is an easy way
 /// to express the underlying sensor's format (quarter degree Celcius) in a self-described way,
 /// especially given that this is a constrained device and the peer is not.
-struct Temperature {
-    softdevice: &'static nrf_softdevice::Softdevice,
-}
+///
+/// As not all CBOR decoders understand bigfloats, builds can pick a different representation
+/// instead: With the `temp-decimal-fraction` feature, values are sent as a CBOR decimal fraction
+/// (through `DecimalFractionFixedI32`), and with `temp-millidegrees` as an integer number of
+/// milli-degrees Celsius (through `MilliFixedI32`). All are sent in the same content format, so
+/// they can not be negotiated through the Accept option.
+#[cfg(feature = "resource-temp")]
+struct Temperature<T: 'static>(&'static T);
 
 /// Newtype around fixed::Fixed expressing it as a bigfloat
 ///
@@ -57,8 +194,18 @@ struct Temperature {
 ///   * either dynamic mantissa length calculations to decide the type (CTZ), or
 ///   * a good estimate for realistic ranges (2**30°C is pretty much out of spec) that allows
 ///     picking a fixed float format (half might suffice, with its 10+1 bit mantissa length).
+#[cfg(all(
+    feature = "resource-temp",
+    not(feature = "temp-decimal-fraction"),
+    not(feature = "temp-millidegrees")
+))]
 struct BigfloatFixedI32<Frac>(fixed::FixedI32<Frac>);
 
+#[cfg(all(
+    feature = "resource-temp",
+    not(feature = "temp-decimal-fraction"),
+    not(feature = "temp-millidegrees")
+))]
 impl<Frac: typenum::ToInt<i32>, C> minicbor::encode::Encode<C> for BigfloatFixedI32<Frac> {
     fn encode<W: minicbor::encode::Write>(
         &self,
@@ -73,18 +220,74 @@ impl<Frac: typenum::ToInt<i32>, C> minicbor::encode::Encode<C> for BigfloatFixed
     }
 }
 
-impl coap_handler_implementations::TypeRenderable for Temperature {
-    type Get = BigfloatFixedI32<fixed::types::extra::U2>;
+/// Newtype around fixed::Fixed expressing it as a decimal fraction
+///
+/// As 2**-n is 5**n * 10**-n, this is exact for any number of fractional bits that does not
+/// overflow the mantissa.
+#[cfg(feature = "temp-decimal-fraction")]
+struct DecimalFractionFixedI32<Frac>(fixed::FixedI32<Frac>);
+
+#[cfg(feature = "temp-decimal-fraction")]
+impl<Frac: typenum::ToInt<i32>, C> minicbor::encode::Encode<C> for DecimalFractionFixedI32<Frac> {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        let e = e.tag(minicbor::data::IanaTag::Decimal)?;
+        let e = e.array(2)?;
+        e.i32(-Frac::to_int())?;
+        e.i64(i64::from(self.0.to_bits()) * 5i64.pow(Frac::to_int() as u32))?;
+        Ok(())
+    }
+}
+
+/// Newtype around fixed::Fixed expressing it as an integer number of thousandths
+///
+/// This is rounded towards negative infinity, but exact for up to 3 fractional bits.
+#[cfg(feature = "temp-millidegrees")]
+struct MilliFixedI32<Frac>(fixed::FixedI32<Frac>);
+
+#[cfg(feature = "temp-millidegrees")]
+impl<Frac: typenum::ToInt<i32>, C> minicbor::encode::Encode<C> for MilliFixedI32<Frac> {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.i64((i64::from(self.0.to_bits()) * 1000) >> Frac::to_int())?;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "temp-decimal-fraction", feature = "temp-millidegrees"))]
+compile_error!("Only one temperature representation can be selected");
+
+#[cfg(all(
+    feature = "resource-temp",
+    not(feature = "temp-decimal-fraction"),
+    not(feature = "temp-millidegrees")
+))]
+use BigfloatFixedI32 as TemperatureValue;
+#[cfg(feature = "temp-decimal-fraction")]
+use DecimalFractionFixedI32 as TemperatureValue;
+#[cfg(feature = "temp-millidegrees")]
+use MilliFixedI32 as TemperatureValue;
+
+#[cfg(feature = "resource-temp")]
+impl<T: Thermometer> coap_handler_implementations::TypeRenderable for Temperature<T> {
+    type Get = TemperatureValue<fixed::types::extra::U2>;
     type Put = ();
     type Post = ();
 
     fn get(&mut self) -> Result<Self::Get, u8> {
-        defmt::info!("Reading temperature");
-        // Note that this blocks for 50ms according to the docs. If softdevice let us use it as
-        // normal in embassy_nrf, we might handle that smarter. (Although coap-handler is not
-        // helpful there yet anyway).
-        Ok(BigfloatFixedI32(
-            nrf_softdevice::temperature_celsius(self.softdevice)
+        crate::info!("Reading temperature");
+        // Note that on the device this blocks for 50ms according to the softdevice docs. If
+        // softdevice let us use it as normal in embassy_nrf, we might handle that smarter.
+        // (Although coap-handler is not helpful there yet anyway).
+        Ok(TemperatureValue(
+            self.0
+                .temperature()
                 .map_err(|_| coap_numbers::code::INTERNAL_SERVER_ERROR)?,
         ))
     }
@@ -93,9 +296,11 @@ impl coap_handler_implementations::TypeRenderable for Temperature {
 /// Resource handler for number of on LEDs active in idle state
 ///
 /// The number can bet GET or PUT as CBOR unsigned integers.
-struct Leds(&'static crate::blink::Leds);
+#[cfg(feature = "resource-leds")]
+struct Leds<L: 'static>(&'static L);
 
-impl coap_handler_implementations::TypeRenderable for Leds {
+#[cfg(feature = "resource-leds")]
+impl<L: LedControl> coap_handler_implementations::TypeRenderable for Leds<L> {
     type Get = u8;
     type Put = u8;
     type Post = ();
@@ -110,88 +315,1110 @@ impl coap_handler_implementations::TypeRenderable for Leds {
     }
 }
 
+/// Resource handler for the color of an attached RGB LED strip
+///
+/// The color can be GET or PUT as a CBOR array of red, green and blue intensity (0 to 255 each).
+/// This is only available with the `ws2812` feature.
+#[cfg(feature = "ws2812")]
+struct Color<L: 'static>(&'static L);
+
+#[cfg(feature = "ws2812")]
+impl<L: LedControl> coap_handler_implementations::TypeRenderable for Color<L> {
+    type Get = [u8; 3];
+    type Put = [u8; 3];
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        self.0
+            .color()
+            .map_err(|_| coap_numbers::code::SERVICE_UNAVAILABLE)
+    }
+
+    fn put(&mut self, value: &[u8; 3]) -> u8 {
+        match self.0.set_color(*value) {
+            Ok(()) => CHANGED,
+            Err(_) => coap_numbers::code::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
 /// Resource handler for making the LEDs blink in order to identifiy the physical device
 ///
-/// The animation sequence is triggered by an empty POST to this resource.
-struct Identify(&'static crate::blink::Leds);
+/// The animation sequence is triggered by a POST to this resource. The payload may be empty (for
+/// the default animation), or a CBOR map with any of these keys:
+///
+/// * `"pattern"`: 0 for a circling LED, 1 for all LEDs flashing,
+/// * `"repeat"`: number of rounds to show the pattern,
+/// * `"duration"`: seconds for which to show the pattern (overriding `"repeat"`; at most
+///   [IdentifyParameters::MAX_DURATION]).
+///
+/// If an animation is already running, the new one is shown after it. A DELETE cancels the
+/// running animation.
+#[cfg(feature = "resource-identify")]
+struct Identify<L: 'static>(&'static L);
+
+/// Parse the payload of a POST to [Identify]
+#[cfg(feature = "resource-identify")]
+fn parse_identify(payload: &[u8]) -> Result<IdentifyParameters, minicbor::decode::Error> {
+    use minicbor::decode::Error;
 
-impl coap_handler::Handler for Identify {
-    type RequestData = ();
+    let mut parameters = IdentifyParameters::default();
+    if payload.is_empty() {
+        return Ok(parameters);
+    }
+
+    let mut decoder = minicbor::Decoder::new(payload);
+    let entries = decoder
+        .map()?
+        .ok_or_else(|| Error::message("Indefinite length map"))?;
+    for _ in 0..entries {
+        match decoder.str()? {
+            "pattern" => {
+                parameters.pattern = decoder
+                    .u8()?
+                    .try_into()
+                    .map_err(|_| Error::message("Unknown pattern"))?;
+            }
+            "repeat" => {
+                parameters.repeat = decoder.u8()?;
+            }
+            "duration" => {
+                let duration = decoder.u16()?;
+                if duration > IdentifyParameters::MAX_DURATION {
+                    return Err(Error::message("Duration too long"));
+                }
+                parameters.duration = Some(duration);
+            }
+            _ => return Err(Error::message("Unknown key")),
+        }
+    }
+    if decoder.position() != payload.len() {
+        return Err(Error::message("Trailing data"));
+    }
+    Ok(parameters)
+}
+
+#[cfg(feature = "resource-identify")]
+impl<L: LedControl> coap_handler::Handler for Identify<L> {
+    /// The response code
+    type RequestData = u8;
     type ExtractRequestError = Error;
     type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
 
-    fn extract_request_data<M: ReadableMessage>(&mut self, request: &M) -> Result<(), Error> {
+    fn extract_request_data<M: ReadableMessage>(&mut self, request: &M) -> Result<u8, Error> {
         use coap_message_utils::OptionsExt;
         use coap_numbers::code::*;
-        if request.code().into() != POST {
-            return Err(Error::method_not_allowed());
+        request.options().ignore_elective_others()?;
+        match request.code().into() {
+            POST => {
+                let parameters = parse_identify(request.payload())
+                    .map_err(|_| Error::bad_request().with_title("Unusable identify parameters"))?;
+                self.0.run_identify(parameters);
+                Ok(CHANGED)
+            }
+            DELETE => {
+                self.0.cancel_identify();
+                Ok(DELETED)
+            }
+            _ => Err(Error::method_not_allowed()),
         }
+    }
+    fn estimate_length(&mut self, _: &u8) -> usize {
+        1
+    }
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        code: u8,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        response.set_code(M::Code::new(code)?);
+        Ok(())
+    }
+}
+
+/// Resource handler for the [crate::selftest]
+///
+/// A GET produces the latest report as a CBOR map (see [crate::selftest::Report]); an empty POST
+/// runs the tests again.
+struct SelfTest<T: 'static, L: 'static, R> {
+    thermometer: &'static T,
+    leds: &'static L,
+    rng: R,
+    config: &'static crate::CoapcoreConfig,
+}
+
+impl<T: Thermometer, L: LedControl, R: rand_core::RngCore> coap_handler::Handler
+    for SelfTest<T, L, R>
+{
+    /// The report to send, or None if the test was just run
+    type RequestData = Option<crate::selftest::Report>;
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Error> {
+        use coap_message_utils::OptionsExt;
+        use coap_numbers::code::*;
         request.options().ignore_elective_others()?;
-        if !request.payload().is_empty() {
-            return Err(Error::bad_request());
+        match request.code().into() {
+            GET => Ok(Some(crate::selftest::last_report().ok_or_else(|| {
+                Error::service_unavailable().with_title("Self-test has not run yet")
+            })?)),
+            POST => {
+                if !request.payload().is_empty() {
+                    return Err(Error::bad_request().with_title("Payload must be empty"));
+                }
+                crate::selftest::run(self.thermometer, &mut self.rng, self.config, self.leds);
+                Ok(None)
+            }
+            _ => Err(Error::method_not_allowed()),
         }
+    }
+    fn estimate_length(&mut self, _: &Self::RequestData) -> usize {
+        32
+    }
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        report: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        use coap_message::OptionNumber;
+        let Some(report) = report else {
+            response.set_code(M::Code::new(CHANGED)?);
+            return Ok(());
+        };
+        let mut buffer = [0; 32];
+        let mut cursor = minicbor::encode::write::Cursor::new(&mut buffer[..]);
+        if minicbor::encode(report, &mut cursor).is_err() {
+            crate::error!("Report does not fit its buffer");
+            response.set_code(M::Code::new(coap_numbers::code::INTERNAL_SERVER_ERROR)?);
+            return Ok(());
+        }
+        let length = cursor.position();
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT)?);
+        response.add_option_uint(
+            M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+            60u8,
+        )?;
+        response.set_payload(&buffer[..length])?;
+        Ok(())
+    }
+}
+
+/// Resource handler for the [attestation](crate::attestation) of the running firmware
+///
+/// A POST of a nonce (as a CBOR byte string) produces a signed attestation token (a COSE_Sign1
+/// tagged CWT, in content format 61) that contains it.
+///
+/// ## Security
+///
+/// The token reveals nothing beyond the firmware's identity, but producing it takes an ECDSA
+/// signature (and hashing the image the first time), so this is meant to be in the scope of the
+/// peers that need to verify the device, typically the AS's own clients.
+struct Attest {
+    config: &'static crate::CoapcoreConfig,
+}
+
+impl coap_handler::Handler for Attest {
+    type RequestData = heapless::Vec<u8, { crate::attestation::MAX_TOKEN_LEN }>;
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Error> {
+        use crate::attestation::{MAX_NONCE_LEN, MIN_NONCE_LEN};
+        use coap_message_utils::OptionsExt;
+        request.options().ignore_elective_others()?;
+        match request.code().into() {
+            coap_numbers::code::POST => (),
+            _ => return Err(Error::method_not_allowed()),
+        }
+        let nonce = minicbor::Decoder::new(request.payload())
+            .bytes()
+            .ok()
+            .filter(|nonce| (MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len()))
+            .ok_or_else(|| {
+                Error::bad_request().with_title("Payload must be a nonce of 8 to 64 bytes")
+            })?;
+        crate::attestation::token(self.config, nonce)
+            .map_err(|_| Error::service_unavailable().with_title("Attestation is not available"))
+    }
+    fn estimate_length(&mut self, token: &Self::RequestData) -> usize {
+        token.len() + 4
+    }
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        token: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        use coap_message::OptionNumber;
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT)?);
+        response.add_option_uint(
+            M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+            61u8,
+        )?;
+        response.set_payload(&token)?;
+        Ok(())
+    }
+}
+
+/// Resource handler for the [configurable](crate::settings::Key::configurable) device settings
+///
+/// A GET produces all settings that have a value as a CBOR map from their names to their numeric
+/// values (see [crate::settings::Config]). A POST of such a map modifies the contained settings;
+/// settings not contained are left alone.
+///
+/// The same handler serves the connection policy in `/config/ble`, with the settings of that
+/// [section](crate::settings::Section).
+///
+/// ## Security
+///
+/// Settings like the temperature calibration affect what all other users see, so this is meant to
+/// be in the scope of administrators only.
+struct Config(crate::settings::Section);
+
+impl coap_handler_implementations::TypeRenderable for Config {
+    type Get = crate::settings::Config;
+    type Put = ();
+    type Post = crate::settings::Config;
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::settings::Config::current(self.0))
+    }
+
+    fn post(&mut self, representation: &Self::Post) -> u8 {
+        match representation.apply(self.0) {
+            Ok(()) => CHANGED,
+            Err(_) => coap_numbers::code::BAD_REQUEST,
+        }
+    }
+}
+
+/// Resource handler for rotating the key shared with the AS (see
+/// [crate::security::rotate_as_key])
+///
+/// A POST of a CBOR array of the new 32 byte key and an overlap window in seconds installs the
+/// key; until the window ends, tokens and security contexts of the previous key remain usable.
+///
+/// ## Security
+///
+/// Whoever can set this key can issue arbitrary tokens for the device, so this is meant to be in
+/// the scope of the AS's own management client only.
+struct AsKey;
+
+impl coap_handler_implementations::TypeRenderable for AsKey {
+    type Get = ();
+    type Put = ();
+    type Post = (minicbor::bytes::ByteArray<32>, u32);
+
+    fn post(&mut self, representation: &Self::Post) -> u8 {
+        let (key, overlap) = representation;
+        crate::security::rotate_as_key(**key, embassy_time::Duration::from_secs((*overlap).into()));
+        CHANGED
+    }
+}
+
+/// Resource handler for the request counters of [crate::stats]
+///
+/// The counters are read through GET as a CBOR map as described at [crate::stats::Report].
+///
+/// ## Security
+///
+/// The counters tell how the device is used by all peers, so this is meant to be in the scope of
+/// administrators only.
+struct Stats;
+
+impl coap_handler_implementations::TypeRenderable for Stats {
+    type Get = crate::stats::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::stats::report())
+    }
+}
+
+/// Resource handler for the duty cycle estimates of [crate::power]
+///
+/// The estimates are read through GET as a CBOR map as described at [crate::power::Report].
+struct Power;
+
+impl coap_handler_implementations::TypeRenderable for Power {
+    type Get = crate::power::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::power::report())
+    }
+}
+
+/// Resource handler for the durations of cryptographic operations of [crate::latency]
+///
+/// The durations are read through GET as a CBOR map as described at [crate::latency::Report].
+struct Crypto;
+
+impl coap_handler_implementations::TypeRenderable for Crypto {
+    type Get = crate::latency::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::latency::report())
+    }
+}
+
+/// Resource handler for the failed authentication counters of [crate::lockout]
+///
+/// The counters are read through GET as a CBOR map as described at [crate::lockout::Report].
+///
+/// ## Security
+///
+/// The counters reveal the addresses of peers that failed to authenticate, so this is meant to be
+/// in the scope of administrators only.
+struct Auth;
+
+impl coap_handler_implementations::TypeRenderable for Auth {
+    type Get = crate::lockout::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::lockout::report())
+    }
+}
+
+/// Resource handler for the disconnect counters of [crate::disconnects]
+///
+/// The counters are read through GET as a CBOR map as described at [crate::disconnects::Report].
+struct Disconnects;
+
+impl coap_handler_implementations::TypeRenderable for Disconnects {
+    type Get = crate::disconnects::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::disconnects::report())
+    }
+}
+
+/// Resource handler for the firmware versions of [crate::rollback]
+///
+/// The versions are read through GET as a CBOR map as described at [crate::rollback::Report].
+struct Info;
+
+impl coap_handler_implementations::TypeRenderable for Info {
+    type Get = crate::rollback::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::rollback::report())
+    }
+}
+
+/// Resource handler for the battery state of [crate::battery]
+///
+/// The state is read through GET as a CBOR map as described at [crate::battery::Report].
+struct Battery;
+
+impl coap_handler_implementations::TypeRenderable for Battery {
+    type Get = crate::battery::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::battery::report())
+    }
+}
+
+/// Resource handler for GET requests on `/authz-info`, reporting the requester's token
+///
+/// Tokens are POSTed to `/authz-info` unprotected, which the resource server processes before
+/// the request reaches any handler here. A GET protected by the security context that a token
+/// established reaches this instead, and produces what the client learned when the token was
+/// accepted (see [crate::tokens::Installed]), so that it can decide whether to get a new token
+/// before it attempts an operation. The result is null if the token is not recorded (anymore).
+///
+/// ## Security
+///
+/// Like any protected request, this is only processed if the token's scope allows GET on
+/// `/authz-info`; clients whose tokens lack that learn the expiry when POSTing the token.
+struct AuthzInfo;
+
+impl coap_handler_implementations::TypeRenderable for AuthzInfo {
+    type Get = Option<crate::tokens::Installed>;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::audit::requester().and_then(crate::tokens::installed))
+    }
+}
 
-        self.0.run_identify();
+/// Resource type of the temperature resource
+const RT_TEMPERATURE: &str = "urn:x-coap-ace-poc:temperature";
+/// Resource type of the idle LED level resource
+const RT_LEDS: &str = "urn:x-coap-ace-poc:led-level";
+/// Resource type of the LED strip color resource
+const RT_COLOR: &str = "urn:x-coap-ace-poc:color";
+/// Resource type of the identify resource
+const RT_IDENTIFY: &str = "urn:x-coap-ace-poc:identify";
+/// Resource type of the UNIX time resource
+const RT_TIME: &str = "urn:x-coap-ace-poc:unixtime";
+/// Resource type of the battery resource
+const RT_BATTERY: &str = "urn:x-coap-ace-poc:battery";
+
+/// A resource that generic clients can render a control for, as listed in the [Description]
+struct Control {
+    href: &'static str,
+    /// Interface description (`if`), as in `/.well-known/core`
+    interface: &'static str,
+    /// Resource type (`rt`), as in `/.well-known/core`
+    resource_type: &'static str,
+    /// Methods the resource implements, as a REST-method-set of RFC 9237
+    methods: u8,
+}
 
+/// The application resources, in the order in which clients should show them
+const CONTROLS: &[Control] = &[
+    #[cfg(feature = "resource-temp")]
+    Control {
+        href: "/temp",
+        interface: "core.s",
+        resource_type: RT_TEMPERATURE,
+        methods: 1,
+    },
+    Control {
+        href: "/battery",
+        interface: "core.s",
+        resource_type: RT_BATTERY,
+        methods: 1,
+    },
+    #[cfg(feature = "resource-leds")]
+    Control {
+        href: "/leds",
+        interface: "core.a",
+        resource_type: RT_LEDS,
+        methods: 1 | 4,
+    },
+    #[cfg(feature = "ws2812")]
+    Control {
+        href: "/leds/color",
+        interface: "core.a",
+        resource_type: RT_COLOR,
+        methods: 1 | 4,
+    },
+    #[cfg(feature = "resource-identify")]
+    Control {
+        href: "/identify",
+        interface: "core.a",
+        resource_type: RT_IDENTIFY,
+        methods: 2 | 8,
+    },
+    #[cfg(feature = "resource-time")]
+    Control {
+        href: "/time",
+        interface: "core.p",
+        resource_type: RT_TIME,
+        methods: 1 | 4,
+    },
+];
+
+/// Resource handler for a machine-readable description of the application resources
+///
+/// A GET produces a CBOR array with an entry per resource that a generic client can render a
+/// control for, each an array of its path, interface description (`core.s` for sensors,
+/// `core.a` for actuators and `core.p` for parameters), resource type and the methods it
+/// implements (as a REST-method-set). All values are CBOR encoded (content format 60). The same
+/// interface descriptions and resource types are in `/.well-known/core`, which only lists paths
+/// and attributes, though; this adds the methods, and leaves out the operational resources.
+///
+/// This is a compact stand-in for a WoT Thing Description, which would not fit into a single
+/// response without block-wise transfer.
+struct Description;
+
+/// The encoded [CONTROLS]
+struct Controls;
+
+impl<C> minicbor::encode::Encode<C> for Controls {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(CONTROLS.len() as u64)?;
+        for control in CONTROLS {
+            e.array(4)?
+                .str(control.href)?
+                .str(control.interface)?
+                .str(control.resource_type)?
+                .u8(control.methods)?;
+        }
         Ok(())
     }
-    fn estimate_length(&mut self, _: &()) -> usize {
+}
+
+impl coap_handler_implementations::TypeRenderable for Description {
+    type Get = Controls;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(Controls)
+    }
+}
+
+/// Resource handler for the runtime log filter of [crate::logging]
+///
+/// The most verbose level that gets logged can be GET or PUT as a CBOR unsigned integer, using
+/// the numeric values of [crate::logging::Level] (0 for off up to 5 for trace).
+///
+/// ## Security
+///
+/// Verbose logs may contain details of other peers' interactions, and excessive logging can slow
+/// the device down, so this is meant to be in the scope of administrators only.
+struct LogLevel;
+
+impl coap_handler_implementations::TypeRenderable for LogLevel {
+    type Get = u8;
+    type Put = u8;
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::logging::max_level() as u8)
+    }
+
+    fn put(&mut self, value: &u8) -> u8 {
+        match crate::logging::Level::try_from(*value) {
+            Ok(level) => {
+                crate::logging::set_max_level(level);
+                CHANGED
+            }
+            Err(_) => coap_numbers::code::BAD_REQUEST,
+        }
+    }
+}
+
+/// Resource handler for the recently logged warnings and errors (see [crate::logging])
+///
+/// The events are read through GET as a CBOR array as described at
+/// [crate::logging::RecordedEvents]; it contains the newest events that fit into a single response.
+///
+/// ## Security
+///
+/// Like [LogLevel], this is meant to be in the scope of administrators only.
+struct Log;
+
+impl coap_handler_implementations::TypeRenderable for Log {
+    type Get = crate::logging::RecordedEvents;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::logging::recorded())
+    }
+}
+
+/// Resource handler for the claims of the accepted tokens (see [crate::tokens])
+///
+/// The claims are read through GET as a CBOR array as described at [crate::tokens::Report].
+///
+/// ## Security
+///
+/// This tells who may do what on the device, so like [LogLevel], it is meant to be in the scope
+/// of administrators only.
+struct Claims;
+
+impl coap_handler_implementations::TypeRenderable for Claims {
+    type Get = crate::tokens::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::tokens::report())
+    }
+}
+
+/// Resource handler for the record of state-changing operations (see [crate::audit])
+///
+/// The operations are read through GET as a CBOR array as described at [crate::audit::Report].
+///
+/// ## Security
+///
+/// This tells who did what on the device, so like [Claims], it is meant to be in the scope of
+/// administrators only.
+struct Audit;
+
+impl coap_handler_implementations::TypeRenderable for Audit {
+    type Get = crate::audit::Report;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::audit::report())
+    }
+}
+
+/// Resource handler for dropping the established security contexts
+///
+/// A DELETE drops all security contexts and accepted tokens (see [crate::security::revoke_all]),
+/// so that clients that got out of sync with the device can start over without a reboot; the
+/// response to the DELETE itself is still protected with the requester's context.
+///
+/// The contexts can neither be listed nor dropped individually, as coapcore keeps them to itself.
+///
+/// ## Security
+///
+/// This affects all peers, so it is meant to be in the scope of administrators only.
+struct Contexts;
+
+impl coap_handler::Handler for Contexts {
+    /// The response code
+    type RequestData = u8;
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(&mut self, request: &M) -> Result<u8, Error> {
+        use coap_message_utils::OptionsExt;
+        use coap_numbers::code::*;
+        request.options().ignore_elective_others()?;
+        match request.code().into() {
+            DELETE => {
+                crate::security::revoke_all();
+                Ok(DELETED)
+            }
+            _ => Err(Error::method_not_allowed()),
+        }
+    }
+    fn estimate_length(&mut self, _: &u8) -> usize {
         1
     }
     fn build_response<M: MutableWritableMessage>(
         &mut self,
         response: &mut M,
-        _: (),
+        code: u8,
     ) -> Result<(), Self::BuildResponseError<M>> {
-        response.set_code(M::Code::new(CHANGED)?);
+        response.set_code(M::Code::new(code)?);
         Ok(())
     }
 }
 
-/// Create a tree of CoAP resource as described in this module's documentation out of the
-/// individual handler implementations in this module.
+/// Resource handler that echoes requests, for end-to-end tests
 ///
-/// The tree also features a `/.well-known/core` resource listing the other resources.
-pub fn create_coap_handler(
-    softdevice: &'static nrf_softdevice::Softdevice,
-    leds: &'static crate::blink::Leds,
-) -> CoapHandler {
-    use coap_handler_implementations::HandlerBuilder;
-    use coap_handler_implementations::ReportingHandlerBuilder;
+/// A POST is answered with a 2.05 Content response that carries the request's payload and
+/// Content-Format option unchanged. This lets tests of the webapp and the AS check that a payload
+/// makes it through the whole chain (including OSCORE) and back.
+struct Echo;
 
-    // Going through TypeHandler is not particularly slim on message sizes, given it adds ETag
-    // and Block2 unconditionally, but that could be fixed there on the long run (with a somewhat
-    // improved MutableWritableMessage, or better bounds on CBOR serialization size)
-    let time_handler = coap_handler_implementations::TypeHandler::new_minicbor(Time);
+impl coap_handler::Handler for Echo {
+    /// The Content-Format (if any) and payload to send back
+    type RequestData = (Option<u16>, heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }>);
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Error> {
+        use coap_message::MessageOption;
+        use coap_numbers::code::POST;
+        let mut format = None;
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::CONTENT_FORMAT => {
+                    format = Some(option.value_uint().ok_or_else(Error::bad_request)?)
+                }
+                // Critical options have odd numbers.
+                number if number & 1 == 1 => return Err(Error::bad_option(number)),
+                _ => (),
+            }
+        }
+        match request.code().into() {
+            // The payload is part of a message no longer than the buffer.
+            POST => Ok((
+                format,
+                heapless::Vec::from_slice(request.payload()).map_err(|_| Error::bad_request())?,
+            )),
+            _ => Err(Error::method_not_allowed()),
+        }
+    }
+    fn estimate_length(&mut self, (_, payload): &Self::RequestData) -> usize {
+        payload.len() + 4
+    }
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        (format, payload): Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        use coap_message::OptionNumber;
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT)?);
+        if let Some(format) = format {
+            response.add_option_uint(
+                M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+                format,
+            )?;
+        }
+        response.set_payload(&payload)?;
+        Ok(())
+    }
+}
+
+/// Resource handler for the fatal fault that caused the latest reset (see [crate::faults])
+///
+/// The fault is read through GET as a CBOR map as described at [crate::faults::Fault], or as CBOR
+/// null if the device did not reset after a fault since it was powered up.
+///
+/// ## Security
+///
+/// Like [LogLevel], this is meant to be in the scope of administrators only.
+struct LastFault;
+
+impl coap_handler_implementations::TypeRenderable for LastFault {
+    type Get = Option<crate::faults::Fault>;
+    type Put = ();
+    type Post = ();
+
+    fn get(&mut self) -> Result<Self::Get, u8> {
+        Ok(crate::faults::last())
+    }
+}
+
+/// Builder for a tree of CoAP resources
+///
+/// Each resource added through [at](Self::at) is counted in [crate::stats] and listed in
+/// `/.well-known/core`. Access to it is controlled by the surrounding resource server through the
+/// path alone: Peers may use the methods that their token's AIF scope lists for the path (eg.
+/// `["/debug/log", 1]` for GET on `/debug/log`), so no further wiring is needed.
+///
+/// The built-in resources are declared in [crate::rs_configuration::resources]. Integrators adding
+/// their own resources can start from [builtin_resources], and pass a function that finishes their
+/// tree to [crate::build_main_rs] in place of [create_coap_handler].
+pub struct ResourceTree<H>(H);
 
-    let identify_handler = Identify(leds);
+impl<H: coap_handler::Handler + coap_handler::Reporting> ResourceTree<H> {
+    /// Add a resource.
+    ///
+    /// The resource is served at its path, and counted under its name. The attributes are
+    /// reported in `/.well-known/core`.
+    pub fn at<R: coap_handler::Handler>(
+        self,
+        resource: crate::rs_configuration::Resource,
+        attributes: &'static [coap_handler::Attribute],
+        handler: R,
+    ) -> ResourceTree<impl coap_handler::Handler + coap_handler::Reporting> {
+        use coap_handler_implementations::HandlerBuilder;
 
-    let temperature_handler =
-        coap_handler_implementations::TypeHandler::new_minicbor_0_24(Temperature { softdevice });
+        ResourceTree(self.0.at(
+            resource.path,
+            crate::stats::Metered::new(
+                resource.name,
+                coap_handler_implementations::wkc::ConstantSingleRecordReport::new(
+                    handler, attributes,
+                ),
+            ),
+        ))
+    }
 
-    let leds_handler = coap_handler_implementations::TypeHandler::new_minicbor_0_24(Leds(leds));
+    /// Complete the tree with the `/.well-known/core` resource.
+    pub fn finish(self) -> impl coap_handler::Handler {
+        use coap_handler_implementations::ReportingHandlerBuilder;
+
+        self.0.with_wkc()
+    }
+}
+
+/// Create the tree of CoAP resources served in safe mode, which only contains `/info`.
+///
+/// ## Security
+///
+/// In safe mode, no keys are trusted, so there is no resource server around this, and anyone can
+/// read the firmware versions. The platform serves provisioning next to it (see
+/// [crate::provisioning]).
+pub fn create_safe_mode_handler() -> impl coap_handler::Handler {
+    use crate::rs_configuration::resources;
+    use coap_handler::Attribute::Ct;
+    use coap_handler_implementations::TypeHandler;
+
+    resource_tree()
+        .at(
+            resources::info,
+            &[Ct(60)],
+            TypeHandler::new_minicbor_0_24(Info),
+        )
+        .finish()
+}
+
+/// Start a resource tree without any resources.
+pub fn resource_tree() -> ResourceTree<impl coap_handler::Handler + coap_handler::Reporting> {
+    ResourceTree(coap_handler_implementations::new_dispatcher())
+}
 
-    // Why isn't TypeHandler Reporting?
-    let time_handler = coap_handler_implementations::wkc::ConstantSingleRecordReport::new(
-        time_handler,
-        &[coap_handler::Attribute::Ct(60)],
+/// Start a resource tree with the resources described in this module's documentation.
+pub fn builtin_resources<T: Thermometer, L: LedControl, R: rand_core::RngCore + Clone + 'static>(
+    config: &'static crate::CoapcoreConfig,
+    thermometer: &'static T,
+    leds: &'static L,
+    rng: R,
+) -> ResourceTree<impl coap_handler::Handler + coap_handler::Reporting> {
+    use crate::rs_configuration::resources;
+    use coap_handler::Attribute::{Ct, Interface, ResourceType};
+    use coap_handler_implementations::TypeHandler;
+
+    // Going through TypeHandler is not particularly slim on message sizes, given it adds ETag
+    // and Block2 unconditionally, but that could be fixed there on the long run (with a somewhat
+    // improved MutableWritableMessage, or better bounds on CBOR serialization size)
+    let tree = resource_tree();
+    #[cfg(feature = "ws2812")]
+    let tree = tree.at(
+        resources::leds_color,
+        &[Ct(60), Interface("core.a"), ResourceType(RT_COLOR)],
+        TypeHandler::new_minicbor_0_24(Color(leds)),
+    );
+
+    // Fully unprotected in the demo only
+    #[cfg(feature = "resource-time")]
+    let tree = tree.at(
+        resources::time,
+        &[Ct(60), Interface("core.p"), ResourceType(RT_TIME)],
+        TypeHandler::new_minicbor(Time),
+    );
+    #[cfg(feature = "resource-time")]
+    let tree = tree.at(resources::time_sync, &[Ct(60)], TimeSync(rng.clone()));
+    #[cfg(feature = "resource-leds")]
+    let tree = tree.at(
+        resources::leds,
+        &[Ct(60), Interface("core.a"), ResourceType(RT_LEDS)],
+        TypeHandler::new_minicbor_0_24(Leds(leds)),
     );
-    let temperature_handler = coap_handler_implementations::wkc::ConstantSingleRecordReport::new(
-        temperature_handler,
-        &[coap_handler::Attribute::Ct(60)],
+    #[cfg(feature = "resource-temp")]
+    let tree = tree.at(
+        resources::temp,
+        &[Ct(60), Interface("core.s"), ResourceType(RT_TEMPERATURE)],
+        TypeHandler::new_minicbor_0_24(Temperature(thermometer)),
     );
-    let leds_handler = coap_handler_implementations::wkc::ConstantSingleRecordReport::new(
-        leds_handler,
-        &[coap_handler::Attribute::Ct(60)],
+    #[cfg(feature = "lwm2m")]
+    let tree = {
+        use crate::lwm2m::{Item, Resource};
+        let resource = |item| Resource {
+            item,
+            thermometer,
+            leds,
+        };
+        tree.at(
+            resources::device_manufacturer,
+            &[Ct(0)],
+            resource(Item::Manufacturer),
+        )
+        .at(
+            resources::device_model_number,
+            &[Ct(0)],
+            resource(Item::ModelNumber),
+        )
+        .at(
+            resources::device_firmware_version,
+            &[Ct(0)],
+            resource(Item::FirmwareVersion),
+        )
+        .at(
+            resources::device_power_source_voltage,
+            &[Ct(0)],
+            resource(Item::PowerSourceVoltage),
+        )
+        .at(
+            resources::device_current_time,
+            &[Ct(0)],
+            resource(Item::CurrentTime),
+        )
+        .at(
+            resources::temperature_sensor_value,
+            &[Ct(0)],
+            resource(Item::SensorValue),
+        )
+        .at(
+            resources::temperature_sensor_units,
+            &[Ct(0)],
+            resource(Item::SensorUnits),
+        )
+        .at(
+            resources::light_control_on_off,
+            &[Ct(0)],
+            resource(Item::OnOff),
+        )
+    };
+    #[cfg(feature = "resource-identify")]
+    let tree = tree.at(
+        resources::identify,
+        &[Interface("core.a"), ResourceType(RT_IDENTIFY)],
+        Identify(leds),
     );
-    let identify_handler =
-        coap_handler_implementations::wkc::ConstantSingleRecordReport::new(identify_handler, &[]);
-
-    coap_handler_implementations::new_dispatcher()
-        // Fully unprotected in the demo only
-        .at(&["time"], time_handler)
-        .at(&["leds"], leds_handler)
-        .at(&["temp"], temperature_handler)
-        .at(&["identify"], identify_handler)
-        .with_wkc()
+
+    tree.at(
+        resources::selftest,
+        &[Ct(60)],
+        SelfTest {
+            thermometer,
+            leds,
+            rng,
+            config,
+        },
+    )
+    .at(resources::attest, &[Ct(61)], Attest { config })
+    .at(
+        resources::info,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(Info),
+    )
+    .at(
+        resources::config,
+        &[Ct(60), Interface("core.p")],
+        TypeHandler::new_minicbor_0_24(Config(crate::settings::Section::General)),
+    )
+    .at(
+        resources::config_ble,
+        &[Ct(60), Interface("core.p")],
+        TypeHandler::new_minicbor_0_24(Config(crate::settings::Section::Ble)),
+    )
+    .at(
+        resources::keys_as,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(AsKey),
+    )
+    .at(
+        resources::stats_resources,
+        &[Ct(60), Interface("core.rp")],
+        TypeHandler::new_minicbor_0_24(Stats),
+    )
+    .at(
+        resources::stats_power,
+        &[Ct(60), Interface("core.rp")],
+        TypeHandler::new_minicbor_0_24(Power),
+    )
+    .at(
+        resources::stats_crypto,
+        &[Ct(60), Interface("core.rp")],
+        TypeHandler::new_minicbor_0_24(Crypto),
+    )
+    .at(
+        resources::stats_auth,
+        &[Ct(60), Interface("core.rp")],
+        TypeHandler::new_minicbor_0_24(Auth),
+    )
+    .at(
+        resources::stats_ble,
+        &[Ct(60), Interface("core.rp")],
+        TypeHandler::new_minicbor_0_24(Disconnects),
+    )
+    .at(
+        resources::battery,
+        &[Ct(60), Interface("core.s"), ResourceType(RT_BATTERY)],
+        TypeHandler::new_minicbor_0_24(Battery),
+    )
+    .at(
+        resources::description,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(Description),
+    )
+    .at(
+        resources::authz_info,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(AuthzInfo),
+    )
+    .at(
+        resources::debug_loglevel,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(LogLevel),
+    )
+    .at(
+        resources::debug_log,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(Log),
+    )
+    .at(
+        resources::debug_claims,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(Claims),
+    )
+    .at(resources::debug_contexts, &[], Contexts)
+    .at(resources::debug_echo, &[], Echo)
+    .at(
+        resources::debug_sdfault,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(LastFault),
+    )
+    .at(
+        resources::debug_audit,
+        &[Ct(60)],
+        TypeHandler::new_minicbor_0_24(Audit),
+    )
+}
+
+/// Create a tree of CoAP resource as described in this module's documentation out of the
+/// individual handler implementations in this module.
+///
+/// The tree also features a `/.well-known/core` resource listing the other resources.
+///
+/// ## Security
+///
+/// The `/.well-known/core` report is not filtered by the requester's permissions (the handlers in
+/// here never get to see those; authorization happens in the surrounding
+/// [coapcore::OscoreEdhocHandler]). Instead, the whole report is gated: It is only reachable for
+/// peers whose scope explicitly contains it, which is never the case for unauthenticated peers.
+pub fn create_coap_handler<
+    T: Thermometer,
+    L: LedControl,
+    R: rand_core::RngCore + Clone + 'static,
+>(
+    config: &'static crate::CoapcoreConfig,
+    thermometer: &'static T,
+    leds: &'static L,
+    rng: R,
+) -> impl coap_handler::Handler {
+    builtin_resources(config, thermometer, leds, rng).finish()
+}
+
+#[cfg(all(test, feature = "resource-identify"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identify_parameters() {
+        let default = parse_identify(b"").unwrap();
+        assert_eq!(default.pattern, crate::platform::IdentifyPattern::Circle);
+        assert_eq!((default.repeat, default.duration), (4, None));
+
+        // `{"pattern": 1, "repeat": 2}`
+        let parameters = parse_identify(b"\xa2\x67pattern\x01\x66repeat\x02").unwrap();
+        assert_eq!(parameters.pattern, crate::platform::IdentifyPattern::Flash);
+        assert_eq!((parameters.repeat, parameters.duration), (2, None));
+
+        // `{"duration": 120}`
+        let parameters = parse_identify(b"\xa1\x68duration\x18\x78").unwrap();
+        assert_eq!(parameters.duration, Some(IdentifyParameters::MAX_DURATION));
+
+        for payload in [
+            // `{"duration": 121}`
+            &b"\xa1\x68duration\x18\x79"[..],
+            // `{"pattern": 2}`
+            b"\xa1\x67pattern\x02",
+            // `{"speed": 1}`
+            b"\xa1\x65speed\x01",
+            // `{_ }`
+            b"\xbf\xff",
+            // `{}` followed by `0`
+            b"\xa0\x00",
+            // `[]`
+            b"\x80",
+        ] {
+            assert!(
+                parse_identify(payload).is_err(),
+                "{payload:02x?} was accepted"
+            );
+        }
+    }
 }
@@ -14,10 +14,22 @@
 //!
 //! The module's simplicity is also due to all the message parsing being delegated to the
 //! [coap_gatt_utils] module. In fact, this module might move in there over time.
+//!
+//! Observe is not supported; clients poll instead. Neither is block-wise transfer: Every response
+//! is complete in a single message.
+//!
+//! Requests longer than [crate::MAX_MESSAGE_LEN] are answered with 4.13 Request Entity Too Large,
+//! with the longest payload that would fit along with the request's options in the Size1 option,
+//! so that clients learn how much to shorten it by without trial and error. They are rejected
+//! before parsing (only their option headers are walked to find the payload), so neither the
+//! parser nor the resource server see data beyond the buffer size they are built for. Writes that
+//! do not parse as a message at all are answered with 4.00 Bad Request.
 
 use coap_handler::Handler;
 use coap_message::error::RenderableOnMinimal;
-use coap_message::MinimalWritableMessage;
+use coap_message::{MessageOption, MinimalWritableMessage, ReadableMessage};
+
+use crate::platform::Status;
 
 /// State held inside a single connection
 ///
@@ -50,15 +62,92 @@ use coap_message::MinimalWritableMessage;
 /// Bluetooth connection, or a CoAP request on a different transport altogether), it's OK for it to
 /// return None: Requests arriving during that time will just receive a 5.03 Service Unavailable
 /// response, and clients are free to retry immediately.
-pub struct Connection {
+pub struct Connection<'a, H> {
     /// An accessor to a ResourceServer
-    rs: &'static crate::Rs,
+    rs: &'a crate::Rs<H>,
+    /// Status reached through the latest request, if it was noteworthy
+    status: Option<Status>,
+    /// Outcome of the latest request, if it was an attempt to authenticate
+    attempt: Option<crate::lockout::Attempt>,
+    /// Whether a protected request succeeded since the latest token was posted, and that token
+    /// grants administrative access
+    admin: bool,
+    /// The token posted latest through this connection, to which protected requests are
+    /// attributed
+    token: Option<crate::tokens::Recorded>,
+    /// When the EDHOC exchange in progress through this connection started, until a protected
+    /// request shows that it completed
+    handshake: Option<embassy_time::Instant>,
+}
+
+/// Security setup steps that can be recognized from the outside of the resource server
+#[derive(Copy, Clone)]
+enum Step {
+    Edhoc,
+    Token,
+}
+
+impl Step {
+    fn classify<M: ReadableMessage>(request: &M) -> Option<Self> {
+        let mut path = request
+            .options()
+            .filter(|o| o.number() == coap_numbers::option::URI_PATH)
+            .map(|o| o.value());
+        match (path.next(), path.next(), path.next()) {
+            (Some(b".well-known"), Some(b"edhoc"), None) => Some(Step::Edhoc),
+            (Some(b"authz-info"), None, None) => Some(Step::Token),
+            _ => None,
+        }
+    }
 }
 
 // This will do more once a future version of CoAP-over-GATT is used
-impl Connection {
-    pub fn new(rs: &'static crate::Rs) -> Self {
-        Self { rs }
+impl<'a, H: Handler + crate::security::Previous> Connection<'a, H> {
+    pub fn new(rs: &'a crate::Rs<H>) -> Self {
+        Self {
+            rs,
+            status: None,
+            attempt: None,
+            admin: false,
+            token: None,
+            handshake: None,
+        }
+    }
+
+    /// When the peer started an EDHOC exchange that it did not complete yet
+    ///
+    /// An exchange counts as complete once a protected request succeeded (EDHOC message 3 is
+    /// often combined with the first of them). Platforms close connections whose exchange took
+    /// longer than the [handshake timeout](crate::settings::handshake_timeout).
+    pub fn handshake_started(&self) -> Option<embassy_time::Instant> {
+        self.handshake
+    }
+
+    /// Whether the peer posted a token that grants administrative access (see
+    /// [crate::tokens::record]), and used it.
+    ///
+    /// Posting a token is not enough, as anyone can post a token they sniffed; a protected request
+    /// needs to succeed after it. Like all attribution to tokens, this assumes that the request was
+    /// protected with the security context of the token posted latest. Access ends when another
+    /// token is posted, or when the token expires.
+    pub fn is_admin(&self) -> bool {
+        self.admin && self.token.is_some_and(crate::tokens::is_current)
+    }
+
+    /// Return the status reached through the latest request, if it was any noteworthy.
+    ///
+    /// This is best called after each [write](Self::write), and its result forwarded to
+    /// [crate::platform::LedControl::show_status].
+    pub fn take_status(&mut self) -> Option<Status> {
+        self.status.take()
+    }
+
+    /// Return the outcome of the latest request if it was an attempt to authenticate.
+    ///
+    /// This is best called after each [write](Self::write), and its result forwarded to
+    /// [crate::lockout::record] along with the peer's address.
+    pub fn take_attempt(&mut self) -> Option<crate::lockout::Attempt> {
+        self.attempt.take()
     }
 
     /// Call this whenever a BLE write arrives. The response value is what any BLE read should
@@ -67,46 +156,359 @@ impl Connection {
     /// Note that this passes in data that is primarily supposed to be read as `&mut`. This is to
     /// later allow OSCORE decryption in-place.
     pub fn write(&mut self, written: &mut [u8]) -> heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }> {
-        let request = coap_gatt_utils::parse_mut(written).unwrap();
+        if written.len() > crate::MAX_MESSAGE_LEN {
+            crate::info!("Request of {} bytes is too large", written.len());
+            return too_large(written);
+        }
+
+        let Ok(request) = coap_gatt_utils::parse_mut(written) else {
+            crate::info!("Request could not be parsed");
+            return coap_gatt_utils::write(|response| {
+                response.set_code(coap_numbers::code::BAD_REQUEST);
+            });
+        };
 
-        let mut locked = self
-            .rs
-            .try_lock()
-            // FIXME err properly or become more confident that this never happens
-            .expect("Simultaneous access should not happen through single executor");
+        let step = Step::classify(&request);
+        let protected = request
+            .options()
+            .any(|o| o.number() == coap_numbers::option::OSCORE);
+        let resource = crate::stats::lookup(&request);
+
+        let crypto = (step.is_some() || protected)
+            .then(|| crate::power::track(crate::power::Category::Crypto));
+
+        let mut retargeted = match (step, protected) {
+            (Some(Step::Token), false) => retargeted(&request),
+            _ => None,
+        };
+        let request = match retargeted.as_mut().map(|r| coap_gatt_utils::parse_mut(r)) {
+            Some(Ok(retargeted)) => retargeted,
+            _ => request,
+        };
+
+        let Ok(mut locked) = self.rs.try_lock() else {
+            crate::warn!("Resource server is busy");
+            return coap_gatt_utils::write(|response| {
+                response.set_code(coap_numbers::code::SERVICE_UNAVAILABLE);
+            });
+        };
         let handler = &mut *locked;
 
-        // We have a &mut, but can't tell the handler through the API; maybe an OscoreEdhocHandler
-        // should have something extra that takes a &mut parsed message?
-        let extracted = handler.extract_request_data(&request);
-
-        coap_gatt_utils::write(|response| {
-            // Error handling here is a tad odd: our response has a `.reset()`, but libOSCORE
-            // doesn't have the API (in particular it can't rely on its backend to have a
-            // reset/rewind), so we have to do separate protect steps.
-            //
-            // At the same time, we have to do everything in a single .reset()able
-            // coap_gatt_utils::write, because the lifetimes of the errors unfortunately may be
-            // bound to its buffer.
-            //
-            // This makes this whole mess even more arcane and verbose than is already generally
-            // the trouble with writing servers for coap-handler 0.2.
-
-            match extracted {
-                Ok(extracted) => {
-                    let rendered = handler.build_response(response, extracted);
-
-                    if let Err(e) = rendered {
-                        response.reset();
-                        let rendered = e.render(response);
+        crate::audit::set_requester(self.token.filter(|_| protected));
+        let phase = step.map(|step| {
+            crate::profiling::mark(match step {
+                Step::Edhoc => crate::profiling::Phase::Edhoc,
+                Step::Token => crate::profiling::Phase::Token,
+            })
+        });
+        let measured = match (step, protected) {
+            (Some(Step::Edhoc), _) => Some(crate::latency::Operation::Edhoc),
+            (Some(Step::Token), _) => Some(crate::latency::Operation::Token),
+            (None, true) => Some(crate::latency::Operation::Oscore),
+            (None, false) => None,
+        }
+        .map(crate::latency::measure);
+        let mut response = respond(handler, &request);
+
+        // During the overlap window of an AS key rotation, the request may be meant for the
+        // resource server that knows the old key, or holds the security context established
+        // before. Only token posts and protected requests are retried: The outer code of a
+        // protected request is only an error if its security context is unknown, so the request
+        // was not processed; other requests were, and must not be processed twice.
+        let retriable = protected || matches!(step, Some(Step::Token));
+        if let (true, Some(&(coap_numbers::code::BAD_REQUEST | coap_numbers::code::UNAUTHORIZED))) =
+            (retriable, response.first())
+        {
+            if let Some(previous) = handler.previous() {
+                crate::info!("Retrying with the resource server from before the AS key rotation");
+                response = respond(previous, &request);
+            }
+        }
 
-                        if let Err(_) = rendered {
-                            response.reset();
-                            response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
-                        }
+        drop(phase);
+        drop(measured);
+
+        // The serialized code is the first byte; anything from class 4 up is an error.
+        let failed = response.first().map_or(true, |code| code >> 5 >= 4);
+
+        if let (Some(resource), Some(&code)) = (resource, response.first()) {
+            use coap_numbers::code::{FORBIDDEN, UNAUTHORIZED};
+            if code == UNAUTHORIZED || code == FORBIDDEN {
+                crate::stats::record_rejection(resource);
+            }
+        }
+        if let (None, false, Some(&coap_numbers::code::UNAUTHORIZED)) =
+            (step, protected, response.first())
+        {
+            if let Some(hinted) = with_scope_hint(&response, &request) {
+                response = hinted;
+            }
+        }
+        match (step, failed) {
+            (Some(Step::Token), false) => {
+                // The new token replaces the previous one even if it can not be recorded.
+                self.admin = false;
+                self.token = crate::tokens::record(request.payload());
+                if let Some(token) = self.token {
+                    if let Some(installed) = enrich(&response, token) {
+                        response = installed;
                     }
                 }
-                Err(e) => {
+            }
+            (Some(Step::Edhoc), false) => {
+                self.handshake
+                    .get_or_insert_with(embassy_time::Instant::now);
+            }
+            (None, false) if protected => {
+                self.handshake = None;
+                if let Some(token) = self.token {
+                    crate::tokens::count_use(token);
+                    self.admin = token.admin;
+                }
+            }
+            _ => (),
+        }
+        // Recording a token decrypts it once more.
+        drop(crypto);
+        self.attempt = match (step, failed) {
+            (Some(_), true) => Some(crate::lockout::Attempt::Failed),
+            (Some(Step::Token), false) => Some(crate::lockout::Attempt::Succeeded),
+            (None, false) if protected => Some(crate::lockout::Attempt::Succeeded),
+            // Successful EDHOC messages 1 do not show that the peer is authorized; a protected
+            // request is only rejected if it fails to decrypt, which coapcore does not tell from
+            // other rejections.
+            _ => None,
+        };
+        self.status = match (step, failed) {
+            (None, _) => None,
+            (Some(_), true) => Some(Status::Error),
+            (Some(Step::Edhoc), false) => Some(Status::Handshaking),
+            (Some(Step::Token), false) => Some(Status::Authorized),
+        };
+
+        response
+    }
+}
+
+/// Process a write through a handler that is not wrapped in a resource server, and return the
+/// response.
+///
+/// This is for platforms in safe mode, where no keys can be trusted: Requests are processed
+/// without any security, so the handler may only contain resources that anyone may use (see
+/// [crate::coap::create_safe_mode_handler]). Size limits apply as in [Connection::write].
+pub fn write_unprotected<H: Handler>(
+    handler: &mut H,
+    written: &mut [u8],
+) -> heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }> {
+    if written.len() > crate::MAX_MESSAGE_LEN {
+        crate::info!("Request of {} bytes is too large", written.len());
+        return too_large(written);
+    }
+    let Ok(request) = coap_gatt_utils::parse_mut(written) else {
+        crate::info!("Request could not be parsed");
+        return coap_gatt_utils::write(|response| {
+            response.set_code(coap_numbers::code::BAD_REQUEST);
+        });
+    };
+    respond(handler, &request)
+}
+
+/// Rebuild a token POST with its token [retargeted](crate::tokens::retarget) to the device's own
+/// audience, or return None if the token is to be processed as it is.
+fn retargeted<M: ReadableMessage>(
+    request: &M,
+) -> Option<heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }>> {
+    let payload = crate::tokens::retarget(request.payload())?;
+    let mut complete = true;
+    let rebuilt = coap_gatt_utils::write(|message| {
+        message.set_code(request.code().into());
+        for option in request.options() {
+            complete &= message.add_option(option.number(), option.value()).is_ok();
+        }
+        complete &= message.set_payload(&payload).is_ok();
+    });
+    complete.then_some(rebuilt)
+}
+
+/// Build a response to a token POST that tells the client about its token (see
+/// [crate::tokens::Installed]).
+///
+/// This only replaces the resource server's response if that is a bare 2.01 Created, as it is in
+/// the ACE EDHOC profile. Responses that carry anything (eg. the nonce and identifier of the ACE
+/// OSCORE profile) are the profile's, and left alone.
+fn enrich(
+    response: &[u8],
+    token: crate::tokens::Recorded,
+) -> Option<heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }>> {
+    if *response != [coap_numbers::code::CREATED] {
+        return None;
+    }
+    let installed = crate::tokens::installed(token)?;
+
+    let mut buffer = [0; 96];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut buffer[..]);
+    // Failing that, the resource server's plain 2.01 is sent.
+    minicbor::encode(installed, &mut cursor).ok()?;
+    let length = cursor.position();
+
+    Some(coap_gatt_utils::write(|response| {
+        response.set_code(coap_numbers::code::CREATED);
+        // Options and payload this short always fit; failing that, the response is still a 2.01.
+        let _ = response.add_option_uint(coap_numbers::option::CONTENT_FORMAT, 60u8);
+        let _ = response.set_payload(&buffer[..length]);
+    }))
+}
+
+/// Add a scope hint to the AS Request Creation Hints of a 4.01 Unauthorized response.
+///
+/// The hint (`scope`, 9) is an AIF that grants just the denied request's method on its path, so
+/// that the client can ask the AS for a token that allows it, rather than the AS guessing. The
+/// response is left alone (by returning None) if its hints are not a definite length CBOR map, or
+/// the path is too long or not text.
+fn with_scope_hint<M: ReadableMessage>(
+    response: &[u8],
+    request: &M,
+) -> Option<heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }>> {
+    use minicbor::encode::write::Cursor;
+    use minicbor::encode::Write as _;
+
+    let mut path = heapless::String::<64>::new();
+    for segment in request
+        .options()
+        .filter(|o| o.number() == coap_numbers::option::URI_PATH)
+    {
+        path.push('/').ok()?;
+        path.push_str(core::str::from_utf8(segment.value()).ok()?)
+            .ok()?;
+    }
+    if path.is_empty() {
+        path.push('/').ok()?;
+    }
+    let method = crate::rs_configuration::method_bit(request.code().into())?;
+    let mut aif = [0; 80];
+    let mut encoder = minicbor::Encoder::new(Cursor::new(&mut aif[..]));
+    encoder
+        .array(1)
+        .ok()?
+        .array(2)
+        .ok()?
+        .str(&path)
+        .ok()?
+        .u64(method)
+        .ok()?;
+    let aif_len = encoder.writer().position();
+
+    let mut original =
+        heapless::Vec::<u8, { crate::MAX_MESSAGE_LEN }>::from_slice(response).ok()?;
+    let original = coap_gatt_utils::parse_mut(&mut original).ok()?;
+    let hints = original.payload();
+    let mut decoder = minicbor::Decoder::new(hints);
+    let entries = decoder.map().ok()??;
+
+    let mut payload = [0; crate::MAX_MESSAGE_LEN];
+    let mut encoder = minicbor::Encoder::new(Cursor::new(&mut payload[..]));
+    encoder.map(entries + 1).ok()?;
+    encoder
+        .writer_mut()
+        .write_all(&hints[decoder.position()..])
+        .ok()?;
+    encoder.u8(9).ok()?.bytes(&aif[..aif_len]).ok()?;
+    let length = encoder.writer().position();
+
+    Some(coap_gatt_utils::write(|response| {
+        response.set_code(coap_numbers::code::UNAUTHORIZED);
+        // The original options (ie. the Content-Format) fit before; failing that, the response
+        // is still a 4.01 with hints.
+        for option in original.options() {
+            let _ = response.add_option(option.number(), option.value());
+        }
+        let _ = response.set_payload(&payload[..length]);
+    }))
+}
+
+/// Serialize a 4.13 Request Entity Too Large response to an oversized request.
+///
+/// The Size1 option indicates the longest payload that fits into [crate::MAX_MESSAGE_LEN] along
+/// with the request's code and options, ie. how much the client needs to shorten it by (eg. by
+/// asking the AS for a shorter token). If the options can not be told apart from the payload, it
+/// is the longest payload that fits with no options at all.
+fn too_large(request: &[u8]) -> heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }> {
+    // Code and payload marker
+    let head = payload_offset(request).unwrap_or(2);
+    let room = crate::MAX_MESSAGE_LEN.saturating_sub(head);
+    coap_gatt_utils::write(|response| {
+        response.set_code(coap_numbers::code::REQUEST_ENTITY_TOO_LARGE);
+        // A short option always fits; without it, the code alone is still accurate.
+        let _ = response.add_option_uint(coap_numbers::option::SIZE1, room as u16);
+    })
+}
+
+/// Find where the payload of a serialized message starts, counting the payload marker as if there
+/// was one.
+///
+/// This only walks the option headers, and does not look into the option values.
+fn payload_offset(message: &[u8]) -> Option<usize> {
+    // Length of an extended option delta or length
+    fn extension(nibble: u8) -> Option<usize> {
+        match nibble {
+            13 => Some(1),
+            14 => Some(2),
+            15 => None,
+            _ => Some(0),
+        }
+    }
+
+    // Skip the code
+    let mut position = 1;
+    loop {
+        let Some(&header) = message.get(position) else {
+            return Some(message.len().max(1) + 1);
+        };
+        if header == 0xff {
+            return Some(position + 1);
+        }
+        position += 1 + extension(header >> 4)?;
+        let length = match header & 0x0f {
+            13 => usize::from(*message.get(position)?) + 13,
+            14 => {
+                usize::from(u16::from_be_bytes([
+                    *message.get(position)?,
+                    *message.get(position + 1)?,
+                ])) + 269
+            }
+            short => usize::from(short),
+        };
+        position += extension(header & 0x0f)? + length;
+    }
+}
+
+/// Process a request through a handler, and serialize the response.
+fn respond<H: Handler, M: ReadableMessage>(
+    handler: &mut H,
+    request: &M,
+) -> heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }> {
+    // We have a &mut, but can't tell the handler through the API; maybe an OscoreEdhocHandler
+    // should have something extra that takes a &mut parsed message?
+    let extracted = handler.extract_request_data(request);
+
+    coap_gatt_utils::write(|response| {
+        // Error handling here is a tad odd: our response has a `.reset()`, but libOSCORE
+        // doesn't have the API (in particular it can't rely on its backend to have a
+        // reset/rewind), so we have to do separate protect steps.
+        //
+        // At the same time, we have to do everything in a single .reset()able
+        // coap_gatt_utils::write, because the lifetimes of the errors unfortunately may be
+        // bound to its buffer.
+        //
+        // This makes this whole mess even more arcane and verbose than is already generally
+        // the trouble with writing servers for coap-handler 0.2.
+
+        match extracted {
+            Ok(extracted) => {
+                let rendered = handler.build_response(response, extracted);
+
+                if let Err(e) = rendered {
+                    response.reset();
                     let rendered = e.render(response);
 
                     if let Err(_) = rendered {
@@ -114,10 +516,124 @@ impl Connection {
                         response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
                     }
                 }
-            };
+            }
+            Err(e) => {
+                let rendered = e.render(response);
+
+                if let Err(_) = rendered {
+                    response.reset();
+                    response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+                }
+            }
+        };
+
+        use coap_message_utils::ShowMessageExt;
+        crate::info!("Responding with {:?}", response.show());
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_offset_skips_options() {
+        // POST /authz-info with a 2 byte payload
+        let message = b"\x02\xbaauthz-info\xff\x01\x02";
+        assert_eq!(payload_offset(message), Some(13));
+        // The same without payload
+        assert_eq!(payload_offset(&message[..12]), Some(13));
+        // An option value of 13 bytes, with an extended length
+        let message = b"\x02\xbd\x00abcdefghijklm\xff";
+        assert_eq!(payload_offset(message), Some(17));
+        // Reserved length nibble
+        assert_eq!(payload_offset(b"\x02\xbf"), None);
+    }
+
+    #[test]
+    fn too_large_indicates_payload_room() {
+        let mut message = b"\x02\xbaauthz-info\xff".to_vec();
+        message.resize(crate::MAX_MESSAGE_LEN + 1, 0);
+        let mut response = too_large(&message);
+        let response = coap_gatt_utils::parse_mut(&mut response).unwrap();
+        assert_eq!(
+            u8::from(response.code()),
+            coap_numbers::code::REQUEST_ENTITY_TOO_LARGE
+        );
+        let size1 = response
+            .options()
+            .find(|o| o.number() == coap_numbers::option::SIZE1)
+            .and_then(|o| o.value_uint::<u16>());
+        assert_eq!(size1, Some(crate::MAX_MESSAGE_LEN as u16 - 13));
+    }
+
+    #[test]
+    fn created_responses_are_enriched() {
+        use coset::{cwt, iana, CborSerializable};
+
+        // [["/temp", GET]]
+        let scope = b"\x81\x82\x65/temp\x01";
+        let claims = cwt::ClaimsSetBuilder::new()
+            .audience("d00".into())
+            .expiration_time(cwt::Timestamp::WholeSeconds(1_700_003_600))
+            .claim(iana::CwtClaimName::Scope, scope.to_vec().into())
+            .build()
+            .to_vec()
+            .unwrap();
+        // Signatures are not checked when recording, so this needs no key.
+        let token = coset::CoseSign1Builder::new()
+            .payload(claims)
+            .build()
+            .to_vec()
+            .unwrap();
+        let token = crate::tokens::record(&token).unwrap();
+
+        // The ACE OSCORE profile's response with its nonce2 and recipient ID
+        let profile_response = b"\x41\xff\xa2\x18\x2a\x41\x01\x18\x2c\x41\x02";
+        assert!(enrich(profile_response, token).is_none());
+
+        let mut response = enrich(&[coap_numbers::code::CREATED], token).unwrap();
+        let response = coap_gatt_utils::parse_mut(&mut response).unwrap();
+        assert_eq!(u8::from(response.code()), coap_numbers::code::CREATED);
+        let format = response
+            .options()
+            .find(|o| o.number() == coap_numbers::option::CONTENT_FORMAT)
+            .and_then(|o| o.value_uint::<u16>());
+        assert_eq!(format, Some(60));
+        // {"exp": 1700003600, "scope": h'...'}, followed by "remaining" while the clock is set
+        let payload = response.payload();
+        assert!(matches!(payload[0], 0xa2 | 0xa3));
+        let expected = [&b"\x63exp\x1a\x65\x53\xff\x10\x65scope\x49"[..], scope].concat();
+        assert!(payload[1..].starts_with(&expected));
+    }
+
+    #[test]
+    fn unauthorized_responses_get_scope_hint() {
+        // 4.01 with Content-Format application/ace+cbor and `{1: "coap://as", 5: "d00"}`
+        let hints = b"\xa2\x01\x69coap://as\x05\x63d00";
+        let response = [&b"\x81\xc1\x13\xff"[..], hints].concat();
+
+        let mut request = *b"\x03\xb4time\x04sync";
+        let request = coap_gatt_utils::parse_mut(&mut request).unwrap();
+        let mut hinted = with_scope_hint(&response, &request).unwrap();
+        let hinted = coap_gatt_utils::parse_mut(&mut hinted).unwrap();
+        assert_eq!(u8::from(hinted.code()), coap_numbers::code::UNAUTHORIZED);
+        let format = hinted
+            .options()
+            .find(|o| o.number() == coap_numbers::option::CONTENT_FORMAT)
+            .and_then(|o| o.value_uint::<u16>());
+        assert_eq!(format, Some(19));
+        // The hints with `9: h'81826a2f74696d652f73796e6304'`, ie. `[["/time/sync", PUT]]`
+        let expected = [
+            &b"\xa3"[..],
+            &hints[1..],
+            b"\x09\x4e\x81\x82\x6a/time/sync\x04",
+        ]
+        .concat();
+        assert_eq!(hinted.payload(), &expected[..]);
 
-            use coap_message_utils::ShowMessageExt;
-            defmt::info!("Responding with {}", response.show());
-        })
+        // Hints that are not a map are left alone.
+        let response = b"\x81\xff\x80";
+        assert!(with_scope_hint(response, &request).is_none());
     }
 }
@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Registry of the currently active BLE connections
+//!
+//! Connections are owned by their [crate::blueworker] tasks; this keeps clones of the connection
+//! handles around so that other components (eg. the debug shell) can enumerate and terminate
+//! them.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use nrf_softdevice::ble::Connection;
+
+use crate::MAX_CONNECTIONS;
+
+static CONNECTIONS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[Option<Connection>; MAX_CONNECTIONS as usize]>,
+> = Mutex::new(RefCell::new([const { None }; MAX_CONNECTIONS as usize]));
+
+/// Error type indicating that there is no connection in the requested slot
+#[derive(defmt::Format)]
+pub struct NoSuchConnection;
+
+/// Add a connection to the registry, returning the slot it was placed in.
+///
+/// Returns None if all slots are taken; that should not happen as long as the
+/// [crate::USED_CONNECTIONS] accounting is pessimistic.
+pub fn register(conn: &Connection) -> Option<usize> {
+    CONNECTIONS.lock(|c| {
+        let mut c = c.borrow_mut();
+        let (index, slot) = c.iter_mut().enumerate().find(|(_, s)| s.is_none())?;
+        *slot = Some(conn.clone());
+        Some(index)
+    })
+}
+
+/// Remove the connection in the given slot from the registry.
+pub fn unregister(index: usize) {
+    CONNECTIONS.lock(|c| c.borrow_mut()[index] = None);
+}
+
+/// Run a function on every registered connection, along with its slot number.
+pub fn for_each(mut f: impl FnMut(usize, &Connection)) {
+    CONNECTIONS.lock(|c| {
+        for (index, conn) in c.borrow().iter().enumerate() {
+            if let Some(conn) = conn {
+                f(index, conn);
+            }
+        }
+    })
+}
+
+/// Terminate the connection in the given slot.
+///
+/// The connection stays registered until its worker task has noticed the disconnect.
+pub fn disconnect(index: usize) -> Result<(), NoSuchConnection> {
+    CONNECTIONS.lock(|c| {
+        let c = c.borrow();
+        let conn = c
+            .get(index)
+            .and_then(|c| c.as_ref())
+            .ok_or(NoSuchConnection)?;
+        // If it fails, it's because it's already disconnected, which is just as well
+        let _ = conn.disconnect();
+        Ok(())
+    })
+}
@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Selection of the cryptography implementation used for EDHOC
+//!
+//! The resource server creates a fresh [lakers::Crypto] for every EDHOC session; a
+//! [CryptoBackend] is what produces them. Which backend is used is decided by crate features
+//! through [selected]:
+//!
+//! * By default, the portable software implementation of RustCrypto is used ([RustCrypto]).
+//! * With the `crypto-cryptocell310` feature, the CryptoCell 310 peripheral of the nRF52840 is
+//!   used ([CryptoCell310]). The firmware runs on that chip when built with the
+//!   `hardware-nrf52840dongle` feature.
+//!
+//! Other accelerated or certified implementations can be plugged in by implementing
+//! [CryptoBackend] and selecting them in [selected] through a further feature.
+//!
+//! All backends serve EDHOC cipher suite 2 (AES-CCM-16-64-128, SHA-256, P-256 for both ECDH and
+//! ECDSA), the only one the responder selects; initiators need to offer it.
+
+/// A source of [lakers::Crypto] instances
+pub trait CryptoBackend: Copy + 'static {
+    type Crypto: lakers::Crypto;
+
+    /// Create an instance for a new EDHOC session.
+    fn crypto(&self) -> Self::Crypto;
+}
+
+/// Software implementation from RustCrypto, drawing randomness from the contained RNG
+#[derive(Copy, Clone)]
+pub struct RustCrypto<R>(pub R);
+
+impl<R> CryptoBackend for RustCrypto<R>
+where
+    R: rand_core::RngCore + rand_core::CryptoRng + Copy + 'static,
+{
+    type Crypto = lakers_crypto_rustcrypto::Crypto<R>;
+
+    fn crypto(&self) -> Self::Crypto {
+        lakers_crypto_rustcrypto::Crypto::new(self.0)
+    }
+}
+
+/// Implementation on the nRF52840's CryptoCell 310, which brings its own random number generator
+#[cfg(feature = "crypto-cryptocell310")]
+#[derive(Copy, Clone)]
+pub struct CryptoCell310;
+
+#[cfg(feature = "crypto-cryptocell310")]
+impl CryptoBackend for CryptoCell310 {
+    type Crypto = lakers_crypto_cryptocell310::Crypto;
+
+    fn crypto(&self) -> Self::Crypto {
+        lakers_crypto_cryptocell310::Crypto
+    }
+}
+
+/// The backend selected through the crate features
+///
+/// `rng` is used by backends that do not bring their own random number generator.
+#[cfg(not(feature = "crypto-cryptocell310"))]
+pub fn selected<R>(rng: R) -> RustCrypto<R>
+where
+    R: rand_core::RngCore + rand_core::CryptoRng + Copy + 'static,
+{
+    RustCrypto(rng)
+}
+
+/// The backend selected through the crate features
+///
+/// `rng` is used by backends that do not bring their own random number generator.
+#[cfg(feature = "crypto-cryptocell310")]
+pub fn selected<R>(_rng: R) -> CryptoCell310 {
+    CryptoCell310
+}
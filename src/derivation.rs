@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Device identity derived from the chip's device ID
+//!
+//! For fleets too large to keep a configuration file per device, the configuration file can
+//! contain a `derivation` section with a 32 byte `batch_secret` and an `audience_prefix`. One
+//! firmware is then built for the whole batch, and each device derives its audience and EDHOC key
+//! pair at startup from its FICR DEVICEID and the batch secret, using HKDF-SHA256:
+//!
+//! * PRK = HKDF-Extract(salt: none, IKM: batch secret)
+//! * The audience is the prefix, followed by the hex encoded HKDF-Expand(PRK, "aud" || device ID,
+//!   4).
+//! * The EDHOC private key is HKDF-Expand(PRK, "edhoc" || device ID || counter, 32), with the
+//!   lowest one byte counter from 0 on for which that is a valid P-256 scalar.
+//!
+//! where the device ID is the 8 bytes of DEVICEID\[1\] and DEVICEID\[0\], big endian.
+//!
+//! The AS side runs the same derivation (eg. through [IdentityDerivation::derive] on the host)
+//! for the device IDs read out on the production line, and registers the resulting audiences and
+//! public keys. The AS URI and keys are taken from the configuration file, and are thus shared by
+//! the batch; an AS public key is preferable over a shared symmetric key there.
+//!
+//! Every device of the batch carries the batch secret, which gives away the keys of all of them.
+//! Devices of a batch thus need to have their debug port locked (APPROTECT) before deployment.
+//!
+//! An identity in the [provisioning] page takes precedence over a derived one.
+//!
+//! [provisioning]: crate::provisioning
+
+use crate::CoapcoreConfig;
+
+/// Longest audience prefix (leaving room for the 8 hex digits)
+pub const MAX_AUDIENCE_PREFIX_LEN: usize = 24;
+/// Longest AS URI, for which the request creation hints are sure to fit their buffer
+pub const MAX_AS_URI_LEN: usize = 120;
+
+const MAX_AUDIENCE_LEN: usize = MAX_AUDIENCE_PREFIX_LEN + 8;
+const MAX_HINTS_LEN: usize = MAX_AS_URI_LEN + MAX_AUDIENCE_LEN + 8;
+/// Length of an encoded EDHOC credential
+const CREDENTIAL_LEN: usize = 84;
+
+/// Parameters of the derivation, as given in the configuration file
+pub struct IdentityDerivation {
+    pub batch_secret: [u8; 32],
+    pub audience_prefix: &'static str,
+    /// URI of the AS, announced in the request creation hints
+    pub as_uri: &'static str,
+}
+
+/// An identity derived for one device
+pub struct Derived {
+    audience: heapless::String<MAX_AUDIENCE_LEN>,
+    request_creation_hints: heapless::Vec<u8, MAX_HINTS_LEN>,
+    edhoc_credential: [u8; CREDENTIAL_LEN],
+    edhoc_q: [u8; 32],
+    edhoc_public: ([u8; 32], [u8; 32]),
+}
+
+impl IdentityDerivation {
+    /// Derive the identity of the device with the given ID.
+    pub fn derive(&self, device_id: u64) -> Derived {
+        use core::fmt::Write;
+        use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(None, &self.batch_secret);
+        let device_id = device_id.to_be_bytes();
+
+        let mut suffix = [0; 4];
+        hkdf.expand_multi_info(&[b"aud", &device_id], &mut suffix)
+            .expect("Length is valid for HKDF");
+        let mut audience = heapless::String::new();
+        // The build script limits the prefix length to fit.
+        let _ = write!(
+            audience,
+            "{}{}",
+            self.audience_prefix,
+            crate::logging::Hex(&suffix)
+        );
+
+        // Each attempt fails with a probability of about 2^-32.
+        let (edhoc_q, secret) = (0..=u8::MAX)
+            .find_map(|counter| {
+                let mut q = [0; 32];
+                hkdf.expand_multi_info(&[b"edhoc", &device_id, &[counter]], &mut q)
+                    .expect("Length is valid for HKDF");
+                Some((q, p256::SecretKey::from_slice(&q).ok()?))
+            })
+            .expect("A valid scalar is found");
+        let point = secret.public_key().to_encoded_point(false);
+        let x: [u8; 32] = (*point.x().expect("Uncompressed point")).into();
+        let y: [u8; 32] = (*point.y().expect("Uncompressed point")).into();
+
+        Derived {
+            request_creation_hints: request_creation_hints(self.as_uri, &audience),
+            audience,
+            edhoc_credential: edhoc_credential(&x, &y),
+            edhoc_q,
+            edhoc_public: (x, y),
+        }
+    }
+}
+
+impl Derived {
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Coordinates of the EDHOC public key, as the AS needs to register them
+    pub fn edhoc_public(&self) -> ([u8; 32], [u8; 32]) {
+        self.edhoc_public
+    }
+
+    /// Build the configuration of the resource server from the identity, and the AS keys and
+    /// group audiences of the built-in configuration.
+    pub fn config(&'static self, built_in: &CoapcoreConfig) -> CoapcoreConfig {
+        let mut config = CoapcoreConfig {
+            audience: &self.audience,
+            group_audiences: built_in.group_audiences,
+            request_creation_hints: &self.request_creation_hints,
+            as_symmetric: built_in.as_symmetric,
+            edhoc_credential: Some(&self.edhoc_credential[..]),
+            edhoc_q: Some(&self.edhoc_q),
+            as_pub: built_in.as_pub,
+            checksum: 0,
+        };
+        // This only lets the self test find damage to the derived identity in RAM; the parameters
+        // are covered by the firmware image's integrity.
+        config.checksum = config.calculate_checksum();
+        config
+    }
+}
+
+/// Encode the AS Request Creation Hints as a CBOR map `{1 /as/: as_uri, 5 /aud/: audience}`.
+///
+/// This needs to match the build script's `request_creation_hints` function.
+fn request_creation_hints(as_uri: &str, audience: &str) -> heapless::Vec<u8, MAX_HINTS_LEN> {
+    let mut buffer = [0; MAX_HINTS_LEN];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut buffer[..]);
+    minicbor::Encoder::new(&mut cursor)
+        .map(2)
+        .and_then(|e| e.u8(1)?.str(as_uri)?.u8(5)?.str(audience))
+        .expect("The build script limits the AS URI length to fit");
+    let len = cursor.position();
+    heapless::Vec::from_slice(&buffer[..len]).expect("Buffers have the same size")
+}
+
+/// Encode an EDHOC credential as a CWT Claims Set (CCS) containing the public key.
+///
+/// This needs to match the build script's `edhoc_credential` function.
+fn edhoc_credential(x: &[u8; 32], y: &[u8; 32]) -> [u8; CREDENTIAL_LEN] {
+    #[rustfmt::skip]
+    const HEAD: [u8; 17] = [
+        0xa2, // map(2)
+        0x02, 0x60, // sub: ""
+        0x08, 0xa1, // cnf: map(1)
+        0x01, 0xa5, // COSE_Key: map(5)
+        0x01, 0x02, // kty: EC2
+        0x02, 0x41, 0x63, // kid: h'63'
+        0x20, 0x01, // crv: P-256
+        0x21, 0x58, 0x20, // x: bytes(32)
+    ];
+    let mut out = [0; CREDENTIAL_LEN];
+    out[..17].copy_from_slice(&HEAD);
+    out[17..49].copy_from_slice(x);
+    out[49..52].copy_from_slice(&[0x22, 0x58, 0x20]); // y: bytes(32)
+    out[52..].copy_from_slice(y);
+    out
+}
@@ -22,18 +22,79 @@ static OFFSET: AtomicU32 = AtomicU32::new(0);
 #[derive(Debug)]
 pub struct ClockNotSet;
 
+/// Latest time that the clock can be set to (the start of 2076)
+///
+/// This keeps the clock from ever reaching the end of the 32-bit range while the device runs.
+pub const LATEST: u32 = 3_345_062_400;
+
+/// Error type indicating that a time can not be expressed by the clock: It is after [LATEST], or
+/// before the device was booted.
+#[derive(Debug)]
+pub struct OutOfRange;
+
 /// State that the current time is `now` (on the UNIX time scale); future calls to [unixtime()]
 /// will return this or a greater value.
-pub fn set_unixtime(now: u32) {
-    OFFSET.store(now - embassy_time::Instant::now().as_secs() as u32, Relaxed)
+pub fn set_unixtime(now: u32) -> Result<(), OutOfRange> {
+    if now > LATEST {
+        return Err(OutOfRange);
+    }
+    let uptime = u32::try_from(embassy_time::Instant::now().as_secs()).map_err(|_| OutOfRange)?;
+    match now.checked_sub(uptime) {
+        // 0 would read as "not set"
+        None | Some(0) => Err(OutOfRange),
+        Some(offset) => {
+            OFFSET.store(offset, Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Largest step back in time that [advance_unixtime] accepts, in seconds
+///
+/// This leaves room for correcting a clock that ran fast, or was set from a source that was a bit
+/// ahead.
+pub const REWIND_TOLERANCE: u32 = 60;
+
+/// Error type indicating why [advance_unixtime] did not set the clock
+#[derive(Debug)]
+pub enum Refused {
+    /// Setting the clock would turn it back too far.
+    Rewind,
+    /// The time can not be expressed by the clock.
+    OutOfRange,
+}
+
+impl From<OutOfRange> for Refused {
+    fn from(_: OutOfRange) -> Self {
+        Refused::OutOfRange
+    }
+}
+
+/// Like [set_unixtime], but refuse to turn the clock back by more than [REWIND_TOLERANCE].
+///
+/// This is for clock writes from sources that are not trusted: Turning the clock forward only
+/// makes tokens expire early, but turning it back would make expired tokens valid again.
+pub fn advance_unixtime(now: u32) -> Result<(), Refused> {
+    if let Ok(current) = unixtime() {
+        if now < current.saturating_sub(REWIND_TOLERANCE) {
+            return Err(Refused::Rewind);
+        }
+    }
+    Ok(set_unixtime(now)?)
 }
 
 /// Obtain the current time as UNIX time
+///
+/// Past the end of the 32-bit range (which [set_unixtime] keeps out of reach), this stays at its
+/// last value rather than wrapping around to 1970.
 pub fn unixtime() -> Result<u32, ClockNotSet> {
     let offset = OFFSET.load(Relaxed);
     match offset {
         0 => Err(ClockNotSet),
-        o => Ok(embassy_time::Instant::now().as_secs() as u32 + o),
+        o => {
+            let uptime = u32::try_from(embassy_time::Instant::now().as_secs()).unwrap_or(u32::MAX);
+            Ok(uptime.saturating_add(o))
+        }
     }
 }
 
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Reasons why connections ended
+//!
+//! The platform reports the HCI reason code of every closed connection through [record]; the
+//! codes are counted by the [Reason] they indicate, and shown in the `/stats/ble` resource. A high
+//! count of supervision timeouts points to range or interference problems, MIC failures to peers
+//! with broken (or attacked) link encryption, and connections that failed to be established to
+//! peers giving up on the device early.
+//!
+//! Counters live in RAM, and are thus lost at a reset.
+
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+/// Kinds of disconnect reasons that are counted separately
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Reason {
+    /// The peer closed the connection (HCI 0x13 to 0x15).
+    RemoteTerminated,
+    /// The device closed the connection (HCI 0x16), eg. after an idle timeout.
+    LocalTerminated,
+    /// The peer was not heard within the supervision timeout (HCI 0x08).
+    SupervisionTimeout,
+    /// A packet failed its integrity check (HCI 0x3d).
+    MicFailure,
+    /// A link layer procedure was not answered in time (HCI 0x22).
+    ResponseTimeout,
+    /// The connection was lost before it was fully established (HCI 0x3e).
+    EstablishmentFailed,
+    /// Any other code
+    Other,
+}
+
+const REASONS: usize = 7;
+
+impl Reason {
+    const ALL: [Reason; REASONS] = [
+        Reason::RemoteTerminated,
+        Reason::LocalTerminated,
+        Reason::SupervisionTimeout,
+        Reason::MicFailure,
+        Reason::ResponseTimeout,
+        Reason::EstablishmentFailed,
+        Reason::Other,
+    ];
+
+    /// Classify an HCI reason code.
+    pub fn from_hci(code: u8) -> Self {
+        match code {
+            0x13..=0x15 => Reason::RemoteTerminated,
+            0x16 => Reason::LocalTerminated,
+            0x08 => Reason::SupervisionTimeout,
+            0x3d => Reason::MicFailure,
+            0x22 => Reason::ResponseTimeout,
+            0x3e => Reason::EstablishmentFailed,
+            _ => Reason::Other,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Reason::RemoteTerminated => "remote",
+            Reason::LocalTerminated => "local",
+            Reason::SupervisionTimeout => "supervision-timeout",
+            Reason::MicFailure => "mic-failure",
+            Reason::ResponseTimeout => "response-timeout",
+            Reason::EstablishmentFailed => "establishment-failed",
+            Reason::Other => "other",
+        }
+    }
+}
+
+static COUNTS: [AtomicU32; REASONS] = [const { AtomicU32::new(0) }; REASONS];
+
+/// The latest reason code, or u16::MAX if no connection ended yet
+static LATEST: AtomicU16 = AtomicU16::new(u16::MAX);
+
+/// Count a closed connection by its HCI reason code.
+pub fn record(code: u8) {
+    let reason = Reason::from_hci(code);
+    crate::info!(
+        "Connection closed, reason 0x{} ({:?})",
+        crate::logging::Hex(&[code]),
+        reason
+    );
+    COUNTS[reason as usize].fetch_add(1, Ordering::Relaxed);
+    LATEST.store(code.into(), Ordering::Relaxed);
+}
+
+/// Copy of the disconnect counters
+///
+/// When encoded into CBOR, this is a map from the reasons' names (eg. `"supervision-timeout"`) to
+/// the number of connections that ended for them, along with the latest HCI reason code under
+/// `"latest"` (or null if no connection ended yet). Reasons that did not occur are left out.
+pub struct Report {
+    counts: [u32; REASONS],
+    latest: Option<u8>,
+}
+
+/// Obtain a copy of the disconnect counters.
+pub fn report() -> Report {
+    Report {
+        counts: core::array::from_fn(|i| COUNTS[i].load(Ordering::Relaxed)),
+        latest: u8::try_from(LATEST.load(Ordering::Relaxed)).ok(),
+    }
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        let occurred = || {
+            Reason::ALL
+                .into_iter()
+                .filter(|r| self.counts[*r as usize] > 0)
+        };
+        e.map(occurred().count() as u64 + 1)?;
+        for reason in occurred() {
+            e.str(reason.name())?.u32(self.counts[reason as usize])?;
+        }
+        e.str("latest")?;
+        match self.latest {
+            Some(code) => e.u8(code)?,
+            None => e.null()?,
+        };
+        Ok(())
+    }
+}
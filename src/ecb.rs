@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! AES-128 through the nRF ECB peripheral
+//!
+//! The ECB peripheral is owned by the softdevice, but accessible through its
+//! `sd_ecb_block_encrypt` call. [EcbAes128] wraps that in the RustCrypto block cipher traits, so
+//! that it can be used wherever those accept a cipher -- for example, `ccm::Ccm<EcbAes128, U8,
+//! U13>` is the AES-CCM-16-64-128 algorithm used by OSCORE and by the symmetrically encrypted
+//! tokens.
+//!
+//! CCM only ever uses the encryption direction of the block cipher, which is all the peripheral
+//! provides.
+//!
+//! At the time of writing, neither coapcore (for token decryption) nor libOSCORE's crypto backend
+//! accept a cipher from the application; they instantiate RustCrypto's software `aes` internally.
+//! Routing their operations through here needs them to become generic over the block cipher.
+
+use cipher::consts::{U1, U16};
+use cipher::{
+    AlgorithmName, Block, BlockBackend, BlockCipher, BlockClosure, BlockEncrypt, BlockSizeUser,
+    InOut, Key, KeyInit, KeySizeUser, ParBlocksSizeUser,
+};
+
+use nrf_softdevice::raw;
+
+/// AES-128 block cipher (encryption only) running on the ECB peripheral
+///
+/// This must only be used while the softdevice is enabled.
+#[derive(Clone)]
+pub struct EcbAes128 {
+    key: [u8; 16],
+}
+
+impl KeySizeUser for EcbAes128 {
+    type KeySize = U16;
+}
+
+impl KeyInit for EcbAes128 {
+    fn new(key: &Key<Self>) -> Self {
+        Self { key: (*key).into() }
+    }
+}
+
+impl BlockSizeUser for EcbAes128 {
+    type BlockSize = U16;
+}
+
+impl BlockCipher for EcbAes128 {}
+
+impl AlgorithmName for EcbAes128 {
+    fn write_alg_name(f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Aes128 (nRF ECB)")
+    }
+}
+
+impl BlockEncrypt for EcbAes128 {
+    fn encrypt_with_backend(&self, f: impl BlockClosure<BlockSize = U16>) {
+        f.call(&mut Backend(self))
+    }
+}
+
+/// The cipher's processing backend, which encrypts one block at a time
+struct Backend<'a>(&'a EcbAes128);
+
+impl BlockSizeUser for Backend<'_> {
+    type BlockSize = U16;
+}
+
+impl ParBlocksSizeUser for Backend<'_> {
+    type ParBlocksSize = U1;
+}
+
+impl BlockBackend for Backend<'_> {
+    fn proc_block(&mut self, mut block: InOut<'_, '_, Block<Self>>) {
+        let mut data = raw::nrf_ecb_hal_data_t {
+            key: self.0.key,
+            cleartext: (*block.get_in()).into(),
+            ciphertext: [0; 16],
+        };
+        // SAFETY: The data is a valid ECB job, and the call is synchronous.
+        let result = unsafe { raw::sd_ecb_block_encrypt(&mut data) };
+        // This only fails if the softdevice is not enabled, which is documented as a requirement
+        assert!(result == raw::NRF_SUCCESS, "ECB encryption failed");
+        block.get_out().copy_from_slice(&data.ciphertext);
+    }
+}
+
+/// Check the peripheral against the AES-128 example vector of FIPS-197 (Appendix C.1).
+pub fn check() -> bool {
+    let cipher = EcbAes128::new(&hex_literal::hex!("000102030405060708090a0b0c0d0e0f").into());
+    let mut block = hex_literal::hex!("00112233445566778899aabbccddeeff").into();
+    cipher.encrypt_block(&mut block);
+    block[..] == hex_literal::hex!("69c4e0d86a7b0430d8cdb78070b4c55a")
+}
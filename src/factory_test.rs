@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Manufacturing test mode
+//!
+//! If button 1 is held while the device is reset, it boots into this mode instead of serving the
+//! resource server, for bring-up of (already provisioned) units on the production line. The test
+//! mode
+//!
+//! * shows the LED walk (to be verified by the operator), followed by the failure pattern if the
+//!   self test failed,
+//! * runs the [self test](coap_ace_poc_firmware::selftest), which covers the temperature sensor,
+//!   the random number generator and the configuration checksum,
+//! * erases, writes and reads back the spare page of the [settings journal](crate::journal), so
+//!   the stored settings are kept, and
+//! * sends bursts of non-connectable advertisements at the fastest interval, under a name that
+//!   carries the results (see [name]), for the radio to be checked by a tester on the line.
+//!
+//! The results are also logged through RTT. The softdevice keeps the radio to itself, so there is
+//! no unmodulated carrier test; that needs a dedicated (eg. DTM) firmware. The device stays in
+//! test mode until it is reset without the button held.
+
+use nrf_softdevice::ble::peripheral;
+use nrf_softdevice::{raw, Softdevice};
+
+use coap_ace_poc_firmware::selftest;
+use coap_ace_poc_firmware::{error, info};
+
+/// Longest name that fits the advertising data next to the flags
+const MAX_NAME_LEN: usize = 26;
+
+/// Pattern written to the flash, chosen so that every bit is programmed in one of its words
+const PATTERN: [u32; 4] = [0x5555_aaaa, 0xaaaa_5555, 0x0000_0000, 0x1234_5678];
+
+/// Duration of an advertising burst, and of the pause after it, in units of 10ms
+const BURST: u16 = 100;
+
+/// Outcome of the test mode's checks
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Results {
+    pub selftest: selftest::Report,
+    pub flash_ok: bool,
+}
+
+impl Results {
+    pub fn ok(&self) -> bool {
+        self.selftest.ok() && self.flash_ok
+    }
+}
+
+/// Whether the test mode was requested, ie. button 1 is held
+pub fn requested(button: &embassy_nrf::gpio::Input<'_>) -> bool {
+    // Active low
+    button.is_low()
+}
+
+/// Name advertised in test mode
+///
+/// This is "CoAP-ACE test OK" when all tests passed, and otherwise "CoAP-ACE test FAIL" followed
+/// by the letters of the failed tests: T(emperature), R(andom number generator), C(onfiguration)
+/// and F(lash).
+pub fn name(results: &Results) -> heapless::String<MAX_NAME_LEN> {
+    let mut name = heapless::String::new();
+    if results.ok() {
+        let _ = name.push_str("CoAP-ACE test OK");
+        return name;
+    }
+    let _ = name.push_str("CoAP-ACE test FAIL ");
+    for (ok, letter) in [
+        (results.selftest.temperature_ok, 'T'),
+        (results.selftest.rng_ok, 'R'),
+        (results.selftest.config_ok, 'C'),
+        (results.flash_ok, 'F'),
+    ] {
+        if !ok {
+            let _ = name.push(letter);
+        }
+    }
+    name
+}
+
+/// Buffer for data to be written; the softdevice requires it to be word aligned.
+#[repr(align(4))]
+struct Aligned([u8; 16]);
+
+/// Erase the journal's spare page, write [PATTERN] to it and read it back, erasing it again
+/// afterwards.
+///
+/// This needs [crate::flash] to be initialized.
+async fn check_flash() -> bool {
+    let page = crate::journal::spare_page();
+    let mut data = Aligned([0; 16]);
+    for (chunk, word) in data.0.chunks_mut(4).zip(PATTERN) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+
+    if let Err(e) = crate::flash::erase(page.start, page.end).await {
+        error!("Erasing flash failed: {:?}", e);
+        return false;
+    }
+    // SAFETY: Flash is memory mapped, and the page is not part of the firmware image.
+    let erased: [u8; 16] = unsafe { core::ptr::read_volatile(page.start as *const [u8; 16]) };
+    if erased != [0xff; 16] {
+        error!("Flash is not blank after erasing");
+        return false;
+    }
+    if let Err(e) = crate::flash::write(page.start, &data.0).await {
+        error!("Writing flash failed: {:?}", e);
+        return false;
+    }
+    // SAFETY: As above
+    let written: [u8; 16] = unsafe { core::ptr::read_volatile(page.start as *const [u8; 16]) };
+    if written != data.0 {
+        error!("Flash does not read back what was written");
+        return false;
+    }
+    // Leaving the pattern would do no harm, but a blank page is what the journal expects.
+    crate::flash::erase(page.start, page.end).await.is_ok()
+}
+
+/// Task running the test mode's checks, and advertising their results
+///
+/// This needs to run on the softdevice's executor, as it uses the flash.
+#[embassy_executor::task]
+pub async fn run(sd: &'static Softdevice, selftest: selftest::Report) {
+    let results = Results {
+        selftest,
+        flash_ok: check_flash().await,
+    };
+    let name = name(&results);
+    if results.ok() {
+        info!("Factory test passed");
+    } else {
+        error!("Factory test failed: {:?}", results);
+    }
+    info!("Advertising test results as {}", name.as_str());
+
+    let mut adv_data = heapless::Vec::<u8, 31>::new();
+    defmt::unwrap!(adv_data.extend_from_slice(&[
+        0x02,
+        0x01,
+        raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+        name.len() as u8 + 1,
+        // Complete Local Name
+        0x09,
+    ]));
+    defmt::unwrap!(adv_data.extend_from_slice(name.as_bytes()));
+
+    let config = peripheral::Config {
+        // in units of 0.625ms: the shortest interval the softdevice allows
+        interval: 32,
+        // in units of 10ms
+        timeout: Some(BURST),
+        ..Default::default()
+    };
+    loop {
+        let adv = peripheral::NonconnectableAdvertisement::NonscannableUndirected {
+            adv_data: &adv_data,
+        };
+        match peripheral::advertise(sd, adv, &config).await {
+            Ok(()) | Err(peripheral::AdvertiseError::Timeout) => {}
+            Err(err) => error!("Failed to advertise: {:?}", err),
+        }
+        embassy_time::Timer::after_millis(u64::from(BURST) * 10).await;
+    }
+}
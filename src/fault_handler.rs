@@ -0,0 +1,113 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Recovery from fatal faults
+//!
+//! All fatal faults end in a hard fault: panic-probe raises one after printing the panic message
+//! (and that includes the softdevice's fault reports, which nrf-softdevice turns into panics). The
+//! hard fault handler of this module records the fault in a part of the RAM that is not
+//! initialized at startup, and resets the device. At the next startup, [load] passes the record on
+//! to [coap_ace_poc_firmware::faults].
+//!
+//! While a debugger is attached, the handler stops at a breakpoint instead of resetting, so that
+//! the fault can be inspected.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+use coap_ace_poc_firmware::faults::{Fault, Kind};
+use coap_ace_poc_firmware::{info, warn};
+
+/// Marks a valid [Record] ("FLT1")
+const MAGIC: u32 = 0x464c_5431;
+
+/// The fault as kept across the reset
+#[repr(C)]
+struct Record {
+    magic: u32,
+    kind: u32,
+    pc: u32,
+    uptime: u32,
+    count: u32,
+    /// Inverted copy of the other fields XOR'ed together, guarding against random RAM content
+    check: u32,
+}
+
+impl Record {
+    fn check(&self) -> u32 {
+        !(self.magic ^ self.kind ^ self.pc ^ self.uptime ^ self.count)
+    }
+
+    fn fault(&self) -> Option<Fault> {
+        if self.magic != MAGIC || self.check != self.check() {
+            return None;
+        }
+        Some(Fault {
+            kind: match self.kind {
+                1 => Kind::Panic,
+                2 => Kind::HardFault,
+                _ => return None,
+            },
+            pc: self.pc,
+            uptime: self.uptime,
+            count: self.count,
+        })
+    }
+}
+
+#[link_section = ".uninit.FAULT_RECORD"]
+static mut RECORD: MaybeUninit<Record> = MaybeUninit::uninit();
+
+/// Set while a panic is being handled, so that the hard fault it ends in is recorded as a panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Read the record left by a fault before the latest reset, and pass it on to the library.
+///
+/// The record is not cleared, so that [Fault::count] keeps counting until the device loses power.
+pub fn load() {
+    // SAFETY: Any bit pattern is a valid Record, and nothing else accesses it before the hard
+    // fault handler.
+    let record = unsafe { (*core::ptr::addr_of!(RECORD)).assume_init_read() };
+    let fault = record.fault();
+    if let Some(fault) = fault {
+        warn!("Device was reset after a fault");
+        info!("Fault before the reset: {}", fault);
+    }
+    coap_ace_poc_firmware::faults::set_last(fault);
+}
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    PANICKING.store(true, Relaxed);
+    panic_probe::hard_fault()
+}
+
+#[cortex_m_rt::exception]
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    // SAFETY: Any bit pattern is a valid Record, and this is the only place writing it; faults
+    // inside the hard fault handler lock up the CPU rather than reentering it.
+    let record = unsafe { &mut *core::ptr::addr_of_mut!(RECORD) };
+    let count = unsafe { record.assume_init_ref() }
+        .fault()
+        .map_or(1, |f| f.count.wrapping_add(1));
+    let mut new = Record {
+        magic: MAGIC,
+        kind: match PANICKING.load(Relaxed) {
+            true => Kind::Panic as u32,
+            false => Kind::HardFault as u32,
+        },
+        pc: frame.pc(),
+        uptime: embassy_time::Instant::now().as_secs() as u32,
+        count,
+        check: 0,
+    };
+    new.check = new.check();
+    record.write(new);
+
+    if cortex_m::peripheral::DCB::is_debugger_attached() {
+        loop {
+            cortex_m::asm::bkpt();
+        }
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Record of the fatal fault that caused the latest reset
+//!
+//! The firmware resets the device on fatal faults rather than leaving it unreachable until it is
+//! power cycled. Those are panics, which include the softdevice's assertions and memory access
+//! violations (nrf-softdevice turns its fault reports into panics), and hard faults. What the
+//! firmware recorded about the fault survives the reset in RAM, and is passed in here at startup
+//! through [set_last], to be shown in the `/debug/sdfault` resource.
+//!
+//! As the record lives in RAM, it is lost when the device loses power.
+
+use core::cell::Cell;
+
+/// Kinds of fatal faults
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum Kind {
+    /// A panic, either of the firmware or reported by the softdevice
+    Panic = 1,
+    /// A hard fault that did not originate in a panic
+    HardFault = 2,
+}
+
+/// A fatal fault as recorded by the firmware
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Fault {
+    pub kind: Kind,
+    /// Program counter at the time of the hard fault
+    ///
+    /// Panics end in a hard fault in the panic handler, so for them, this is not where the panic
+    /// originated.
+    pub pc: u32,
+    /// Seconds since boot when the fault happened
+    pub uptime: u32,
+    /// Number of faults since the device was powered up
+    pub count: u32,
+}
+
+static LAST: critical_section::Mutex<Cell<Option<Fault>>> =
+    critical_section::Mutex::new(Cell::new(None));
+
+/// Set the fault that caused the latest reset, if any.
+pub fn set_last(fault: Option<Fault>) {
+    critical_section::with(|cs| LAST.borrow(cs).set(fault));
+}
+
+/// Return the fault that caused the latest reset, if any.
+pub fn last() -> Option<Fault> {
+    critical_section::with(|cs| LAST.borrow(cs).get())
+}
+
+/// Encoded as a CBOR map with the fault's kind (`"kind"`, as in [Kind]), the program counter
+/// (`"pc"`), the uptime in seconds (`"uptime"`) and the number of faults since power-up
+/// (`"count"`).
+impl<C> minicbor::encode::Encode<C> for Fault {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(4)?
+            .str("kind")?
+            .u8(self.kind as u8)?
+            .str("pc")?
+            .u32(self.pc)?
+            .str("uptime")?
+            .u32(self.uptime)?
+            .str("count")?
+            .u32(self.count)?;
+        Ok(())
+    }
+}
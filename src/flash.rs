@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Shared access to the flash
+//!
+//! While the softdevice is enabled, flash operations are carried out by the softdevice in time
+//! slots between radio events. It reports an operation as failed if it could not find such a slot
+//! in time, which is more likely the busier the radio is (eg. with several connections at short
+//! intervals). The operations of this module take care of that: They retry failed operations with
+//! an increasing delay, and queue concurrent users behind each other, so that subsystems
+//! persisting data (like the [settings journal](crate::journal)) only need to await the outcome.
+//!
+//! Every attempt is started right after radio activity ended (see [crate::radio::gap]), when
+//! the softdevice is most likely to find a time slot for it.
+//!
+//! Operations are queued in the order in which they are requested; each completes (ie. its future
+//! resolves) once the data is in flash, or once retrying was given up. As all waiting happens in
+//! timers, the executor keeps running other tasks meanwhile.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage_async::nor_flash::NorFlash;
+use nrf_softdevice::FlashError;
+
+use coap_ace_poc_firmware::info;
+use coap_ace_poc_firmware::profiling::Phase;
+
+/// Number of attempts made at an operation before giving up
+const ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry; it is doubled for every further one.
+const FIRST_BACKOFF: embassy_time::Duration = embassy_time::Duration::from_millis(20);
+
+/// The flash, once [init] provided it
+static FLASH: Mutex<CriticalSectionRawMutex, Option<nrf_softdevice::Flash>> = Mutex::new(None);
+
+/// Make the flash available to the operations of this module.
+///
+/// This needs to be called before any operation is requested.
+pub fn init(flash: nrf_softdevice::Flash) {
+    *FLASH
+        .try_lock()
+        .expect("Nothing uses the flash before it is initialized") = Some(flash);
+}
+
+/// A flash operation
+#[derive(Copy, Clone)]
+enum Operation<'d> {
+    /// Write data (which needs to be word aligned) at an address
+    Write { address: u32, data: &'d [u8] },
+    /// Erase the pages from one address up to (excluding) another
+    Erase { from: u32, to: u32 },
+}
+
+/// Run an operation on the flash, retrying while the softdevice reports failures.
+///
+/// Errors other than failures (ie. misaligned arguments) are returned right away, as retrying
+/// would not change their outcome.
+async fn run(operation: Operation<'_>) -> Result<(), FlashError> {
+    let mut flash = FLASH.lock().await;
+    let flash = flash.as_mut().expect("Flash is initialized before use");
+
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        crate::radio::gap().await;
+        let phase = coap_ace_poc_firmware::profiling::mark(Phase::Flash);
+        let result = match operation {
+            Operation::Write { address, data } => flash.write(address, data).await,
+            Operation::Erase { from, to } => flash.erase(from, to).await,
+        };
+        drop(phase);
+        match result {
+            Err(FlashError::Failed) if attempt < ATTEMPTS => {
+                info!("Flash operation failed (attempt {}), retrying", attempt);
+                embassy_time::Timer::after(backoff).await;
+                backoff = backoff * 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Write data at an address.
+///
+/// The data needs to be word aligned, as the softdevice reads it from RAM while writing.
+pub async fn write(address: u32, data: &[u8]) -> Result<(), FlashError> {
+    run(Operation::Write { address, data }).await
+}
+
+/// Erase the pages from one address up to (excluding) another.
+pub async fn erase(from: u32, to: u32) -> Result<(), FlashError> {
+    run(Operation::Erase { from, to }).await
+}
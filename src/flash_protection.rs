@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Write protection of the flash holding key material
+//!
+//! The built-in keys are part of the firmware image, and a provisioned identity is in the
+//! [provisioning page](crate::serial_provisioning) right above it. At startup, [protect] makes
+//! all flash below the settings journal (softdevice, firmware and provisioning page) read-only:
+//! through BPROT on the nRF52832, and through an ACL region on the nRF52833 and nRF52840. Code
+//! paths that erase or write flash by mistake then fail rather than destroying or replacing keys;
+//! only the journal pages stay writable.
+//!
+//! Neither peripheral allows lifting the protection until the next reset. Re-provisioning thus
+//! goes through [restart_unprotected], which resets the chip with a flag in GPREGRET2 that keeps
+//! [protect] from running at the following start. The protection is applied again once the
+//! identity was written (see [crate::serial_provisioning]), or at the start after that. Only the
+//! provisioning fixture can ask for that restart, so its authorization is physical access to the
+//! serial port.
+//!
+//! The pages are not read protected (which only the ACL could do): The firmware reads the
+//! built-in keys from its image, and borrows a provisioned identity from its page for as long as
+//! it runs. Both peripherals lift their protection while a debugger is attached, so the
+//! protection is no defense against extraction through the debug port (APPROTECT is).
+
+use coap_ace_poc_firmware::info;
+
+/// Start of the settings journal, up to which the flash is protected
+#[cfg(not(feature = "hardware-nrf52840dongle"))]
+const PROTECTED_END: u32 = 0x7e000;
+/// Start of the settings journal, up to which the flash is protected
+#[cfg(feature = "hardware-nrf52840dongle")]
+const PROTECTED_END: u32 = 0xde000;
+
+/// Size of the blocks BPROT protects, and of the pages the ACL regions are aligned to
+const BLOCK_SIZE: u32 = 4096;
+
+/// Flag in GPREGRET2 that requests a start without protection
+///
+/// GPREGRET (without the 2) is left alone, as bootloaders (eg. the dongle's) use it.
+const UNPROTECTED_FLAG: u8 = 0x01;
+
+/// Address of the GPREGRET2 register
+const GPREGRET2: *mut u32 = 0x4000_0520 as *mut u32;
+
+/// Whether this start was requested to be without protection; the request is used up by this.
+///
+/// This needs to be called before the softdevice is enabled, which restricts access to the
+/// register.
+pub fn unprotected_start() -> bool {
+    // SAFETY: GPREGRET2 is a plain retained register; the softdevice is not running yet.
+    unsafe {
+        let value = core::ptr::read_volatile(GPREGRET2);
+        core::ptr::write_volatile(GPREGRET2, value & !u32::from(UNPROTECTED_FLAG));
+        value & u32::from(UNPROTECTED_FLAG) != 0
+    }
+}
+
+/// Write protect all flash below the settings journal until the next reset.
+#[cfg(feature = "hardware-nrf52dk")]
+pub fn protect() {
+    /// Addresses of BPROT's CONFIG0 to CONFIG3 registers, each covering 32 blocks
+    const CONFIG: [u32; 4] = [0x4000_0600, 0x4000_0604, 0x4000_0610, 0x4000_0614];
+
+    let blocks = PROTECTED_END / BLOCK_SIZE;
+    for (index, register) in CONFIG.iter().enumerate() {
+        let first = index as u32 * 32;
+        let bits = match blocks.saturating_sub(first) {
+            0 => continue,
+            32.. => u32::MAX,
+            count => (1 << count) - 1,
+        };
+        // SAFETY: Setting protection bits only restricts later flash writes.
+        unsafe { core::ptr::write_volatile(*register as *mut u32, bits) };
+    }
+    info!("Flash below {:#x} is write protected", PROTECTED_END);
+}
+
+/// Write protect all flash below the settings journal until the next reset.
+#[cfg(not(feature = "hardware-nrf52dk"))]
+pub fn protect() {
+    /// Addresses of the ADDR, SIZE and PERM registers of ACL region 7, the last one (leaving the
+    /// first ones to bootloaders, which tend to start counting from 0)
+    const ACL: [u32; 3] = [0x4001_e870, 0x4001_e874, 0x4001_e878];
+    /// Value of PERM that disables writing and erasing
+    const WRITE_DISABLED: u32 = 1 << 1;
+
+    const _: () = assert!(PROTECTED_END % BLOCK_SIZE == 0);
+    // SAFETY: Configuring the region only restricts later flash writes; it can only be
+    // configured once per reset, and nothing else uses it.
+    unsafe {
+        core::ptr::write_volatile(ACL[0] as *mut u32, 0);
+        core::ptr::write_volatile(ACL[1] as *mut u32, PROTECTED_END);
+        core::ptr::write_volatile(ACL[2] as *mut u32, WRITE_DISABLED);
+    }
+    info!("Flash below {:#x} is write protected", PROTECTED_END);
+}
+
+/// Restart the device without the write protection, for re-provisioning.
+#[cfg(feature = "serial-provisioning")]
+pub fn restart_unprotected() -> ! {
+    use nrf_softdevice::raw;
+
+    info!("Restarting without flash protection");
+    // SAFETY: Plain softdevice calls without pointers
+    unsafe {
+        raw::sd_power_gpregret_set(1, u32::from(UNPROTECTED_FLAG));
+        raw::sd_nvic_SystemReset();
+    }
+    // Only reached if the softdevice is not enabled
+    cortex_m::peripheral::SCB::sys_reset()
+}
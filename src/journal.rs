@@ -0,0 +1,192 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Flash journal persisting the [settings](coap_ace_poc_firmware::settings)
+//!
+//! Two flash pages at the end of the flash, which the build script's `memory.x` keeps out of the
+//! firmware's reach, are used alternatingly. Each starts with a header of a magic number and a
+//! generation counter; the page with the valid header of the highest generation is the active one.
+//! Following the header, changed settings are appended as records in the format of
+//! [settings::encode_record]; later records override earlier ones.
+//!
+//! A record whose check value does not match (eg. because writing it was interrupted) ends the
+//! replay: The settings before it are used, the ones after it are left at their defaults, and the
+//! next change is written through a compaction, so nothing is appended after the corrupted record.
+//!
+//! When the active page is full, all current settings are written to the other page, whose header
+//! is written last, so that an interruption at any point leaves a usable journal. This spreads
+//! the wear over many writes of settings, and over both pages.
+//!
+//! Changes are only written after they have settled for a moment, so that an operator trying out
+//! values does not cause a write every time. The writes themselves go through [crate::flash],
+//! which retries them while the radio keeps the softdevice from carrying them out.
+
+use coap_ace_poc_firmware::settings::{self, Key, MAX_VALUE_LEN};
+use coap_ace_poc_firmware::{info, warn};
+
+/// Addresses of the flash pages reserved in `memory.x`
+#[cfg(not(feature = "hardware-nrf52840dongle"))]
+const PAGES: [u32; 2] = [0x7e000, 0x7f000];
+/// Addresses of the flash pages reserved in `memory.x`, which on the dongle end before its
+/// bootloader
+#[cfg(feature = "hardware-nrf52840dongle")]
+const PAGES: [u32; 2] = [0xde000, 0xdf000];
+/// Size of a flash page on the nRF52
+const PAGE_SIZE: u32 = 4096;
+
+/// Marks a page as holding a journal in this format
+const MAGIC: [u8; 4] = *b"SET3";
+/// Length of the page header (magic number and generation)
+const HEADER_LEN: u32 = 8;
+
+/// Time a change needs to remain unchanged before it is written
+const SETTLE_TIME: embassy_time::Duration = embassy_time::Duration::from_secs(2);
+
+/// Largest record, rounded up to the write granularity
+const MAX_RECORD_LEN: usize = (settings::RECORD_HEADER_LEN + MAX_VALUE_LEN + 3) / 4 * 4;
+
+/// Buffer for data to be written; the softdevice requires it to be word aligned.
+#[repr(align(4))]
+struct Aligned<const N: usize>([u8; N]);
+
+/// Read from the memory mapped flash.
+fn read<const N: usize>(address: u32) -> [u8; N] {
+    // SAFETY: Flash is memory mapped, and the pages are not part of the firmware image. Their
+    // content only changes through the persist task, which does not hold references into them.
+    unsafe { core::ptr::read_volatile(address as *const [u8; N]) }
+}
+
+/// Generation of the page, or None if it does not hold a valid header
+fn generation(page: u32) -> Option<u32> {
+    let header: [u8; HEADER_LEN as usize] = read(page);
+    if header[..4] != MAGIC {
+        return None;
+    }
+    let generation = u32::from_le_bytes(header[4..].try_into().unwrap());
+    // An erased generation field would be a header whose write was interrupted.
+    (generation != u32::MAX).then_some(generation)
+}
+
+/// Index of the active page along with its generation, if any page is valid
+fn active() -> Option<(usize, u32)> {
+    (0..PAGES.len())
+        .filter_map(|i| Some((i, generation(PAGES[i])?)))
+        .max_by_key(|(_, generation)| *generation)
+}
+
+/// Length of a record with a value of the given length, including padding
+fn record_len(value_len: usize) -> u32 {
+    settings::record_len(value_len) as u32
+}
+
+/// Addresses of the page that does not hold the active journal
+///
+/// Its content is not needed: The next compaction erases it anyway. The
+/// [factory test](crate::factory_test) uses it to exercise the flash without losing settings.
+pub fn spare_page() -> core::ops::Range<u32> {
+    let page = PAGES[active().map_or(1, |(index, _)| 1 - index)];
+    page..page + PAGE_SIZE
+}
+
+/// Replay the records of a page into the settings, returning the offset of the first free byte,
+/// or None if a corrupted record ended the replay.
+fn replay(page: u32) -> Option<u32> {
+    // SAFETY: As for [read]; the slice is dropped before the persist task writes to the page.
+    let records = unsafe {
+        core::slice::from_raw_parts(
+            (page + HEADER_LEN) as *const u8,
+            (PAGE_SIZE - HEADER_LEN) as usize,
+        )
+    };
+    settings::replay(records).map(|len| HEADER_LEN + len as u32)
+}
+
+/// Load the persisted settings.
+///
+/// This needs to run before the settings are used.
+pub fn load() {
+    match active() {
+        Some((index, generation)) => match replay(PAGES[index]) {
+            Some(_) => info!("Loaded settings of generation {}", generation),
+            None => warn!("Settings journal is corrupted, using the settings before the damage"),
+        },
+        None => info!("No settings stored, starting with defaults"),
+    }
+}
+
+/// Write a record at the given address.
+async fn write_record(
+    address: u32,
+    key: Key,
+    value: &[u8],
+) -> Result<(), nrf_softdevice::FlashError> {
+    let mut buffer = Aligned([0xff; MAX_RECORD_LEN]);
+    let len = settings::encode_record(key, value, &mut buffer.0);
+    crate::flash::write(address, &buffer.0[..len]).await
+}
+
+/// Write all current settings to the inactive page and make it the active one.
+///
+/// Returns the new active page's index and its first free offset.
+async fn compact(
+    current: Option<(usize, u32)>,
+) -> Result<(usize, u32), nrf_softdevice::FlashError> {
+    let (index, generation) = match current {
+        Some((index, generation)) => (1 - index, generation + 1),
+        None => (0, 0),
+    };
+    let page = PAGES[index];
+
+    crate::flash::erase(page, page + PAGE_SIZE).await?;
+    let mut offset = HEADER_LEN;
+    for (key, value) in settings::all() {
+        write_record(page + offset, key, &value).await?;
+        offset += record_len(value.len());
+    }
+
+    let mut header = Aligned([0; HEADER_LEN as usize]);
+    header.0[..4].copy_from_slice(&MAGIC);
+    header.0[4..].copy_from_slice(&generation.to_le_bytes());
+    crate::flash::write(page, &header.0).await?;
+
+    info!("Compacted settings into generation {}", generation);
+    Ok((index, offset))
+}
+
+/// Task appending changed settings to the journal
+#[embassy_executor::task]
+pub async fn persist() {
+    use embassy_futures::select::{select, Either};
+
+    let mut position = active().and_then(|(index, _)| Some((index, replay(PAGES[index])?)));
+
+    loop {
+        settings::CHANGED.wait().await;
+        while let Either::Second(()) = select(
+            embassy_time::Timer::after(SETTLE_TIME),
+            settings::CHANGED.wait(),
+        )
+        .await
+        {}
+
+        for (key, value) in settings::take_changed() {
+            let result = match position {
+                Some((index, offset)) if offset + record_len(value.len()) <= PAGE_SIZE => {
+                    write_record(PAGES[index] + offset, key, &value)
+                        .await
+                        .map(|()| (index, offset + record_len(value.len())))
+                }
+                // Compaction writes all current values, including this one.
+                _ => compact(active()).await,
+            };
+            match result {
+                Ok(new_position) => position = Some(new_position),
+                Err(e) => {
+                    warn!("Failed to persist settings: {:?}", e);
+                    // Start over on a fresh page with the next change.
+                    position = None;
+                }
+            }
+        }
+    }
+}
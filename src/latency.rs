@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Durations of cryptographic operations
+//!
+//! Components time the operations they perform by holding a guard obtained from [measure]; the
+//! shortest, average and longest duration of each [Operation] is kept, and shown in the
+//! `/stats/crypto` resource. The figures help in choosing a crypto backend (see [crate::crypto]),
+//! and in judging how much the message size (and thus the MTU) matters.
+//!
+//! EDHOC, OSCORE and the token processing of the resource server happen inside coapcore, so those
+//! operations are timed around the whole processing of the respective requests: For OSCORE, that
+//! is decrypting the request, running the resource's handler, and encrypting the response; the
+//! handlers respond right away, so the cryptography dominates.
+//!
+//! Figures live in RAM, and are thus lost at a reset.
+
+use core::cell::RefCell;
+
+use embassy_time::{Duration, Instant};
+
+/// Kinds of timed operations
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Operation {
+    /// Processing an EDHOC message (message 1 or 3 along with its response)
+    Edhoc,
+    /// Processing an OSCORE protected request
+    Oscore,
+    /// Processing a posted token in the resource server
+    Token,
+    /// Decrypting a token (AES-CCM) for the [records](crate::tokens)
+    TokenDecryption,
+}
+
+const OPERATIONS: usize = 4;
+
+impl Operation {
+    const ALL: [Operation; OPERATIONS] = [
+        Operation::Edhoc,
+        Operation::Oscore,
+        Operation::Token,
+        Operation::TokenDecryption,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Edhoc => "edhoc",
+            Operation::Oscore => "oscore",
+            Operation::Token => "token",
+            Operation::TokenDecryption => "token-decryption",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Durations {
+    count: u32,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+impl Durations {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::from_ticks(0),
+            total: Duration::from_ticks(0),
+        }
+    }
+
+    fn add(&mut self, duration: Duration) {
+        self.count = self.count.saturating_add(1);
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        self.total += duration;
+    }
+}
+
+static DURATIONS: critical_section::Mutex<RefCell<[Durations; OPERATIONS]>> =
+    critical_section::Mutex::new(RefCell::new([Durations::new(); OPERATIONS]));
+
+/// Guard timing an operation until it is dropped
+#[must_use]
+pub struct Measured(Operation, Instant);
+
+impl Drop for Measured {
+    fn drop(&mut self) {
+        let duration = self.1.elapsed();
+        critical_section::with(|cs| DURATIONS.borrow_ref_mut(cs)[self.0 as usize].add(duration));
+    }
+}
+
+/// Time an operation until the returned guard is dropped.
+pub fn measure(operation: Operation) -> Measured {
+    Measured(operation, Instant::now())
+}
+
+/// Copy of the recorded durations
+///
+/// When encoded into CBOR, this is a map from the operations' names (eg. `"edhoc"`) to arrays of
+/// the number of operations and the shortest, average and longest duration in microseconds.
+/// Operations that did not happen yet are left out.
+pub struct Report([Durations; OPERATIONS]);
+
+/// Obtain a copy of the recorded durations.
+pub fn report() -> Report {
+    critical_section::with(|cs| Report(*DURATIONS.borrow_ref(cs)))
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        let happened = || {
+            Operation::ALL
+                .into_iter()
+                .filter(|o| self.0[*o as usize].count > 0)
+        };
+        e.map(happened().count() as u64)?;
+        for operation in happened() {
+            let durations = &self.0[operation as usize];
+            e.str(operation.name())?
+                .array(4)?
+                .u32(durations.count)?
+                .u64(durations.min.as_micros())?
+                .u64(durations.total.as_micros() / u64::from(durations.count))?
+                .u64(durations.max.as_micros())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! CoAP/ACE PoC: Firmware
+//! ======================
+//!
+//! For general introductions, see the project's README.md file. This text is focused at people who
+//! want to not just use the crate, but build on it, understand its workings or alter it.
+//!
+//! Building and running
+//! --------------------
+//!
+//! When developing with this application, it is recommended to use a debugger rather than flashing
+//! a monolithic hex file.
+//!
+//! You'll need:
+//!
+//! * a Rust compiler with support for the thumbv7em-none-eabihf target
+//! * a copy of the [S132 softdevice] (eg. `s132_nrf52_7.3.0_softdevice.hex`)
+//!
+//!   Note that that software is limited in how it can be distributed; you will find the precise
+//!   license terms along with the file.
+//!
+//! * `probe-rs` and `nrf-recover` installed: `$ cargo install probe-rs-cli nrf-recover`
+//!
+//!   Other debuggers will do as well, but to display debug output, some tool that supports `defmt`
+//!   is needed; `probe-rs` does that.
+//!
+//! To build and install the firmware:
+//!
+//! * Flash the softdevice. This is only necessary if that version was not installed previously
+//!   already (eg. if the device was just erased).
+//!
+//!   ```shell
+//!   $ probe-rs download --chip nrf52832_xxAA --binary-format hex /tmp/s132_nrf52_7.3.0/s132_nrf52_7.3.0_softdevice.hex
+//!   ```
+//!
+//!   * If you encounter errors in the style of "Error: AP ApAddress { dp: Default, ap: 0 } is not
+//!     a memory AP", the target chip may be in a locked state; this depends on the previously
+//!     flashed firmware and/or the debugger. Run `nrf-recover` to unlock it; this erases all data
+//!     on the target device.
+//!
+//! * Restore operation of the reset pin after the `nrf-recover` wipe:
+//!
+//!   ```shell
+//!   $ cat uicr_reset_pin21.hex | grep -v '//' | probe-rs download --chip nrf52832_xxAA --binary-format hex /dev/stdin
+//!   ```
+//!
+//!   (where the grep is a workaround for probe-rs not accepting comments in ihex files<!-- https://github.com/martinmroz/ihex/issues/16#issuecomment-1374406055 -->).
+//!
+//! * Run
+//!
+//!   ```shell
+//!   $ cargo run --release
+//!   ```
+//!
+//!   which downloads all relevant crates, builds them and flashes them, all using `probe-rs`.
+//!
+//!   After a long horizontal line, the program will print any debug output the firmware produces.
+//!   To increase verbosity, prefix the command with `DEFMT_LOG=info`.
+//!
+//!   If you run into any trouble that look like they stem from C code, there may be inconsistent
+//!   versions of clang in use; setting `LLVM_CONFIG_PATH=/usr/bin/llvm-config-18
+//!   LIBCLANG_PATH=/usr/lib/llvm-18/lib/` or similar helps in those situations.
+//!
+//! Once the firmware is flashed, it will start whenever the device is powered.
+//!
+//! [S132 softdevice]: https://www.nordicsemi.com/Products/Development-software/s132/
+//!
+//! ### Other boards
+//!
+//! Instead of the nRF52-DK, the firmware can be built for other boards by replacing the default
+//! `hardware-nrf52dk` feature:
+//!
+//! * `hardware-nrf52833dk` for the nRF52833 DK, and
+//! * `hardware-nrf52840dongle` for the nRF52840 Dongle.
+//!
+//! Both need the [S140 softdevice] instead of S132 (and its `softdevice-s140` feature instead of
+//! `softdevice-s132`), and the `--chip` argument to `probe-rs` (including the one in
+//! `.cargo/config.toml`) adjusted to `nrf52833_xxAA` or `nrf52840_xxAA`. The pin configuration of
+//! the reset pin is not needed on either board. For example:
+//!
+//! ```shell
+//! $ cargo run --release --no-default-features --features hardware-nrf52833dk,softdevice-s140,resources
+//! ```
+//!
+//! On any of the boards, the [S113 softdevice] can be used instead (with the `softdevice-s113`
+//! feature). It only supports the peripheral role, which is all the firmware needs, and thus
+//! takes less flash and RAM.
+//!
+//! The dongle has no debugger on board. Unless one is attached to its SWD pads, softdevice and
+//! firmware are installed through its bootloader (eg. using `nrfutil`), which the memory layout
+//! leaves in place. That also means that there is no debug output. When powered from USB, the
+//! dongle's GPIOs run at 1.8V unless the REGOUT0 register in the UICR is set to 3.0V, which makes
+//! the LEDs dim or dark.
+//!
+//! [S140 softdevice]: https://www.nordicsemi.com/Products/Development-software/s140/
+//! [S113 softdevice]: https://www.nordicsemi.com/Products/Development-software/s113/
+//!
+//! ## Device identity
+//!
+//! By default, `configs/d00.yaml` is used to configure the AS to use, and contains a key
+//! shared between the device and its corresponding AS. When using multiple devices, they should
+//! all be provisioned with individual identities (i.e. different audience values and individual
+//! keys). The file to be used for a particular build can be passed in through the
+//! `RS_AS_ASSOCIATION` environment variable.
+//!
+//! Alternatively, one firmware can be built for all devices, and each device's identity injected
+//! at production time through a serial protocol (see [provisioning]); the identity from the
+//! `RS_AS_ASSOCIATION` file is then only used by devices that were not provisioned. Large fleets
+//! can also have the identity [derived](derivation) from each chip's device ID.
+//!
+//! ## Transports
+//!
+//! The only transport is CoAP-over-GATT (see [coap_gatt]). The firmware has no IP stack, so there
+//! is no UDP transport, and thus no multicast: Group requests (eg. discovery, or an `/identify` to
+//! all demo devices at once) are not available, and devices need to be addressed one connection at
+//! a time.
+//!
+//! ### Bluetooth stack
+//!
+//! The firmware is built on the softdevice, through nrf-softdevice. Everything in this library is
+//! independent of the Bluetooth stack: [coap_gatt] only takes the written bytes and produces the
+//! value to read, and the [platform] traits abstract the rest. A firmware on a different stack
+//! (like Nordic's SoftDevice Controller with a Rust host such as TrouBLE) could thus reuse the
+//! library unchanged. Besides advertising, connections and the GATT service, the firmware goes
+//! through the softdevice for the flash (settings journal, provisioning), the random number
+//! generator, the temperature sensor, AES-ECB, radio notifications, and the reset into unprotected
+//! flash.
+//!
+//! Host-side simulation
+//! --------------------
+//!
+//! All parts that do not immediately touch the hardware (the CoAP resources, the CoAP-over-GATT
+//! message handling and the ACE/OSCORE/EDHOC resource server around them) live in this library;
+//! the firmware binary only adds the softdevice, the LEDs and the executor.
+//!
+//! Building the library with the `std` feature (and without the default hardware feature) enables
+//! the `sim` module, which provides mock implementations of the [platform] traits and drives the
+//! CoAP-over-GATT layer from in-process byte buffers. This allows running integration tests of the
+//! full request path on the development machine:
+//!
+//! ```shell
+//! $ cargo test --no-default-features --features std,resources --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! (The explicit target is needed because the repository's cargo configuration defaults to the
+//! device's target.)
+//!
+//! The same simulation backs the fuzz targets in the `fuzz/` directory, which feed arbitrary data
+//! into characteristic writes and into the token claims processing:
+//!
+//! ```shell
+//! $ cargo +nightly fuzz run gatt_write --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod attestation;
+pub mod audit;
+pub mod battery;
+pub mod coap;
+pub mod coap_gatt;
+pub mod crypto;
+pub mod derivation;
+pub mod devicetime;
+pub mod disconnects;
+pub mod faults;
+pub mod latency;
+pub mod lockout;
+pub mod logging;
+#[cfg(feature = "lwm2m")]
+pub mod lwm2m;
+pub mod platform;
+pub mod power;
+pub mod profiling;
+pub mod provisioning;
+pub mod rollback;
+pub mod rs_configuration;
+pub mod security;
+pub mod selftest;
+pub mod settings;
+#[cfg(feature = "std")]
+pub mod sim;
+pub mod stats;
+pub mod timesync;
+pub mod tokens;
+pub mod trace;
+
+/// Board and identity settings of a device
+///
+/// This is populated at build time from the optional fields of the same file as the
+/// [CoapcoreConfig], by including the `board_config.rs` file that the build script generates.
+/// Fields that are absent from the file take the nRF52-DK's defaults.
+pub struct BoardConfig {
+    /// GAP device name; if unset, it is derived from the audience.
+    pub device_name: Option<&'static str>,
+    /// Pin numbers of the LEDs 1 to 4 (P1 pins counting from 32)
+    pub led_pins: [u8; 4],
+    /// Advertising interval in milliseconds that is used unless the `adv-interval`
+    /// [setting](settings::Key) is set
+    pub advertising_interval: Option<u16>,
+    /// Seconds after which the device's resolvable private address is replaced, or None if it
+    /// uses its static address (see the firmware's `advertising::set_privacy`)
+    pub address_rotation: Option<u16>,
+}
+
+/// Configuration of the resource server's security setup
+///
+/// This is populated at build time from the file indicated in `RS_AS_ASSOCIATION` by including
+/// the `rs_as_association.rs` file that the build script generates, or [loaded](provisioning::load)
+/// from an identity provisioned at runtime. The firmware keeps it in a static, so that the
+/// [selftest] can verify the copy in flash.
+pub struct CoapcoreConfig {
+    /// The device's own audience
+    ///
+    /// Tokens signed by the AS need to carry it as their sole `aud` claim. Tokens encrypted with
+    /// [as_symmetric](Self::as_symmetric) may instead name it or any of the
+    /// [group_audiences](Self::group_audiences), alone or in an array; they are adapted to the
+    /// resource server before it sees them.
+    pub audience: &'static str,
+    /// Further audiences the device belongs to, as an encoded CBOR array of text strings (or
+    /// empty if there are none)
+    pub group_audiences: &'static [u8],
+    /// The encoded AS Request Creation Hints sent in 4.01 responses
+    ///
+    /// These are the same in every response.
+    pub request_creation_hints: &'static [u8],
+
+    pub as_symmetric: Option<[u8; 32]>,
+
+    /// The device's own EDHOC credential (a CCS containing the public key), as encoded by the
+    /// build script
+    pub edhoc_credential: Option<&'static [u8]>,
+    pub edhoc_q: Option<&'static [u8; 32]>,
+
+    pub as_pub: Option<([u8; 32], [u8; 32])>,
+
+    /// CRC-32 over the other fields, as calculated by the build script
+    pub checksum: u32,
+}
+
+impl CoapcoreConfig {
+    /// Whether the configuration contains an EDHOC key and any means to verify tokens
+    pub fn is_provisioned(&self) -> bool {
+        self.edhoc_credential.is_some()
+            && self.edhoc_q.is_some()
+            && (self.as_symmetric.is_some() || self.as_pub.is_some())
+    }
+
+    /// Calculate the CRC-32 that should be in the [checksum](Self::checksum) field.
+    ///
+    /// This needs to match the build script's `config_checksum` function.
+    pub fn calculate_checksum(&self) -> u32 {
+        config_checksum(
+            self.audience,
+            self.group_audiences,
+            self.request_creation_hints,
+            self.as_symmetric.as_ref().map(|k| &k[..]),
+            self.edhoc_credential,
+            self.edhoc_q.map(|q| &q[..]),
+            self.as_pub.as_ref(),
+        )
+    }
+}
+
+/// CRC-32 over the fields of a [CoapcoreConfig], whether built in or
+/// [provisioned](provisioning)
+fn config_checksum(
+    audience: &str,
+    group_audiences: &[u8],
+    request_creation_hints: &[u8],
+    as_symmetric: Option<&[u8]>,
+    edhoc_credential: Option<&[u8]>,
+    edhoc_q: Option<&[u8]>,
+    as_pub: Option<&([u8; 32], [u8; 32])>,
+) -> u32 {
+    const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let mut digest = CRC.digest();
+    digest.update(audience.as_bytes());
+    digest.update(group_audiences);
+    digest.update(request_creation_hints);
+    for item in [as_symmetric, edhoc_credential, edhoc_q].iter().flatten() {
+        digest.update(item);
+    }
+    if let Some((x, y)) = as_pub {
+        digest.update(x);
+        digest.update(y);
+    }
+    digest.finalize()
+}
+
+// 700 exceeds some internal limits, but 400 is plenty for our a-bit-over-200 byte tokens.
+pub const MAX_MESSAGE_LEN: usize = 400;
+
+/// Build the complete CoAP handler: the resource tree built by `resources` (typically
+/// [coap::create_coap_handler]), wrapped in the ACE / OSCORE / EDHOC resource server configured
+/// from `coapcore_config`.
+///
+/// `resources` is called again whenever the resource server is rebuilt after a change of keys.
+///
+/// `rng` needs to be cryptographically secure on the device (the host-side simulation uses a
+/// deterministic one for reproducibility). EDHOC uses the implementation picked by
+/// [crypto::selected].
+///
+/// The keys are taken from `coapcore_config` initially, and can be changed at runtime through
+/// [security].
+///
+/// The handler's type can not be named, as it contains closures; it stays with whoever builds it
+/// (on the device, the task processing the requests).
+pub fn build_main_rs<H: coap_handler::Handler, R>(
+    coapcore_config: &'static CoapcoreConfig,
+    mut resources: impl FnMut() -> H,
+    rng: R,
+) -> impl coap_handler::Handler + security::Previous
+where
+    R: rand_core::RngCore + rand_core::CryptoRng + Copy + 'static,
+{
+    use cbor_macro::cbor;
+
+    security::init(security::Keys::from_config(coapcore_config));
+    tokens::set_audiences(coapcore_config);
+    let crypto_backend = crypto::selected(rng);
+
+    security::Reloading::new(move |keys: security::Keys| {
+        let Some((credential, edhoc_q)) = keys.edhoc else {
+            crate::error!("No EDHOC key is provisioned");
+            return None;
+        };
+        crate::info!("Using own credential {}", crate::logging::Hex(credential));
+        let Ok(credential) = lakers::Credential::parse_ccs(credential) else {
+            crate::error!("Own credential could not be parsed");
+            return None;
+        };
+
+        // This deliberately does not include `/.well-known/core`: The report lists all
+        // resources regardless of what the requester may access, so it is only served to
+        // peers whose token scope contains it, and unauthenticated scanners learn nothing
+        // about the resource layout (they receive the 4.01 response with the request creation
+        // hints instead).
+        let mut our_seccfg = coapcore::seccfg::ConfigBuilder::new()
+            .allow_unauthenticated(
+                coapcore::scope::AifValue::parse(&cbor!([
+                    ["/time", 5/GET+PUT/],
+                    ["/time/sync", 5/GET+PUT/]
+                ]))
+                .expect("Literal is a valid AIF value")
+                .into(),
+            )
+            .with_request_creation_hints(coapcore_config.request_creation_hints)
+            .with_own_edhoc_credential(credential, edhoc_q);
+        if let Some((x, y)) = keys.as_pub {
+            let Ok(audience) = coapcore_config.audience.try_into() else {
+                crate::error!("Audience is too long for asymmetric tokens");
+                return None;
+            };
+            our_seccfg = our_seccfg.with_aif_asymmetric_es256(x, y, audience);
+        }
+        if let Some(key) = keys.as_symmetric {
+            our_seccfg = our_seccfg.with_aif_symmetric_as_aesccm256(key);
+        }
+
+        Some(coapcore::OscoreEdhocHandler::new(
+            resources(),
+            our_seccfg,
+            move || crypto::CryptoBackend::crypto(&crypto_backend),
+            rng,
+            devicetime::Time,
+        ))
+    })
+}
+
+/// The resource server as it is shared between all connections
+///
+/// Access happens through a mutex; see [coap_gatt::Connection] on how failure to obtain it is
+/// handled.
+pub type Rs<H> = embassy_sync::mutex::Mutex<embassy_sync::blocking_mutex::raw::NoopRawMutex, H>;
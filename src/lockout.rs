@@ -0,0 +1,168 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Counters of failed authentication attempts, and the delays they incur
+//!
+//! A peer that posts tokens the resource server can not decrypt (or verify), or sends EDHOC
+//! messages that fail, might be trying to find the symmetric AS key (or a credential) online. The
+//! platform reports the outcome of every such [Attempt] through [record], keyed by the peer's
+//! Bluetooth address, and holds back the response by the delay it returns: The first
+//! [FREE_FAILURES] consecutive failures of a peer are answered right away (so that a client that
+//! merely retries with a stale token is not slowed down), and from then on every further failure
+//! doubles the delay, starting at [FIRST_DELAY] and up to [MAX_DELAY]. A successful attempt resets
+//! the peer's count.
+//!
+//! Failures are counted per address rather than per connection, so reconnecting does not reset
+//! them. Centrals that use a new random address for every connection are not slowed down beyond
+//! the cost of connecting; their attempts still show in the total. Only the latest
+//! [MAX_PEERS] addresses with failures are tracked; when more fail, the one with the fewest
+//! failures is forgotten.
+//!
+//! The counters are shown in the `/stats/auth` resource. They live in RAM, and are thus lost at a
+//! reset.
+
+use core::cell::RefCell;
+
+use embassy_time::Duration;
+
+/// A Bluetooth device address
+pub type Address = [u8; 6];
+
+/// Number of peers whose failures are tracked
+pub const MAX_PEERS: usize = 8;
+
+/// Number of consecutive failures of a peer that are answered without delay
+pub const FREE_FAILURES: u32 = 3;
+
+/// Delay added after the first failure beyond [FREE_FAILURES]
+pub const FIRST_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay added after a failure
+pub const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Outcome of an attempt to authenticate
+///
+/// Token posts and EDHOC messages are attempts, and so are protected requests (as they show that
+/// an EDHOC exchange completed).
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Attempt {
+    Succeeded,
+    Failed,
+}
+
+#[derive(Copy, Clone)]
+struct Peer {
+    address: Address,
+    /// Consecutive failures
+    failures: u32,
+}
+
+struct Counters {
+    /// Failures of all peers since startup
+    total: u32,
+    peers: heapless::Vec<Peer, MAX_PEERS>,
+}
+
+static COUNTERS: critical_section::Mutex<RefCell<Counters>> =
+    critical_section::Mutex::new(RefCell::new(Counters {
+        total: 0,
+        peers: heapless::Vec::new(),
+    }));
+
+/// The delay to add after a peer's consecutive failures
+fn delay(failures: u32) -> Duration {
+    let Some(beyond) = failures.checked_sub(FREE_FAILURES + 1) else {
+        return Duration::from_ticks(0);
+    };
+    // Anything beyond 2^16 is capped anyway.
+    let factor = 1u64 << beyond.min(16);
+    Duration::from_ticks(FIRST_DELAY.as_ticks().saturating_mul(factor)).min(MAX_DELAY)
+}
+
+/// Record the outcome of a peer's attempt to authenticate, returning how long to hold back the
+/// response.
+pub fn record(address: Address, attempt: Attempt) -> Duration {
+    critical_section::with(|cs| {
+        let mut counters = COUNTERS.borrow_ref_mut(cs);
+        let known = counters.peers.iter().position(|p| p.address == address);
+        match (attempt, known) {
+            (Attempt::Succeeded, Some(index)) => {
+                counters.peers.swap_remove(index);
+                Duration::from_ticks(0)
+            }
+            (Attempt::Succeeded, None) => Duration::from_ticks(0),
+            (Attempt::Failed, known) => {
+                counters.total = counters.total.saturating_add(1);
+                let index = match known {
+                    Some(index) => index,
+                    None => {
+                        let new = Peer {
+                            address,
+                            failures: 0,
+                        };
+                        if counters.peers.push(new).is_err() {
+                            let (fewest, _) = counters
+                                .peers
+                                .iter()
+                                .enumerate()
+                                .min_by_key(|(_, p)| p.failures)
+                                .expect("The list is full");
+                            counters.peers[fewest] = new;
+                            fewest
+                        } else {
+                            counters.peers.len() - 1
+                        }
+                    }
+                };
+                let peer = &mut counters.peers[index];
+                peer.failures = peer.failures.saturating_add(1);
+                let delay = delay(peer.failures);
+                if delay.as_ticks() > 0 {
+                    crate::warn!(
+                        "{} consecutive failed authentication attempts from {}, delaying by {}ms",
+                        peer.failures,
+                        crate::logging::Hex(&peer.address),
+                        delay.as_millis()
+                    );
+                }
+                delay
+            }
+        }
+    })
+}
+
+/// Copy of the failure counters
+///
+/// When encoded into CBOR, this is a map containing the number of failures since startup under
+/// `"total"`, and under `"peers"` a map from the addresses (as byte strings, in the little endian
+/// order the softdevice uses) of peers with consecutive failures to their number.
+pub struct Report {
+    total: u32,
+    peers: heapless::Vec<Peer, MAX_PEERS>,
+}
+
+/// Obtain a copy of the failure counters.
+pub fn report() -> Report {
+    critical_section::with(|cs| {
+        let counters = COUNTERS.borrow_ref(cs);
+        Report {
+            total: counters.total,
+            peers: counters.peers.clone(),
+        }
+    })
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(2)?.str("total")?.u32(self.total)?;
+        e.str("peers")?.map(self.peers.len() as u64)?;
+        for peer in self.peers.iter() {
+            e.bytes(&peer.address)?.u32(peer.failures)?;
+        }
+        Ok(())
+    }
+}
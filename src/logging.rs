@@ -0,0 +1,295 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Runtime log level filtering
+//!
+//! On the device, the macros of this module emit through defmt. On the host (with the `std`
+//! feature), they emit through the [log] crate instead, where any logger the test or tool installs
+//! picks them up; defmt's binary output could not be decoded there anyway. Messages are thus
+//! written in the common subset of the formatting syntaxes: `{}` for values that implement both
+//! [core::fmt::Display] and [defmt::Format], `{:?}` for values that implement [core::fmt::Debug]
+//! and [defmt::Format], and [Hex] for byte strings.
+//!
+//! defmt decides at build time (through `DEFMT_LOG`) which log statements are included in the
+//! firmware at all. The macros of this module ([crate::error!], [crate::warn!], [crate::info!] and
+//! [crate::debug!]) additionally consult a runtime filter, which can be adjusted through the
+//! `/debug/loglevel` resource.
+//!
+//! A field unit built with `DEFMT_LOG=debug` can thus run quietly until someone with the right
+//! permissions raises the level over the air. The runtime filter starts out letting everything
+//! through, so that on builds without special configuration, `DEFMT_LOG` works as before.
+//!
+//! Independently of any filter, warnings and errors are also recorded in a small ring buffer,
+//! which is exposed through the `/debug/log` resource. As defmt only transports its format strings
+//! out of band, what is recorded is the format string (without the formatted arguments), the level
+//! and the uptime.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering::Relaxed};
+
+/// Verbosity levels, ordered from least to most verbose
+///
+/// The numeric values are what is transported in the `/debug/loglevel` resource.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Error type indicating that a number does not represent any [Level]
+#[derive(Debug)]
+pub struct InvalidLevel;
+
+impl TryFrom<u8> for Level {
+    type Error = InvalidLevel;
+
+    fn try_from(value: u8) -> Result<Self, InvalidLevel> {
+        Ok(match value {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return Err(InvalidLevel),
+        })
+    }
+}
+
+/// Most verbose level that currently passes the filter
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+/// Set the most verbose level that is logged henceforth.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Relaxed);
+}
+
+/// Return the most verbose level that is currently logged.
+pub fn max_level() -> Level {
+    MAX_LEVEL
+        .load(Relaxed)
+        .try_into()
+        .expect("Only ever stored from a Level")
+}
+
+/// Check whether a message at the given level should be emitted.
+///
+/// This is what the logging macros consult; it rarely needs to be called directly.
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Relaxed)
+}
+
+/// Number of warning and error events kept for the `/debug/log` resource
+const RECORDED_EVENTS: usize = 16;
+
+/// Longest encoding of [RecordedEvents], which leaves room for the CoAP and OSCORE overhead of a
+/// response within [crate::MAX_MESSAGE_LEN]
+pub const MAX_RECORDED_LEN: usize = 320;
+
+/// A single warning or error event
+#[derive(Copy, Clone)]
+pub struct Event {
+    /// Seconds since boot
+    pub uptime: u32,
+    pub level: Level,
+    /// The format string of the log message
+    pub message: &'static str,
+}
+
+impl Event {
+    /// Length of the event's CBOR encoding in [RecordedEvents]
+    fn encoded_len(&self) -> usize {
+        fn head(value: usize) -> usize {
+            match value {
+                0..=23 => 1,
+                24..=0xff => 2,
+                0x100..=0xffff => 3,
+                _ => 5,
+            }
+        }
+        head(3)
+            + head(self.uptime as usize)
+            + head(self.level as usize)
+            + head(self.message.len())
+            + self.message.len()
+    }
+}
+
+static RECORDED: critical_section::Mutex<RefCell<heapless::Deque<Event, RECORDED_EVENTS>>> =
+    critical_section::Mutex::new(RefCell::new(heapless::Deque::new()));
+
+/// Store an event in the ring buffer, discarding the oldest one if it is full.
+///
+/// This is what the [crate::error!] and [crate::warn!] macros call; it rarely needs to be called
+/// directly.
+pub fn record(level: Level, message: &'static str) {
+    let event = Event {
+        uptime: embassy_time::Instant::now().as_secs() as u32,
+        level,
+        message,
+    };
+    critical_section::with(|cs| {
+        let mut recorded = RECORDED.borrow_ref_mut(cs);
+        if recorded.is_full() {
+            recorded.pop_front();
+        }
+        // Can't fail: we just made room
+        let _ = recorded.push_back(event);
+    });
+}
+
+/// Copy of the recorded events, oldest first
+///
+/// When encoded into CBOR, this is an array of `[uptime, level, message]` arrays, with the level
+/// as in [Level]. The encoding is at most [MAX_RECORDED_LEN] bytes long.
+pub struct RecordedEvents(heapless::Vec<Event, RECORDED_EVENTS>);
+
+/// Obtain a copy of the newest recorded events, as many as fit into [MAX_RECORDED_LEN].
+pub fn recorded() -> RecordedEvents {
+    critical_section::with(|cs| {
+        let recorded = RECORDED.borrow_ref(cs);
+        // The array head is a single byte for up to 23 events.
+        let mut room = MAX_RECORDED_LEN - 1;
+        let fitting = recorded
+            .iter()
+            .rev()
+            .take_while(|event| {
+                let fits = event.encoded_len() <= room;
+                room = room.saturating_sub(event.encoded_len());
+                fits
+            })
+            .count();
+        RecordedEvents(
+            recorded
+                .iter()
+                .skip(recorded.len() - fitting)
+                .copied()
+                .collect(),
+        )
+    })
+}
+
+impl<C> minicbor::encode::Encode<C> for RecordedEvents {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(self.0.len() as u64)?;
+        for event in self.0.iter() {
+            e.array(3)?
+                .u32(event.uptime)?
+                .u8(event.level as u8)?
+                .str(event.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Byte string that is shown in hexadecimal, in defmt and in [log] output alike
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl defmt::Format for Hex<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[u8]:02x}", self.0)
+    }
+}
+
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+pub use log;
+
+/// Emit a message through the backend: defmt on the device
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __emit {
+    (error, $($arg:tt)*) => { defmt::error!($($arg)*) };
+    (warn, $($arg:tt)*) => { defmt::warn!($($arg)*) };
+    (info, $($arg:tt)*) => { defmt::info!($($arg)*) };
+    (debug, $($arg:tt)*) => { defmt::debug!($($arg)*) };
+}
+
+/// Emit a message through the backend: the log crate on the host
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __emit {
+    ($level:ident, $($arg:tt)*) => { $crate::logging::log::$level!($($arg)*) };
+}
+
+/// Like [defmt::error!], but subject to the runtime filter, and recorded in the ring buffer
+#[macro_export]
+macro_rules! error {
+    ($fmt:literal $($arg:tt)*) => {{
+        $crate::logging::record($crate::logging::Level::Error, $fmt);
+        if $crate::logging::enabled($crate::logging::Level::Error) {
+            $crate::__emit!(error, $fmt $($arg)*);
+        }
+    }};
+}
+
+/// Like [defmt::warn!], but subject to the runtime filter, and recorded in the ring buffer
+#[macro_export]
+macro_rules! warn {
+    ($fmt:literal $($arg:tt)*) => {{
+        $crate::logging::record($crate::logging::Level::Warn, $fmt);
+        if $crate::logging::enabled($crate::logging::Level::Warn) {
+            $crate::__emit!(warn, $fmt $($arg)*);
+        }
+    }};
+}
+
+/// Like [defmt::info!], but subject to the runtime filter
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Info) {
+            $crate::__emit!(info, $($arg)*);
+        }
+    };
+}
+
+/// Like [defmt::debug!], but subject to the runtime filter
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Debug) {
+            $crate::__emit!(debug, $($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_events_fit() {
+        const LONG: &str = "A warning long enough that not all of the recorded events fit \
+                            into a single response";
+        for _ in 0..RECORDED_EVENTS {
+            record(Level::Warn, LONG);
+        }
+        let recorded = recorded();
+        assert!(!recorded.0.is_empty());
+
+        let mut buffer = [0; MAX_RECORDED_LEN];
+        let mut cursor = minicbor::encode::write::Cursor::new(&mut buffer[..]);
+        minicbor::encode(recorded, &mut cursor).unwrap();
+    }
+}
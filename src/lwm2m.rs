@@ -0,0 +1,156 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! OMA LwM2M objects
+//!
+//! With the `lwm2m` feature, the device's core data is additionally exposed in the
+//! object/instance/resource path scheme of LwM2M, with one instance each of these objects:
+//!
+//! * Device (`/3/0`): Manufacturer (`/0`), Model Number (`/1`), Firmware Version (`/3`), Power
+//!   Source Voltage (`/7`, in millivolts, see [crate::battery]) and Current Time (`/13`, writable).
+//! * Temperature (`/3303/0`): Sensor Value (`/5700`) and Sensor Units (`/5701`).
+//! * Light Control (`/3311/0`): On/Off (`/5850`, writable), where on means that the idle LEDs are
+//!   lit.
+//!
+//! Resources are read and written one at a time in the LwM2M plain text format (content format
+//! 0); object and instance level requests (and their TLV / SenML formats) are not supported. This
+//! is all a LwM2M management server needs to read and set the data, but there is no LwM2M client:
+//! The device can not register with a server, as it has no IP transport to reach one. Servers
+//! that reach the device (eg. through a gateway) need a token whose scope contains the paths,
+//! like for any other resource.
+
+use coap_message::{Code as _, MinimalWritableMessage, MutableWritableMessage, ReadableMessage};
+use coap_message_utils::Error;
+use coap_numbers::code::{CHANGED, CONTENT};
+
+use crate::platform::{LedControl, Thermometer};
+
+/// The LwM2M resources that are served (see the module documentation for their paths)
+#[derive(Copy, Clone)]
+pub enum Item {
+    Manufacturer,
+    ModelNumber,
+    FirmwareVersion,
+    PowerSourceVoltage,
+    CurrentTime,
+    SensorValue,
+    SensorUnits,
+    OnOff,
+}
+
+/// Resource handler for one [Item]
+pub struct Resource<T: 'static, L: 'static> {
+    pub item: Item,
+    pub thermometer: &'static T,
+    pub leds: &'static L,
+}
+
+/// A value in the LwM2M plain text format
+type Text = heapless::String<32>;
+
+impl<T: Thermometer, L: LedControl> Resource<T, L> {
+    fn read(&self) -> Result<Text, Error> {
+        use core::fmt::Write;
+
+        let mut text = Text::new();
+        let written = match self.item {
+            Item::Manufacturer => write!(text, "Nordic Semiconductor"),
+            Item::ModelNumber => write!(text, "CoAP-ACE demo"),
+            Item::FirmwareVersion => write!(text, "{}", env!("CARGO_PKG_VERSION")),
+            Item::PowerSourceVoltage => {
+                let millivolts =
+                    crate::battery::millivolts().ok_or_else(Error::service_unavailable)?;
+                write!(text, "{}", millivolts)
+            }
+            Item::CurrentTime => {
+                let now =
+                    crate::devicetime::unixtime().map_err(|_| Error::service_unavailable())?;
+                write!(text, "{}", now)
+            }
+            Item::SensorValue => {
+                let temperature = self
+                    .thermometer
+                    .temperature()
+                    .map_err(|_| Error::service_unavailable())?;
+                write!(text, "{}", temperature)
+            }
+            Item::SensorUnits => write!(text, "Cel"),
+            Item::OnOff => write!(text, "{}", u8::from(self.leds.idle() > 0)),
+        };
+        written.map_err(|_| Error::internal_server_error())?;
+        Ok(text)
+    }
+
+    fn write(&self, text: &str) -> Result<(), Error> {
+        match self.item {
+            Item::CurrentTime => {
+                use crate::devicetime::Refused;
+                let now = text.parse().map_err(|_| Error::bad_request())?;
+                // As with a PUT to `/time`, the clock can only be turned back a little; setting it
+                // freely takes a POST to `/time`.
+                match crate::devicetime::advance_unixtime(now) {
+                    Ok(()) => (),
+                    Err(Refused::Rewind) => return Err(Error::forbidden()),
+                    Err(Refused::OutOfRange) => return Err(Error::bad_request()),
+                }
+            }
+            Item::OnOff => match (text, self.leds.idle()) {
+                ("0", _) => self.leds.set_idle(0),
+                ("1", 0) => self.leds.set_idle(1),
+                ("1", _) => (),
+                _ => return Err(Error::bad_request()),
+            },
+            _ => return Err(Error::method_not_allowed()),
+        }
+        Ok(())
+    }
+}
+
+impl<T: Thermometer, L: LedControl> coap_handler::Handler for Resource<T, L> {
+    /// The value to send after a GET, or None after a PUT
+    type RequestData = Option<Text>;
+    type ExtractRequestError = Error;
+    type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Error> {
+        use coap_message_utils::OptionsExt;
+        use coap_numbers::code::{GET, PUT};
+        request.options().ignore_elective_others()?;
+        match request.code().into() {
+            GET => Ok(Some(self.read()?)),
+            PUT => {
+                let text =
+                    core::str::from_utf8(request.payload()).map_err(|_| Error::bad_request())?;
+                self.write(text.trim())?;
+                Ok(None)
+            }
+            _ => Err(Error::method_not_allowed()),
+        }
+    }
+
+    fn estimate_length(&mut self, _: &Self::RequestData) -> usize {
+        40
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        text: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        use coap_message::OptionNumber;
+        let Some(text) = text else {
+            response.set_code(M::Code::new(CHANGED)?);
+            return Ok(());
+        };
+        response.set_code(M::Code::new(CONTENT)?);
+        response.add_option_uint(
+            M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+            0u8,
+        )?;
+        response.set_payload(text.as_bytes())?;
+        Ok(())
+    }
+}
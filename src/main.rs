@@ -1,103 +1,80 @@
 // SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
 // SPDX-License-Identifier: BSD-3-Clause
 // See README for all details on copyright, authorship and license.
-//! CoAP/ACE PoC: Firmware
-//! ======================
+//! CoAP/ACE PoC: Firmware binary
 //!
-//! For general introductions, see the project's README.md file. This text is focused at people who
-//! want to not just use the crate, but build on it, understand its workings or alter it.
-//!
-//! Building and running
-//! --------------------
-//!
-//! When developing with this application, it is recommended to use a debugger rather than flashing
-//! a monolithic hex file.
-//!
-//! You'll need:
-//!
-//! * a nightly Rust compiler with support for the thumbv7em-none-eabihf target
-//! * a copy of the [S132 softdevice] (eg. `s132_nrf52_7.3.0_softdevice.hex`)
-//!
-//!   Note that that software is limited in how it can be distributed; you will find the precise
-//!   license terms along with the file.
-//!
-//! * `probe-rs` and `nrf-recover` installed: `$ cargo install probe-rs-cli nrf-recover`
-//!
-//!   Other debuggers will do as well, but to display debug output, some tool that supports `defmt`
-//!   is needed; `probe-rs` does that.
-//!
-//! To build and install the firmware:
-//!
-//! * Flash the softdevice. This is only necessary if that version was not installed previously
-//!   already (eg. if the device was just erased).
-//!
-//!   ```shell
-//!   $ probe-rs download --chip nrf52832_xxAA --binary-format hex /tmp/s132_nrf52_7.3.0/s132_nrf52_7.3.0_softdevice.hex
-//!   ```
-//!
-//!   * If you encounter errors in the style of "Error: AP ApAddress { dp: Default, ap: 0 } is not
-//!     a memory AP", the target chip may be in a locked state; this depends on the previously
-//!     flashed firmware and/or the debugger. Run `nrf-recover` to unlock it; this erases all data
-//!     on the target device.
-//!
-//! * Restore operation of the reset pin after the `nrf-recover` wipe:
-//!
-//!   ```shell
-//!   $ cat uicr_reset_pin21.hex | grep -v '//' | probe-rs download --chip nrf52832_xxAA --binary-format hex /dev/stdin
-//!   ```
-//!
-//!   (where the grep is a workaround for probe-rs not accepting comments in ihex files<!-- https://github.com/martinmroz/ihex/issues/16#issuecomment-1374406055 -->).
-//!
-//! * Run
-//!
-//!   ```shell
-//!   $ cargo +nightly run --release
-//!   ```
-//!
-//!   which downloads all relevant crates, builds them and flashes them, all using `probe-rs`.
-//!
-//!   After a long horizontal line, the program will print any debug output the firmware produces.
-//!   To increase verbosity, prefix the command with `DEFMT_LOG=info`.
-//!
-//!   If you run into any trouble that look like they stem from C code, there may be inconsistent
-//!   versions of clang in use; setting `LLVM_CONFIG_PATH=/usr/bin/llvm-config-18
-//!   LIBCLANG_PATH=/usr/lib/llvm-18/lib/` or similar helps in those situations.
-//!
-//! Once the firmware is flashed, it will start whenever the device is powered.
-//!
-//! [S132 softdevice]: https://www.nordicsemi.com/Products/Development-software/s132/
-//!
-//! ## Device identity
-//!
-//! By default, `configs/d00.yaml` is used to configure the AS to use, and contains a key
-//! shared between the device and its corresponding AS. When using multiple devices, they should
-//! all be provisioned with individual identities (i.e. different audience values and individual
-//! keys). The file to be used for a particular build can be passed in through the
-//! `RS_AS_ASSOCIATION` environment variable.
+//! This binds the [coap_ace_poc_firmware] library to the supported boards (see the `hardware-*`
+//! features; the nRF52-DK by default): It starts the
+//! softdevice, runs the Bluetooth advertising and connection tasks, and drives the board LEDs. See
+//! the library's documentation for how to build and run it.
 #![no_std]
 #![no_main]
-#![feature(type_alias_impl_trait)]
-
-mod coap_gatt;
-mod rs_configuration;
 
+mod advertising;
 mod alloc;
 mod blink;
-mod coap;
-mod devicetime;
+mod connections;
+mod ecb;
+mod factory_test;
+mod fault_handler;
+mod flash;
+mod flash_protection;
+mod journal;
+mod peer;
+#[cfg(feature = "profiling")]
+mod profiling_pins;
+mod radio;
+mod requests;
+mod rollback_counter;
+mod serial_provisioning;
+mod service_changed;
+#[cfg(feature = "debug-shell")]
+mod shell;
+mod supply;
+#[cfg(feature = "ws2812")]
+mod ws2812;
+// The strip takes P0.11 and P0.12, which other boards use for a button or an LED (the build script
+// rejects this as well, but only once it runs).
+#[cfg(all(feature = "ws2812", not(feature = "hardware-nrf52dk")))]
+compile_error!("The `ws2812` feature is only available on the nRF52-DK");
 
+#[cfg(not(feature = "debug-shell"))]
 use defmt_rtt as _;
 use embassy_nrf as _;
 use panic_probe as _;
 
+use coap_ace_poc_firmware::derivation::{Derived, IdentityDerivation};
+use coap_ace_poc_firmware::platform::{LedControl, SensorUnavailable, Status, Thermometer};
+use coap_ace_poc_firmware::{error, info, warn};
+use coap_ace_poc_firmware::{
+    lockout, power, rollback, settings, BoardConfig, CoapcoreConfig, MAX_MESSAGE_LEN,
+};
 use cortex_m_rt::entry;
-use defmt::{error, info, unwrap, warn};
-use embassy_executor::{Executor, Spawner};
+use defmt::unwrap;
+use embassy_executor::{Executor, InterruptExecutor, Spawner};
+use embassy_futures::select::{Either3, Either4};
 use nrf_softdevice::ble::{gatt_server, peripheral};
 use nrf_softdevice::{raw, Softdevice};
 
 static EXECUTOR: static_cell::StaticCell<Executor> = static_cell::StaticCell::new();
 
+/// Executor of the tasks that use the softdevice
+///
+/// These run at a priority above thread mode, where the [requests] are processed, so that they
+/// keep reacting to softdevice events (eg. by sending the response on one connection, or by
+/// completing a flash operation) while the cryptography of a request on another connection takes
+/// its time. Everything that uses the softdevice's asynchronous operations needs to run here, as
+/// [nrf_softdevice] dispatches their events from the [softdevice_task] without synchronizing with
+/// other priorities.
+///
+/// Its interrupt is SWI3, at the lowest priority (SWI2 is the softdevice's own event interrupt).
+static SD_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[embassy_nrf::interrupt]
+unsafe fn SWI3_EGU3() {
+    SD_EXECUTOR.on_interrupt()
+}
+
 /// Maximum number of concurrent BLE connections to manage
 ///
 /// Careful: Must match the executor::task(pool_size) manually (see also [USED_CONNECTIONS])
@@ -113,13 +90,83 @@ const MAX_CONNECTIONS: u8 = 4;
 /// <https://github.com/embassy-rs/embassy/issues/1080> anyway.
 static USED_CONNECTIONS: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
 
+/// Time within which a peer in a reserved connection slot needs to present and use a token that
+/// grants administrative access (see
+/// [is_admin](coap_ace_poc_firmware::coap_gatt::Connection::is_admin))
+///
+/// The last of the [MAX_CONNECTIONS] slots is reserved for maintenance operators, so that they can
+/// reach the device even when demo participants have taken all other slots. As it is not known who
+/// connects until they use a token, anyone can take it, but unless they are admins, they are
+/// disconnected after this time -- unless other slots became free in the meantime. (There is no
+/// bonding, so there are no admin identities that would be known at connection time.)
+///
+/// The connection policy in the settings can reserve more slots (see [peer_slots]).
+const ADMIN_GRACE: embassy_time::Duration = embassy_time::Duration::from_secs(20);
+
+/// Number of connection slots open to peers other than admins
+///
+/// That is all but the last of the [MAX_CONNECTIONS] slots, fewer if the connection policy limits
+/// them through [settings::max_peers], and none while it closes the device to them (see
+/// [settings::open_for_peers]). Admins can always connect, as slots beyond these are handled like
+/// the reserved one (see [ADMIN_GRACE]).
+fn peer_slots() -> u8 {
+    if !settings::open_for_peers() {
+        return 0;
+    }
+    let slots = MAX_CONNECTIONS - 1;
+    settings::max_peers().map_or(slots, |peers| peers.min(slots))
+}
+
 /// Background task in which the Softdevice handless all its tasks.
 ///
 /// Note that many softdevice tasks are handled in interrupts, which must not be disabled; see the
 /// [nrf_softdevice] documentation for details.
 #[embassy_executor::task]
 async fn softdevice_task(sd: &'static Softdevice) {
-    sd.run().await;
+    // The disconnect reason is not passed on to the connection's tasks, so it is taken from the
+    // event before the softdevice wrapper dispatches it.
+    sd.run_with_callback(|event| {
+        // SAFETY: The event pointer is valid for the duration of the callback, and the union
+        // field is the one the event ID indicates.
+        unsafe {
+            if u32::from((*event).header.evt_id) == raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED {
+                let gap_event = (*event).evt.gap_evt.as_ref();
+                coap_ace_poc_firmware::disconnects::record(
+                    gap_event.params.disconnected.as_ref().reason,
+                );
+            }
+        }
+    })
+    .await;
+}
+
+/// Interval in which expired tokens are looked for
+const SWEEP_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
+/// Background task dropping expired tokens
+///
+/// The resource server only notices that a token expired when a request using it arrives. This
+/// regularly sweeps the [record of accepted tokens](coap_ace_poc_firmware::tokens), and once all
+/// the tokens in there expired, drops all security contexts, so that the resource server's pool
+/// slots are free for new peers.
+///
+/// Tokens the record does not know about (eg. those sent inside EDHOC) do not keep the contexts
+/// alive.
+///
+/// The expiry is shown on the LEDs, so that users understand why their controls stopped working.
+/// (Peers are not notified otherwise, as CoAP-over-GATT-02 supports neither Observe nor role
+/// reversal.)
+#[embassy_executor::task]
+async fn expiry_sweeper(leds: &'static blink::Leds) {
+    loop {
+        embassy_time::Timer::after(SWEEP_INTERVAL).await;
+        let swept = coap_ace_poc_firmware::tokens::sweep();
+        if swept.expired > 0 && swept.remaining == 0 {
+            info!("All tokens expired, dropping security contexts");
+            coap_ace_poc_firmware::security::revoke_all();
+            leds.show_status(Status::Expired);
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -151,30 +198,39 @@ impl rand_core::RngCore for SdRandomness {
 // nrf_softdevice::random_bytes is advertised as cryptographically secure
 impl rand_core::CryptoRng for SdRandomness {}
 
-pub(crate) struct CoapcoreConfig {
-    pub audience: &'static str,
-    pub request_creation_hints: &'static [u8],
-
-    pub as_symmetric: Option<[u8; 32]>,
-
-    pub edhoc_x: Option<[u8; 32]>,
-    pub edhoc_y: Option<[u8; 32]>,
-    pub edhoc_q: Option<&'static [u8; 32]>,
-
-    pub as_pub: Option<([u8; 32], [u8; 32])>,
-}
-
 // None of our current users take these as actual UUIDs...
 // let coap_gatt_us: Uuid = "8df804b7-3300-496d-9dfa-f8fb40a236bc".parse().unwrap();
 // let coap_gatt_uc: Uuid = "2a58fc3f-3c62-4ecc-8167-d66d4d9410c2".parse().unwrap();
 
-// 700 exceeds some internal limits, but 400 is plenty for our a-bit-over-200 byte tokens.
-const MAX_MESSAGE_LEN: usize = 400;
+/// ATT MTU offered to peers
+///
+/// The minimum is not acceptable in amsuess-core-coap-over-gatt-02 (and the tokens we post are
+/// already in the order of 100 bytes long).
+const ATT_MTU: u16 = 420;
 
+/// Longest value a single ATT Write Request can carry at [ATT_MTU]
+///
+/// The characteristic accepts writes up to this length rather than just [MAX_MESSAGE_LEN], so that
+/// requests too large to be processed still reach
+/// [coap_gatt](coap_ace_poc_firmware::coap_gatt), which answers them with a 4.13 Request Entity
+/// Too Large response instead of having the softdevice reject them at the ATT level.
+const MAX_WRITE_LEN: usize = ATT_MTU as usize - 3;
+
+/// The CoAP-over-GATT service
+///
+/// Messages can be longer than what fits into a single ATT PDU at MTUs below the [ATT_MTU]
+/// configured in [main]:
+///
+/// * Long reads (Read Blob requests with an offset) work: The value is kept in the softdevice's
+///   attribute table, from which the softdevice serves offset reads without involving the
+///   application.
+///
+/// * Long writes (Prepare Write and Execute Write requests) are rejected by the softdevice, so
+///   peers need to negotiate an MTU that fits their requests.
 #[nrf_softdevice::gatt_service(uuid = "8df804b7-3300-496d-9dfa-f8fb40a236bc")]
 struct CoAPGattService {
     #[characteristic(uuid = "2a58fc3f-3c62-4ecc-8167-d66d4d9410c2", read, write, indicate)]
-    message: heapless::Vec<u8, MAX_MESSAGE_LEN>,
+    message: heapless::Vec<u8, MAX_WRITE_LEN>,
 }
 
 // The only GATT attribute we're offering is the CoAP endpoint.
@@ -183,96 +239,190 @@ struct Server {
     coap: CoAPGattService,
 }
 
-mod main_rs_definition {
-    use super::*;
-
-    pub type MainRs = impl coap_handler::Handler;
-
-    pub fn build_main_rs(
-        coapcore_config: CoapcoreConfig,
-        sd: &'static Softdevice,
-        leds: &'static blink::Leds,
-    ) -> MainRs {
-        use cbor_macro::cbor;
-        // FIXME This block is constructing a KCCS out of a raw public key.
-        //
-        // move … somewhere (duplicated w/ webapp)
-        // FIXME: Turned from KCCS to CCS, which is the credential (KCCS is the ID_CRED)
-        let mut credential = hex_literal::hex!("A2 02 60 08 A1 01 A5 01 02 02 41 63 20 01 21 5820 7878787878787878787878787878787878787878787878787878787878787878 22 5820 7979797979797979797979797979797979797979797979797979797979797979");
-        credential[17..17 + 32].copy_from_slice(coapcore_config.edhoc_x.unwrap().as_slice());
-        credential[52..52 + 32].copy_from_slice(coapcore_config.edhoc_y.unwrap().as_slice());
-        let edhoc_q = coapcore_config.edhoc_q.unwrap();
-        defmt::info!("Built own credential as {:02x}", credential);
-
-        let credential = lakers::Credential::parse_ccs(&credential).unwrap();
-
-        let mut our_seccfg = coapcore::seccfg::ConfigBuilder::new()
-            .allow_unauthenticated(
-                coapcore::scope::AifValue::parse(&cbor!([["/time", 7/GET+POST+PUT/]]))
-                    .unwrap()
-                    .into(),
-            )
-            .with_request_creation_hints(coapcore_config.request_creation_hints)
-            .with_own_edhoc_credential(credential, *edhoc_q);
-        if let Some((x, y)) = coapcore_config.as_pub {
-            our_seccfg = our_seccfg.with_aif_asymmetric_es256(
-                x,
-                y,
-                coapcore_config.audience.try_into().unwrap(),
-            );
-        }
-        if let Some(key) = coapcore_config.as_symmetric {
-            our_seccfg = our_seccfg.with_aif_symmetric_as_aesccm256(key);
-        }
+/// Temperature sensor access through the softdevice
+///
+/// The softdevice claims the TEMP peripheral for its own; see also the comments around the
+/// commented-out code in [chip_startup].
+struct SdThermometer(&'static Softdevice);
 
-        coapcore::OscoreEdhocHandler::new(
-            coap::create_coap_handler(&sd, &leds),
-            our_seccfg,
-            move || lakers_crypto_rustcrypto::Crypto::new(SdRandomness(sd)),
-            SdRandomness(sd),
-            devicetime::Time,
-        )
+impl Thermometer for SdThermometer {
+    fn temperature(&self) -> Result<fixed::types::I30F2, SensorUnavailable> {
+        nrf_softdevice::temperature_celsius(self.0)
+            .map(|t| t + settings::temperature_offset())
+            .map_err(|_| SensorUnavailable)
     }
 }
 
-use main_rs_definition::{build_main_rs, MainRs};
-
-type Rs = embassy_sync::mutex::Mutex<embassy_sync::blocking_mutex::raw::NoopRawMutex, MainRs>;
+/// The thermometer the resources read: the die temperature, or a deterministic waveform with the
+/// `synthetic-temperature` feature
+#[cfg(not(feature = "synthetic-temperature"))]
+type AppThermometer = SdThermometer;
+#[cfg(feature = "synthetic-temperature")]
+type AppThermometer = coap_ace_poc_firmware::platform::SyntheticThermometer;
 
 /// Single Bluetooth connection handler
 ///
 /// This is spawned from [bluetooth_task] once a connection arrives, and terminates at
 /// disconnection.
+///
+/// This runs on the [SD_EXECUTOR]. Requests are not processed in the GATT server's event
+/// callback, which runs inside the [softdevice_task]'s event dispatch, but are handed over to the
+/// [requests] task on the thread mode executor; meanwhile, this task and the softdevice task stay
+/// responsive. (The BLE link itself, including the connection supervision, is maintained by the
+/// softdevice in interrupts of even higher priority.)
 // Careful: pool_size must match MAX_CONNECTIONS
 #[embassy_executor::task(pool_size = 4)]
 async fn blueworker(
     server: &'static Server,
     conn: nrf_softdevice::ble::Connection,
-    rs: &'static Rs,
+    reserved: bool,
 ) {
-    let mut cg = coap_gatt::Connection::new(rs);
+    let _connected = power::track(power::Category::Connected);
 
-    info!("Running new BLE connection");
-    gatt_server::run(&conn, server, |e| match e {
-        ServerEvent::Coap(e) => match e {
-            CoAPGattServiceEvent::MessageWrite(mut m) => {
-                let response = cg.write(&mut *m);
+    blink::STATUS.signal(Status::Connected);
 
-                info!("Setting response {:?}", response);
+    let Some(slot) = connections::register(&conn) else {
+        warn!("No slot free for the connection, dropping it");
+        let _ = conn.disconnect();
+        USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
+        return;
+    };
+    requests::open(slot).await;
 
-                // Just in case someone polls
-                unwrap!(server.coap.message_set(&response));
-                unwrap!(server.coap.message_indicate(&conn, &response));
+    let admin = core::cell::Cell::new(false);
+    // Only completes if the connection needs to be terminated
+    let grace = async {
+        if reserved {
+            embassy_time::Timer::after(ADMIN_GRACE).await;
+            if !admin.get()
+                && USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst) > peer_slots()
+            {
+                return;
             }
-            CoAPGattServiceEvent::MessageCccdWrite { indications: ind } => {
-                // Indications are currently specified but not implemented
-                info!("Indications: {}", ind);
+        }
+        core::future::pending::<()>().await
+    };
+
+    let last_traffic = core::cell::Cell::new(embassy_time::Instant::now());
+    // Only completes if the peer stayed silent for longer than the idle timeout. The timeout is
+    // looked up anew on every round, so that changes to it also apply to existing connections.
+    let idle = async {
+        loop {
+            match settings::idle_timeout() {
+                Some(timeout) => {
+                    let deadline = last_traffic.get() + timeout;
+                    if embassy_time::Instant::now() >= deadline {
+                        return;
+                    }
+                    embassy_time::Timer::at(deadline).await;
+                }
+                None => {
+                    // Check again later in case it gets set
+                    embassy_time::Timer::after_secs(60).await;
+                }
             }
-        },
-    })
-    .await;
+        }
+    };
+
+    let handshake = core::cell::Cell::new(None);
+    // Only completes if the peer did not complete an EDHOC exchange within the handshake timeout
+    let stalled = async {
+        loop {
+            match (handshake.get(), settings::handshake_timeout()) {
+                (Some(started), Some(timeout)) => {
+                    let deadline = started + timeout;
+                    if embassy_time::Instant::now() >= deadline {
+                        return;
+                    }
+                    embassy_time::Timer::at(deadline).await;
+                }
+                _ => {
+                    // Check again later in case a handshake starts
+                    embassy_time::Timer::after_secs(5).await;
+                }
+            }
+        }
+    };
+
+    // Requests handed over from the event callback; CoAP-over-GATT-02 only has one request in
+    // flight at a time.
+    let written = embassy_sync::channel::Channel::<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        requests::Message,
+        1,
+    >::new();
+    let process = async {
+        loop {
+            let request = written.receive().await;
+            let outcome = requests::process(slot, request).await;
+            admin.set(outcome.admin);
+            handshake.set(outcome.handshake);
+
+            if let Some(attempt) = outcome.attempt {
+                let delay = lockout::record(conn.peer_address().bytes(), attempt);
+                // Only this connection waits; the requests of others are processed meanwhile.
+                embassy_time::Timer::after(delay).await;
+            }
+
+            info!("Setting response {:?}", outcome.response);
+            // Just in case someone polls
+            if let Err(e) = server.coap.message_set(&outcome.response) {
+                warn!("Response could not be set: {:?}", e);
+            }
+            // This fails if the central did not enable indications; it can still read.
+            if let Err(e) = server.coap.message_indicate(&conn, &outcome.response) {
+                info!("Response could not be indicated: {:?}", e);
+            }
+        }
+    };
+
+    info!("Running new BLE connection");
+    let gatt = gatt_server::run(&conn, server, |e| match e {
+        ServerEvent::Coap(e) => {
+            last_traffic.set(embassy_time::Instant::now());
+            match e {
+                CoAPGattServiceEvent::MessageWrite(m) => {
+                    if written.try_send(m).is_err() {
+                        warn!("Request arrived before the previous one was answered, dropping it");
+                    }
+                }
+                CoAPGattServiceEvent::MessageCccdWrite { indications: ind } => {
+                    // Indications are currently specified but not implemented
+                    info!("Indications: {}", ind);
+                }
+            }
+        }
+    });
+    // Processing never ends on its own, so this completes when the connection ends.
+    let served = embassy_futures::select::select(gatt, process);
+    // Reading from the central's GATT server and indicating Service Changed run alongside serving
+    // it, and are done long before the connection ends.
+    let served = embassy_futures::join::join3(
+        served,
+        peer::explore(&conn, Some(slot)),
+        service_changed::indicate(&conn),
+    );
+    match embassy_futures::select::select4(served, grace, idle, stalled).await {
+        Either4::First(_) => (),
+        Either4::Second(()) => {
+            info!("Peer in a reserved slot is no admin, disconnecting");
+            // If it fails, it's because it's already disconnected, which is just as well
+            let _ = conn.disconnect();
+        }
+        Either4::Third(()) => {
+            info!("Peer sent nothing within the idle timeout, disconnecting");
+            let _ = conn.disconnect();
+        }
+        Either4::Fourth(()) => {
+            info!("Peer did not complete its EDHOC exchange in time, disconnecting");
+            let _ = conn.disconnect();
+        }
+    }
     info!("Peer disconnected");
 
+    connections::unregister(slot);
+    peer::clear(slot);
+
     USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
 }
 
@@ -282,37 +432,65 @@ async fn blueworker(
 /// per-connection tasks.
 ///
 /// It alternates between sending connectable advertisements (when connectable) and unconnectable
-/// advertisements (while the pool of connections is exhausted).
+/// advertisements (while the pool of connections is exhausted, or while the device is not ready
+/// and the [settings::AdvertisingPolicy] says so). When no one connected for the time set in
+/// [settings::beacon_after], it sends [beacons](advertising::beacon) instead until button 1 is
+/// pressed.
 #[embassy_executor::task]
 async fn bluetooth_task(
     sd: &'static Softdevice,
     server: &'static Server,
-    scan_data: &'static [u8],
-    spawner: Spawner,
-    rs: &'static Rs,
+    coapcore_config: &'static CoapcoreConfig,
 ) {
-    #[rustfmt::skip]
-    let adv_data = &[
-        // length, type, value; types see Generic Access Profile
-        //
-        // We'd only send the minimal data here; once we get someone's attention they'll scan us
-        // for the more information below.
-
-        // AD structure 1: Flags (they can't be in the scan data, which is enforced by the
-        // softdevice; and without these, blueman-manager won't show the device)
-        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        // AD structure 2: Appearance: generic thermometer
-        0x03, 0x19, 0x00, 0x03,
-    ];
+    let spawner = Spawner::for_current_executor().await;
+    // Built for every advertisement, as it ends in the number of connection slots that are free
+    // for anyone (ie. not counting those reserved for admins, see [peer_slots]). The webapp uses
+    // this to steer users towards devices that can accept their connection.
+    //
+    // We'd only send the minimal data here; once we get someone's attention they'll scan us for
+    // the more information in the scan data.
+    let build_adv_data = |free_slots: u8| {
+        let mut adv_data = ADV_DATA;
+        adv_data[ADV_DATA.len() - 1] = free_slots;
+        adv_data
+    };
+    let free_slots =
+        || peer_slots().saturating_sub(USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst));
+
+    let (scan_data, name_len) = scan_data(coapcore_config.audience);
+    let scan_data = &scan_data[..];
+    // Scan data with only the first AD structure (the name), ie. without the CoAP service
+    let scan_data_without_service = &scan_data[..name_len];
+
+    let policy = || {
+        let ready = coapcore_config.is_provisioned()
+            && coap_ace_poc_firmware::devicetime::unixtime().is_ok();
+        match ready {
+            true => settings::AdvertisingPolicy::Always,
+            false => settings::advertising_policy(),
+        }
+    };
+
+    let mut backoff = advertising::Backoff::new(BOARD_CONFIG.advertising_interval);
+    let mut last_connection = embassy_time::Instant::now();
 
     loop {
-        while USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst) >= MAX_CONNECTIONS {
-            info!("Connections full; advertising unconnectable");
+        loop {
+            if USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst) >= MAX_CONNECTIONS {
+                info!("Connections full; advertising unconnectable");
+            } else if policy() == settings::AdvertisingPolicy::Refuse {
+                info!("Not ready; advertising unconnectable");
+            } else {
+                break;
+            }
             // FIXME: Does this need to contain different info?
+            // (No slot is free as far as peers are concerned, as none can connect)
+            let adv_data = build_adv_data(0);
             let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected {
-                adv_data,
+                adv_data: &adv_data,
                 scan_data,
             };
+            let _advertising = power::track(power::Category::Advertising);
             let nonconn = peripheral::advertise(
                 sd,
                 adv,
@@ -329,23 +507,77 @@ async fn bluetooth_task(
             }
         }
 
+        // Time after which to turn into a beacon, unless someone connects or is connected
+        let beacon_in = settings::beacon_after()
+            .filter(|_| USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst) == 0)
+            .map(|after| {
+                after
+                    .checked_sub(last_connection.elapsed())
+                    .unwrap_or_default()
+            });
+        if beacon_in == Some(embassy_time::Duration::from_ticks(0)) {
+            advertising::beacon(sd, &SdThermometer(sd)).await;
+            last_connection = embassy_time::Instant::now();
+            backoff.reset();
+            continue;
+        }
+
         info!("Advertising as connectable until a connection is establsihed");
+        blink::STATUS.signal(Status::Advertising);
+        let adv_data = build_adv_data(free_slots());
         let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
-            adv_data,
-            scan_data,
+            adv_data: &adv_data,
+            scan_data: match policy() {
+                settings::AdvertisingPolicy::HideService => scan_data_without_service,
+                _ => scan_data,
+            },
         };
-        USED_CONNECTIONS.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-        let conn = peripheral::advertise_connectable(sd, adv, &peripheral::Config::default()).await;
+        let reserved =
+            USED_CONNECTIONS.fetch_add(1, core::sync::atomic::Ordering::SeqCst) + 1 > peer_slots();
+        let advertising_time = power::track(power::Category::Advertising);
+        let conn = embassy_futures::select::select3(
+            peripheral::advertise_connectable(sd, adv, &backoff.config()),
+            advertising::WAKE.wait(),
+            async {
+                match beacon_in {
+                    Some(duration) => embassy_time::Timer::after(duration).await,
+                    None => core::future::pending().await,
+                }
+            },
+        )
+        .await;
+        drop(advertising_time);
 
         let conn = match conn {
-            Ok(c) => c,
-            Err(e) => {
+            Either3::First(Ok(c)) => {
+                backoff.reset();
+                last_connection = embassy_time::Instant::now();
+                c
+            }
+            Either3::First(Err(peripheral::AdvertiseError::Timeout)) => {
+                backoff.next();
+                USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
+                continue;
+            }
+            Either3::First(Err(e)) => {
                 error!("Failed to advertise connectable due to {:?}, continuing", e);
                 continue;
             }
+            Either3::Second(()) => {
+                info!("Button pressed, advertising fast again");
+                backoff.reset();
+                last_connection = embassy_time::Instant::now();
+                USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
+                continue;
+            }
+            Either3::Third(()) => {
+                // Turning into a beacon is handled at the start of the next round.
+                USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
+                continue;
+            }
         };
 
-        if let Err(_) = spawner.spawn(blueworker(server, conn, rs)) {
+        if let Err(_) = spawner.spawn(blueworker(server, conn, reserved)) {
             // Counting should make sure this never happens, but it's a bit racy.
             warn!("Spawn failure, dropping conn right away");
             USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
@@ -353,9 +585,111 @@ async fn bluetooth_task(
     }
 }
 
+/// Device name used in safe mode
+const SAFE_MODE_NAME: &str = "CoAP-ACE safe mode";
+
+/// Longest GAP device name; names derived from longer audiences are truncated.
+///
+/// The softdevice would take up to 248 bytes, but centrals show little more than this.
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+// Advertising and scan response data, as assembled from the configuration by the build script
+include!(concat!(env!("OUT_DIR"), "/advertising_data.rs"));
+
+/// Scan response data for an audience
+///
+/// This is [SCAN_DATA] with the name derived from the given audience, which differs from the
+/// built-in one if the identity was [provisioned](serial_provisioning). Returns the data along
+/// with the length of its first AD structure (the name).
+fn scan_data(audience: &str) -> (heapless::Vec<u8, 31>, usize) {
+    let service = &SCAN_DATA[SCAN_DATA_NAME_LEN..];
+    // Room for the name next to the length and type bytes of its AD structure
+    let room = 31 - service.len() - 2;
+    let mut name = heapless::String::<31>::new();
+    for c in "CoAP ".chars().chain(audience.chars()) {
+        if name.len() + c.len_utf8() > room {
+            break;
+        }
+        unwrap!(name.push(c));
+    }
+    let mut data = heapless::Vec::new();
+    // Shortened Local Name
+    unwrap!(data.extend_from_slice(&[name.len() as u8 + 1, 0x08]));
+    unwrap!(data.extend_from_slice(name.as_bytes()));
+    let name_len = data.len();
+    unwrap!(data.extend_from_slice(service));
+    (data, name_len)
+}
+
+/// Advertising task of the safe mode
+///
+/// The device enters safe mode when its configuration (built-in or provisioned) does not match its
+/// checksum (ie. the flash is damaged), instead of building a resource server from keys that
+/// can't be trusted, or panicking on them. In safe mode, the device advertises with
+/// [SAFE_MODE_NAME], so that it can be identified as needing repair; the self test has shown the
+/// failure on the LEDs already. Centrals that connect are served by [requests::run_safe_mode]:
+/// They can read `/info`, and provision an identity that replaces the damaged one at the next
+/// start (see [serial_provisioning::respond]).
+///
+/// Nothing in safe mode needs the heap, which is thus never [initialized](alloc::init) then.
+#[embassy_executor::task]
+async fn safe_mode_advertiser(sd: &'static Softdevice, server: &'static Server) {
+    let spawner = Spawner::for_current_executor().await;
+
+    let mut adv_data = heapless::Vec::<u8, 31>::new();
+    unwrap!(adv_data.extend_from_slice(&[
+        0x02,
+        0x01,
+        raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+        SAFE_MODE_NAME.len() as u8 + 1,
+        // Complete Local Name
+        0x09,
+    ]));
+    unwrap!(adv_data.extend_from_slice(SAFE_MODE_NAME.as_bytes()));
+
+    loop {
+        if USED_CONNECTIONS.load(core::sync::atomic::Ordering::SeqCst) >= MAX_CONNECTIONS {
+            // Checking again once a connection may have ended
+            embassy_time::Timer::after_secs(1).await;
+            continue;
+        }
+        let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+            adv_data: &adv_data,
+            scan_data: &[],
+        };
+        let conn = match peripheral::advertise_connectable(sd, adv, &Default::default()).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("Failed to advertise: {:?}", err);
+                embassy_time::Timer::after_secs(1).await;
+                continue;
+            }
+        };
+        USED_CONNECTIONS.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        if let Err(_) = spawner.spawn(blueworker(server, conn, false)) {
+            warn!("Spawn failure, dropping conn right away");
+            USED_CONNECTIONS.fetch_sub(1, core::sync::atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+/// Board setup from the provisioning file
+static BOARD_CONFIG: BoardConfig = include!(concat!(env!("OUT_DIR"), "/board_config.rs"));
+
 /// Parts of the peripherals that are needed by the application
 struct ChipParts {
     leds: blink::LedPins,
+    /// Button 1, which wakes up advertisements
+    button: embassy_nrf::gpio::Input<'static>,
+    /// ADC through which the supply voltage is measured
+    saadc: embassy_nrf::peripherals::SAADC,
+    /// Spare pins showing the phases of [coap_ace_poc_firmware::profiling]
+    #[cfg(feature = "profiling")]
+    profiling_pins: [embassy_nrf::gpio::Output<'static>; coap_ace_poc_firmware::profiling::PHASES],
+    #[cfg(feature = "ws2812")]
+    strip: ws2812::Spim,
+    #[cfg(feature = "serial-provisioning")]
+    uart: embassy_nrf::uarte::Uarte<'static, embassy_nrf::peripherals::UARTE0>,
 }
 
 /// Initialize chip peripherals, in particular clocks, interrupts and LEDs.
@@ -365,19 +699,58 @@ fn chip_startup() -> ChipParts {
     let mut config: embassy_nrf::config::Config = Default::default();
     // We have these on the board
     config.hfclk_source = embassy_nrf::config::HfclkSource::ExternalXtal;
-    config.lfclk_source = embassy_nrf::config::LfclkSource::ExternalXtal;
+    #[cfg(not(feature = "hardware-nrf52840dongle"))]
+    {
+        config.lfclk_source = embassy_nrf::config::LfclkSource::ExternalXtal;
+    }
+    // Not relying on a 32.768kHz crystal being populated; see the softdevice's clock
+    // configuration.
+    #[cfg(feature = "hardware-nrf52840dongle")]
+    {
+        config.lfclk_source = embassy_nrf::config::LfclkSource::InternalRC;
+    }
     // Differing from default, these stay out of softdevice's hair
     config.gpiote_interrupt_priority = embassy_nrf::interrupt::Priority::P7;
     config.time_interrupt_priority = embassy_nrf::interrupt::Priority::P6;
 
     let peripherals = embassy_nrf::init(config);
 
-    use embassy_nrf::gpio::{Level, Output, OutputDrive};
-    // See https://infocenter.nordicsemi.com/topic/ug_nrf52832_dk/UG/nrf52_DK/hw_btns_leds.html
-    let led1_pin = Output::new(peripherals.P0_17, Level::Low, OutputDrive::Standard);
-    let led2_pin = Output::new(peripherals.P0_18, Level::Low, OutputDrive::Standard);
-    let led3_pin = Output::new(peripherals.P0_19, Level::Low, OutputDrive::Standard);
-    let led4_pin = Output::new(peripherals.P0_20, Level::Low, OutputDrive::Standard);
+    use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+    // See https://infocenter.nordicsemi.com/topic/ug_nrf52832_dk/UG/nrf52_DK/hw_btns_leds.html for
+    // the defaults on the nRF52-DK; the build script knows those of the other supported boards,
+    // and custom boards configure theirs in the provisioning file.
+    let led_pin = |index: usize| {
+        // SAFETY: The build script ensures that the LED pins are distinct pins of the chip, and
+        // none of them are used by any other peripheral taken here.
+        let pin = unsafe { embassy_nrf::gpio::AnyPin::steal(BOARD_CONFIG.led_pins[index]) };
+        Output::new(pin, Level::Low, OutputDrive::Standard)
+    };
+    let led1_pin = led_pin(0);
+    let led2_pin = led_pin(1);
+    let led3_pin = led_pin(2);
+    let led4_pin = led_pin(3);
+    #[cfg(feature = "hardware-nrf52dk")]
+    let button1_pin = Input::new(peripherals.P0_13, Pull::Up);
+    #[cfg(feature = "hardware-nrf52833dk")]
+    let button1_pin = Input::new(peripherals.P0_11, Pull::Up);
+    #[cfg(feature = "hardware-nrf52840dongle")]
+    let button1_pin = Input::new(peripherals.P1_06, Pull::Up);
+    // Pins as in the build script's board definitions
+    #[cfg(all(
+        feature = "profiling",
+        any(feature = "hardware-nrf52dk", feature = "hardware-nrf52833dk")
+    ))]
+    let profiling_pins = [
+        Output::new(peripherals.P0_03, Level::Low, OutputDrive::Standard),
+        Output::new(peripherals.P0_04, Level::Low, OutputDrive::Standard),
+        Output::new(peripherals.P0_28, Level::Low, OutputDrive::Standard),
+    ];
+    #[cfg(all(feature = "profiling", feature = "hardware-nrf52840dongle"))]
+    let profiling_pins = [
+        Output::new(peripherals.P0_02, Level::Low, OutputDrive::Standard),
+        Output::new(peripherals.P0_29, Level::Low, OutputDrive::Standard),
+        Output::new(peripherals.P0_31, Level::Low, OutputDrive::Standard),
+    ];
 
     // Left in as a template for other interrupt driven components -- but the softdevice wants the
     // temperature interrupt for its own. See also complaints about how the softdevice handles this
@@ -399,9 +772,70 @@ fn chip_startup() -> ChipParts {
             l3: led3_pin,
             l4: led4_pin,
         },
+        button: button1_pin,
+        saadc: peripherals.SAADC,
+        #[cfg(feature = "profiling")]
+        profiling_pins,
+        #[cfg(feature = "ws2812")]
+        strip: ws2812::spim(peripherals.SPI2, peripherals.P0_12, peripherals.P0_11),
+        // The development kits' virtual COM port
+        #[cfg(all(
+            feature = "serial-provisioning",
+            not(feature = "hardware-nrf52840dongle")
+        ))]
+        uart: serial_provisioning::uart(peripherals.UARTE0, peripherals.P0_08, peripherals.P0_06),
+        #[cfg(all(feature = "serial-provisioning", feature = "hardware-nrf52840dongle"))]
+        uart: serial_provisioning::uart(peripherals.UARTE0, peripherals.P1_13, peripherals.P1_10),
     }
 }
 
+/// The chip's 64-bit device ID from the FICR, from which an identity can be
+/// [derived](coap_ace_poc_firmware::derivation)
+fn device_id() -> u64 {
+    // SAFETY: The FICR is always readable, and never changes.
+    let [low, high] = unsafe { core::ptr::read_volatile(0x1000_0060 as *const [u32; 2]) };
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// The firmware image as it is in flash, from the vector table up to the end of the initial
+/// values of the `.data` section
+fn firmware_image() -> &'static [u8] {
+    // Symbols of cortex-m-rt's linker script
+    extern "C" {
+        static __vector_table: u8;
+        static __sidata: u8;
+        static __sdata: u8;
+        static __edata: u8;
+    }
+    // SAFETY: Only the symbols' addresses are used. The image lies in flash, which is always
+    // readable and does not change while the firmware runs.
+    unsafe {
+        let start = core::ptr::addr_of!(__vector_table);
+        let data_len =
+            core::ptr::addr_of!(__edata) as usize - core::ptr::addr_of!(__sdata) as usize;
+        let end = core::ptr::addr_of!(__sidata) as usize + data_len;
+        core::slice::from_raw_parts(start, end - start as usize)
+    }
+}
+
+/// Firmware ID of the softdevice, from its info structure
+fn softdevice_id() -> u16 {
+    // SAFETY: The softdevice info structure is at a fixed address behind the MBR, and never
+    // changes.
+    unsafe { core::ptr::read_volatile(0x300c as *const u16) }
+}
+
+/// Fingerprint of the attribute table, from the softdevice (whose services come first) and the
+/// handles of the CoAP characteristic (see [service_changed])
+fn gatt_fingerprint(server: &Server) -> u16 {
+    const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
+    let mut digest = CRC.digest();
+    digest.update(&softdevice_id().to_le_bytes());
+    digest.update(&server.coap.message_value_handle.to_le_bytes());
+    digest.update(&server.coap.message_cccd_handle.to_le_bytes());
+    digest.finalize()
+}
+
 /// Technical entry point
 ///
 /// This defers to an non-decorated entry function to more easily debug any issues arising from
@@ -417,52 +851,102 @@ fn outer_main() -> ! {
 /// This assembles the configuration, starts up the softdevice, and lets both the softdevice and
 /// other tasks (LED animations, Bluetooth handlers) run in parallel.
 fn main() -> ! {
+    #[cfg(feature = "debug-shell")]
+    let shell_input = shell::init();
+
     info!("Device is starting up...");
 
-    let coapcore_config = include!(concat!(env!("OUT_DIR"), "/rs_as_association.rs"));
+    fault_handler::load();
+
+    match rollback::check(rollback_counter::read()) {
+        Ok(None) => (),
+        Ok(Some(counter)) => {
+            info!("Raising the rollback counter to {}", counter);
+            rollback_counter::raise(counter);
+            rollback::set_counter(rollback_counter::read());
+        }
+        Err(e) => {
+            error!(
+                "Security version {} is below the rollback counter {}, refusing to run",
+                rollback::SECURITY_VERSION,
+                e.counter
+            );
+            loop {
+                cortex_m::asm::wfe();
+            }
+        }
+    }
+
+    journal::load();
+    settings::count_boot();
+    coap_ace_poc_firmware::attestation::init(firmware_image(), softdevice_id());
+
+    static COAPCORE_CONFIG: CoapcoreConfig =
+        include!(concat!(env!("OUT_DIR"), "/rs_as_association.rs"));
+    static IDENTITY_DERIVATION: Option<IdentityDerivation> =
+        include!(concat!(env!("OUT_DIR"), "/identity_derivation.rs"));
+    static PROVISIONED_CONFIG: static_cell::StaticCell<CoapcoreConfig> =
+        static_cell::StaticCell::new();
+    static DERIVED: static_cell::StaticCell<Derived> = static_cell::StaticCell::new();
+    let (coapcore_config, damaged): (&'static CoapcoreConfig, bool) =
+        match (serial_provisioning::load(), &IDENTITY_DERIVATION) {
+            (None, None) => (&COAPCORE_CONFIG, false),
+            (None, Some(derivation)) => {
+                let derived: &'static Derived = DERIVED.init(derivation.derive(device_id()));
+                info!("Using the identity derived for {}", derived.audience());
+                (
+                    PROVISIONED_CONFIG.init(derived.config(&COAPCORE_CONFIG)),
+                    false,
+                )
+            }
+            (Some(Ok(config)), _) => {
+                info!("Using the provisioned identity");
+                (PROVISIONED_CONFIG.init(config), false)
+            }
+            (Some(Err(_)), _) => {
+                error!("Provisioned identity can not be read");
+                (&COAPCORE_CONFIG, true)
+            }
+        };
 
-    let mut full_name = heapless::String::<20>::new();
-    full_name.push_str("CoAP-ACE demo #").unwrap();
-    full_name.push_str(coapcore_config.audience).unwrap();
+    if flash_protection::unprotected_start() {
+        warn!("Starting without flash protection for re-provisioning");
+    } else {
+        flash_protection::protect();
+    }
+
+    let safe_mode = damaged || coapcore_config.calculate_checksum() != coapcore_config.checksum;
+    if safe_mode {
+        error!("Configuration does not match its checksum, starting in safe mode");
+    }
+
+    // Names too long for the buffer are truncated.
+    let mut full_name = heapless::String::<MAX_DEVICE_NAME_LEN>::new();
+    let name_parts: &[&str] = if safe_mode {
+        &[SAFE_MODE_NAME]
+    } else if let Some(device_name) = BOARD_CONFIG.device_name {
+        &[device_name]
+    } else {
+        &["CoAP-ACE demo #", coapcore_config.audience]
+    };
+    for c in name_parts.iter().flat_map(|part| part.chars()) {
+        if full_name.push(c).is_err() {
+            warn!("Device name truncated to {}", full_name.as_str());
+            break;
+        }
+    }
     let full_name = full_name.into_bytes();
-    let full_name_len: u16 = full_name.len().try_into().unwrap();
-
-    #[rustfmt::skip]
-    static SCAN_DATA: static_cell::StaticCell<heapless::Vec::<u8, 28>> = static_cell::StaticCell::new();
-    let scan_data = SCAN_DATA.init({
-        let mut scan_data = heapless::Vec::<u8, 28>::new();
-        scan_data
-            .push((1 + 5 + coapcore_config.audience.len()).try_into().unwrap())
-            .unwrap();
-        scan_data.push(0x08).unwrap();
-        scan_data.extend_from_slice(b"CoAP ").unwrap();
-        scan_data
-            .extend_from_slice(coapcore_config.audience.as_bytes())
-            .unwrap();
-        scan_data
-            .extend_from_slice(&[
-                // AD structure: Incomplete list of 128-bit Service Class UUIDs -- beware the endianness
-                // (we could also send a complete one, not-sure/not-care at this stage)
-                // Data from coap_gatt_us (but we build this literally right now, so meh)
-                0x11, 0x06, 0xbc, 0x36, 0xa2, 0x40, 0xfb, 0xf8, 0xfa, 0x9d, 0x6d, 0x49, 0x00, 0x33,
-                0xb7, 0x04, 0xf8, 0x8d,
-            ])
-            .unwrap();
-        scan_data
-    });
+    // At most MAX_DEVICE_NAME_LEN
+    let full_name_len = full_name.len() as u16;
 
     let config = nrf_softdevice::Config {
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
-            // The minimum is not acceptable in amsuess-core-coap-over-gatt-02
-            // (and the tokens we post are already in the order of 100 bytes long).
-            att_mtu: 420,
-        }),
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: ATT_MTU }),
         gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
             // It needs a mut ptr, but we don't allow writing in the permissions
             p_value: full_name.as_ptr() as *mut u8,
             current_len: full_name_len,
             max_len: full_name_len,
-            write_perm: nrf_softdevice_s132::ble_gap_conn_sec_mode_t {
+            write_perm: raw::ble_gap_conn_sec_mode_t {
                 _bitfield_1: raw::ble_gap_conn_sec_mode_t::new_bitfield_1(0, 0),
             },
             // No writes allowed or planned, so we can just take the const pointer.
@@ -474,19 +958,54 @@ fn main() -> ! {
             conn_count: MAX_CONNECTIONS,
             event_length: raw::BLE_GAP_EVENT_LENGTH_DEFAULT as _,
         }),
+        // The RC oscillator works on any board, at the cost of the softdevice calibrating it
+        // every 4 seconds (and when the temperature changed by 0.5°C in 8 seconds).
+        #[cfg(feature = "hardware-nrf52840dongle")]
+        clock: Some(raw::nrf_clock_lf_cfg_t {
+            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
+            rc_ctiv: 16,
+            rc_temp_ctiv: 2,
+            accuracy: raw::NRF_CLOCK_LF_ACCURACY_500_PPM as u8,
+        }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
             periph_role_count: MAX_CONNECTIONS,
-            central_role_count: 0,
-            central_sec_count: 0,
-            _bitfield_1: Default::default(),
+            // No central roles; the peripheral-only softdevice does not even have the fields.
+            ..Default::default()
         }),
         ..Default::default()
     };
 
-    let ChipParts { leds } = chip_startup();
+    let ChipParts {
+        leds: led_pins,
+        button,
+        saadc,
+        #[cfg(feature = "profiling")]
+        profiling_pins,
+        #[cfg(feature = "ws2812")]
+        strip,
+        #[cfg(feature = "serial-provisioning")]
+        uart,
+    } = chip_startup();
+    #[cfg(feature = "profiling")]
+    profiling_pins::init(profiling_pins);
+
+    // Give the pull-up a moment to charge the line (a few microseconds at 64MHz).
+    cortex_m::asm::delay(1000);
+    let factory_test = factory_test::requested(&button);
+    if factory_test {
+        info!("Button held at startup, entering factory test mode");
+    }
 
     let sd = Softdevice::enable(&config);
+    radio::init();
+    advertising::set_privacy(sd, BOARD_CONFIG.address_rotation);
+
+    if ecb::check() {
+        info!("ECB peripheral passed AES known answer test");
+    } else {
+        error!("ECB peripheral failed AES known answer test");
+    }
 
     let executor = EXECUTOR.init(Executor::new());
 
@@ -496,18 +1015,82 @@ fn main() -> ! {
     let sd: &'static Softdevice = sd;
 
     static LEDS: static_cell::StaticCell<blink::Leds> = static_cell::StaticCell::new();
-    static RS: static_cell::StaticCell<Rs> = static_cell::StaticCell::new();
+    #[cfg(feature = "ws2812")]
+    static STRIP: static_cell::StaticCell<ws2812::Strip> = static_cell::StaticCell::new();
+    static THERMOMETER: static_cell::StaticCell<AppThermometer> = static_cell::StaticCell::new();
 
     executor.run(move |spawner| {
-        let leds: &'static blink::Leds = LEDS.init(blink::Leds::new(spawner, leds));
-        leds.set_idle(2);
+        #[cfg(feature = "ws2812")]
+        let strip_state: &'static ws2812::Strip = STRIP.init(ws2812::Strip::new());
+        #[cfg(feature = "ws2812")]
+        unwrap!(spawner.spawn(ws2812::run(strip, strip_state)));
+
+        let leds: &'static blink::Leds = LEDS.init(blink::Leds::new(
+            #[cfg(feature = "ws2812")]
+            strip_state,
+        ));
+        unwrap!(spawner.spawn(blink::run(leds, led_pins)));
+        unwrap!(spawner.spawn(supply::run(saadc, leds)));
+        leds.set_idle(settings::idle_level().unwrap_or(2));
+        leds.run_walk();
+
+        #[cfg(not(feature = "synthetic-temperature"))]
+        let thermometer: &'static AppThermometer = THERMOMETER.init(SdThermometer(sd));
+        #[cfg(feature = "synthetic-temperature")]
+        let thermometer: &'static AppThermometer = THERMOMETER.init(AppThermometer::new());
+
+        // The failure pattern, if any, will only be shown after the walk is over, which is a
+        // feature.
+        let selftest = coap_ace_poc_firmware::selftest::run(
+            thermometer,
+            &mut SdRandomness(sd),
+            coapcore_config,
+            leds,
+        );
+
+        use embassy_nrf::interrupt::InterruptExt;
+        // Differing from default, this stays out of the softdevice's hair
+        embassy_nrf::interrupt::SWI3_EGU3.set_priority(embassy_nrf::interrupt::Priority::P7);
+        let sd_spawner = SD_EXECUTOR.start(embassy_nrf::interrupt::SWI3_EGU3);
 
-        let handler = build_main_rs(coapcore_config, sd, leds);
+        if factory_test {
+            if let Ok(temperature) = thermometer.temperature() {
+                info!("Temperature: {}°C", temperature.to_num::<f32>());
+            }
+            unwrap!(sd_spawner.spawn(softdevice_task(sd)));
+            flash::init(nrf_softdevice::Flash::take(sd));
+            unwrap!(sd_spawner.spawn(factory_test::run(sd, selftest)));
+            return;
+        }
+
+        // Available in safe mode too, so that a damaged identity can be replaced.
+        #[cfg(feature = "serial-provisioning")]
+        unwrap!(sd_spawner.spawn(serial_provisioning::serve(uart)));
 
-        let rs = RS.init(embassy_sync::mutex::Mutex::new(handler));
+        service_changed::check(gatt_fingerprint(server));
 
-        unwrap!(spawner.spawn(softdevice_task(sd)));
-        unwrap!(spawner.spawn(bluetooth_task(sd, server, scan_data, spawner, rs,)));
+        if safe_mode {
+            unwrap!(spawner.spawn(requests::run_safe_mode()));
+            unwrap!(sd_spawner.spawn(softdevice_task(sd)));
+            flash::init(nrf_softdevice::Flash::take(sd));
+            unwrap!(sd_spawner.spawn(safe_mode_advertiser(sd, server)));
+            return;
+        }
+
+        unwrap!(spawner.spawn(requests::run(
+            coapcore_config,
+            thermometer,
+            leds,
+            SdRandomness(sd),
+        )));
+        unwrap!(spawner.spawn(advertising::button(button)));
+        unwrap!(spawner.spawn(expiry_sweeper(leds)));
+        unwrap!(sd_spawner.spawn(softdevice_task(sd)));
+        flash::init(nrf_softdevice::Flash::take(sd));
+        unwrap!(sd_spawner.spawn(journal::persist()));
+        unwrap!(sd_spawner.spawn(bluetooth_task(sd, server, coapcore_config)));
+        #[cfg(feature = "debug-shell")]
+        unwrap!(spawner.spawn(shell::shell(shell_input, leds)));
         info!(
             "Device is ready at {}.",
             nrf_softdevice::ble::get_address(sd)
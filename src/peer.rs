@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Data read from the centrals' GATT servers
+//!
+//! When a central connects, the device acts as a GATT client towards it, and opportunistically
+//! looks for well-known services on it: the Current Time Service (its Current Time characteristic)
+//! and the Battery Service (its Battery Level characteristic). Many phones offer these, which makes
+//! them convenient data sources for demonstrations. What was found is logged, and kept per
+//! connection slot (as in [crate::connections]) for the debug shell to show.
+//!
+//! The data is not used for anything else: In particular, the clock is not set from the central's
+//! time, as that is neither authenticated nor necessarily in UTC. Centrals that offer neither
+//! service (or do not let the device read them) are served just the same.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use nrf_softdevice::ble::{gatt_client, Connection};
+
+use coap_ace_poc_firmware::info;
+
+use crate::MAX_CONNECTIONS;
+
+#[nrf_softdevice::gatt_client(uuid = "180f")]
+struct BatteryServiceClient {
+    #[characteristic(uuid = "2a19", read)]
+    battery_level: u8,
+}
+
+// The Current Time characteristic is an Exact Time 256 followed by an Adjust Reason; see
+// [PeerTime] for the parts that are used.
+#[nrf_softdevice::gatt_client(uuid = "1805")]
+struct CurrentTimeServiceClient {
+    #[characteristic(uuid = "2a2b", read)]
+    current_time: [u8; 10],
+}
+
+/// Wall clock time as reported by a central
+///
+/// This is the central's local time, in whichever time zone it is set to.
+#[derive(Copy, Clone, defmt::Format)]
+pub struct PeerTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl PeerTime {
+    fn from_current_time(value: &[u8; 10]) -> Self {
+        Self {
+            year: u16::from_le_bytes([value[0], value[1]]),
+            month: value[2],
+            day: value[3],
+            hours: value[4],
+            minutes: value[5],
+            seconds: value[6],
+        }
+    }
+}
+
+/// What was read from a central
+#[derive(Copy, Clone, Default, defmt::Format)]
+pub struct PeerData {
+    /// Battery level in percent
+    pub battery: Option<u8>,
+    pub time: Option<PeerTime>,
+}
+
+static PEERS: Mutex<CriticalSectionRawMutex, RefCell<[PeerData; MAX_CONNECTIONS as usize]>> =
+    Mutex::new(RefCell::new(
+        [PeerData {
+            battery: None,
+            time: None,
+        }; MAX_CONNECTIONS as usize],
+    ));
+
+/// Discover the well-known services on a central and read their data, storing it for the
+/// connection's slot.
+///
+/// This completes once everything was tried; failures (eg. because the central does not offer a
+/// service, or disconnected) only leave out the respective data.
+pub async fn explore(conn: &Connection, slot: Option<usize>) {
+    let mut data = PeerData::default();
+
+    if let Ok(client) = gatt_client::discover::<BatteryServiceClient>(conn).await {
+        data.battery = client.battery_level_read().await.ok();
+    }
+    if let Ok(client) = gatt_client::discover::<CurrentTimeServiceClient>(conn).await {
+        data.time = client
+            .current_time_read()
+            .await
+            .ok()
+            .map(|value| PeerTime::from_current_time(&value));
+    }
+    info!("Read from central: {:?}", data);
+
+    if let Some(slot) = slot {
+        PEERS.lock(|p| p.borrow_mut()[slot] = data);
+    }
+}
+
+/// Obtain the data read from the central in the given slot.
+pub fn get(slot: usize) -> PeerData {
+    PEERS.lock(|p| p.borrow()[slot])
+}
+
+/// Forget the data read from the central in the given slot when it disconnected.
+pub fn clear(slot: usize) {
+    PEERS.lock(|p| p.borrow_mut()[slot] = PeerData::default());
+}
@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Hardware abstractions used by the CoAP resources
+//!
+//! The resources in [crate::coap] only need a few things from the board; these traits describe
+//! them. The firmware implements them on top of the softdevice and the board LEDs, and the
+//! [crate::sim] module provides mock implementations for running the same handler on a host.
+
+/// Error type indicating that the temperature sensor could not be read
+#[derive(Debug)]
+pub struct SensorUnavailable;
+
+/// A source of device temperature readings
+pub trait Thermometer {
+    /// Read the current temperature in degrees Celsius, at the sensor's precision of a quarter
+    /// degree.
+    fn temperature(&self) -> Result<fixed::types::I30F2, SensorUnavailable>;
+}
+
+/// Progress of a peer through the connection and security setup, as shown on the LEDs
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Status {
+    /// The device is waiting for connections.
+    Advertising,
+    /// A peer has connected.
+    Connected,
+    /// A peer has started an EDHOC key exchange.
+    Handshaking,
+    /// A peer's token was accepted.
+    Authorized,
+    /// A key exchange or token submission failed.
+    Error,
+    /// All tokens expired, so peers lost their authorization.
+    Expired,
+}
+
+/// Animations available for identifying a device
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum IdentifyPattern {
+    /// A single LED running in a circle
+    Circle,
+    /// All LEDs flashing together
+    Flash,
+}
+
+/// Error type indicating that a number does not represent any [IdentifyPattern]
+#[derive(Debug)]
+pub struct UnknownPattern;
+
+impl TryFrom<u8> for IdentifyPattern {
+    type Error = UnknownPattern;
+
+    fn try_from(value: u8) -> Result<Self, UnknownPattern> {
+        match value {
+            0 => Ok(IdentifyPattern::Circle),
+            1 => Ok(IdentifyPattern::Flash),
+            _ => Err(UnknownPattern),
+        }
+    }
+}
+
+/// A thermometer that produces a deterministic waveform instead of measurements
+///
+/// Successive readings form a triangle wave from [LOW](Self::LOW) up to [HIGH](Self::HIGH) and
+/// back, in steps of a quarter degree, starting at the low end. Firmware built with the
+/// `synthetic-temperature` feature reads this instead of the die temperature, so that end-to-end
+/// tests can assert exact values; note that the self test at startup takes the first reading.
+pub struct SyntheticThermometer(core::sync::atomic::AtomicU16);
+
+impl SyntheticThermometer {
+    /// Lowest reading
+    pub const LOW: fixed::types::I30F2 = fixed::types::I30F2::from_bits(20 << 2);
+    /// Highest reading
+    pub const HIGH: fixed::types::I30F2 = fixed::types::I30F2::from_bits(25 << 2);
+
+    pub const fn new() -> Self {
+        Self(core::sync::atomic::AtomicU16::new(0))
+    }
+}
+
+impl Default for SyntheticThermometer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Thermometer for SyntheticThermometer {
+    fn temperature(&self) -> Result<fixed::types::I30F2, SensorUnavailable> {
+        let steps = (Self::HIGH - Self::LOW).to_bits() as u16;
+        let reading = self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed) % (2 * steps);
+        let offset = if reading <= steps {
+            reading
+        } else {
+            2 * steps - reading
+        };
+        Ok(Self::LOW + fixed::types::I30F2::from_bits(offset.into()))
+    }
+}
+
+/// How an identify animation should be run
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct IdentifyParameters {
+    pub pattern: IdentifyPattern,
+    /// Number of rounds the pattern is shown
+    pub repeat: u8,
+    /// Time in seconds for which the pattern is shown; if set, this takes precedence over
+    /// [repeat](Self::repeat).
+    pub duration: Option<u16>,
+}
+
+impl IdentifyParameters {
+    /// Longest duration that can be requested, in seconds
+    pub const MAX_DURATION: u16 = 120;
+}
+
+impl Default for IdentifyParameters {
+    /// Four rounds of the circle, which take about 1.6 seconds
+    fn default() -> Self {
+        Self {
+            pattern: IdentifyPattern::Circle,
+            repeat: 4,
+            duration: None,
+        }
+    }
+}
+
+/// The LED operations that are exposed through CoAP
+pub trait LedControl {
+    /// Set the number of LEDs to be active when idle.
+    fn set_idle(&self, level: u8);
+
+    /// Return the number of LEDs active when idle.
+    fn idle(&self) -> u8;
+
+    /// Run some animation useful for visually identifying a device.
+    ///
+    /// If an animation is already running, this one is queued to run after it; if too many are
+    /// queued, this is a no-op.
+    fn run_identify(&'static self, parameters: IdentifyParameters);
+
+    /// Stop the identify animation that is currently running, if any.
+    ///
+    /// Animations that were queued after it still run.
+    fn cancel_identify(&self);
+
+    /// Show a pattern indicating that something is wrong with the device (eg. a failed
+    /// [self test](crate::selftest)).
+    ///
+    /// If an animation is running, the pattern is shown after it.
+    fn show_failure(&'static self);
+
+    /// Show a brief pattern indicating that the given status was reached.
+    ///
+    /// If any animation is running, this is a no-op: the status patterns are merely a visual aid
+    /// for demonstrations.
+    fn show_status(&'static self, status: Status);
+
+    /// Set the color of an attached RGB LED strip (as red, green and blue intensity).
+    ///
+    /// The default implementation is for devices without such a strip, and reports
+    /// [NoColorLeds].
+    fn set_color(&self, _color: [u8; 3]) -> Result<(), NoColorLeds> {
+        Err(NoColorLeds)
+    }
+
+    /// Return the color most recently set on an attached RGB LED strip.
+    fn color(&self) -> Result<[u8; 3], NoColorLeds> {
+        Err(NoColorLeds)
+    }
+}
+
+/// Error type indicating that no RGB LEDs are attached
+#[derive(Debug)]
+pub struct NoColorLeds;
@@ -0,0 +1,198 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Accounting of the time spent in power relevant activities
+//!
+//! Components mark the activities they perform by holding a guard obtained from [track]; the time
+//! during which at least one guard of a [Category] is held is added up. Activities may overlap
+//! (eg. crypto operations happen while connected).
+//!
+//! The time during which no activity is tracked at all is counted as asleep. This is an estimate
+//! only: the CPU sleeps whenever there is nothing to do, which is most of the time even within
+//! activities, and short background work (like LED animations) is not tracked.
+//!
+//! The resulting duty cycles are shown in the `/stats/power` resource; integrators can combine
+//! them with the current consumption figures of the chip to size batteries.
+
+use core::cell::RefCell;
+
+use embassy_time::{Duration, Instant};
+
+/// Kinds of tracked activities
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Category {
+    /// Sending advertisements
+    Advertising,
+    /// Having at least one peer connected
+    Connected,
+    /// Processing EDHOC messages, token posts and OSCORE protected requests (including the work of
+    /// the protected request's resource, which happens between decryption and encryption)
+    Crypto,
+    /// The radio being active (as reported by the softdevice's radio notifications)
+    Radio,
+}
+
+const CATEGORIES: usize = 4;
+
+impl Category {
+    const ALL: [Category; CATEGORIES] = [
+        Category::Advertising,
+        Category::Connected,
+        Category::Crypto,
+        Category::Radio,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Advertising => "advertising",
+            Category::Connected => "connected",
+            Category::Crypto => "crypto",
+            Category::Radio => "radio",
+        }
+    }
+}
+
+/// Accumulated time of one category, or of all of them together
+#[derive(Copy, Clone)]
+struct Account {
+    /// Number of guards currently held
+    active: u16,
+    /// When `active` last rose from zero
+    since: Instant,
+    /// Time accumulated during earlier activity
+    total: Duration,
+}
+
+impl Account {
+    const fn new() -> Self {
+        Self {
+            active: 0,
+            since: Instant::from_ticks(0),
+            total: Duration::from_ticks(0),
+        }
+    }
+
+    fn begin(&mut self, now: Instant) {
+        if self.active == 0 {
+            self.since = now;
+        }
+        self.active += 1;
+    }
+
+    fn end(&mut self, now: Instant) {
+        self.active -= 1;
+        if self.active == 0 {
+            self.total += now - self.since;
+        }
+    }
+
+    /// Accumulated time including any ongoing activity
+    fn total(&self, now: Instant) -> Duration {
+        match self.active {
+            0 => self.total,
+            _ => self.total + (now - self.since),
+        }
+    }
+}
+
+struct Accounts {
+    categories: [Account; CATEGORIES],
+    /// Time in which any category was active
+    any: Account,
+}
+
+static ACCOUNTS: critical_section::Mutex<RefCell<Accounts>> =
+    critical_section::Mutex::new(RefCell::new(Accounts {
+        categories: [Account::new(); CATEGORIES],
+        any: Account::new(),
+    }));
+
+/// Start accounting an activity.
+///
+/// This can be called from interrupts too.
+pub fn begin(category: Category) {
+    let now = Instant::now();
+    critical_section::with(|cs| {
+        let mut accounts = ACCOUNTS.borrow_ref_mut(cs);
+        accounts.categories[category as usize].begin(now);
+        accounts.any.begin(now);
+    });
+}
+
+/// Stop accounting an activity started by [begin].
+pub fn end(category: Category) {
+    let now = Instant::now();
+    critical_section::with(|cs| {
+        let mut accounts = ACCOUNTS.borrow_ref_mut(cs);
+        accounts.categories[category as usize].end(now);
+        accounts.any.end(now);
+    });
+}
+
+/// Guard accounting an activity for as long as it is held
+#[must_use]
+pub struct Tracked(Category);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        end(self.0);
+    }
+}
+
+/// Account an activity until the returned guard is dropped.
+pub fn track(category: Category) -> Tracked {
+    begin(category);
+    Tracked(category)
+}
+
+/// Snapshot of the accounts
+///
+/// When encoded into CBOR, this is a map containing the uptime in seconds under `"uptime"`, and
+/// the duty cycle of each category and of being asleep in permille under the category's name (eg.
+/// `"advertising"`) and `"asleep"`, respectively.
+pub struct Report {
+    uptime: Duration,
+    categories: [Duration; CATEGORIES],
+    awake: Duration,
+}
+
+/// Obtain a snapshot of the current accounts.
+pub fn report() -> Report {
+    let now = Instant::now();
+    critical_section::with(|cs| {
+        let accounts = ACCOUNTS.borrow_ref(cs);
+        Report {
+            uptime: now.duration_since(Instant::from_ticks(0)),
+            categories: accounts.categories.map(|account| account.total(now)),
+            awake: accounts.any.total(now),
+        }
+    })
+}
+
+impl Report {
+    fn permille(&self, duration: Duration) -> u32 {
+        match self.uptime.as_ticks() {
+            0 => 0,
+            uptime => (duration.as_ticks() * 1000 / uptime) as u32,
+        }
+    }
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(2 + CATEGORIES as u64)?
+            .str("uptime")?
+            .u64(self.uptime.as_secs())?;
+        for category in Category::ALL {
+            e.str(category.name())?
+                .u32(self.permille(self.categories[category as usize]))?;
+        }
+        e.str("asleep")?
+            .u32(self.permille(self.uptime - self.awake))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Markers around protocol phases for external measurement
+//!
+//! Components mark the phases they run through by holding a guard obtained from [mark]. With the
+//! `profiling` feature, the hook that the platform installed through [set_hook] is called at the
+//! start and the end of each phase; the firmware sets one spare GPIO per [Phase] high for the
+//! duration of the phase, so that a logic analyzer or the digital inputs of a power profiler can
+//! attribute latency and energy to them. Without the feature, the markers compile to nothing.
+//!
+//! EDHOC and the token processing happen inside coapcore, so their phases cover the whole
+//! processing of the respective request by the resource server rather than just the cryptographic
+//! operations.
+
+/// Phases that are marked
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Phase {
+    /// Processing an EDHOC message
+    Edhoc,
+    /// Processing a posted token, and decrypting tokens (AES-CCM) for the [records](crate::tokens)
+    Token,
+    /// Writing to or erasing the flash
+    Flash,
+}
+
+/// Number of distinct phases
+pub const PHASES: usize = 3;
+
+#[cfg(feature = "profiling")]
+static HOOK: critical_section::Mutex<core::cell::Cell<Option<fn(Phase, bool)>>> =
+    critical_section::Mutex::new(core::cell::Cell::new(None));
+
+/// Install the function that is called with a phase and `true` when it starts, and with `false`
+/// when it ends.
+///
+/// The hook is called from within critical sections, and thus needs to be quick.
+#[cfg(feature = "profiling")]
+pub fn set_hook(hook: fn(Phase, bool)) {
+    critical_section::with(|cs| HOOK.borrow(cs).set(Some(hook)));
+}
+
+#[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+fn signal(phase: Phase, active: bool) {
+    #[cfg(feature = "profiling")]
+    critical_section::with(|cs| {
+        if let Some(hook) = HOOK.borrow(cs).get() {
+            hook(phase, active);
+        }
+    });
+}
+
+/// Guard marking a phase for as long as it is held
+#[must_use]
+pub struct Marked(Phase);
+
+impl Drop for Marked {
+    fn drop(&mut self) {
+        signal(self.0, false);
+    }
+}
+
+/// Mark a phase until the returned guard is dropped.
+pub fn mark(phase: Phase) -> Marked {
+    signal(phase, true);
+    Marked(phase)
+}
@@ -0,0 +1,30 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! GPIOs showing the phases marked through [coap_ace_poc_firmware::profiling]
+//!
+//! Each [Phase] has its pin (in the order of the enum's variants), which is high while the phase
+//! lasts. The pins are spare pins of the board (see the build script's board definitions): On the
+//! DKs, those of A0 to A2 of the Arduino header; on the dongle, P0.02, P0.29 and P0.31.
+
+use core::cell::RefCell;
+
+use coap_ace_poc_firmware::profiling::{Phase, PHASES};
+use embassy_nrf::gpio::Output;
+
+static PINS: critical_section::Mutex<RefCell<Option<[Output<'static>; PHASES]>>> =
+    critical_section::Mutex::new(RefCell::new(None));
+
+fn hook(phase: Phase, active: bool) {
+    critical_section::with(|cs| {
+        if let Some(pins) = PINS.borrow_ref_mut(cs).as_mut() {
+            pins[phase as usize].set_level(active.into());
+        }
+    });
+}
+
+/// Take the pins (which need to be set low initially), and start showing phases on them.
+pub fn init(pins: [Output<'static>; PHASES]) {
+    critical_section::with(|cs| PINS.borrow(cs).replace(Some(pins)));
+    coap_ace_poc_firmware::profiling::set_hook(hook);
+}
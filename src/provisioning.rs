@@ -0,0 +1,263 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Device identity provisioned at runtime
+//!
+//! Instead of building a firmware per device (with its `RS_AS_ASSOCIATION` file), a production
+//! line can flash one firmware into all units, and inject each unit's identity into the
+//! provisioning flash page through the serial provisioning protocol (see the firmware's
+//! `serial_provisioning` module for the transport). At startup, an identity found in that page
+//! replaces the built-in [CoapcoreConfig].
+//!
+//! ## Identity
+//!
+//! An identity is a CBOR map with these integer keys:
+//!
+//! * 1: audience (text string, required)
+//! * 2: encoded AS Request Creation Hints (byte string, required)
+//! * 3: own EDHOC credential, ie. a CCS containing the public key (byte string)
+//! * 4: own EDHOC private key (32 byte string)
+//! * 5: key shared with the AS (32 byte string)
+//! * 6 and 7: x and y coordinates of the AS's public key (32 byte strings, both or neither)
+//! * 8: further audiences the device belongs to (array of text strings, see
+//!   [CoapcoreConfig::group_audiences])
+//!
+//! ## Page format
+//!
+//! The page starts with [MAGIC], followed by the length of the identity and its checksum (as
+//! [CoapcoreConfig::calculate_checksum] gives it) as 32-bit little endian numbers, and the
+//! identity itself. The checksum is checked at startup like that of the built-in configuration, so
+//! a damaged page sends the device into safe mode.
+//!
+//! ## Protocol
+//!
+//! Requests and responses are CBOR arrays. A request is one of:
+//!
+//! * `[1, identity]`: Validate the identity and write it into the page.
+//! * `[2]`: Query the identity in the page.
+//! * `[3]`: Erase the page, returning to the built-in configuration.
+//! * `[4]`: Restart the device with the write protection of the page lifted. Provisioning and
+//!   erasing fail while the page is protected, which it is after any other start.
+//!
+//! The response is `[0, digest]` on success, where the digest is the checksum of the identity in
+//! the page (or null if the page is empty), and `[1, message]` with a text message on failure. A
+//! fixture confirms provisioning by comparing the digest against its own calculation. The new
+//! identity takes effect at the next start.
+//!
+//! In safe mode, the firmware also accepts requests as the payload of a POST to `/provision`,
+//! except for `[4]`, which only the serial port may ask for.
+
+use minicbor::decode::Error;
+
+use crate::CoapcoreConfig;
+
+/// Marks a page as holding an identity in this format
+pub const MAGIC: [u8; 4] = *b"PRV1";
+/// Length of the page header (magic number, identity length and checksum)
+pub const HEADER_LEN: usize = 12;
+/// Size of a flash page on the nRF52
+pub const PAGE_SIZE: usize = 4096;
+/// Longest identity that fits the page
+pub const MAX_IDENTITY_LEN: usize = PAGE_SIZE - HEADER_LEN;
+
+/// Content of an identity, borrowed from its encoded form
+pub struct Identity<'a> {
+    audience: &'a str,
+    group_audiences: &'a [u8],
+    request_creation_hints: &'a [u8],
+    edhoc_credential: Option<&'a [u8]>,
+    edhoc_q: Option<&'a [u8; 32]>,
+    as_symmetric: Option<[u8; 32]>,
+    as_pub: Option<([u8; 32], [u8; 32])>,
+}
+
+impl<'a> Identity<'a> {
+    /// Decode and validate an encoded identity.
+    pub fn decode(encoded: &'a [u8]) -> Result<Self, Error> {
+        fn key(d: &mut minicbor::Decoder<'_>) -> Result<[u8; 32], Error> {
+            d.bytes()?
+                .try_into()
+                .map_err(|_| Error::message("Key is not 32 bytes long"))
+        }
+
+        let mut d = minicbor::Decoder::new(encoded);
+        let entries = d
+            .map()?
+            .ok_or_else(|| Error::message("Indefinite length map"))?;
+        let mut audience = None;
+        let mut group_audiences: &[u8] = &[];
+        let mut request_creation_hints = None;
+        let mut edhoc_credential = None;
+        let mut edhoc_q = None;
+        let mut as_symmetric = None;
+        let (mut as_x, mut as_y) = (None, None);
+        for _ in 0..entries {
+            match d.u8()? {
+                1 => audience = Some(d.str()?),
+                2 => request_creation_hints = Some(d.bytes()?),
+                3 => edhoc_credential = Some(d.bytes()?),
+                4 => {
+                    edhoc_q = Some(
+                        d.bytes()?
+                            .try_into()
+                            .map_err(|_| Error::message("Key is not 32 bytes long"))?,
+                    )
+                }
+                5 => as_symmetric = Some(key(&mut d)?),
+                6 => as_x = Some(key(&mut d)?),
+                7 => as_y = Some(key(&mut d)?),
+                8 => {
+                    let start = d.position();
+                    for audience in d.array_iter::<&str>()? {
+                        audience?;
+                    }
+                    group_audiences = &encoded[start..d.position()];
+                }
+                _ => return Err(Error::message("Unknown key")),
+            }
+        }
+        if d.position() != encoded.len() {
+            return Err(Error::message("Trailing data"));
+        }
+        let as_pub = match (as_x, as_y) {
+            (Some(x), Some(y)) => Some((x, y)),
+            (None, None) => None,
+            _ => return Err(Error::message("Incomplete AS public key")),
+        };
+        if let Some(credential) = edhoc_credential {
+            lakers::Credential::parse_ccs(credential)
+                .map_err(|_| Error::message("EDHOC credential does not parse"))?;
+        }
+        Ok(Self {
+            audience: audience.ok_or_else(|| Error::message("Audience missing"))?,
+            group_audiences,
+            request_creation_hints: request_creation_hints
+                .ok_or_else(|| Error::message("Request creation hints missing"))?,
+            edhoc_credential,
+            edhoc_q,
+            as_symmetric,
+            as_pub,
+        })
+    }
+
+    /// The checksum that the [CoapcoreConfig] built from this would carry
+    pub fn checksum(&self) -> u32 {
+        crate::config_checksum(
+            self.audience,
+            self.group_audiences,
+            self.request_creation_hints,
+            self.as_symmetric.as_ref().map(|k| &k[..]),
+            self.edhoc_credential,
+            self.edhoc_q.map(|q| &q[..]),
+            self.as_pub.as_ref(),
+        )
+    }
+}
+
+impl Identity<'static> {
+    fn into_config(self, checksum: u32) -> CoapcoreConfig {
+        CoapcoreConfig {
+            audience: self.audience,
+            group_audiences: self.group_audiences,
+            request_creation_hints: self.request_creation_hints,
+            as_symmetric: self.as_symmetric,
+            edhoc_credential: self.edhoc_credential,
+            edhoc_q: self.edhoc_q,
+            as_pub: self.as_pub,
+            checksum,
+        }
+    }
+}
+
+/// Read the identity from the content of the provisioning page.
+///
+/// Returns None if the page holds no identity, and an error if it holds one that can not be
+/// decoded (which only happens if the page was damaged). The checksum is not verified here, but
+/// carried into the configuration, to be checked like that of the built-in one.
+pub fn load(page: &'static [u8]) -> Option<Result<CoapcoreConfig, Error>> {
+    if page.get(..4)? != MAGIC {
+        return None;
+    }
+    let word = |offset: usize| u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+    let (len, checksum) = (word(4) as usize, word(8));
+    let Some(encoded) = page[HEADER_LEN..].get(..len) else {
+        return Some(Err(Error::message("Identity exceeds the page")));
+    };
+    Some(Identity::decode(encoded).map(|identity| identity.into_config(checksum)))
+}
+
+/// Write the page image for an encoded identity into `out`, which needs to have room for the
+/// header, the identity and up to 3 bytes of padding.
+///
+/// Returns the length of the image, padded to the flash's write granularity of 4 bytes.
+pub fn page_image(encoded: &[u8], checksum: u32, out: &mut [u8]) -> usize {
+    let len = HEADER_LEN + encoded.len();
+    out[..4].copy_from_slice(&MAGIC);
+    out[4..8].copy_from_slice(&(encoded.len() as u32).to_le_bytes());
+    out[8..12].copy_from_slice(&checksum.to_le_bytes());
+    out[HEADER_LEN..len].copy_from_slice(encoded);
+    let padded = (len + 3) / 4 * 4;
+    out[len..padded].fill(0xff);
+    padded
+}
+
+/// A request of the protocol
+pub enum Request<'a> {
+    /// Write an identity, given in encoded form, into the page.
+    Provision(&'a [u8]),
+    Query,
+    Erase,
+    /// Restart without the write protection of the page (see the firmware's `flash_protection`
+    /// module).
+    Unprotect,
+}
+
+impl<'a> Request<'a> {
+    pub fn decode(frame: &'a [u8]) -> Result<Self, Error> {
+        let mut d = minicbor::Decoder::new(frame);
+        let len = d.array()?;
+        let request = match (d.u8()?, len) {
+            (1, Some(2)) => {
+                let start = d.position();
+                d.skip()?;
+                Request::Provision(&frame[start..d.position()])
+            }
+            (2, Some(1)) => Request::Query,
+            (3, Some(1)) => Request::Erase,
+            (4, Some(1)) => Request::Unprotect,
+            _ => return Err(Error::message("Unknown request")),
+        };
+        if d.position() != frame.len() {
+            return Err(Error::message("Trailing data"));
+        }
+        Ok(request)
+    }
+}
+
+/// Encode a response: the digest of the identity in the page (if any) on success, or a message
+/// on failure.
+///
+/// Returns the length of the response in `out`; messages that do not fit are truncated.
+pub fn encode_response(result: Result<Option<u32>, &str>, out: &mut [u8]) -> usize {
+    // Leaving room for the array, the status and the head of the text
+    let room = out.len().saturating_sub(5);
+    let mut cursor = minicbor::encode::write::Cursor::new(out);
+    let mut e = minicbor::Encoder::new(&mut cursor);
+    let encoded = match result {
+        Ok(Some(digest)) => e.array(2).and_then(|e| e.u8(0)?.u32(digest)).is_ok(),
+        Ok(None) => e.array(2).and_then(|e| e.u8(0)?.null()).is_ok(),
+        Err(message) => {
+            let mut end = message.len().min(room);
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            e.array(2)
+                .and_then(|e| e.u8(1)?.str(&message[..end]))
+                .is_ok()
+        }
+    };
+    match encoded {
+        true => cursor.position(),
+        false => 0,
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Radio activity accounting through the softdevice's radio notifications
+//!
+//! The softdevice triggers the SWI1 interrupt right before the radio becomes active, and right
+//! after it becomes inactive again; the handler feeds this into [power].
+//!
+//! The end of radio activity also starts the longest stretch until the next connection or
+//! advertising event. Work that competes with the radio awaits that through [gap]: flash
+//! operations (which the softdevice can only carry out between radio events, and otherwise
+//! reports as failed), and the processing of requests, whose cryptography (eg. decrypting a token,
+//! or the EDHOC key agreement) takes tens of milliseconds. The softdevice preempts the processing
+//! for every radio event anyway; starting right after one leaves it the most undisturbed time, and
+//! keeps the events that interrupt it at a minimum. The cryptography runs inside coapcore in one
+//! piece, so it can not be split into chunks that each fit a gap.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering::Relaxed};
+
+use coap_ace_poc_firmware::power::{self, Category};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use nrf_softdevice::raw;
+
+/// Whether the latest notification was the one before radio activity
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Number of times the radio became inactive
+static GAPS: AtomicU32 = AtomicU32::new(0);
+
+/// Tasks waiting in [gap]; that is at most the flash and the request processing.
+static WAITERS: Mutex<CriticalSectionRawMutex, RefCell<MultiWakerRegistration<4>>> =
+    Mutex::new(RefCell::new(MultiWakerRegistration::new()));
+
+/// Longest time [gap] waits
+///
+/// This exceeds common connection intervals; at longer intervals (or while only advertising), the
+/// radio is idle most of the time anyway.
+const MAX_WAIT: embassy_time::Duration = embassy_time::Duration::from_millis(100);
+
+pub struct RadioNotificationHandler;
+
+impl embassy_nrf::interrupt::typelevel::Handler<embassy_nrf::interrupt::typelevel::SWI1_EGU1>
+    for RadioNotificationHandler
+{
+    unsafe fn on_interrupt() {
+        // The notifications strictly alternate, so it is sufficient to keep track of which one
+        // came last.
+        if ACTIVE.fetch_xor(true, Relaxed) {
+            power::end(Category::Radio);
+            GAPS.fetch_add(1, Relaxed);
+            WAITERS.lock(|w| w.borrow_mut().wake());
+        } else {
+            power::begin(Category::Radio);
+        }
+    }
+}
+
+embassy_nrf::bind_interrupts!(struct Irqs {
+    SWI1_EGU1 => RadioNotificationHandler;
+});
+
+/// Wait until the radio became inactive, or for at most [MAX_WAIT].
+///
+/// This completes at the next end of radio activity, even if the radio is inactive already: Only
+/// then is it known that the gap is as long as it gets.
+pub async fn gap() {
+    let start = GAPS.load(Relaxed);
+    let ended = core::future::poll_fn(|cx| {
+        // Registering before checking, so that an end in between is not missed
+        WAITERS.lock(|w| {
+            // If this fails, there are more waiters than expected, which the timeout covers.
+            let _ = w.borrow_mut().register(cx.waker());
+        });
+        match GAPS.load(Relaxed) == start {
+            true => core::task::Poll::Pending,
+            false => core::task::Poll::Ready(()),
+        }
+    });
+    // Without radio notifications (or radio activity), this just takes the timeout.
+    let _ = embassy_time::with_timeout(MAX_WAIT, ended).await;
+}
+
+/// Enable radio notifications.
+///
+/// This needs to be called after the softdevice was enabled.
+pub fn init() {
+    use embassy_nrf::interrupt::InterruptExt;
+
+    // Binding happens through the type; this just keeps the compiler from considering it unused.
+    let _ = Irqs;
+
+    // Differing from default, this stays out of the softdevice's hair
+    embassy_nrf::interrupt::SWI1_EGU1.set_priority(embassy_nrf::interrupt::Priority::P7);
+    // SAFETY: The handler is bound above, and has no preconditions.
+    unsafe { embassy_nrf::interrupt::SWI1_EGU1.enable() };
+
+    // SAFETY: Plain softdevice call without pointers
+    let result = unsafe {
+        raw::sd_radio_notification_cfg_set(
+            raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_INT_ON_BOTH as u8,
+            raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_NONE as u8,
+        )
+    };
+    if result != raw::NRF_SUCCESS {
+        coap_ace_poc_firmware::warn!("Radio notifications unavailable: {}", result);
+    }
+}
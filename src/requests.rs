@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Processing of CoAP requests on the thread mode executor
+//!
+//! The Bluetooth tasks run on an interrupt executor (see [crate::main]), so that they keep
+//! reacting to softdevice events while a request is processed: Processing an EDHOC message or a
+//! token takes tens of milliseconds of cryptography, which the interrupt executor preempts. The
+//! connection tasks hand the requests written to them over to the [run] task here, and wait for
+//! the outcome.
+//!
+//! The resource server is built and kept by the [run] task, along with the [coap_gatt] connection
+//! state (which token was posted, etc.) per connection slot (as in [crate::connections]).
+//! Everything the resource server touches (like the LEDs) thus stays on the thread mode executor.
+//!
+//! The job queue is also what keeps access to the resource server fair: Jobs are processed in the
+//! order in which they were queued, and the [run] task is the only user of the resource server's
+//! mutex, so no connection can hold it while others wait (and the 5.03 Service Unavailable path of
+//! [coap_gatt] is not taken). GATT is the only CoAP transport of the firmware (the serial port
+//! only carries [provisioning](crate::serial_provisioning)).
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use zeroize::Zeroize;
+
+use coap_ace_poc_firmware::coap_gatt;
+use coap_ace_poc_firmware::platform::LedControl;
+use coap_ace_poc_firmware::trace;
+use coap_ace_poc_firmware::{build_main_rs, coap, CoapcoreConfig, Rs};
+
+use crate::{AppThermometer, SdRandomness, MAX_CONNECTIONS, MAX_WRITE_LEN};
+
+/// A message as written to or read from the CoAP characteristic
+pub type Message = heapless::Vec<u8, MAX_WRITE_LEN>;
+
+// Responses are never longer than requests may be, so they always fit a [Message].
+const _: () = assert!(coap_ace_poc_firmware::MAX_MESSAGE_LEN <= MAX_WRITE_LEN);
+
+enum Job {
+    /// A new connection was established in the slot
+    Open(usize),
+    /// A request was written through the connection in the slot
+    Request(usize, Message),
+}
+
+/// The result of processing a request
+pub struct Outcome {
+    pub response: Message,
+    /// See [coap_gatt::Connection::is_admin]
+    pub admin: bool,
+    /// See [coap_gatt::Connection::handshake_started]
+    pub handshake: Option<embassy_time::Instant>,
+    /// See [coap_gatt::Connection::take_attempt]
+    pub attempt: Option<coap_ace_poc_firmware::lockout::Attempt>,
+}
+
+static JOBS: Channel<CriticalSectionRawMutex, Job, { MAX_CONNECTIONS as usize }> = Channel::new();
+
+static OUTCOMES: [Signal<CriticalSectionRawMutex, Outcome>; MAX_CONNECTIONS as usize] =
+    [const { Signal::new() }; MAX_CONNECTIONS as usize];
+
+/// Start over with fresh connection state in a slot.
+pub async fn open(slot: usize) {
+    JOBS.send(Job::Open(slot)).await;
+}
+
+/// Process a request written through the connection in a slot.
+pub async fn process(slot: usize, request: Message) -> Outcome {
+    JOBS.send(Job::Request(slot, request)).await;
+    OUTCOMES[slot].wait().await
+}
+
+/// Task building the resource server, and processing the requests of all connections with it, one
+/// at a time
+///
+/// The resource server is kept in the task's future, whose size is thus dominated by it; the task
+/// arena (see the `task-arena-size-*` feature of embassy-executor in Cargo.toml) is sized for
+/// that.
+#[embassy_executor::task]
+pub async fn run(
+    coapcore_config: &'static CoapcoreConfig,
+    thermometer: &'static AppThermometer,
+    leds: &'static crate::blink::Leds,
+    rng: SdRandomness,
+) {
+    let resources = move || coap::create_coap_handler(coapcore_config, thermometer, leds, rng);
+    let rs = Rs::new(build_main_rs(coapcore_config, resources, rng));
+    let rs = &rs;
+    let mut connections: [Option<coap_gatt::Connection<_>>; MAX_CONNECTIONS as usize] =
+        [const { None }; MAX_CONNECTIONS as usize];
+    loop {
+        match JOBS.receive().await {
+            Job::Open(slot) => {
+                trace::record(slot, trace::Event::Open);
+                connections[slot] = Some(coap_gatt::Connection::new(rs));
+                // Any outcome still pending is for the previous connection in the slot.
+                OUTCOMES[slot].reset();
+            }
+            Job::Request(slot, mut request) => {
+                trace::record(slot, trace::Event::Write(&request));
+                // Starting right after radio activity, see [crate::radio::gap]
+                crate::radio::gap().await;
+                let cg = connections[slot].get_or_insert_with(|| coap_gatt::Connection::new(rs));
+                let response = cg.write(&mut request);
+                trace::record(slot, trace::Event::Read(&response));
+                if let Some(status) = cg.take_status() {
+                    leds.show_status(status);
+                }
+                OUTCOMES[slot].signal(Outcome {
+                    response: Message::from_slice(&response).unwrap_or_default(),
+                    admin: cg.is_admin(),
+                    handshake: cg.handshake_started(),
+                    attempt: cg.take_attempt(),
+                });
+            }
+        }
+    }
+}
+
+/// Task processing the requests of all connections in safe mode, one at a time
+///
+/// There is no resource server in safe mode: Requests to provision the device are served by
+/// [crate::serial_provisioning::respond], and all others by the resources of
+/// [coap::create_safe_mode_handler]. They are not recorded in the [trace], as provisioning
+/// requests carry private keys, which are also wiped from the request once it was processed.
+#[embassy_executor::task]
+pub async fn run_safe_mode() {
+    let mut resources = coap::create_safe_mode_handler();
+    loop {
+        match JOBS.receive().await {
+            Job::Open(slot) => {
+                OUTCOMES[slot].reset();
+            }
+            Job::Request(slot, mut request) => {
+                let response = match crate::serial_provisioning::respond(&mut request).await {
+                    Some(response) => response,
+                    None => coap_gatt::write_unprotected(&mut resources, &mut request),
+                };
+                request[..].zeroize();
+                OUTCOMES[slot].signal(Outcome {
+                    response: Message::from_slice(&response).unwrap_or_default(),
+                    admin: false,
+                    handshake: None,
+                    attempt: None,
+                });
+            }
+        }
+    }
+}
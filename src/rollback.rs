@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Protection against rolling back to older firmware
+//!
+//! Each firmware carries a [SECURITY_VERSION], which is raised whenever a release fixes a
+//! vulnerability that should not be reintroduced by installing an older image. The device keeps a
+//! monotonic counter of the highest security version it ran; at startup, the platform refuses to
+//! run an image whose security version is below the counter, and raises the counter to the
+//! image's version otherwise. (There is no update subsystem that would raise the counter on
+//! installation; the first start of the new image does.)
+//!
+//! The firmware keeps the counter in the UICR, whose bits can only be cleared without erasing the
+//! whole register block (see the firmware's `rollback_counter` module). The counter can thus count
+//! up to [MAX_COUNTER]; security versions beyond that are treated like that.
+//!
+//! The protection holds against images installed through a bootloader (like the dongle's), which
+//! leaves the UICR alone. Whoever has the debug port can erase the UICR along with everything
+//! else, and thus start over at any version.
+//!
+//! Firmware version, security version and counter are shown in the `/info` resource.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Security version of this firmware
+pub const SECURITY_VERSION: u32 = 1;
+
+/// Highest value the counter can hold
+pub const MAX_COUNTER: u32 = 32;
+
+/// Counter as read at startup (and raised then), or u32::MAX before the platform reported it
+static COUNTER: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Error type indicating that the firmware is older than the counter allows
+#[derive(Debug, defmt::Format)]
+pub struct RolledBack {
+    pub counter: u32,
+}
+
+/// Check the counter read at startup against this firmware's security version.
+///
+/// Returns the value to raise the counter to, if it needs raising.
+pub fn check(counter: u32) -> Result<Option<u32>, RolledBack> {
+    let version = SECURITY_VERSION.min(MAX_COUNTER);
+    if version < counter {
+        return Err(RolledBack { counter });
+    }
+    set_counter(counter);
+    Ok((version > counter).then_some(version))
+}
+
+/// Record the counter's current value, after the platform read or raised it.
+pub fn set_counter(counter: u32) {
+    COUNTER.store(counter, Ordering::Relaxed);
+}
+
+/// Snapshot of the firmware's versions
+///
+/// When encoded into CBOR, this is a map containing the firmware version under `"version"`, the
+/// [SECURITY_VERSION] under `"security-version"`, and the rollback counter under
+/// `"rollback-counter"` (or null if the platform keeps none, like the simulation).
+pub struct Report {
+    counter: Option<u32>,
+}
+
+/// Obtain the current versions.
+pub fn report() -> Report {
+    Report {
+        counter: match COUNTER.load(Ordering::Relaxed) {
+            u32::MAX => None,
+            counter => Some(counter),
+        },
+    }
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(3)?.str("version")?.str(env!("CARGO_PKG_VERSION"))?;
+        e.str("security-version")?.u32(SECURITY_VERSION)?;
+        e.str("rollback-counter")?;
+        match self.counter {
+            Some(counter) => e.u32(counter)?,
+            None => e.null()?,
+        };
+        Ok(())
+    }
+}
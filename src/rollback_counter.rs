@@ -0,0 +1,46 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! The [rollback](coap_ace_poc_firmware::rollback) counter in the UICR
+//!
+//! The counter is the number of leading CUSTOMER registers of the UICR that were written to zero.
+//! Raising it writes further registers; as the NVMC allows only a limited number of writes to a
+//! word between erasures, every register is written only once. Lowering the counter would need
+//! erasing the UICR, which the firmware never does.
+//!
+//! All of this happens before the softdevice is enabled, as the NVMC is not directly accessible
+//! afterwards.
+
+use coap_ace_poc_firmware::rollback::MAX_COUNTER;
+
+/// Address of the UICR's CUSTOMER registers
+const CUSTOMER: *mut u32 = 0x1000_1080 as *mut u32;
+
+/// Address of the NVMC's READY register
+const NVMC_READY: *const u32 = 0x4001_e400 as *const u32;
+/// Address of the NVMC's CONFIG register
+const NVMC_CONFIG: *mut u32 = 0x4001_e504 as *mut u32;
+/// Value of the NVMC's CONFIG register that enables writing
+const NVMC_CONFIG_WEN: u32 = 1;
+
+/// Read the counter.
+pub fn read() -> u32 {
+    (0..MAX_COUNTER)
+        // SAFETY: The UICR is always readable.
+        .take_while(|i| unsafe { core::ptr::read_volatile(CUSTOMER.add(*i as usize)) } == 0)
+        .count() as u32
+}
+
+/// Raise the counter to a value (at most [MAX_COUNTER]).
+pub fn raise(to: u32) {
+    // SAFETY: The softdevice is not enabled yet, so the NVMC is ours. Only words that are still
+    // erased are written, which does not affect the other registers of the UICR.
+    unsafe {
+        core::ptr::write_volatile(NVMC_CONFIG, NVMC_CONFIG_WEN);
+        for i in read()..to.min(MAX_COUNTER) {
+            core::ptr::write_volatile(CUSTOMER.add(i as usize), 0);
+            while core::ptr::read_volatile(NVMC_READY) == 0 {}
+        }
+        core::ptr::write_volatile(NVMC_CONFIG, 0);
+    }
+}
@@ -11,7 +11,11 @@
 ///
 /// It also does not encode the technical details on how the peer identifies in the security
 /// protocol: These are stored inside the RS's token pool, and already processed there.
-#[derive(defmt::Format)]
+///
+/// Requests are not authorized through this: The resource server matches the request's path and
+/// method against each security context's encoded AIF before the request reaches any handler of
+/// this crate.
+#[derive(Debug, defmt::Format)]
 pub struct ApplicationClaims {
     pub scope: Permissions,
     pub exp: u32,
@@ -22,10 +26,10 @@ impl ApplicationClaims {
         let now = crate::devicetime::unixtime();
         if let Ok(now) = now {
             if self.exp >= now {
-                defmt::info!("Token is good for another {} seconds", self.exp - now);
+                crate::info!("Token is good for another {} seconds", self.exp - now);
                 true
             } else {
-                defmt::info!("Token has expired for {} seconds", now - self.exp);
+                crate::info!("Token has expired for {} seconds", now - self.exp);
                 false
             }
         } else {
@@ -36,43 +40,204 @@ impl ApplicationClaims {
     }
 }
 
-/// The pre-parsed AIF.
+/// The REST-method-set bit (RFC 9237) that grants a request method, given by its CoAP code
+///
+/// This covers all methods registered so far, including FETCH, PATCH and iPATCH. Codes that are
+/// not methods have no bit.
+pub fn method_bit(code: u8) -> Option<u64> {
+    match code {
+        1..=31 => Some(1 << (code - 1)),
+        _ => None,
+    }
+}
+
+/// Longest encoded AIF that a scope is normalized into
+pub const MAX_SCOPE_LEN: usize = 64;
+
+/// Most entries (paths) that a text scope may contain
+const MAX_SCOPE_ENTRIES: usize = 8;
+
+/// Paths and their REST-method-sets, as read from a text scope
+type ScopeEntries<'a> = heapless::Vec<(&'a str, u64), MAX_SCOPE_ENTRIES>;
+
+/// Normalize a scope that an AS sent as a text string into the encoded AIF that binary scopes
+/// carry.
+///
+/// Two forms are understood:
+///
+/// * The JSON form of AIF, eg. `[["/temp", 1], ["/leds", 5]]` (without escapes in the paths).
+/// * Space separated entries of a path and its methods, eg. `/temp:GET /leds:GET,PUT`. Methods
+///   are given by their names, or as a REST-method-set number (eg. `/leds:5`).
 ///
-/// Struct members correspond to URI-local-part Toid, values to REST-method-set Tperm.
+/// Returns None if the text is in neither form, or if it does not fit.
 ///
-/// Note that this is custom and manual; a better solution would be deriving this struct and the
-/// match in its parsing function from a description of the CoAP tree.
-#[derive(defmt::Format, Default)]
-pub struct Permissions {
-    /// Permissions on `/temp`
-    pub temp: u8,
-    /// Permissions on `/identify`
-    pub identify: u8,
-    /// Permissions on `/leds`
-    pub leds: u8,
+/// This is used where the application decodes claims sets itself ([ApplicationClaims]). The
+/// resource server only accepts tokens whose scope is the binary AIF, so tokens with text scopes
+/// are still rejected when they are posted.
+pub fn normalize_scope(text: &str) -> Option<heapless::Vec<u8, MAX_SCOPE_LEN>> {
+    let entries = match text.trim_start().starts_with('[') {
+        true => json_scope_entries(text)?,
+        false => space_separated_scope_entries(text)?,
+    };
+
+    let mut buffer = [0; MAX_SCOPE_LEN];
+    let mut encoder = minicbor::Encoder::new(minicbor::encode::write::Cursor::new(&mut buffer[..]));
+    encoder.array(entries.len() as u64).ok()?;
+    for (path, methods) in entries {
+        encoder.array(2).ok()?.str(path).ok()?.u64(methods).ok()?;
+    }
+    let length = encoder.writer().position();
+    heapless::Vec::from_slice(&buffer[..length]).ok()
 }
 
-impl Permissions {
-    fn parse(input: &[u8]) -> Result<Self, minicbor::decode::Error> {
-        let mut decoder = minicbor::Decoder::new(input);
-        let mut parsed = Self::default();
-        for item in decoder.array_iter::<(&str, u8)>()? {
-            let (path, perms) = item?;
-            match path {
-                "/temp" => {
-                    parsed.temp = perms;
-                }
-                "/identify" => {
-                    parsed.identify = perms;
-                }
-                "/leds" => {
-                    parsed.leds = perms;
+/// Read the entries of the JSON form of AIF.
+fn json_scope_entries(text: &str) -> Option<ScopeEntries<'_>> {
+    let mut rest = text.trim().strip_prefix('[')?.strip_suffix(']')?.trim();
+    let mut entries = ScopeEntries::new();
+    while !rest.is_empty() {
+        let (entry, after) = rest.strip_prefix('[')?.split_once(']')?;
+        let (path, methods) = entry.split_once(',')?;
+        let path = path.trim().strip_prefix('"')?.strip_suffix('"')?;
+        entries.push((path, methods.trim().parse().ok()?)).ok()?;
+        let after = after.trim_start();
+        rest = after.strip_prefix(',').unwrap_or(after).trim_start();
+    }
+    Some(entries)
+}
+
+/// Read space separated entries of a path and its methods.
+fn space_separated_scope_entries(text: &str) -> Option<ScopeEntries<'_>> {
+    let mut entries = ScopeEntries::new();
+    for entry in text.split_ascii_whitespace() {
+        let (path, methods) = entry.rsplit_once(':')?;
+        let methods = match methods.parse() {
+            Ok(methods) => methods,
+            Err(_) => methods.split(',').try_fold(0, |set, name| {
+                // CoAP method codes
+                let code = match name {
+                    "GET" => 1,
+                    "POST" => 2,
+                    "PUT" => 3,
+                    "DELETE" => 4,
+                    "FETCH" => 5,
+                    "PATCH" => 6,
+                    "iPATCH" => 7,
+                    _ => return None,
+                };
+                Some(set | method_bit(code)?)
+            })?,
+        };
+        entries.push((path, methods)).ok()?;
+    }
+    Some(entries)
+}
+
+/// A resource whose permissions [Permissions] carries
+pub struct Resource {
+    /// Path segments, as passed to [crate::coap::ResourceTree::at]
+    pub path: &'static [&'static str],
+    /// Path segments joined by slashes, under which the resource is counted
+    pub name: &'static str,
+}
+
+/// Declare the resources, generating [Permissions] (its fields and the arms of its parser) and
+/// the [resources] that [crate::coap::builtin_resources] registers from one list.
+///
+/// Each entry is the feature that builds the resource (if it is optional), the field name and the
+/// path segments; the name is the segments joined by slashes. Authorization needs no further
+/// wiring, as the resource server matches requests against the scope by path (see
+/// [ApplicationClaims]).
+macro_rules! declare_resources {
+    ($($($feature:literal)? $field:ident: [$first:literal $(, $segment:literal)*];)*) => {
+        /// The pre-parsed AIF.
+        ///
+        /// Struct members correspond to URI-local-part Toid, values to REST-method-set Tperm.
+        /// That is an unsigned integer of up to 64 bits (the upper half is for the Dynamic-*
+        /// variants of methods), so the values are kept in full, even though the resources here
+        /// only implement a few methods.
+        #[derive(Debug, defmt::Format, Default)]
+        pub struct Permissions {
+            $(
+                #[doc = concat!("Permissions on `/", $first $(, "/", $segment)*, "`")]
+                $(#[cfg(feature = $feature)])?
+                pub $field: u64,
+            )*
+        }
+
+        impl Permissions {
+            fn parse(input: &[u8]) -> Result<Self, minicbor::decode::Error> {
+                let mut decoder = minicbor::Decoder::new(input);
+                let mut parsed = Self::default();
+                for item in decoder.array_iter::<(&str, u64)>()? {
+                    let (path, perms) = item?;
+                    match path {
+                        $(
+                            $(#[cfg(feature = $feature)])?
+                            concat!("/", $first $(, "/", $segment)*) => {
+                                parsed.$field = perms;
+                            }
+                        )*
+                        _ => {
+                            let _ = perms;
+                        }
+                    }
                 }
-                _ => (),
+                Ok(parsed)
             }
         }
-        Ok(parsed)
-    }
+
+        /// The resources, named as their fields in [Permissions]
+        #[allow(non_upper_case_globals)]
+        pub mod resources {
+            use super::Resource;
+
+            $(
+                $(#[cfg(feature = $feature)])?
+                pub const $field: Resource = Resource {
+                    path: &[$first $(, $segment)*],
+                    name: concat!($first $(, "/", $segment)*),
+                };
+            )*
+        }
+    };
+}
+
+declare_resources! {
+    "ws2812" leds_color: ["leds", "color"];
+    "resource-time" time: ["time"];
+    "resource-time" time_sync: ["time", "sync"];
+    "resource-leds" leds: ["leds"];
+    "resource-temp" temp: ["temp"];
+    "lwm2m" device_manufacturer: ["3", "0", "0"];
+    "lwm2m" device_model_number: ["3", "0", "1"];
+    "lwm2m" device_firmware_version: ["3", "0", "3"];
+    "lwm2m" device_power_source_voltage: ["3", "0", "7"];
+    "lwm2m" device_current_time: ["3", "0", "13"];
+    "lwm2m" temperature_sensor_value: ["3303", "0", "5700"];
+    "lwm2m" temperature_sensor_units: ["3303", "0", "5701"];
+    "lwm2m" light_control_on_off: ["3311", "0", "5850"];
+    "resource-identify" identify: ["identify"];
+    selftest: ["selftest"];
+    attest: ["attest"];
+    info: ["info"];
+    config: ["config"];
+    config_ble: ["config", "ble"];
+    keys_as: ["keys", "as"];
+    stats_resources: ["stats", "resources"];
+    stats_power: ["stats", "power"];
+    stats_crypto: ["stats", "crypto"];
+    stats_auth: ["stats", "auth"];
+    stats_ble: ["stats", "ble"];
+    battery: ["battery"];
+    description: ["description"];
+    authz_info: ["authz-info"];
+    debug_loglevel: ["debug", "loglevel"];
+    debug_log: ["debug", "log"];
+    debug_claims: ["debug", "claims"];
+    debug_contexts: ["debug", "contexts"];
+    debug_echo: ["debug", "echo"];
+    debug_sdfault: ["debug", "sdfault"];
+    debug_audit: ["debug", "audit"];
 }
 
 /// Error type indicating that a token contains credentials not for us, and/or contains claims that
@@ -97,46 +262,123 @@ impl<'a> TryFrom<&'a coset::cwt::ClaimsSet> for ApplicationClaims {
             _ => None,
         };
         for (key, value) in claims.rest.iter() {
-            match (key, value) {
-                (
-                    coset::RegisteredLabelWithPrivate::Assigned(coset::iana::CwtClaimName::Scope),
-                    ciborium::value::Value::Bytes(s),
-                ) => {
-                    // FIXME value goes back and forth between J/S and AIF
-                    let new = Permissions::parse(s.as_slice()).map_err(|_e| {
-                        // Not reporting value, see https://gitlab.com/twittner/minicbor/-/issues/41
-                        defmt::info!("Unparsable scope claim, rejecting.");
+            let coset::RegisteredLabelWithPrivate::Assigned(coset::iana::CwtClaimName::Scope) = key
+            else {
+                continue;
+            };
+            let normalized;
+            let encoded = match value {
+                ciborium::value::Value::Bytes(s) => s.as_slice(),
+                ciborium::value::Value::Text(s) => {
+                    normalized = normalize_scope(s).ok_or_else(|| {
+                        crate::info!("Unparsable text scope claim, rejecting.");
                         UnrecognizedCredentials
                     })?;
-                    if scope.replace(new).is_some() {
-                        // Double key
-                        defmt::info!("Duplicate scope claim, rejecting.");
-                        return Err(UnrecognizedCredentials);
-                    }
+                    &normalized[..]
                 }
-                _ => (),
+                _ => continue,
+            };
+            let new = Permissions::parse(encoded).map_err(|_e| {
+                // Not reporting value, see https://gitlab.com/twittner/minicbor/-/issues/41
+                crate::info!("Unparsable scope claim, rejecting.");
+                UnrecognizedCredentials
+            })?;
+            if scope.replace(new).is_some() {
+                // Double key
+                crate::info!("Duplicate scope claim, rejecting.");
+                return Err(UnrecognizedCredentials);
             }
         }
 
         let Some(scope) = scope else {
-            defmt::info!("No scope set, rejecting.");
+            crate::info!("No scope set, rejecting.");
             return Err(UnrecognizedCredentials);
         };
 
         let Some(exp) = exp else {
             // Let's not even get started with infinite credentials
-            defmt::info!("No expiry set, rejecting.");
+            crate::info!("No expiry set, rejecting.");
             return Err(UnrecognizedCredentials);
         };
 
         let appclaims = ApplicationClaims { scope, exp };
 
         if !appclaims.valid() {
-            defmt::info!("Token recognized, but validity test failed.");
+            crate::info!("Token recognized, but validity test failed.");
             return Err(UnrecognizedCredentials);
         }
 
-        defmt::info!("Token accepted: {:?}", appclaims);
+        crate::info!("Token accepted: {:?}", appclaims);
         Ok(appclaims)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_scope;
+
+    /// `[["/temp", 1], ["/leds", 5]]` as CBOR
+    const TEMP_AND_LEDS: &[u8] = b"\x82\x82\x65/temp\x01\x82\x65/leds\x05";
+
+    #[test]
+    fn json_form() {
+        let normalized = normalize_scope(r#"[["/temp", 1], ["/leds", 5]]"#);
+        assert_eq!(normalized.as_deref(), Some(TEMP_AND_LEDS));
+        let normalized = normalize_scope(" [ [\"/temp\",1] ,[\"/leds\" , 5] ] ");
+        assert_eq!(normalized.as_deref(), Some(TEMP_AND_LEDS));
+        assert_eq!(normalize_scope("[]").as_deref(), Some(&[0x80][..]));
+    }
+
+    #[test]
+    fn space_separated_form() {
+        let normalized = normalize_scope("/temp:GET /leds:GET,PUT");
+        assert_eq!(normalized.as_deref(), Some(TEMP_AND_LEDS));
+        let normalized = normalize_scope("/temp:1  /leds:5");
+        assert_eq!(normalized.as_deref(), Some(TEMP_AND_LEDS));
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [
+            r#"[["/temp", 1]"#,
+            r#"[["/temp" 1]]"#,
+            r#"[[/temp, 1]]"#,
+            r#"[["/temp", -1]]"#,
+            r#"[["/temp", GET]]"#,
+            "/temp",
+            "/temp:",
+            "/temp:GET,",
+        ] {
+            assert_eq!(normalize_scope(text), None, "{text} was accepted");
+        }
+    }
+
+    #[test]
+    fn unknown_method() {
+        assert_eq!(normalize_scope("/temp:BREW"), None);
+        assert_eq!(normalize_scope("/temp:get"), None);
+    }
+
+    #[test]
+    fn oversized() {
+        // More entries than are kept
+        let many = "/a:GET ".repeat(super::MAX_SCOPE_ENTRIES + 1);
+        assert_eq!(normalize_scope(&many), None);
+        // Fewer entries, but too long when encoded
+        let long = format!("/{}:GET", "x".repeat(super::MAX_SCOPE_LEN));
+        assert_eq!(normalize_scope(&long), None);
+    }
+
+    #[test]
+    fn resources_are_named_by_path() {
+        use super::resources;
+
+        assert_eq!(resources::debug_log.path, ["debug", "log"]);
+        assert_eq!(resources::debug_log.name, "debug/log");
+        assert_eq!(resources::info.name, "info");
+
+        let permissions = super::Permissions::parse(b"\x81\x82\x6a/debug/log\x01").unwrap();
+        assert_eq!(permissions.debug_log, 1);
+        assert_eq!(permissions.debug_loglevel, 0);
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Runtime-mutable security configuration
+//!
+//! The keys the resource server works with start out as those of the [crate::CoapcoreConfig],
+//! but are kept in a global handle from which they can be changed at runtime (eg. by provisioning
+//! or key rotation) through [update], and all established security contexts can be dropped
+//! through [revoke_all].
+//!
+//! coapcore takes its security configuration only at construction time, and does not allow
+//! changing it later. Therefore, the resource server is wrapped in a [Reloading] handler, which
+//! rebuilds the resource server (but not the application's resources) from the handle's current
+//! content before processing the first request after a change. This necessarily drops all security
+//! contexts and accepted tokens, so peers need to go through EDHOC and token submission again.
+//!
+//! The exception is a rotation of the key shared with the AS through [rotate_as_key]: There, the
+//! previous resource server is kept alongside the new one for an overlap window, and token posts
+//! and protected requests the new one rejects are retried on it (see [Previous]); other requests
+//! are never processed twice. Thus, tokens issued with the old key still validate, and peers that
+//! already established a security context keep working, until the window ends. This comes at the
+//! cost of holding two resource servers in memory during the window, and does not cover tokens sent
+//! with the old key inside an EDHOC exchange.
+//!
+//! Tokens are only ever validated locally; tokens the resource server can not decode (eg.
+//! reference tokens) are rejected like any other unrecognized credential.
+//!
+//! Secrets that this crate handles are wiped from memory after use: decrypted tokens (see
+//! [crate::tokens]) through [zeroize::Zeroizing], and all heap memory (where tokens are decoded)
+//! by the firmware's allocator when it is freed. The ephemeral EDHOC keys and the derived OSCORE
+//! keys are kept inside lakers and coapcore, and are not wiped.
+//!
+//! EDHOC message_4 is never sent: message_3 is answered with an empty response (or, with the
+//! EDHOC + OSCORE combined request, with the response to the protected request). The first OSCORE
+//! protected response confirms the key to the client (see RFC 9528 section 5.5).
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+use coap_handler::Handler;
+use coap_message::{MinimalWritableMessage, MutableWritableMessage, ReadableMessage};
+
+/// The keys of the security configuration
+#[derive(Copy, Clone)]
+pub struct Keys {
+    /// Own EDHOC credential (a CCS containing the public key) and private key
+    ///
+    /// This is used with the signature and static DH methods of RFC 9528.
+    pub edhoc: Option<(&'static [u8], [u8; 32])>,
+    /// Key shared with the AS for symmetrically encrypted tokens
+    pub as_symmetric: Option<[u8; 32]>,
+    /// Public key of the AS for signed tokens
+    pub as_pub: Option<([u8; 32], [u8; 32])>,
+}
+
+impl Keys {
+    /// Keys as provisioned at build time
+    pub fn from_config(config: &crate::CoapcoreConfig) -> Self {
+        let edhoc = match (config.edhoc_credential, config.edhoc_q) {
+            (Some(credential), Some(q)) => Some((credential, *q)),
+            _ => None,
+        };
+        Self {
+            edhoc,
+            as_symmetric: config.as_symmetric,
+            as_pub: config.as_pub,
+        }
+    }
+}
+
+static KEYS: critical_section::Mutex<RefCell<Option<Keys>>> =
+    critical_section::Mutex::new(RefCell::new(None));
+
+/// Counter of changes, through which [Reloading] learns that it needs to rebuild
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// Overlap window of an AS key rotation that is about to take effect
+static ROTATION: critical_section::Mutex<Cell<Option<embassy_time::Duration>>> =
+    critical_section::Mutex::new(Cell::new(None));
+
+/// Key shared with the AS before the latest rotation, along with the end of its overlap window
+///
+/// This follows the previous handler of [Reloading], for messages from the AS that are decrypted
+/// outside the resource server (see [crate::tokens::decrypt]).
+static PREVIOUS_KEY: critical_section::Mutex<Cell<Option<([u8; 32], embassy_time::Instant)>>> =
+    critical_section::Mutex::new(Cell::new(None));
+
+/// Install the initial keys.
+pub(crate) fn init(keys: Keys) {
+    critical_section::with(|cs| *KEYS.borrow_ref_mut(cs) = Some(keys));
+    GENERATION.fetch_add(1, Relaxed);
+}
+
+/// The keys currently in effect (or about to be, as of the next request)
+pub fn keys() -> Option<Keys> {
+    critical_section::with(|cs| *KEYS.borrow_ref(cs))
+}
+
+/// Change the keys; the change takes effect from the next request on.
+///
+/// Does nothing if the resource server was not built yet.
+pub fn update(f: impl FnOnce(&mut Keys)) {
+    let updated = critical_section::with(|cs| KEYS.borrow_ref_mut(cs).as_mut().map(f));
+    if updated.is_some() {
+        GENERATION.fetch_add(1, Relaxed);
+        crate::info!("Security configuration changed");
+    }
+}
+
+/// Replace the key shared with the AS as of the next request, while tokens and security contexts
+/// of the previous key remain usable for the `overlap` time.
+pub fn rotate_as_key(key: [u8; 32], overlap: embassy_time::Duration) {
+    critical_section::with(|cs| ROTATION.borrow(cs).set(Some(overlap)));
+    update(|keys| keys.as_symmetric = Some(key));
+    crate::info!(
+        "AS key rotated, previous key remains valid for {} seconds",
+        overlap.as_secs()
+    );
+}
+
+/// The key shared with the AS before the latest [rotation](rotate_as_key), as long as its overlap
+/// window lasts
+pub fn previous_as_key() -> Option<[u8; 32]> {
+    let (key, until) = critical_section::with(|cs| PREVIOUS_KEY.borrow(cs).get())?;
+    (embassy_time::Instant::now() < until).then_some(key)
+}
+
+/// Drop all security contexts and tokens as of the next request.
+pub fn revoke_all() {
+    GENERATION.fetch_add(1, Relaxed);
+    crate::info!("All security contexts revoked");
+}
+
+/// Handlers that can retain the resource server from before an AS key rotation
+pub trait Previous {
+    type Handler: Handler;
+
+    /// The resource server that was in use before the latest [AS key rotation](rotate_as_key),
+    /// as long as its overlap window lasts
+    fn previous(&mut self) -> Option<&mut Self::Handler>;
+}
+
+/// A handler that is rebuilt from its factory whenever the security configuration changes
+///
+/// The factory returns `None` if the keys are unusable (eg. a credential that does not parse); the
+/// handler built before then stays in use.
+pub struct Reloading<F, H> {
+    factory: F,
+    handler: H,
+    generation: u32,
+    /// Key shared with the AS that `handler` was built with
+    as_symmetric: Option<[u8; 32]>,
+    /// Handler from before an AS key rotation, along with the end of its overlap window
+    previous: Option<(H, embassy_time::Instant)>,
+}
+
+impl<F: FnMut(Keys) -> Option<H>, H> Reloading<F, H> {
+    /// Build the handler from the current keys.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the keys were not [initialized](init), or if the factory can not build a
+    /// handler from them. Both happen at startup, from the built-in configuration.
+    pub fn new(mut factory: F) -> Self {
+        let generation = GENERATION.load(Relaxed);
+        let keys = keys().expect("Keys are initialized before use");
+        let handler = factory(keys).expect("Built-in keys are usable");
+        Self {
+            factory,
+            handler,
+            generation,
+            as_symmetric: keys.as_symmetric,
+            previous: None,
+        }
+    }
+
+    /// Drop the previous handler if its overlap window has ended.
+    fn expire(&mut self) {
+        if let Some((_, until)) = &self.previous {
+            if embassy_time::Instant::now() >= *until {
+                self.previous = None;
+                critical_section::with(|cs| PREVIOUS_KEY.borrow(cs).set(None));
+                crate::info!("Overlap window of the previous AS key ended");
+            }
+        }
+    }
+}
+
+impl<F: FnMut(Keys) -> Option<H>, H: Handler> Previous for Reloading<F, H> {
+    type Handler = H;
+
+    fn previous(&mut self) -> Option<&mut H> {
+        self.expire();
+        self.previous.as_mut().map(|(handler, _)| handler)
+    }
+}
+
+impl<F: FnMut(Keys) -> Option<H>, H: Handler> Handler for Reloading<F, H> {
+    type RequestData = H::RequestData;
+    type ExtractRequestError = H::ExtractRequestError;
+    type BuildResponseError<M: MinimalWritableMessage> = H::BuildResponseError<M>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let generation = GENERATION.load(Relaxed);
+        if generation != self.generation {
+            let rebuilt = keys().and_then(|keys| Some((keys, (self.factory)(keys)?)));
+            if let Some((keys, handler)) = rebuilt {
+                let rotation = critical_section::with(|cs| ROTATION.borrow(cs).take());
+                let replaced = core::mem::replace(&mut self.handler, handler);
+                let replaced_key = core::mem::replace(&mut self.as_symmetric, keys.as_symmetric);
+                // Any other change invalidates what the previous handler accepted as well.
+                self.previous =
+                    rotation.map(|overlap| (replaced, embassy_time::Instant::now() + overlap));
+                let previous_key = self
+                    .previous
+                    .as_ref()
+                    .zip(replaced_key)
+                    .map(|((_, until), key)| (key, *until));
+                critical_section::with(|cs| PREVIOUS_KEY.borrow(cs).set(previous_key));
+                if self.previous.is_none() {
+                    crate::tokens::clear();
+                }
+                crate::info!("Resource server rebuilt with new security configuration");
+            } else {
+                crate::error!("New security configuration is unusable, keeping the previous one");
+            }
+            self.generation = generation;
+        }
+        self.expire();
+        self.handler.extract_request_data(request)
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        self.handler.estimate_length(request)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        self.handler.build_response(response, request)
+    }
+}
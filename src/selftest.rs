@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Power-on self test
+//!
+//! The self test is run once at startup (see [run]), and can be re-run through a POST to the
+//! `/selftest` resource. It checks:
+//!
+//! * that the temperature sensor gives plausible values,
+//! * that the random number generator does not produce stuck or repeating output, and
+//! * that the configuration in flash still matches the checksum calculated at build time.
+//!
+//! The LEDs are exercised at startup as well, but as their function can not be verified by the
+//! device itself, that is left to the observer.
+//!
+//! Failures are reported through the LEDs' failure pattern (see
+//! [crate::platform::LedControl::show_failure]), and the latest results are available through a
+//! GET to `/selftest`.
+
+use core::sync::atomic::{AtomicU8, Ordering::Relaxed};
+
+use crate::platform::{LedControl, Thermometer};
+use crate::CoapcoreConfig;
+
+/// Range of temperatures the nRF52 is specified to operate in, in °C
+const PLAUSIBLE_TEMPERATURES: core::ops::RangeInclusive<i32> = -40..=85;
+
+/// Outcome of a self test run
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Report {
+    pub temperature_ok: bool,
+    pub rng_ok: bool,
+    pub config_ok: bool,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.temperature_ok && self.rng_ok && self.config_ok
+    }
+
+    fn to_bits(self) -> u8 {
+        RAN | (!self.temperature_ok as u8) << 1
+            | (!self.rng_ok as u8) << 2
+            | (!self.config_ok as u8) << 3
+    }
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        if bits & RAN == 0 {
+            return None;
+        }
+        Some(Self {
+            temperature_ok: bits & (1 << 1) == 0,
+            rng_ok: bits & (1 << 2) == 0,
+            config_ok: bits & (1 << 3) == 0,
+        })
+    }
+}
+
+/// The report is encoded as a CBOR map from the test names ("temp", "rng", "config") to booleans
+/// indicating success.
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(3)?
+            .str("temp")?
+            .bool(self.temperature_ok)?
+            .str("rng")?
+            .bool(self.rng_ok)?
+            .str("config")?
+            .bool(self.config_ok)?;
+        Ok(())
+    }
+}
+
+/// Flag in [LAST_REPORT] indicating that a test was run at all
+const RAN: u8 = 1;
+
+/// Result of the latest self test, as produced by [Report::to_bits]
+static LAST_REPORT: AtomicU8 = AtomicU8::new(0);
+
+/// Result of the latest self test run, if any
+pub fn last_report() -> Option<Report> {
+    Report::from_bits(LAST_REPORT.load(Relaxed))
+}
+
+/// Run all self tests, store the result, and show the failure pattern on the LEDs if any failed.
+pub fn run<L: LedControl>(
+    thermometer: &impl Thermometer,
+    rng: &mut impl rand_core::RngCore,
+    config: &CoapcoreConfig,
+    leds: &'static L,
+) -> Report {
+    let report = Report {
+        temperature_ok: check_temperature(thermometer),
+        rng_ok: check_rng(rng),
+        config_ok: config.calculate_checksum() == config.checksum,
+    };
+    LAST_REPORT.store(report.to_bits(), Relaxed);
+
+    if report.ok() {
+        crate::info!("Self test passed");
+    } else {
+        crate::error!("Self test failed: {:?}", report);
+        leds.show_failure();
+    }
+
+    report
+}
+
+fn check_temperature(thermometer: &impl Thermometer) -> bool {
+    match thermometer.temperature() {
+        Ok(t) => PLAUSIBLE_TEMPERATURES.contains(&t.to_num::<i32>()),
+        Err(_) => false,
+    }
+}
+
+/// Check for the most blatant failures of a random number generator: erroring out, being stuck,
+/// or repeating itself.
+fn check_rng(rng: &mut impl rand_core::RngCore) -> bool {
+    let mut a = [0; 16];
+    let mut b = [0; 16];
+    if rng.try_fill_bytes(&mut a).is_err() || rng.try_fill_bytes(&mut b).is_err() {
+        return false;
+    }
+    let stuck = |x: &[u8; 16]| x.iter().all(|byte| *byte == x[0]);
+    !stuck(&a) && !stuck(&b) && a != b
+}
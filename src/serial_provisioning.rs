@@ -0,0 +1,249 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Provisioning page, and the serial transport of the provisioning protocol
+//!
+//! The identity format and the protocol are described in [coap_ace_poc_firmware::provisioning];
+//! this module holds the page (reserved in `memory.x` below the settings journal) and, with the
+//! `serial-provisioning` feature, serves the protocol on UARTE0. That is at 115200 baud, 8N1,
+//! without flow control, on the pins of the development kits' virtual COM port (TX P0.06, RX
+//! P0.08), or on P1.10 (TX) and P1.13 (RX) of the dongle.
+//!
+//! Each request and response is framed by its length as a 16-bit big endian number. A request
+//! that does not arrive completely within a second is discarded, along with anything that arrives
+//! until the line was quiet for a second, so that a fixture can get back in step by pausing.
+//!
+//! In safe mode, the protocol is also served over CoAP-over-GATT (see [respond]).
+
+use coap_message::{MessageOption, MinimalWritableMessage, ReadableMessage};
+use zeroize::Zeroize;
+
+use coap_ace_poc_firmware::provisioning::{self, Identity, Request, HEADER_LEN, PAGE_SIZE};
+use coap_ace_poc_firmware::{info, CoapcoreConfig, MAX_MESSAGE_LEN};
+
+/// Address of the provisioning page reserved in `memory.x`
+#[cfg(not(feature = "hardware-nrf52840dongle"))]
+const PAGE: u32 = 0x7d000;
+/// Address of the provisioning page reserved in `memory.x`, which on the dongle ends before the
+/// settings journal and its bootloader
+#[cfg(feature = "hardware-nrf52840dongle")]
+const PAGE: u32 = 0xdd000;
+
+/// Content of the provisioning page
+fn page() -> &'static [u8] {
+    // SAFETY: Flash is memory mapped, and the page is not part of the firmware image. Its content
+    // only changes at the request of the fixture, and is only read before (at startup) and in
+    // between such requests.
+    unsafe { core::slice::from_raw_parts(PAGE as *const u8, PAGE_SIZE) }
+}
+
+/// Read the provisioned identity, if any (see [provisioning::load]).
+pub fn load() -> Option<Result<CoapcoreConfig, minicbor::decode::Error>> {
+    provisioning::load(page())
+}
+
+/// Longest request that is accepted
+const MAX_FRAME: usize = 512;
+
+// Requests written over the air fit as well.
+const _: () = assert!(crate::MAX_WRITE_LEN <= MAX_FRAME);
+
+/// Buffer for data to be written; the softdevice requires it to be word aligned.
+#[repr(align(4))]
+struct Aligned([u8; HEADER_LEN + MAX_FRAME + 3]);
+
+/// The buffer holds an identity with its private key.
+impl Drop for Aligned {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Digest of the identity in the page, checked against the stored one
+fn current() -> Result<Option<u32>, &'static str> {
+    match load() {
+        None => Ok(None),
+        Some(Ok(config)) if config.calculate_checksum() == config.checksum => {
+            Ok(Some(config.checksum))
+        }
+        Some(_) => Err("Provisioning page is damaged"),
+    }
+}
+
+/// Error message of failed flash operations, which are most likely due to the protection
+const PROTECTED: &str = "Flash operation failed; is the page still protected?";
+
+async fn erase() -> Result<(), &'static str> {
+    crate::flash::erase(PAGE, PAGE + PAGE_SIZE as u32)
+        .await
+        .map_err(|_| PROTECTED)
+}
+
+async fn process(request: Request<'_>) -> Result<Option<u32>, &'static str> {
+    match request {
+        Request::Provision(encoded) => {
+            let identity = Identity::decode(encoded).map_err(|_| "Invalid identity")?;
+            let mut image = Aligned([0xff; HEADER_LEN + MAX_FRAME + 3]);
+            let len = provisioning::page_image(encoded, identity.checksum(), &mut image.0);
+            erase().await?;
+            crate::flash::write(PAGE, &image.0[..len])
+                .await
+                .map_err(|_| PROTECTED)?;
+            info!("Identity provisioned, effective after restart");
+            crate::flash_protection::protect();
+            // Read back, so that the digest confirms what is in flash.
+            current()
+        }
+        Request::Query => current(),
+        Request::Erase => {
+            erase().await?;
+            info!("Provisioned identity erased, effective after restart");
+            crate::flash_protection::protect();
+            Ok(None)
+        }
+        // The restart happens after the response was sent.
+        Request::Unprotect => current(),
+    }
+}
+
+/// Serve a request of the provisioning protocol that was written as a CoAP request in safe mode.
+///
+/// Such requests are POSTed to `/provision`, and answered with 2.04 Changed and the protocol's
+/// response (which may indicate a failure) as payload; this returns None for all other requests.
+/// As nothing protects them, the restart without write protection is refused: Provisioning and
+/// erasing over the air only succeed after a restart requested through the serial port.
+pub async fn respond(written: &mut [u8]) -> Option<heapless::Vec<u8, MAX_MESSAGE_LEN>> {
+    let request = coap_gatt_utils::parse_mut(written).ok()?;
+    let mut path = request
+        .options()
+        .filter(|o| o.number() == coap_numbers::option::URI_PATH)
+        .map(|o| o.value());
+    let (Some(b"provision"), None) = (path.next(), path.next()) else {
+        return None;
+    };
+    let code: u8 = request.code().into();
+    if code != coap_numbers::code::POST {
+        return Some(coap_gatt_utils::write(|response| {
+            response.set_code(coap_numbers::code::METHOD_NOT_ALLOWED);
+        }));
+    }
+
+    let result = match Request::decode(request.payload()) {
+        Ok(Request::Unprotect) => Err("Only available through the serial port"),
+        Ok(request) => process(request).await,
+        Err(_) => Err("Malformed request"),
+    };
+    if let Err(message) = result {
+        info!("Provisioning request failed: {}", message);
+    }
+    let mut payload = [0; 64];
+    let len = provisioning::encode_response(result, &mut payload);
+    Some(coap_gatt_utils::write(|response| {
+        response.set_code(coap_numbers::code::CHANGED);
+        // A response this short always fits.
+        let _ = response.add_option_uint(coap_numbers::option::CONTENT_FORMAT, 60u8);
+        let _ = response.set_payload(&payload[..len]);
+    }))
+}
+
+#[cfg(feature = "serial-provisioning")]
+pub use serve::*;
+
+#[cfg(feature = "serial-provisioning")]
+mod serve {
+    use super::*;
+
+    use embassy_nrf::peripherals::UARTE0;
+    use embassy_nrf::uarte::{self, Uarte};
+
+    use coap_ace_poc_firmware::warn;
+
+    /// Time within which the rest of a request needs to arrive once its length was received
+    const FRAME_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+    embassy_nrf::bind_interrupts!(struct Irqs {
+        UARTE0_UART0 => uarte::InterruptHandler<UARTE0>;
+    });
+
+    /// Set up UARTE0 on the given pins.
+    pub fn uart(
+        uarte: UARTE0,
+        rxd: impl embassy_nrf::gpio::Pin,
+        txd: impl embassy_nrf::gpio::Pin,
+    ) -> Uarte<'static, UARTE0> {
+        use embassy_nrf::interrupt::InterruptExt;
+        // Differing from default, this stays out of the softdevice's hair
+        embassy_nrf::interrupt::UARTE0_UART0.set_priority(embassy_nrf::interrupt::Priority::P7);
+        Uarte::new(uarte, Irqs, rxd, txd, Default::default())
+    }
+
+    /// Read the next request into `frame`, returning its length.
+    async fn receive(
+        uart: &mut Uarte<'static, UARTE0>,
+        frame: &mut [u8; MAX_FRAME],
+    ) -> Result<usize, &'static str> {
+        use embassy_time::with_timeout;
+
+        let mut len = [0; 2];
+        uart.read(&mut len[..1]).await.map_err(|_| "Read failed")?;
+        with_timeout(FRAME_TIMEOUT, uart.read(&mut len[1..]))
+            .await
+            .map_err(|_| "Request incomplete")?
+            .map_err(|_| "Read failed")?;
+        let len = usize::from(u16::from_be_bytes(len));
+        let frame = frame.get_mut(..len).ok_or("Request too long")?;
+        with_timeout(FRAME_TIMEOUT, uart.read(frame))
+            .await
+            .map_err(|_| "Request incomplete")?
+            .map_err(|_| "Read failed")?;
+        Ok(len)
+    }
+
+    /// Discard anything received until the line is quiet for [FRAME_TIMEOUT].
+    async fn drain(uart: &mut Uarte<'static, UARTE0>) {
+        let mut byte = [0];
+        while embassy_time::with_timeout(FRAME_TIMEOUT, uart.read(&mut byte))
+            .await
+            .is_ok()
+        {}
+    }
+
+    /// Task serving the provisioning protocol
+    ///
+    /// This needs to run on the softdevice's executor, as it uses the flash.
+    #[embassy_executor::task]
+    pub async fn serve(mut uart: Uarte<'static, UARTE0>) {
+        let mut frame = [0; MAX_FRAME];
+        let mut response = [0; 64];
+        loop {
+            let len = match receive(&mut uart, &mut frame).await {
+                Ok(len) => len,
+                Err(message) => {
+                    warn!("Provisioning request discarded: {}", message);
+                    drain(&mut uart).await;
+                    continue;
+                }
+            };
+
+            let request = Request::decode(&frame[..len]);
+            let restart = matches!(request, Ok(Request::Unprotect));
+            let result = match request {
+                Ok(request) => process(request).await,
+                Err(_) => Err("Malformed request"),
+            };
+            if let Err(message) = result {
+                warn!("Provisioning request failed: {}", message);
+            }
+            let len = provisioning::encode_response(result, &mut response[2..]);
+            response[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            if let Err(e) = uart.write(&response[..2 + len]).await {
+                warn!("Serial provisioning write failed: {:?}", e);
+            }
+            // Requests can contain private keys.
+            frame[..len].zeroize();
+            if restart {
+                crate::flash_protection::restart_unprotected();
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Service Changed indications after the attribute table changed
+//!
+//! Centrals may cache the attribute table of a device they discovered (iOS does even without
+//! bonding), and then address the CoAP characteristic by a handle that moved. The table is built
+//! at startup and never changes while the firmware runs; it only changes with the firmware (eg.
+//! a different softdevice, or a new version of the [Server](crate::Server)). Safe mode serves the
+//! same table, and neither it nor provisioning or identity derivation change anything but the
+//! value of the device name characteristic.
+//!
+//! At startup, [check] compares a fingerprint of the table (the softdevice's firmware ID and the
+//! handles of the CoAP characteristic) with the one persisted in the [settings]. If it differs,
+//! every connection of this run gets a Service Changed indication over the whole handle range,
+//! once the central enabled indications on that characteristic. As the device does not bond, it
+//! can not tell which centrals saw the old table, and does not know after this run: Centrals
+//! that only connect after the next restart still need to rediscover on their own.
+//!
+//! The resources listed in `/.well-known/core` are fixed at build time as well, so they change
+//! exactly when the fingerprint can; clients that rediscover the services after an indication are
+//! best off fetching it anew.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use coap_ace_poc_firmware::{info, settings};
+use nrf_softdevice::{ble::Connection, raw};
+
+/// Whether the attribute table differs from the one of the previous run
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Interval in which sending the indication is attempted until the central enabled it
+const RETRY_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_millis(500);
+
+/// Number of attempts at sending the indication
+const ATTEMPTS: usize = 20;
+
+/// Compare the fingerprint of the current attribute table with the persisted one, and persist it
+/// if it changed.
+///
+/// This needs to run after the settings were loaded.
+pub fn check(fingerprint: u16) {
+    let persisted = settings::gatt_layout();
+    if persisted == Some(fingerprint) {
+        return;
+    }
+    // Without a persisted fingerprint, there may be an older firmware's table cached.
+    info!(
+        "Attribute table changed (fingerprint {:?} to {}), indicating Service Changed",
+        persisted, fingerprint
+    );
+    CHANGED.store(true, Ordering::Relaxed);
+    settings::set_gatt_layout(fingerprint);
+}
+
+/// Send a Service Changed indication through the connection if the attribute table changed.
+///
+/// This completes once the indication was sent, or once the central did not enable it within
+/// a few seconds (many never do), or once the connection ended.
+pub async fn indicate(conn: &Connection) {
+    if !CHANGED.load(Ordering::Relaxed) {
+        return;
+    }
+    for _ in 0..ATTEMPTS {
+        let Some(handle) = conn.handle() else {
+            return;
+        };
+        // SAFETY: Plain softdevice call with a valid connection handle.
+        let result = unsafe { raw::sd_ble_gatts_service_changed(handle, 0x0001, 0xffff) };
+        match result {
+            raw::NRF_SUCCESS => {
+                info!("Service Changed indicated");
+                return;
+            }
+            // Indications are not enabled (yet), or a previous indication is still pending
+            raw::NRF_ERROR_INVALID_STATE | raw::NRF_ERROR_BUSY => (),
+            _ => {
+                info!("Service Changed could not be indicated: {}", result);
+                return;
+            }
+        }
+        embassy_time::Timer::after(RETRY_INTERVAL).await;
+    }
+}
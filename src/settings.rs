@@ -0,0 +1,751 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Device settings that survive a power cycle
+//!
+//! Settings are small values identified by a [Key]. This module keeps them in RAM and tracks which
+//! were changed; persisting them is up to the platform. The firmware keeps a journal in flash,
+//! which it [replays](replay) at startup, and to which it appends whatever [take_changed] reports
+//! once [CHANGED] is signaled.
+//!
+//! Values are stored as bytes; the typed accessors ([idle_level] etc.) are what components use.
+//! Operators can read and modify the [configurable](Key::configurable) ones through the `/config`
+//! resource, or (for the connection policy) the `/config/ble` resource (see [Section]).
+//!
+//! Not covered are the tokens and security contexts, which coapcore keeps to itself.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+/// Number of distinct keys
+const KEYS: usize = 14;
+
+/// Longest value that can be stored under any key
+pub const MAX_VALUE_LEN: usize = 8;
+
+pub type Value = heapless::Vec<u8, MAX_VALUE_LEN>;
+
+/// Identifiers of settings
+///
+/// The numeric values are what is stored in flash, and must thus not be changed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum Key {
+    /// Number of LEDs lit when idle (a `u8`)
+    IdleLevel = 0,
+    /// Calibration offset added to temperature readings, in quarter degrees (an `i8`)
+    TemperatureOffset = 1,
+    /// Advertising interval in milliseconds (a `u16`)
+    AdvertisingInterval = 2,
+    /// Whether the low-power advertising profile is used (a `u8` that is 0 or 1)
+    LowPowerAdvertising = 3,
+    /// How to advertise while the device is not ready (a `u8`, see [AdvertisingPolicy])
+    AdvertisingPolicy = 4,
+    /// Seconds without ATT traffic after which a connection is closed (a `u16`, 0 to never close)
+    IdleTimeout = 5,
+    /// Whether peers other than admins may connect (a `u8` that is 0 or 1)
+    Connectable = 6,
+    /// Maximum number of concurrent connections of peers other than admins (a `u8`)
+    MaxPeers = 7,
+    /// Start of the daily window in which peers other than admins may connect, in minutes after
+    /// midnight UTC (a `u16`)
+    OpenFrom = 8,
+    /// End of the daily window in which peers other than admins may connect, in minutes after
+    /// midnight UTC (a `u16`)
+    OpenUntil = 9,
+    /// Minutes without connections after which the device turns into a beacon (a `u16`, 0 to
+    /// never do that; see [beacon_after])
+    BeaconAfter = 10,
+    /// Seconds within which a peer needs to complete an EDHOC exchange it started (a `u16`, 0 for
+    /// no limit)
+    HandshakeTimeout = 11,
+    /// Number of times the firmware started (a `u32`, see [count_boot])
+    BootCount = 12,
+    /// Fingerprint of the GATT attribute table at the latest start (a `u16`, see the firmware's
+    /// `service_changed` module)
+    GattLayout = 13,
+}
+
+/// Resources through which settings are configured
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Section {
+    /// The `/config` resource
+    General,
+    /// The `/config/ble` resource, which holds the connection policy
+    Ble,
+}
+
+/// Error type indicating that a number does not represent any [Key]
+#[derive(Debug)]
+pub struct UnknownKey;
+
+impl TryFrom<u8> for Key {
+    type Error = UnknownKey;
+
+    fn try_from(value: u8) -> Result<Self, UnknownKey> {
+        Self::ALL
+            .into_iter()
+            .find(|key| *key as u8 == value)
+            .ok_or(UnknownKey)
+    }
+}
+
+/// Error type indicating that a value is out of the range acceptable for its key
+#[derive(Debug)]
+pub struct InvalidValue;
+
+impl Key {
+    pub const ALL: [Key; KEYS] = [
+        Key::IdleLevel,
+        Key::TemperatureOffset,
+        Key::AdvertisingInterval,
+        Key::LowPowerAdvertising,
+        Key::AdvertisingPolicy,
+        Key::IdleTimeout,
+        Key::Connectable,
+        Key::MaxPeers,
+        Key::OpenFrom,
+        Key::OpenUntil,
+        Key::BeaconAfter,
+        Key::HandshakeTimeout,
+        Key::BootCount,
+        Key::GattLayout,
+    ];
+
+    /// Name under which the setting is shown in the `/config` resource
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::IdleLevel => "idle",
+            Key::TemperatureOffset => "temp-offset",
+            Key::AdvertisingInterval => "adv-interval",
+            Key::LowPowerAdvertising => "low-power",
+            Key::AdvertisingPolicy => "adv-policy",
+            Key::IdleTimeout => "idle-timeout",
+            Key::Connectable => "connectable",
+            Key::MaxPeers => "max-peers",
+            Key::OpenFrom => "open-from",
+            Key::OpenUntil => "open-until",
+            Key::BeaconAfter => "beacon-after",
+            Key::HandshakeTimeout => "handshake-timeout",
+            Key::BootCount => "boot-count",
+            Key::GattLayout => "gatt-layout",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+
+    /// Whether the setting may be read and modified through the `/config` or `/config/ble`
+    /// resources
+    pub fn configurable(self) -> bool {
+        self.section().is_some()
+    }
+
+    /// Resource through which the setting is configured
+    ///
+    /// The idle level has none because it has its own resource (`/leds`), and the boot count and
+    /// GATT layout none because only the firmware changes them.
+    pub fn section(self) -> Option<Section> {
+        match self {
+            Key::IdleLevel | Key::BootCount | Key::GattLayout => None,
+            Key::Connectable | Key::MaxPeers | Key::OpenFrom | Key::OpenUntil => Some(Section::Ble),
+            _ => Some(Section::General),
+        }
+    }
+
+    /// Convert a number to the stored form, checking its range.
+    fn encode(self, number: i32) -> Result<Value, InvalidValue> {
+        let value = match self {
+            Key::IdleLevel => Value::from_slice(
+                &u8::try_from(number)
+                    .map_err(|_| InvalidValue)?
+                    .to_le_bytes(),
+            ),
+            Key::TemperatureOffset => Value::from_slice(
+                &i8::try_from(number)
+                    .map_err(|_| InvalidValue)?
+                    .to_le_bytes(),
+            ),
+            Key::AdvertisingInterval => {
+                // Range allowed for advertising intervals by the Bluetooth Core Specification
+                if !(20..=10240).contains(&number) {
+                    return Err(InvalidValue);
+                }
+                Value::from_slice(&(number as u16).to_le_bytes())
+            }
+            Key::LowPowerAdvertising | Key::Connectable => {
+                if !(0..=1).contains(&number) {
+                    return Err(InvalidValue);
+                }
+                Value::from_slice(&[number as u8])
+            }
+            Key::MaxPeers => Value::from_slice(
+                &u8::try_from(number)
+                    .map_err(|_| InvalidValue)?
+                    .to_le_bytes(),
+            ),
+            Key::OpenFrom | Key::OpenUntil => {
+                if !(0..MINUTES_PER_DAY).contains(&number) {
+                    return Err(InvalidValue);
+                }
+                Value::from_slice(&(number as u16).to_le_bytes())
+            }
+            Key::AdvertisingPolicy => {
+                AdvertisingPolicy::try_from(number).map_err(|_| InvalidValue)?;
+                Value::from_slice(&[number as u8])
+            }
+            Key::IdleTimeout | Key::BeaconAfter | Key::HandshakeTimeout | Key::GattLayout => {
+                Value::from_slice(
+                    &u16::try_from(number)
+                        .map_err(|_| InvalidValue)?
+                        .to_le_bytes(),
+                )
+            }
+            Key::BootCount => Value::from_slice(
+                &u32::try_from(number)
+                    .map_err(|_| InvalidValue)?
+                    .to_le_bytes(),
+            ),
+        };
+        Ok(value.expect("All values fit"))
+    }
+
+    /// Convert a stored value to a number.
+    ///
+    /// Values of unexpected length (which may be left over from a different firmware version)
+    /// are ignored.
+    fn decode(self, value: &[u8]) -> Option<i32> {
+        Some(match self {
+            Key::IdleLevel => u8::from_le_bytes(value.try_into().ok()?).into(),
+            Key::TemperatureOffset => i8::from_le_bytes(value.try_into().ok()?).into(),
+            Key::AdvertisingInterval
+            | Key::IdleTimeout
+            | Key::OpenFrom
+            | Key::OpenUntil
+            | Key::BeaconAfter
+            | Key::HandshakeTimeout
+            | Key::GattLayout => u16::from_le_bytes(value.try_into().ok()?).into(),
+            Key::LowPowerAdvertising
+            | Key::AdvertisingPolicy
+            | Key::Connectable
+            | Key::MaxPeers => u8::from_le_bytes(value.try_into().ok()?).into(),
+            // Counts beyond i32::MAX are not expected in the device's lifetime.
+            Key::BootCount => u32::from_le_bytes(value.try_into().ok()?) as i32,
+        })
+    }
+}
+
+struct Store {
+    values: [Option<Value>; KEYS],
+    /// Bit mask of keys changed since the last [take_changed]
+    changed: u16,
+}
+
+/// Initializer for [Store::values], as [Value] is not `Copy`
+const UNSET: Option<Value> = None;
+
+static STORE: critical_section::Mutex<RefCell<Store>> =
+    critical_section::Mutex::new(RefCell::new(Store {
+        values: [UNSET; KEYS],
+        changed: 0,
+    }));
+
+/// Signaled whenever a setting was changed
+pub static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Read the stored form of a setting.
+pub fn get(key: Key) -> Option<Value> {
+    critical_section::with(|cs| STORE.borrow_ref(cs).values[key as usize].clone())
+}
+
+fn get_number(key: Key) -> Option<i32> {
+    key.decode(&get(key)?)
+}
+
+/// Change a setting, and mark it for persisting if it actually changed.
+pub fn set(key: Key, number: i32) -> Result<(), InvalidValue> {
+    let value = key.encode(number)?;
+    let changed = critical_section::with(|cs| {
+        let mut store = STORE.borrow_ref_mut(cs);
+        let slot = &mut store.values[key as usize];
+        if slot.as_ref() == Some(&value) {
+            return false;
+        }
+        *slot = Some(value);
+        store.changed |= 1 << key as u16;
+        true
+    });
+    if changed {
+        CHANGED.signal(());
+    }
+    Ok(())
+}
+
+/// Check value for a persisted record of a setting
+///
+/// This is a CRC-8 over the key and the value, through which platforms that persist settings
+/// (the firmware's flash journal, and the provisioning tool that writes its initial image) detect
+/// corrupted records.
+pub fn record_check(key: Key, value: &[u8]) -> u8 {
+    const CRC: crc::Crc<u8> = crc::Crc::<u8>::new(&crc::CRC_8_SMBUS);
+    let mut digest = CRC.digest();
+    digest.update(&[key as u8]);
+    digest.update(value);
+    digest.finalize()
+}
+
+/// Set a setting from persisted data, without marking it as changed.
+pub fn restore(key: Key, value: &[u8]) {
+    let Ok(value) = Value::from_slice(value) else {
+        return;
+    };
+    critical_section::with(|cs| STORE.borrow_ref_mut(cs).values[key as usize] = Some(value));
+}
+
+/// Length of a persisted record's key, length and check value
+pub const RECORD_HEADER_LEN: usize = 3;
+
+/// Key byte of erased flash, which ends a sequence of persisted records
+pub const RECORDS_END: u8 = 0xff;
+
+/// Length of a persisted record with a value of the given length, including the padding to the 4
+/// bytes flash write granularity
+pub fn record_len(value_len: usize) -> usize {
+    (RECORD_HEADER_LEN + value_len + 3) / 4 * 4
+}
+
+/// Write a persisted record of a setting to the start of the buffer, returning its length.
+///
+/// Padding bytes are left as they are, so that they can stay erased.
+pub fn encode_record(key: Key, value: &[u8], buffer: &mut [u8]) -> usize {
+    buffer[0] = key as u8;
+    buffer[1] = value.len() as u8;
+    buffer[2] = record_check(key, value);
+    buffer[RECORD_HEADER_LEN..RECORD_HEADER_LEN + value.len()].copy_from_slice(value);
+    record_len(value.len())
+}
+
+/// [Restore](restore) the settings from a sequence of persisted records, returning the length of
+/// the records, or None if a corrupted record ended the replay.
+///
+/// Later records override earlier ones. Records of keys this firmware does not know are skipped,
+/// as they can't be checked. The records end at [RECORDS_END], or where the data ends.
+pub fn replay(records: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(&[key, len, check, _]) = records.get(offset..offset + 4) {
+        if key == RECORDS_END {
+            break;
+        }
+        let len = usize::from(len);
+        if len > MAX_VALUE_LEN || offset + record_len(len) > records.len() {
+            return None;
+        }
+        let value = &records[offset + RECORD_HEADER_LEN..offset + RECORD_HEADER_LEN + len];
+        if let Ok(key) = Key::try_from(key) {
+            if record_check(key, value) != check {
+                return None;
+            }
+            restore(key, value);
+        }
+        offset += record_len(len);
+    }
+    Some(offset)
+}
+
+/// Obtain the settings changed since the last call.
+pub fn take_changed() -> heapless::Vec<(Key, Value), KEYS> {
+    critical_section::with(|cs| {
+        let mut store = STORE.borrow_ref_mut(cs);
+        let changed = core::mem::take(&mut store.changed);
+        Key::ALL
+            .into_iter()
+            .filter(|key| changed & (1 << *key as u16) != 0)
+            .filter_map(|key| Some((key, store.values[key as usize].clone()?)))
+            .collect()
+    })
+}
+
+/// Obtain all settings that have a value.
+pub fn all() -> heapless::Vec<(Key, Value), KEYS> {
+    Key::ALL
+        .into_iter()
+        .filter_map(|key| Some((key, get(key)?)))
+        .collect()
+}
+
+pub fn idle_level() -> Option<u8> {
+    get_number(Key::IdleLevel).map(|level| level as u8)
+}
+
+pub fn set_idle_level(level: u8) {
+    set(Key::IdleLevel, level.into()).expect("All u8 are valid levels");
+}
+
+/// Number of times the firmware started, including the current start once [count_boot] ran
+pub fn boot_count() -> u32 {
+    get_number(Key::BootCount).map_or(0, |count| count as u32)
+}
+
+/// Count the current start of the firmware.
+///
+/// This is called once at startup, after the persisted settings were restored. Like any other
+/// change, the new count is persisted by the platform; that costs a journal record per start.
+pub fn count_boot() {
+    let count = boot_count().saturating_add(1).min(i32::MAX as u32);
+    set(Key::BootCount, count as i32).expect("Counts up to i32::MAX are valid");
+}
+
+/// Fingerprint of the GATT attribute table at the latest start, if one was recorded
+pub fn gatt_layout() -> Option<u16> {
+    get_number(Key::GattLayout).map(|fingerprint| fingerprint as u16)
+}
+
+pub fn set_gatt_layout(fingerprint: u16) {
+    set(Key::GattLayout, fingerprint.into()).expect("All u16 are valid fingerprints");
+}
+
+/// Calibration offset for temperature readings (zero if not set)
+pub fn temperature_offset() -> fixed::types::I30F2 {
+    fixed::types::I30F2::from_bits(get_number(Key::TemperatureOffset).unwrap_or(0))
+}
+
+/// Advertising interval in milliseconds, if one was configured
+pub fn advertising_interval() -> Option<u16> {
+    get_number(Key::AdvertisingInterval).map(|interval| interval as u16)
+}
+
+/// Whether the low-power advertising profile is used (off if not set)
+pub fn low_power_advertising() -> bool {
+    get_number(Key::LowPowerAdvertising) == Some(1)
+}
+
+/// How to advertise while the device is not ready to serve authorized requests, ie. before it has
+/// been provisioned with keys and before its clock was set
+///
+/// The numeric values are what is stored under [Key::AdvertisingPolicy].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub enum AdvertisingPolicy {
+    /// Advertise as usual; clients that connect get their tokens rejected.
+    Always = 0,
+    /// Stay connectable (eg. for setting the time), but leave the CoAP service out of the scan
+    /// data, so that clients looking for it do not find the device.
+    HideService = 1,
+    /// Only send non-connectable advertisements.
+    ///
+    /// As the clock is usually set through a connection, this only makes sense where it is set by
+    /// other means.
+    Refuse = 2,
+}
+
+impl TryFrom<i32> for AdvertisingPolicy {
+    type Error = InvalidValue;
+
+    fn try_from(value: i32) -> Result<Self, InvalidValue> {
+        Ok(match value {
+            0 => AdvertisingPolicy::Always,
+            1 => AdvertisingPolicy::HideService,
+            2 => AdvertisingPolicy::Refuse,
+            _ => return Err(InvalidValue),
+        })
+    }
+}
+
+/// How to advertise while the device is not ready ([AdvertisingPolicy::Always] if not set)
+pub fn advertising_policy() -> AdvertisingPolicy {
+    get_number(Key::AdvertisingPolicy)
+        .and_then(|number| number.try_into().ok())
+        .unwrap_or(AdvertisingPolicy::Always)
+}
+
+/// Time without ATT traffic after which a connection is closed, if any (5 minutes if not set)
+///
+/// This reclaims the connection slots of centrals that stay connected without doing anything.
+pub fn idle_timeout() -> Option<embassy_time::Duration> {
+    match get_number(Key::IdleTimeout).unwrap_or(300) {
+        0 => None,
+        seconds => Some(embassy_time::Duration::from_secs(seconds as u64)),
+    }
+}
+
+/// Time within which a peer needs to complete an EDHOC exchange, if any (30 seconds if not set)
+///
+/// Connections of peers that take longer are closed, so that initiators that stop halfway can't
+/// hold on to connection slots, nor start further exchanges through them. The state of the
+/// exchange itself is kept in coapcore's pool of security contexts, which offers no way to drop
+/// individual entries; [crate::security::revoke_all] drops all of them.
+pub fn handshake_timeout() -> Option<embassy_time::Duration> {
+    match get_number(Key::HandshakeTimeout).unwrap_or(30) {
+        0 => None,
+        seconds => Some(embassy_time::Duration::from_secs(seconds as u64)),
+    }
+}
+
+/// Time without connections after which the device only sends beacons, if any (never if not set)
+///
+/// In beacon mode, the device is not connectable, and sends telemetry at a slow interval for
+/// passive monitoring; pressing button 1 returns it to normal operation.
+pub fn beacon_after() -> Option<embassy_time::Duration> {
+    match get_number(Key::BeaconAfter).unwrap_or(0) {
+        0 => None,
+        minutes => Some(embassy_time::Duration::from_secs(minutes as u64 * 60)),
+    }
+}
+
+const MINUTES_PER_DAY: i32 = 24 * 60;
+
+/// Whether peers other than admins may connect now
+///
+/// That is the case unless connections were disabled through [Key::Connectable], or the current
+/// time is outside the daily window set through [Key::OpenFrom] and [Key::OpenUntil]. The window
+/// is only in effect if both ends are set and differ (it may span midnight), and while the clock
+/// is set.
+pub fn open_for_peers() -> bool {
+    if get_number(Key::Connectable) == Some(0) {
+        return false;
+    }
+    let (Some(from), Some(until), Ok(now)) = (
+        get_number(Key::OpenFrom),
+        get_number(Key::OpenUntil),
+        crate::devicetime::unixtime(),
+    ) else {
+        return true;
+    };
+    let minute = (now / 60 % MINUTES_PER_DAY as u32) as i32;
+    match from <= until {
+        true => (from..until).contains(&minute) || from == until,
+        false => minute >= from || minute < until,
+    }
+}
+
+/// Maximum number of concurrent connections of peers other than admins, if one was configured
+pub fn max_peers() -> Option<u8> {
+    get_number(Key::MaxPeers).map(|peers| peers as u8)
+}
+
+/// The configurable settings of a [Section], as shown in and modified through its resource
+///
+/// When encoded into CBOR, this is a map from the settings' names to their numeric values.
+#[derive(Default)]
+pub struct Config(heapless::Vec<(Key, i32), KEYS>);
+
+impl Config {
+    /// Collect the current values of all settings of a section.
+    pub fn current(section: Section) -> Self {
+        Self(
+            Key::ALL
+                .into_iter()
+                .filter(|key| key.section() == Some(section))
+                .filter_map(|key| Some((key, get_number(key)?)))
+                .collect(),
+        )
+    }
+
+    /// Apply all contained values, which need to be of the given section.
+    ///
+    /// Values are checked before any is set, so that either all or none are applied.
+    pub fn apply(&self, section: Section) -> Result<(), InvalidValue> {
+        for (key, number) in self.0.iter() {
+            if key.section() != Some(section) {
+                return Err(InvalidValue);
+            }
+            key.encode(*number)?;
+        }
+        for (key, number) in self.0.iter() {
+            set(*key, *number)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C> minicbor::encode::Encode<C> for Config {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(self.0.len() as u64)?;
+        for (key, number) in self.0.iter() {
+            e.str(key.name())?.i32(*number)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'b, C> minicbor::decode::Decode<'b, C> for Config {
+    fn decode(d: &mut minicbor::Decoder<'b>, _: &mut C) -> Result<Self, minicbor::decode::Error> {
+        use minicbor::decode::Error;
+
+        let entries = d
+            .map()?
+            .ok_or_else(|| Error::message("Indefinite length map"))?;
+        let mut config = Config::default();
+        for _ in 0..entries {
+            let key = Key::from_name(d.str()?)
+                .filter(|key| key.configurable())
+                .ok_or_else(|| Error::message("Unknown setting"))?;
+            config
+                .0
+                .push((key, d.i32()?))
+                .map_err(|_| Error::message("Too many settings"))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_range_checked() {
+        for (key, valid, invalid) in [
+            (Key::IdleLevel, [0, 255], [-1, 256]),
+            (Key::TemperatureOffset, [-128, 127], [-129, 128]),
+            (Key::AdvertisingInterval, [20, 10240], [19, 10241]),
+            (Key::LowPowerAdvertising, [0, 1], [-1, 2]),
+            (Key::AdvertisingPolicy, [0, 2], [-1, 3]),
+            (Key::Connectable, [0, 1], [-1, 2]),
+            (Key::OpenFrom, [0, 1439], [-1, 1440]),
+            (Key::OpenUntil, [0, 1439], [-1, 1440]),
+            (Key::IdleTimeout, [0, 65535], [-1, 65536]),
+            (Key::BootCount, [0, i32::MAX], [-1, i32::MIN]),
+        ] {
+            for number in valid {
+                let value = key.encode(number).unwrap();
+                assert_eq!(key.decode(&value), Some(number), "{key:?}");
+            }
+            for number in invalid {
+                assert!(key.encode(number).is_err(), "{key:?} {number}");
+            }
+        }
+        // Left over from a firmware version that stored a different type
+        assert_eq!(Key::AdvertisingInterval.decode(&[100]), None);
+    }
+
+    #[test]
+    fn config_is_applied_entirely_or_not_at_all() {
+        let before = get(Key::AdvertisingInterval);
+        let partly_invalid = Config(
+            [
+                (Key::AdvertisingInterval, 100),
+                (Key::TemperatureOffset, 1000),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert!(partly_invalid.apply(Section::General).is_err());
+        assert_eq!(get(Key::AdvertisingInterval), before);
+
+        let other_section = Config(
+            [(Key::AdvertisingInterval, 100), (Key::Connectable, 1)]
+                .into_iter()
+                .collect(),
+        );
+        assert!(other_section.apply(Section::General).is_err());
+        assert_eq!(get(Key::AdvertisingInterval), before);
+
+        let valid = Config(
+            [
+                (Key::AdvertisingInterval, 100),
+                (Key::TemperatureOffset, -4),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        valid.apply(Section::General).unwrap();
+        assert_eq!(advertising_interval(), Some(100));
+        assert_eq!(temperature_offset(), fixed::types::I30F2::from_num(-1));
+    }
+
+    #[test]
+    fn record_check_covers_key_and_value() {
+        let check = record_check(Key::IdleLevel, &[3]);
+        assert_ne!(record_check(Key::IdleLevel, &[4]), check);
+        assert_ne!(record_check(Key::MaxPeers, &[3]), check);
+        assert_ne!(record_check(Key::IdleLevel, &[3, 0]), check);
+    }
+
+    /// Records as they are found in flash, followed by erased space
+    fn records(records: &[(Key, &[u8])]) -> [u8; 64] {
+        let mut buffer = [RECORDS_END; 64];
+        let mut offset = 0;
+        for (key, value) in records {
+            offset += encode_record(*key, value, &mut buffer[offset..]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn replay_restores_latest_records() {
+        let buffer = records(&[
+            (Key::IdleLevel, &[3]),
+            (Key::GattLayout, &[0x34, 0x12]),
+            (Key::IdleLevel, &[5]),
+        ]);
+        assert_eq!(replay(&buffer), Some(2 * record_len(1) + record_len(2)));
+        assert_eq!(idle_level(), Some(5));
+        assert_eq!(gatt_layout(), Some(0x1234));
+
+        // Compaction writes every setting once, which replays to the same values.
+        let compacted = records(&[
+            (Key::IdleLevel, &get(Key::IdleLevel).unwrap()),
+            (Key::GattLayout, &get(Key::GattLayout).unwrap()),
+        ]);
+        assert_eq!(replay(&compacted), Some(record_len(1) + record_len(2)));
+        assert_eq!(idle_level(), Some(5));
+        assert_eq!(gatt_layout(), Some(0x1234));
+    }
+
+    #[test]
+    fn replay_skips_unknown_keys() {
+        let mut buffer = records(&[(Key::BeaconAfter, &[10, 0])]);
+        buffer.copy_within(..record_len(2), record_len(1));
+        buffer[..record_len(1)].copy_from_slice(&[0x80, 1, 0, 0]);
+        assert_eq!(replay(&buffer), Some(record_len(1) + record_len(2)));
+        assert_eq!(get_number(Key::BeaconAfter), Some(10));
+    }
+
+    #[test]
+    fn replay_stops_at_corrupted_record() {
+        let mut buffer = records(&[
+            (Key::HandshakeTimeout, &[20, 0]),
+            (Key::HandshakeTimeout, &[40, 0]),
+            (Key::HandshakeTimeout, &[60, 0]),
+        ]);
+        buffer[record_len(2) + RECORD_HEADER_LEN] ^= 1;
+        assert_eq!(replay(&buffer), None);
+        assert_eq!(get_number(Key::HandshakeTimeout), Some(20));
+
+        // A record that extends beyond the page
+        let buffer = records(&[(Key::IdleTimeout, &[20, 0])]);
+        assert_eq!(replay(&buffer[..record_len(2) - 1]), None);
+        assert_eq!(get(Key::IdleTimeout), None);
+    }
+
+    #[test]
+    fn window_spans_midnight() {
+        const MIDNIGHT: u32 = 19675 * 24 * 60 * 60;
+        let open_at = |minute: u32| {
+            crate::devicetime::set_unixtime(MIDNIGHT + minute * 60 + 30).unwrap();
+            open_for_peers()
+        };
+
+        set(Key::OpenFrom, 23 * 60).unwrap();
+        set(Key::OpenUntil, 60).unwrap();
+        assert!(open_at(23 * 60 + 30));
+        assert!(open_at(30));
+        assert!(!open_at(60));
+        assert!(!open_at(12 * 60));
+
+        set(Key::OpenFrom, 60).unwrap();
+        set(Key::OpenUntil, 23 * 60).unwrap();
+        assert!(!open_at(23 * 60 + 30));
+        assert!(open_at(12 * 60));
+
+        set(Key::Connectable, 0).unwrap();
+        assert!(!open_at(12 * 60));
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Interactive debug shell on the RTT down channel
+//!
+//! This is only available with the `debug-shell` feature, in which RTT is set up by rtt-target
+//! (rather than defmt-rtt) to provide a down channel next to the defmt output. Commands are typed
+//! line-wise into the "Shell" down channel (eg. using `probe-rs attach` or any RTT capable
+//! terminal); their output goes through defmt.
+//!
+//! Available commands:
+//!
+//! * `help`: List commands.
+//! * `conns`: List active BLE connections, with what was [read from the centrals](crate::peer).
+//! * `disconnect N`: Terminate the BLE connection in slot N.
+//! * `leds N`: Set the idle LED level.
+//! * `heap`: Show heap usage.
+//!
+//! The token pool is not available for inspection: it is kept inside the coapcore resource
+//! server, which does not provide any introspection.
+
+use coap_ace_poc_firmware::info;
+use coap_ace_poc_firmware::platform::LedControl;
+
+/// Maximum length of a command line; longer lines are discarded.
+const MAX_LINE: usize = 32;
+
+/// Set up RTT with the defmt up channel and the shell's down channel.
+///
+/// This needs to run before any logging happens.
+pub fn init() -> rtt_target::DownChannel {
+    let channels = rtt_target::rtt_init! {
+        up: {
+            0: {
+                size: 1024,
+                name: "defmt"
+            }
+        }
+        down: {
+            0: {
+                size: 64,
+                name: "Shell"
+            }
+        }
+    };
+    rtt_target::set_defmt_channel(channels.up.0);
+    channels.down.0
+}
+
+/// Task polling the down channel for commands
+#[embassy_executor::task]
+pub async fn shell(mut input: rtt_target::DownChannel, leds: &'static crate::blink::Leds) {
+    let mut line = heapless::Vec::<u8, MAX_LINE>::new();
+    let mut overflowed = false;
+    loop {
+        let mut buf = [0; 16];
+        let count = input.read(&mut buf);
+        if count == 0 {
+            embassy_time::Timer::after(embassy_time::Duration::from_millis(100)).await;
+            continue;
+        }
+        for byte in &buf[..count] {
+            match byte {
+                b'\r' | b'\n' => {
+                    if overflowed {
+                        info!("Line too long, ignored");
+                    } else if let Ok(line) = core::str::from_utf8(&line) {
+                        run(line.trim(), leds);
+                    }
+                    line.clear();
+                    overflowed = false;
+                }
+                b => {
+                    if line.push(*b).is_err() {
+                        overflowed = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Execute a single command line
+fn run(line: &str, leds: &'static crate::blink::Leds) {
+    let mut words = line.split_ascii_whitespace();
+    let command = words.next();
+    let argument = words.next().map(|a| a.parse::<u8>());
+    match (command, argument) {
+        (None, _) => (),
+        (Some("help"), None) => {
+            info!("Commands: help, conns, disconnect N, leds N, heap");
+        }
+        (Some("conns"), None) => {
+            crate::connections::for_each(|index, conn| {
+                info!(
+                    "Slot {}: {}, {:?}",
+                    index,
+                    conn.peer_address(),
+                    crate::peer::get(index)
+                );
+            });
+        }
+        (Some("disconnect"), Some(Ok(index))) => {
+            match crate::connections::disconnect(index.into()) {
+                Ok(()) => info!("Disconnecting slot {}", index),
+                Err(e) => info!("Can not disconnect: {}", e),
+            }
+        }
+        (Some("leds"), Some(Ok(level))) => {
+            leds.set_idle(level);
+        }
+        (Some("heap"), None) => {
+            let (used, free) = crate::alloc::stats();
+            info!("Heap: {} bytes used, {} bytes free", used, free);
+        }
+        _ => {
+            info!("Unknown command or bad arguments; try `help`.");
+        }
+    }
+}
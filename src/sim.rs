@@ -0,0 +1,317 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Host-side simulation of the device
+//!
+//! This replaces the hardware parts (softdevice temperature readout, LEDs, randomness) with mock
+//! implementations, and lets requests be passed into the CoAP-over-GATT layer as plain byte
+//! buffers, just as they would arrive through characteristic writes.
+//!
+//! The simulated [Device] runs the very same handler as the firmware, so any request that
+//! succeeds here exercises the full ACE / OSCORE / EDHOC and resource handling path.
+//!
+//! Time is provided by embassy-time's std driver; as on the device, [crate::devicetime] needs to
+//! be set before any time dependent operation succeeds.
+//!
+//! Sessions recorded on a real device (see [crate::trace]) are played back with [replay].
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use crate::platform::{IdentifyParameters, LedControl, SensorUnavailable, Status, Thermometer};
+use crate::{build_main_rs, coap_gatt, CoapcoreConfig, Rs};
+
+/// Sink for defmt output of the simulated device
+///
+/// defmt's binary format can only be decoded with the ELF file's symbol table at hand, so rather
+/// than printing garbage, all output is discarded. This only affects the dependencies: The
+/// library's own messages go to the [log] crate (see [crate::logging]).
+#[defmt::global_logger]
+struct DiscardingLogger;
+
+unsafe impl defmt::Logger for DiscardingLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+/// A thermometer whose value is set by the test
+pub struct SimThermometer(Cell<Option<fixed::types::I30F2>>);
+
+impl SimThermometer {
+    /// Set the temperature that will be read henceforth; `None` simulates a failing sensor.
+    pub fn set(&self, value: Option<fixed::types::I30F2>) {
+        self.0.set(value);
+    }
+}
+
+impl Default for SimThermometer {
+    fn default() -> Self {
+        Self(Cell::new(Some(fixed::types::I30F2::from_num(21.25))))
+    }
+}
+
+impl Thermometer for SimThermometer {
+    fn temperature(&self) -> Result<fixed::types::I30F2, SensorUnavailable> {
+        self.0.get().ok_or(SensorUnavailable)
+    }
+}
+
+/// LEDs that only record what they were asked to do
+#[derive(Default)]
+pub struct SimLeds {
+    idle_state: Cell<u8>,
+    identify_count: Cell<usize>,
+    cancel_count: Cell<usize>,
+    failure_shown: Cell<bool>,
+    last_status: Cell<Option<Status>>,
+}
+
+impl SimLeds {
+    /// Number of times the identify animation was started
+    pub fn identify_count(&self) -> usize {
+        self.identify_count.get()
+    }
+
+    /// Number of times a running identify animation was canceled
+    pub fn cancel_count(&self) -> usize {
+        self.cancel_count.get()
+    }
+
+    /// Whether the failure pattern was ever requested
+    pub fn failure_shown(&self) -> bool {
+        self.failure_shown.get()
+    }
+
+    /// The status that was shown most recently
+    pub fn last_status(&self) -> Option<Status> {
+        self.last_status.get()
+    }
+}
+
+impl LedControl for SimLeds {
+    fn set_idle(&self, level: u8) {
+        self.idle_state.set(level);
+    }
+
+    fn idle(&self) -> u8 {
+        self.idle_state.get()
+    }
+
+    fn run_identify(&'static self, _parameters: IdentifyParameters) {
+        self.identify_count.set(self.identify_count.get() + 1);
+    }
+
+    fn cancel_identify(&self) {
+        self.cancel_count.set(self.cancel_count.get() + 1);
+    }
+
+    fn show_failure(&'static self) {
+        self.failure_shown.set(true);
+    }
+
+    fn show_status(&'static self, status: Status) {
+        self.last_status.set(Some(status));
+    }
+}
+
+/// State of the [SimRandomness] generator
+///
+/// This is global (rather than inside the generator) so that copies of the generator, as they are
+/// handed out for each EDHOC session, do not repeat each other's output.
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+/// Deterministic stand-in for the softdevice's random number generator
+///
+/// This is a plain xorshift generator. It is marked as [rand_core::CryptoRng] only so that it can
+/// be used where the device uses its hardware RNG; it is in no way suitable for anything but
+/// simulation.
+#[derive(Copy, Clone)]
+pub struct SimRandomness;
+
+impl rand_core::RngCore for SimRandomness {
+    fn next_u64(&mut self) -> u64 {
+        let mut x = RANDOM_STATE.load(Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        RANDOM_STATE.store(x, Relaxed);
+        x
+    }
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl rand_core::CryptoRng for SimRandomness {}
+
+/// The parts of a [coap_gatt::Connection] used by the simulation
+///
+/// The type of the [handler](build_main_rs) can not be named, so connections are kept behind this
+/// trait.
+trait GattConnection {
+    fn write(&mut self, written: &mut [u8]) -> Vec<u8>;
+    fn take_status(&mut self) -> Option<Status>;
+    fn take_attempt(&mut self) -> Option<crate::lockout::Attempt>;
+}
+
+impl<H: coap_handler::Handler + crate::security::Previous> GattConnection
+    for coap_gatt::Connection<'_, H>
+{
+    fn write(&mut self, written: &mut [u8]) -> Vec<u8> {
+        coap_gatt::Connection::write(self, written).to_vec()
+    }
+    fn take_status(&mut self) -> Option<Status> {
+        coap_gatt::Connection::take_status(self)
+    }
+    fn take_attempt(&mut self) -> Option<crate::lockout::Attempt> {
+        coap_gatt::Connection::take_attempt(self)
+    }
+}
+
+/// A simulated device, consisting of the mock hardware and the resource server
+///
+/// All parts are leaked to obtain the `'static` references the firmware works with; that is
+/// acceptable in tests, which only create a handful of devices.
+pub struct Device {
+    pub thermometer: &'static SimThermometer,
+    pub leds: &'static SimLeds,
+    /// Creates connections to the resource server
+    connect: Box<dyn Fn() -> Box<dyn GattConnection>>,
+}
+
+impl Device {
+    pub fn new(coapcore_config: CoapcoreConfig) -> Self {
+        let coapcore_config: &'static CoapcoreConfig = Box::leak(Box::new(coapcore_config));
+        let thermometer: &'static SimThermometer = Box::leak(Box::default());
+        let leds: &'static SimLeds = Box::leak(Box::default());
+        let resources = move || {
+            crate::coap::create_coap_handler(coapcore_config, thermometer, leds, SimRandomness)
+        };
+        let handler = build_main_rs(coapcore_config, resources, SimRandomness);
+        let rs: &'static Rs<_> = Box::leak(Box::new(Rs::new(handler)));
+        Self {
+            thermometer,
+            leds,
+            connect: Box::new(move || {
+                Box::new(coap_gatt::Connection::new(rs)) as Box<dyn GattConnection>
+            }),
+        }
+    }
+
+    /// Create a device with the same configuration that the firmware is built with (i.e., the
+    /// file indicated in `RS_AS_ASSOCIATION`).
+    pub fn from_build_config() -> Self {
+        Self::new(include!(concat!(env!("OUT_DIR"), "/rs_as_association.rs")))
+    }
+
+    /// Create a new CoAP-over-GATT connection, as it would be created when a central connects.
+    pub fn connect(&self) -> Connection {
+        self.connect_from([0; 6])
+    }
+
+    /// Create a new CoAP-over-GATT connection from a central with the given address.
+    ///
+    /// The address only matters to [crate::lockout]; as its counters are global, tests that look
+    /// at them use addresses of their own.
+    pub fn connect_from(&self, address: crate::lockout::Address) -> Connection {
+        Connection {
+            inner: (self.connect)(),
+            leds: self.leds,
+            address,
+            delay: embassy_time::Duration::from_ticks(0),
+        }
+    }
+}
+
+/// A difference between a recorded session and its replay
+#[derive(Debug)]
+pub struct Divergence {
+    /// Line of the trace (counting from 1) whose response differs
+    pub line: usize,
+    pub recorded: Vec<u8>,
+    pub replayed: Vec<u8>,
+}
+
+/// Replay a trace recorded through [crate::trace] against a fresh device with the build
+/// configuration.
+///
+/// Every write is passed into the connection of its slot, and the response is compared with the
+/// value recorded next for that slot by response code (see [crate::trace] for why only that).
+/// Returns all divergences; an empty result means the replay went as recorded.
+pub fn replay(trace: &str) -> Vec<Divergence> {
+    let device = Device::from_build_config();
+    let mut connections: std::collections::HashMap<usize, Connection> = Default::default();
+    let mut responses: std::collections::HashMap<usize, Vec<u8>> = Default::default();
+    let mut divergences = vec![];
+    for (number, line) in trace.lines().enumerate() {
+        let Some(recorded) = crate::trace::parse_line(line) else {
+            continue;
+        };
+        match recorded.value {
+            None => {
+                connections.insert(recorded.slot, device.connect());
+                responses.remove(&recorded.slot);
+            }
+            Some((true, request)) => {
+                let connection = connections
+                    .entry(recorded.slot)
+                    .or_insert_with(|| device.connect());
+                responses.insert(recorded.slot, connection.exchange(&request));
+            }
+            Some((false, response)) => {
+                let replayed = responses.remove(&recorded.slot).unwrap_or_default();
+                if replayed.first() != response.first() {
+                    divergences.push(Divergence {
+                        line: number + 1,
+                        recorded: response,
+                        replayed,
+                    });
+                }
+            }
+        }
+    }
+    divergences
+}
+
+/// A simulated CoAP-over-GATT connection
+pub struct Connection {
+    inner: Box<dyn GattConnection>,
+    leds: &'static SimLeds,
+    address: crate::lockout::Address,
+    delay: embassy_time::Duration,
+}
+
+impl Connection {
+    /// Simulate a characteristic write of `request`, and return what a subsequent characteristic
+    /// read (or indication) would produce.
+    ///
+    /// Like the firmware, this forwards any status change to the device's LEDs, and records
+    /// authentication attempts in [crate::lockout]. The response is not actually held back; see
+    /// [Self::delay] for how long the firmware would.
+    pub fn exchange(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut written = request.to_vec();
+        let response = self.inner.write(&mut written);
+        if let Some(status) = self.inner.take_status() {
+            self.leds.show_status(status);
+        }
+        self.delay = match self.inner.take_attempt() {
+            Some(attempt) => crate::lockout::record(self.address, attempt),
+            None => embassy_time::Duration::from_ticks(0),
+        };
+        response
+    }
+
+    /// How long the firmware would have held back the latest response
+    pub fn delay(&self) -> embassy_time::Duration {
+        self.delay
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Per-resource request statistics
+//!
+//! Resources are counted in two places, because authorization happens between them:
+//!
+//! * Each resource registered in [crate::coap] is wrapped in a [Metered] handler, which sees the
+//!   requests that made it through authorization. It counts them, and the errors the resource
+//!   responds with. (As the resource's errors are only given a code when they are rendered, this
+//!   does not tell client from server errors).
+//!
+//! * Requests that the resource server rejects (with 4.01 Unauthorized or 4.03 Forbidden) never
+//!   reach the resource. They are counted by [crate::coap_gatt] through [record_rejection], which
+//!   can see their path as long as the request was not OSCORE protected. Rejections of protected
+//!   requests thus go uncounted.
+//!
+//! The counters are shown in the `/stats/resources` resource.
+
+use core::cell::RefCell;
+
+use coap_handler::{Handler, Reporting};
+use coap_message::{
+    MessageOption, MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
+};
+
+/// Number of resources that can be tracked
+const MAX_RESOURCES: usize = 32;
+
+#[derive(Copy, Clone, Default)]
+struct Counters {
+    requests: u32,
+    rejected: u32,
+    errors: u32,
+}
+
+struct Resource {
+    /// Path segments joined by slashes
+    path: &'static str,
+    counters: Counters,
+}
+
+static RESOURCES: critical_section::Mutex<RefCell<heapless::Vec<Resource, MAX_RESOURCES>>> =
+    critical_section::Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Find the index of a registered path, registering it if it is new.
+///
+/// Returns None if there is no space left, in which case the resource is not counted.
+fn register(path: &'static str) -> Option<usize> {
+    critical_section::with(|cs| {
+        let mut resources = RESOURCES.borrow_ref_mut(cs);
+        if let Some(index) = resources.iter().position(|r| r.path == path) {
+            return Some(index);
+        }
+        resources
+            .push(Resource {
+                path,
+                counters: Counters::default(),
+            })
+            .ok()?;
+        Some(resources.len() - 1)
+    })
+}
+
+fn count(index: Option<usize>, f: impl FnOnce(&mut Counters)) {
+    let Some(index) = index else {
+        return;
+    };
+    critical_section::with(|cs| f(&mut RESOURCES.borrow_ref_mut(cs)[index].counters));
+}
+
+/// Find the registered resource a request is addressed to, judging from its Uri-Path options.
+pub fn lookup<M: ReadableMessage>(request: &M) -> Option<usize> {
+    critical_section::with(|cs| {
+        RESOURCES.borrow_ref(cs).iter().position(|resource| {
+            let mut registered = resource.path.split('/').map(str::as_bytes);
+            let mut requested = request
+                .options()
+                .filter(|o| o.number() == coap_numbers::option::URI_PATH);
+            loop {
+                match (registered.next(), requested.next()) {
+                    (None, None) => break true,
+                    (Some(r), Some(o)) if r == o.value() => continue,
+                    _ => break false,
+                }
+            }
+        })
+    })
+}
+
+/// Count a rejection by the resource server of a request to the resource found through [lookup].
+pub fn record_rejection(index: usize) {
+    count(Some(index), |c| c.rejected += 1);
+}
+
+/// A handler wrapper that counts requests and errors of the wrapped resource
+///
+/// It also passes the state-changing requests the resource accepted on to [crate::audit].
+pub struct Metered<H> {
+    index: Option<usize>,
+    path: &'static str,
+    /// Code of the request being processed, if it is to be recorded in [crate::audit]
+    audited: Option<u8>,
+    inner: H,
+}
+
+impl<H> Metered<H> {
+    /// Wrap a handler, counting it under `path` (its path segments joined with slashes).
+    pub fn new(path: &'static str, inner: H) -> Self {
+        Self {
+            index: register(path),
+            path,
+            audited: None,
+            inner,
+        }
+    }
+}
+
+impl<H: Handler> Handler for Metered<H> {
+    type RequestData = H::RequestData;
+    type ExtractRequestError = H::ExtractRequestError;
+    type BuildResponseError<M: MinimalWritableMessage> = H::BuildResponseError<M>;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let extracted = self.inner.extract_request_data(request);
+        let method = request.code().into();
+        self.audited = (extracted.is_ok() && crate::audit::changes_state(method)).then_some(method);
+        count(self.index, |c| {
+            c.requests += 1;
+            c.errors += extracted.is_err() as u32;
+        });
+        extracted
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        self.inner.estimate_length(request)
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let built = self.inner.build_response(response, request);
+        if built.is_err() {
+            count(self.index, |c| c.errors += 1);
+        }
+        if let (Some(method), true) = (self.audited.take(), built.is_ok()) {
+            crate::audit::record(method, self.path);
+        }
+        built
+    }
+}
+
+impl<H: Reporting> Reporting for Metered<H> {
+    type Record<'a>
+        = H::Record<'a>
+    where
+        Self: 'a;
+    type Reporter<'a>
+        = H::Reporter<'a>
+    where
+        Self: 'a;
+
+    fn report(&self) -> Self::Reporter<'_> {
+        self.inner.report()
+    }
+}
+
+/// Copy of the counters of all resources
+///
+/// When encoded into CBOR, this is a map from the resources' paths to arrays of the number of
+/// requests, rejections and errors.
+pub struct Report(heapless::Vec<(&'static str, Counters), MAX_RESOURCES>);
+
+/// Obtain a copy of the current counters.
+pub fn report() -> Report {
+    critical_section::with(|cs| {
+        Report(
+            RESOURCES
+                .borrow_ref(cs)
+                .iter()
+                .map(|r| (r.path, r.counters))
+                .collect(),
+        )
+    })
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(self.0.len() as u64)?;
+        for (path, counters) in self.0.iter() {
+            e.str(path)?
+                .array(3)?
+                .u32(counters.requests)?
+                .u32(counters.rejected)?
+                .u32(counters.errors)?;
+        }
+        Ok(())
+    }
+}
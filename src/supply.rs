@@ -0,0 +1,41 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Measurement of the supply voltage through the SAADC
+//!
+//! The measurements feed [coap_ace_poc_firmware::battery], which decides on power saving.
+
+use coap_ace_poc_firmware::battery;
+
+/// Time between measurements
+const INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
+embassy_nrf::bind_interrupts!(struct Irqs {
+    SAADC => embassy_nrf::saadc::InterruptHandler;
+});
+
+/// Task measuring the supply voltage periodically, and returning the LEDs to their idle level
+/// whenever power saving starts or ends
+#[embassy_executor::task]
+pub async fn run(saadc: embassy_nrf::peripherals::SAADC, leds: &'static crate::blink::Leds) {
+    use embassy_nrf::interrupt::InterruptExt;
+    use embassy_nrf::saadc;
+
+    // Differing from default, this stays out of the softdevice's hair
+    embassy_nrf::interrupt::SAADC.set_priority(embassy_nrf::interrupt::Priority::P7);
+
+    // The defaults (internal 0.6V reference, gain 1/6, 12 bit) cover 0 to 3.6V.
+    let channel = saadc::ChannelConfig::single_ended(saadc::VddInput);
+    let mut saadc = saadc::Saadc::new(saadc, Irqs, saadc::Config::default(), [channel]);
+    saadc.calibrate().await;
+
+    loop {
+        let mut sample = [0];
+        saadc.sample(&mut sample).await;
+        let millivolts = (i32::from(sample[0]).max(0) * 3600 / 4096) as u16;
+        if battery::measured(millivolts) {
+            leds.show_idle();
+        }
+        embassy_time::Timer::after(INTERVAL).await;
+    }
+}
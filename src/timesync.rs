@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Authenticated setting of the clock through the AS
+//!
+//! The `/time` resource lets anyone set the clock, which is convenient for the demo, but allows
+//! anyone in radio range to make expired tokens valid again. This module offers a way to set the
+//! clock from the AS instead:
+//!
+//! 1. A client fetches a fresh random nonce from the device (a GET to `/time/sync`, see
+//!    [challenge]).
+//! 2. It passes the nonce to the AS, which responds with a COSE_Encrypt0 whose plaintext is the
+//!    current time (a CBOR unsigned integer of seconds since the UNIX epoch), encrypted with the
+//!    key the device shares with the AS (AES-CCM-16-128-256, as for tokens), with the nonce as
+//!    external AAD.
+//! 3. The client sends that to the device (a PUT to `/time/sync`, see [answer]), which sets the
+//!    clock if it decrypts with a nonce it issued.
+//!
+//! The device needs no uplink for this, as the client carries the messages; the client can not
+//! forge or replay them, as it knows neither the key nor (ahead of time) the nonce. Each nonce is
+//! only accepted once, and only within [NONCE_LIFETIME]. The delay between the AS producing the
+//! time and the device receiving it is not compensated for; it is small compared to token
+//! lifetimes.
+//!
+//! As `/time/sync` is open to anyone, the latest [MAX_NONCES] nonces are kept, and a nonce is
+//! only used up by an answer that verifies: Other peers can neither spoil a pending
+//! synchronization by sending made-up answers, nor by fetching a single nonce of their own.
+//!
+//! Devices that only have the AS's public key can not use this.
+
+use core::cell::RefCell;
+
+/// Length of the nonces
+pub const NONCE_LEN: usize = 16;
+
+/// Time within which a nonce needs to be answered
+const NONCE_LIFETIME: embassy_time::Duration = embassy_time::Duration::from_secs(60);
+
+/// Number of nonces that can be pending at the same time
+pub const MAX_NONCES: usize = 4;
+
+/// The nonces handed out latest, oldest first, along with when they were
+static NONCES: critical_section::Mutex<
+    RefCell<heapless::Vec<([u8; NONCE_LEN], embassy_time::Instant), MAX_NONCES>>,
+> = critical_section::Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Error type indicating that an answer was not accepted
+///
+/// Reasons are not told apart, as a peer that sends a wrong answer can't do anything about it
+/// but to start over with a new challenge.
+#[derive(Debug)]
+pub struct Rejected;
+
+/// Create a new nonce, replacing the oldest pending one if [MAX_NONCES] are pending.
+pub fn challenge(rng: &mut impl rand_core::RngCore) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+    critical_section::with(|cs| {
+        let mut nonces = NONCES.borrow_ref_mut(cs);
+        if nonces.is_full() {
+            nonces.remove(0);
+        }
+        // Can't fail: we just made room
+        let _ = nonces.push((nonce, embassy_time::Instant::now()));
+    });
+    nonce
+}
+
+/// Process the AS's response to any pending nonce, and set the clock from it.
+///
+/// The nonce is used up by this if the answer decrypts with it; other answers leave all nonces
+/// pending.
+pub fn answer(message: &[u8]) -> Result<u32, Rejected> {
+    use coset::{CborSerializable, TaggedCborSerializable};
+
+    let encrypted = coset::CoseEncrypt0::from_slice(message)
+        .or_else(|_| coset::CoseEncrypt0::from_tagged_slice(message))
+        .map_err(|_| Rejected)?;
+    let pending: heapless::Vec<_, MAX_NONCES> = critical_section::with(|cs| {
+        let mut nonces = NONCES.borrow_ref_mut(cs);
+        nonces.retain(|(_, issued)| issued.elapsed() <= NONCE_LIFETIME);
+        nonces.iter().map(|(nonce, _)| *nonce).collect()
+    });
+    let (nonce, plaintext) = pending
+        .iter()
+        .find_map(|nonce| Some((nonce, crate::tokens::decrypt(&encrypted, nonce)?)))
+        .ok_or_else(|| {
+            crate::info!("Time sync answer does not decrypt with any pending nonce");
+            Rejected
+        })?;
+    critical_section::with(|cs| {
+        NONCES
+            .borrow_ref_mut(cs)
+            .retain(|(pending, _)| pending != nonce)
+    });
+
+    let mut decoder = minicbor::Decoder::new(&plaintext);
+    let now = decoder.u32().map_err(|_| Rejected)?;
+    if decoder.position() != plaintext.len() {
+        return Err(Rejected);
+    }
+
+    crate::devicetime::set_unixtime(now).map_err(|_| {
+        crate::info!("Time sync answer is out of range");
+        Rejected
+    })?;
+    crate::info!("Clock set to {} through the AS", now);
+    Ok(now)
+}
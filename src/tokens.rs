@@ -0,0 +1,556 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Record of the tokens the resource server accepted
+//!
+//! coapcore keeps its token pool to itself, so this keeps a shadow copy of the claims relevant to
+//! troubleshooting: [crate::coap_gatt] passes every token POSTed to `/authz-info` that was
+//! accepted to [record], which decodes it once more (decrypting it with the AS key if needed). The
+//! most recent ones are shown in the `/debug/claims` resource.
+//!
+//! Along with the claims, the requests made with each token are counted: [crate::coap_gatt]
+//! attributes every OSCORE protected request that succeeds to the token posted latest through the
+//! same connection, and passes it to [count_use]. As the resource server does not tell which
+//! token a security context is bound to, this is a guess; it is right for clients that post their
+//! token and run EDHOC on the connection they use, which is what the demo clients do.
+//!
+//! As this is a shadow, it may disagree with the resource server in corner cases: Tokens sent
+//! inside EDHOC messages are not seen here, and the resource server may evict tokens before they
+//! expire (whereas here they are only evicted by newer ones, when they expire, or when the
+//! resource server is [rebuilt](crate::security::Reloading) without them).
+//!
+//! When the record is full, the token that expires soonest is evicted to make space (see
+//! [record]), as that is the one whose loss costs its client least. That policy only applies to
+//! this record: coapcore evicts from its own pool by its own rules and does not report it, so a
+//! client whose token was evicted there gets the same 4.01 Unauthorized as one that never posted a
+//! token, and can not be told specifically to post its token again.
+//!
+//! Before a token reaches the resource server, [retarget] adapts symmetric tokens issued for
+//! several audiences or for a group the device belongs to, as the resource server only accepts
+//! the device's own audience.
+
+extern crate alloc;
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+use coset::{CborSerializable, TaggedCborSerializable};
+use zeroize::Zeroizing;
+
+/// Number of tokens that are remembered
+const MAX_TOKENS: usize = 4;
+
+/// Subject claim of a token, as far as it is kept
+pub type Subject = heapless::String<16>;
+
+/// The claims of an accepted token
+#[derive(Clone)]
+struct Claims {
+    /// Identifies the token in [Recorded]
+    id: u32,
+    audience: heapless::String<16>,
+    /// The subject claim, if present and short enough
+    subject: Option<Subject>,
+    /// The scope claim's (AIF) encoded value
+    scope: heapless::Vec<u8, { crate::rs_configuration::MAX_SCOPE_LEN }>,
+    exp: u32,
+    /// Number of requests attributed to the token
+    requests: u32,
+    /// Time of the latest request attributed to the token (in seconds since the UNIX epoch), if
+    /// the clock was set then
+    last_used: Option<u32>,
+}
+
+/// Handle to a recorded token, through which requests are attributed to it in [count_use]
+#[derive(Copy, Clone)]
+pub struct Recorded {
+    id: u32,
+    /// Whether the token grants administrative access
+    pub admin: bool,
+}
+
+static TOKENS: critical_section::Mutex<RefCell<heapless::Vec<Claims, MAX_TOKENS>>> =
+    critical_section::Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// Number of recorded tokens that were dropped because they expired
+static EXPIRED: AtomicU32 = AtomicU32::new(0);
+
+/// Number of recorded tokens that were dropped to make space for newer ones
+static EVICTED: AtomicU32 = AtomicU32::new(0);
+
+/// Identifier for the next recorded token
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Find the access token in the payload of a POST to `/authz-info`.
+///
+/// That is either the token itself, or (in the ACE OSCORE profile) a CBOR map that contains the
+/// token under the `access_token` (1) key.
+fn access_token(payload: &[u8]) -> Option<&[u8]> {
+    let mut decoder = minicbor::Decoder::new(payload);
+    if decoder.datatype().ok()? != minicbor::data::Type::Map {
+        return Some(payload);
+    }
+    for _ in 0..decoder.map().ok()?? {
+        if decoder.u8().ok()? == 1 {
+            return decoder.bytes().ok();
+        }
+        decoder.skip().ok()?;
+    }
+    None
+}
+
+/// Decrypt a message from the AS with the key shared with it (using AES-CCM-16-128-256, as the
+/// resource server does for tokens).
+///
+/// During the overlap window of an AS key rotation, messages the current key does not decrypt are
+/// tried with the [previous key](crate::security::previous_as_key), just as the resource server
+/// accepts tokens with it.
+///
+/// Tokens have no external AAD; other messages use it to bind the message to its purpose.
+///
+/// The plaintext is wiped when it is dropped, as tokens of the ACE OSCORE profile carry the OSCORE
+/// input material in their `cnf` claim.
+pub(crate) fn decrypt(
+    token: &coset::CoseEncrypt0,
+    external_aad: &[u8],
+) -> Option<Zeroizing<alloc::vec::Vec<u8>>> {
+    decrypt_with_key(token, external_aad).map(|(plaintext, _)| plaintext)
+}
+
+/// The cipher with which the AS encrypts messages
+type Cipher = ccm::Ccm<aes::Aes256, ccm::consts::U16, ccm::consts::U13>;
+
+/// The nonce of a message encrypted by the AS, if it has the right length
+fn iv(token: &coset::CoseEncrypt0) -> Option<&[u8]> {
+    let iv = if token.unprotected.iv.is_empty() {
+        &token.protected.header.iv
+    } else {
+        &token.unprotected.iv
+    };
+    (iv.len() == 13).then_some(iv)
+}
+
+/// Like [decrypt], and also return the key that decrypted the message.
+fn decrypt_with_key(
+    token: &coset::CoseEncrypt0,
+    external_aad: &[u8],
+) -> Option<(Zeroizing<alloc::vec::Vec<u8>>, Zeroizing<[u8; 32]>)> {
+    use ccm::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+    let _phase = crate::profiling::mark(crate::profiling::Phase::Token);
+    let _measured = crate::latency::measure(crate::latency::Operation::TokenDecryption);
+
+    let keys = Zeroizing::new([
+        crate::security::keys().and_then(|keys| keys.as_symmetric),
+        crate::security::previous_as_key(),
+    ]);
+    let iv = iv(token)?;
+    keys.iter().flatten().find_map(|key| {
+        let cipher = Cipher::new_from_slice(&key[..]).ok()?;
+        let plaintext = token
+            .decrypt(external_aad, |msg, aad| {
+                cipher.decrypt(GenericArray::from_slice(iv), Payload { msg, aad })
+            })
+            .ok()?;
+        Some((Zeroizing::new(plaintext), Zeroizing::new(*key)))
+    })
+}
+
+/// The device's own audience, and the encoded array of its group audiences
+static AUDIENCES: critical_section::Mutex<Cell<(&str, &[u8])>> =
+    critical_section::Mutex::new(Cell::new(("", &[])));
+
+/// Set the audiences that [retarget] accepts tokens for.
+pub(crate) fn set_audiences(config: &'static crate::CoapcoreConfig) {
+    critical_section::with(|cs| {
+        AUDIENCES
+            .borrow(cs)
+            .set((config.audience, config.group_audiences))
+    });
+}
+
+/// Make a token that was issued for several audiences, or for one of the device's
+/// [group audiences](crate::CoapcoreConfig::group_audiences), acceptable to the resource server.
+///
+/// The resource server only accepts tokens whose `aud` claim is the device's own audience as a
+/// single text string. A token encrypted with the key shared with the AS whose `aud` is one of the
+/// device's audiences (or an array that contains any of them) is thus decrypted, its `aud` is
+/// replaced with the device's own audience, and it is encrypted again with the same key and
+/// nonce. That token only ever reaches the resource server, so reusing the nonce reveals nothing.
+/// Signed tokens can not be altered, and thus need to be issued for the device's own audience.
+///
+/// `payload` is that of a POST to `/authz-info`; the result replaces it. None is returned if the
+/// token is to be processed as it is.
+pub(crate) fn retarget(payload: &[u8]) -> Option<heapless::Vec<u8, { crate::MAX_MESSAGE_LEN }>> {
+    use ccm::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+    use ciborium::value::Value;
+    use minicbor::encode::write::{Cursor, Write as _};
+
+    let token = access_token(payload)?;
+    let (encrypted, tagged) = match coset::CoseEncrypt0::from_slice(token) {
+        Ok(encrypted) => (encrypted, false),
+        Err(_) => (coset::CoseEncrypt0::from_tagged_slice(token).ok()?, true),
+    };
+    let (plaintext, key) = decrypt_with_key(&encrypted, &[])?;
+    let mut claims: Value = ciborium::de::from_reader(&plaintext[..]).ok()?;
+
+    let (own, group) = critical_section::with(|cs| AUDIENCES.borrow(cs).get());
+    let ours = |audience: &Value| {
+        audience.as_text().is_some_and(|audience| {
+            audience == own
+                || minicbor::Decoder::new(group)
+                    .array_iter::<&str>()
+                    .is_ok_and(|mut group| group.any(|member| member.ok() == Some(audience)))
+        })
+    };
+    let (_, audience) = claims
+        .as_map_mut()?
+        .iter_mut()
+        .find(|(key, _)| *key == Value::Integer(3.into()))?;
+    let acceptable = match &*audience {
+        Value::Text(text) if text == own => return None,
+        Value::Text(_) => ours(audience),
+        Value::Array(audiences) => audiences.iter().any(ours),
+        _ => false,
+    };
+    if !acceptable {
+        return None;
+    }
+    crate::info!("Retargeting token to the device's own audience");
+    *audience = Value::Text(own.into());
+    let mut plaintext = Zeroizing::new(alloc::vec::Vec::new());
+    ciborium::ser::into_writer(&claims, &mut *plaintext).ok()?;
+
+    let iv = iv(&encrypted)?;
+    let cipher = Cipher::new_from_slice(&key[..]).ok()?;
+    let retargeted = coset::CoseEncrypt0Builder::new()
+        .protected(encrypted.protected.header.clone())
+        .unprotected(encrypted.unprotected.clone())
+        // Failing that, the resource server rejects the empty ciphertext.
+        .create_ciphertext(&plaintext, &[], |msg, aad| {
+            cipher
+                .encrypt(GenericArray::from_slice(iv), Payload { msg, aad })
+                .unwrap_or_default()
+        })
+        .build();
+    let retargeted = match tagged {
+        true => retargeted.to_tagged_vec(),
+        false => retargeted.to_vec(),
+    }
+    .ok()?;
+
+    let mut decoder = minicbor::Decoder::new(payload);
+    if decoder.datatype().ok()? != minicbor::data::Type::Map {
+        return heapless::Vec::from_slice(&retargeted).ok();
+    }
+    // The ACE OSCORE profile's map, with the token replaced and all other entries as they are
+    let mut buffer = [0; crate::MAX_MESSAGE_LEN];
+    let mut encoder = minicbor::Encoder::new(Cursor::new(&mut buffer[..]));
+    let entries = decoder.map().ok()??;
+    encoder.map(entries).ok()?;
+    for _ in 0..entries {
+        let start = decoder.position();
+        let key = decoder.u8().ok()?;
+        decoder.skip().ok()?;
+        if key == 1 {
+            encoder.u8(1).ok()?.bytes(&retargeted).ok()?;
+        } else {
+            let entry = &payload[start..decoder.position()];
+            encoder.writer_mut().write_all(entry).ok()?;
+        }
+    }
+    let length = encoder.writer().position();
+    heapless::Vec::from_slice(&buffer[..length]).ok()
+}
+
+/// Extract the claims from a token that the resource server accepted.
+///
+/// Signatures are not checked again, as the resource server already did that.
+fn parse(token: &[u8]) -> Option<Claims> {
+    let claims = if let Ok(signed) =
+        coset::CoseSign1::from_slice(token).or_else(|_| coset::CoseSign1::from_tagged_slice(token))
+    {
+        Zeroizing::new(signed.payload?)
+    } else {
+        let encrypted = coset::CoseEncrypt0::from_slice(token)
+            .or_else(|_| coset::CoseEncrypt0::from_tagged_slice(token))
+            .ok()?;
+        decrypt(&encrypted, &[])?
+    };
+    let claims = coset::cwt::ClaimsSet::from_slice(&claims).ok()?;
+
+    let scope = claims
+        .rest
+        .iter()
+        .find_map(|(key, value)| match (key, value) {
+            (
+                coset::RegisteredLabelWithPrivate::Assigned(coset::iana::CwtClaimName::Scope),
+                ciborium::value::Value::Bytes(scope),
+            ) => heapless::Vec::from_slice(scope).ok(),
+            _ => None,
+        })?;
+    let exp = match claims.expiration_time? {
+        coset::cwt::Timestamp::WholeSeconds(exp) => exp.try_into().ok()?,
+        coset::cwt::Timestamp::FractionalSeconds(_) => return None,
+    };
+    Some(Claims {
+        id: NEXT_ID.fetch_add(1, Relaxed),
+        audience: heapless::String::try_from(claims.audience?.as_str()).ok()?,
+        subject: claims
+            .subject
+            .and_then(|subject| Subject::try_from(subject.as_str()).ok()),
+        scope,
+        exp,
+        requests: 0,
+        last_used: None,
+    })
+}
+
+/// Whether a scope (the encoded AIF) grants administrative access.
+///
+/// That is the permission to change the [settings](crate::settings) through a POST to `/config`,
+/// which is what maintenance operators need, and what demo participants never get.
+fn is_admin(scope: &[u8]) -> bool {
+    let Some(post) = crate::rs_configuration::method_bit(coap_numbers::code::POST) else {
+        return false;
+    };
+    let mut decoder = minicbor::Decoder::new(scope);
+    let Ok(entries) = decoder.array_iter::<(&str, u64)>() else {
+        return false;
+    };
+    entries
+        .flatten()
+        .any(|(path, methods)| path == "/config" && methods & post != 0)
+}
+
+/// Remember the claims of a token that the resource server accepted in a POST to `/authz-info`.
+///
+/// If there is no space left, the recorded token that expires soonest is evicted. (Expired ones
+/// are pruned first, but those are only recognized while the clock is set).
+///
+/// Returns a handle to the recorded token, unless it could not be decoded.
+pub fn record(payload: &[u8]) -> Option<Recorded> {
+    let Some(claims) = access_token(payload).and_then(parse) else {
+        crate::info!("Accepted token could not be recorded");
+        return None;
+    };
+    let recorded = Recorded {
+        id: claims.id,
+        admin: is_admin(&claims.scope),
+    };
+    critical_section::with(|cs| {
+        let mut tokens = TOKENS.borrow_ref_mut(cs);
+        if let (true, Ok(now)) = (tokens.is_full(), crate::devicetime::unixtime()) {
+            prune(&mut tokens, now);
+        }
+        let soonest = tokens
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, claims)| claims.exp)
+            .map(|(index, _)| index);
+        if let (true, Some(soonest)) = (tokens.is_full(), soonest) {
+            let evicted = tokens.remove(soonest);
+            let total = EVICTED.fetch_add(1, Relaxed) + 1;
+            crate::info!(
+                "Token record full, evicted token for {} expiring at {} ({} evicted in total)",
+                evicted.audience.as_str(),
+                evicted.exp,
+                total
+            );
+        }
+        // There is space now
+        let _ = tokens.push(claims);
+    });
+    Some(recorded)
+}
+
+/// What a client learns about its token when it was accepted
+///
+/// This is encoded as a CBOR map with the token's expiry (`"exp"`, in seconds since the UNIX
+/// epoch, as the device's clock counts them), its scope (`"scope"`, the encoded AIF in a byte
+/// string) and the remaining lifetime in seconds (`"remaining"`, absent while the clock is not
+/// set).
+pub struct Installed {
+    claims: Claims,
+    now: Option<u32>,
+}
+
+/// Obtain the summary of a recorded token, unless it was dropped from the record already.
+pub fn installed(token: Recorded) -> Option<Installed> {
+    let claims = critical_section::with(|cs| {
+        TOKENS
+            .borrow_ref(cs)
+            .iter()
+            .find(|claims| claims.id == token.id)
+            .cloned()
+    })?;
+    Some(Installed {
+        claims,
+        now: crate::devicetime::unixtime().ok(),
+    })
+}
+
+impl<C> minicbor::encode::Encode<C> for Installed {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.map(if self.now.is_some() { 3 } else { 2 })?
+            .str("exp")?
+            .u32(self.claims.exp)?
+            .str("scope")?
+            .bytes(&self.claims.scope)?;
+        if let Some(now) = self.now {
+            e.str("remaining")?
+                .u32(self.claims.exp.saturating_sub(now))?;
+        }
+        Ok(())
+    }
+}
+
+/// Obtain the subject of a recorded token, if it has one and was not dropped from the record.
+pub fn subject(token: Recorded) -> Option<Subject> {
+    critical_section::with(|cs| {
+        TOKENS
+            .borrow_ref(cs)
+            .iter()
+            .find(|claims| claims.id == token.id)?
+            .subject
+            .clone()
+    })
+}
+
+/// Whether a recorded token is still in the record, and has not expired (as far as the clock
+/// tells).
+pub fn is_current(token: Recorded) -> bool {
+    let now = crate::devicetime::unixtime().ok();
+    critical_section::with(|cs| {
+        TOKENS
+            .borrow_ref(cs)
+            .iter()
+            .find(|claims| claims.id == token.id)
+            .is_some_and(|claims| now.map_or(true, |now| claims.exp > now))
+    })
+}
+
+/// Count a request made with a recorded token.
+///
+/// Tokens that were dropped from the record in the meantime are ignored.
+pub fn count_use(token: Recorded) {
+    let now = crate::devicetime::unixtime().ok();
+    critical_section::with(|cs| {
+        if let Some(claims) = TOKENS
+            .borrow_ref_mut(cs)
+            .iter_mut()
+            .find(|claims| claims.id == token.id)
+        {
+            claims.requests = claims.requests.saturating_add(1);
+            claims.last_used = now.or(claims.last_used);
+        }
+    });
+}
+
+/// Drop the recorded tokens that expired by `now`, returning how many were dropped.
+fn prune(tokens: &mut heapless::Vec<Claims, MAX_TOKENS>, now: u32) -> usize {
+    let before = tokens.len();
+    tokens.retain(|claims| claims.exp > now);
+    let expired = before - tokens.len();
+    EXPIRED.fetch_add(expired as u32, Relaxed);
+    expired
+}
+
+/// Outcome of a [sweep]
+pub struct Swept {
+    /// Number of tokens that were dropped
+    pub expired: usize,
+    /// Number of tokens that are still valid
+    pub remaining: usize,
+}
+
+/// Drop the recorded tokens that expired.
+///
+/// This is run periodically by the firmware, so that expiry is noticed even while no requests
+/// arrive. While the clock is not set, nothing is considered expired.
+pub fn sweep() -> Swept {
+    let Ok(now) = crate::devicetime::unixtime() else {
+        return Swept {
+            expired: 0,
+            remaining: critical_section::with(|cs| TOKENS.borrow_ref(cs).len()),
+        };
+    };
+    let swept = critical_section::with(|cs| {
+        let mut tokens = TOKENS.borrow_ref_mut(cs);
+        Swept {
+            expired: prune(&mut tokens, now),
+            remaining: tokens.len(),
+        }
+    });
+    if swept.expired > 0 {
+        crate::info!(
+            "{} tokens expired ({} in total), {} remaining",
+            swept.expired,
+            EXPIRED.load(Relaxed),
+            swept.remaining
+        );
+    }
+    swept
+}
+
+/// Forget all recorded tokens, as the resource server was rebuilt without them.
+pub(crate) fn clear() {
+    critical_section::with(|cs| TOKENS.borrow_ref_mut(cs).clear());
+}
+
+/// The claims of all recorded tokens that have not expired yet
+///
+/// This is encoded as a CBOR array of maps, each with the token's audience (`"aud"`, a text
+/// string), scope (`"scope"`, the encoded AIF in a byte string), expiry (`"exp"`, in seconds since
+/// the UNIX epoch), the remaining lifetime in seconds (`"remaining"`, absent while the clock is
+/// not set), the number of requests attributed to it (`"requests"`, see [count_use]), and when
+/// the latest of them was made (`"last-used"`, in seconds since the UNIX epoch, absent if no
+/// request was made while the clock was set).
+pub struct Report {
+    tokens: heapless::Vec<Claims, MAX_TOKENS>,
+    now: Option<u32>,
+}
+
+/// Obtain a copy of the current tokens' claims, dropping any that expired.
+pub fn report() -> Report {
+    let now = crate::devicetime::unixtime().ok();
+    let tokens = critical_section::with(|cs| {
+        let mut tokens = TOKENS.borrow_ref_mut(cs);
+        if let Some(now) = now {
+            prune(&mut tokens, now);
+        }
+        tokens.clone()
+    });
+    Report { tokens, now }
+}
+
+impl<C> minicbor::encode::Encode<C> for Report {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+        _: &mut C,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        e.array(self.tokens.len() as u64)?;
+        for claims in self.tokens.iter() {
+            let len = 4 + self.now.is_some() as u64 + claims.last_used.is_some() as u64;
+            e.map(len)?
+                .str("aud")?
+                .str(&claims.audience)?
+                .str("scope")?
+                .bytes(&claims.scope)?
+                .str("exp")?
+                .u32(claims.exp)?;
+            if let Some(now) = self.now {
+                e.str("remaining")?.u32(claims.exp.saturating_sub(now))?;
+            }
+            e.str("requests")?.u32(claims.requests)?;
+            if let Some(last_used) = claims.last_used {
+                e.str("last-used")?.u32(last_used)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Recording of the GATT traffic, for replay in the simulation
+//!
+//! Firmware built with the `gatt-trace` feature logs every connection, every write to the CoAP
+//! characteristic and the value it is answered with. Each goes into a line of its own, of the
+//! form:
+//!
+//! ```text
+//! GATT-TRACE <milliseconds of uptime> <connection slot> <open|write|read> [<hex encoded value>]
+//! ```
+//!
+//! These lines can be cut out of the RTT log of a session with a real phone (anything around
+//! them, like the log level and location that defmt adds, is ignored), and replayed against the
+//! simulated device with [crate::sim::replay], which turns the session into a regression test.
+//!
+//! Only the response codes are compared on replay: The simulated device picks different EDHOC
+//! ephemeral keys and nonces than the real one did, so responses from the handshake on differ in
+//! their content, and the phone's later (protected) requests do not match the simulated device's
+//! security contexts. Replays are thus most useful for the unprotected parts of a session, up to
+//! and including the first EDHOC message and token.
+
+/// Start of the lines through which the traffic is recorded
+pub const MARKER: &str = "GATT-TRACE";
+
+/// What happened on a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A central connected in the slot
+    Open,
+    /// The central wrote a request
+    Write(&'a [u8]),
+    /// The device answered with a response
+    Read(&'a [u8]),
+}
+
+/// Record an event on the connection in the given slot.
+///
+/// This does nothing unless the `gatt-trace` feature is enabled.
+#[cfg_attr(not(feature = "gatt-trace"), allow(unused_variables))]
+pub fn record(slot: usize, event: Event<'_>) {
+    #[cfg(feature = "gatt-trace")]
+    {
+        let millis = embassy_time::Instant::now().as_millis();
+        match event {
+            Event::Open => defmt::println!("GATT-TRACE {} {} open", millis, slot),
+            Event::Write(value) => defmt::println!(
+                "GATT-TRACE {} {} write {}",
+                millis,
+                slot,
+                crate::logging::Hex(value)
+            ),
+            Event::Read(value) => defmt::println!(
+                "GATT-TRACE {} {} read {}",
+                millis,
+                slot,
+                crate::logging::Hex(value)
+            ),
+        }
+    }
+}
+
+/// An event as read back from a trace line
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recorded {
+    pub millis: u64,
+    pub slot: usize,
+    /// The event's value: None for [Event::Open], and the bytes along with whether they were
+    /// written (rather than read) otherwise
+    pub value: Option<(bool, Vec<u8>)>,
+}
+
+/// Parse a line of a trace, returning None for lines that are not trace lines.
+///
+/// The value may be given as contiguous hex digits, or in the bracketed list form that defmt
+/// prints byte slices in (eg. `[0a, 01]`).
+#[cfg(feature = "std")]
+pub fn parse_line(line: &str) -> Option<Recorded> {
+    let (_, rest) = line.split_once(MARKER)?;
+    let mut fields = rest.split_whitespace();
+    let millis = fields.next()?.parse().ok()?;
+    let slot = fields.next()?.parse().ok()?;
+    let written = match fields.next()? {
+        "open" => {
+            return Some(Recorded {
+                millis,
+                slot,
+                value: None,
+            })
+        }
+        "write" => true,
+        "read" => false,
+        _ => return None,
+    };
+    // Anything after the value (eg. a location that the log viewer appended) is ignored.
+    let digits: String = fields
+        .take_while(|field| {
+            field
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() || "[],".contains(c))
+        })
+        .flat_map(|field| field.chars())
+        .filter(char::is_ascii_hexdigit)
+        .collect();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    let value = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Recorded {
+        millis,
+        slot,
+        value: Some((written, value)),
+    })
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! Driver for an attached WS2812 ("NeoPixel") LED strip
+//!
+//! This is only available with the `ws2812` feature. The strip's data line is driven from the
+//! MOSI pin of an SPI peripheral running at 4MHz, where each data bit is expressed as 4 SPI bits
+//! (`1000` for a 0, `1110` for a 1), which gets the pulse lengths close enough to the WS2812
+//! timing requirements.
+//!
+//! All LEDs of the strip are set to the same color.
+//!
+//! The strip is connected to P0.11 (data); P0.12 is used as the (unconnected) SPI clock. Both are
+//! free on the nRF52-DK.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+/// Number of LEDs on the strip
+const STRIP_LENGTH: usize = 8;
+
+/// SPI bytes per LED: 24 bits of color, each expanded to 4 SPI bits
+const BYTES_PER_LED: usize = 24 * 4 / 8;
+
+/// Zero bytes to send after the data to latch it (50µs at 4MHz are 25 bytes)
+const RESET_BYTES: usize = 32;
+
+pub type Spim = embassy_nrf::spim::Spim<'static, embassy_nrf::peripherals::SPI2>;
+
+embassy_nrf::bind_interrupts!(pub struct Irqs {
+    SPIM2_SPIS2_SPI2 => embassy_nrf::spim::InterruptHandler<embassy_nrf::peripherals::SPI2>;
+});
+
+/// Set up the SPI peripheral for driving the strip.
+pub fn spim(
+    spi: embassy_nrf::peripherals::SPI2,
+    sck: embassy_nrf::peripherals::P0_12,
+    mosi: embassy_nrf::peripherals::P0_11,
+) -> Spim {
+    use embassy_nrf::interrupt::InterruptExt;
+
+    // Differing from default, this stays out of the softdevice's hair
+    embassy_nrf::interrupt::SPIM2_SPIS2_SPI2.set_priority(embassy_nrf::interrupt::Priority::P7);
+
+    let mut config = embassy_nrf::spim::Config::default();
+    config.frequency = embassy_nrf::spim::Frequency::M4;
+    embassy_nrf::spim::Spim::new_txonly(spi, Irqs, sck, mosi, config)
+}
+
+/// The color state of the strip
+///
+/// Setting the color only stores it; the [run] task picks it up and transmits it to the strip.
+pub struct Strip {
+    color: Cell<[u8; 3]>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Strip {
+    pub fn new() -> Self {
+        Self {
+            color: Cell::new([0; 3]),
+            changed: Signal::new(),
+        }
+    }
+
+    /// Set the color of all LEDs, as red, green and blue intensity.
+    pub fn set_color(&self, color: [u8; 3]) {
+        self.color.set(color);
+        self.changed.signal(());
+    }
+
+    /// Return the most recently set color.
+    pub fn color(&self) -> [u8; 3] {
+        self.color.get()
+    }
+}
+
+/// Expand a color byte into the SPI bit patterns, most significant bit first.
+fn encode_byte(byte: u8, out: &mut [u8]) {
+    for (i, out) in out.iter_mut().enumerate() {
+        let high = byte >> (7 - 2 * i) & 1 != 0;
+        let low = byte >> (6 - 2 * i) & 1 != 0;
+        let nibble = |bit| if bit { 0b1110 } else { 0b1000 };
+        *out = nibble(high) << 4 | nibble(low);
+    }
+}
+
+/// Task transmitting the color to the strip whenever it changes
+#[embassy_executor::task]
+pub async fn run(mut spim: Spim, strip: &'static Strip) {
+    let mut buffer = [0u8; STRIP_LENGTH * BYTES_PER_LED + RESET_BYTES];
+    loop {
+        let [r, g, b] = strip.color();
+        for led in buffer[..STRIP_LENGTH * BYTES_PER_LED].chunks_mut(BYTES_PER_LED) {
+            // WS2812 take their data in GRB order
+            encode_byte(g, &mut led[0..4]);
+            encode_byte(r, &mut led[4..8]);
+            encode_byte(b, &mut led[8..12]);
+        }
+        if let Err(e) = spim.write(&buffer).await {
+            coap_ace_poc_firmware::error!("Failed to write to LED strip: {:?}", e);
+        }
+
+        strip.changed.wait().await;
+    }
+}
@@ -0,0 +1,396 @@
+// SPDX-FileCopyrightText: Copyright 2022-2024 EDF (Électricité de France S.A.)
+// SPDX-License-Identifier: BSD-3-Clause
+// See README for all details on copyright, authorship and license.
+//! End-to-end tests of the CoAP-over-GATT request path against a simulated device
+//!
+//! Messages are given in CoAP-over-GATT serialization: code, options, and (after a 0xff marker) a
+//! payload.
+#![cfg(all(feature = "std", feature = "resources"))]
+
+use coap_ace_poc_firmware::sim::Device;
+
+const GET: u8 = 0x01;
+const POST: u8 = 0x02;
+const PUT: u8 = 0x03;
+const CREATED: u8 = 0x41;
+const CHANGED: u8 = 0x44;
+const CONTENT: u8 = 0x45;
+const BAD_REQUEST: u8 = 0x80;
+const UNAUTHORIZED: u8 = 0x81;
+const FORBIDDEN: u8 = 0x83;
+const NOT_FOUND: u8 = 0x84;
+
+/// The device's clock, keys and records are global, so all tests of this process share them; every
+/// test holds this while it runs.
+static SHARED: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Take the shared state for the duration of a test
+fn exclusive() -> std::sync::MutexGuard<'static, ()> {
+    SHARED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Like [exclusive], and set the clock to `now`
+fn set_clock(now: u32) -> std::sync::MutexGuard<'static, ()> {
+    let guard = exclusive();
+    coap_ace_poc_firmware::devicetime::set_unixtime(now).unwrap();
+    guard
+}
+
+/// Build a request to a single-segment path (which must be shorter than 13 bytes)
+fn request(code: u8, path: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![code, 0xb0 | path.len() as u8];
+    message.extend_from_slice(path.as_bytes());
+    if !payload.is_empty() {
+        message.push(0xff);
+        message.extend_from_slice(payload);
+    }
+    message
+}
+
+#[test]
+fn time_is_unprotected() {
+    let _clock = set_clock(1_600_000_000);
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    // 1700000000 as CBOR
+    let now = [0x1a, 0x65, 0x53, 0xf1, 0x00];
+    let response = connection.exchange(&request(PUT, "time", &now));
+    assert_eq!(response[0], CHANGED);
+
+    let response = connection.exchange(&request(GET, "time", &[]));
+    assert_eq!(response[0], CONTENT);
+}
+
+#[test]
+fn time_is_only_turned_back_a_little() {
+    use coap_ace_poc_firmware::devicetime::{unixtime, REWIND_TOLERANCE};
+
+    let _clock = set_clock(1_700_000_000);
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    // A 32-bit CBOR unsigned integer
+    let time = |now: u32| [&[0x1a][..], &now.to_be_bytes()].concat();
+
+    let far_back = time(1_700_000_000 - REWIND_TOLERANCE - 10);
+    let response = connection.exchange(&request(PUT, "time", &far_back));
+    assert_eq!(response[0], FORBIDDEN);
+    // Setting the clock freely needs a token
+    let response = connection.exchange(&request(POST, "time", &far_back));
+    assert_eq!(response[0], UNAUTHORIZED);
+    assert!(
+        unixtime().unwrap() >= 1_700_000_000,
+        "Clock was turned back"
+    );
+
+    let slightly_back = time(1_700_000_000 - REWIND_TOLERANCE / 2);
+    let response = connection.exchange(&request(PUT, "time", &slightly_back));
+    assert_eq!(response[0], CHANGED);
+    assert!(
+        unixtime().unwrap() < 1_700_000_000,
+        "Clock was not turned back"
+    );
+}
+
+#[test]
+fn time_beyond_range_is_rejected() {
+    use coap_ace_poc_firmware::devicetime::unixtime;
+
+    let _clock = set_clock(1_600_000_000);
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    // u32::MAX - 10 as CBOR, which would make the clock wrap around to 1970 within seconds
+    let response = connection.exchange(&request(PUT, "time", &[0x1a, 0xff, 0xff, 0xff, 0xf5]));
+    assert_eq!(response[0], BAD_REQUEST);
+    assert!(unixtime().unwrap() < 1_600_001_000, "Clock was moved");
+}
+
+#[test]
+fn time_is_synced_through_pending_nonces() {
+    use ccm::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+    use coset::{iana, CborSerializable};
+    type Cipher = ccm::Ccm<aes::Aes256, ccm::consts::U16, ccm::consts::U13>;
+
+    let _clock = set_clock(1_600_000_000);
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+    let key = coap_ace_poc_firmware::security::keys()
+        .unwrap()
+        .as_symmetric
+        .unwrap();
+
+    // A request to `/time/sync`
+    let sync = |code: u8, payload: &[u8]| {
+        let mut message = [&[code, 0xb4][..], b"time", &[0x04], b"sync"].concat();
+        if !payload.is_empty() {
+            message.push(0xff);
+            message.extend_from_slice(payload);
+        }
+        message
+    };
+    // The nonce is the response's payload, a 16 byte CBOR byte string.
+    let response = connection.exchange(&sync(GET, &[]));
+    assert_eq!(response[0], CONTENT);
+    let nonce = response[response.len() - 16..].to_vec();
+    // Another peer asking for a nonce does not invalidate the first one.
+    let response = device.connect().exchange(&sync(GET, &[]));
+    assert_eq!(response[0], CONTENT);
+
+    // 1700000000 as CBOR, as the AS would encrypt it for the nonce
+    let now = [0x1a, 0x65, 0x53, 0xf1, 0x00];
+    let iv = [0x35; 13];
+    let cipher = Cipher::new_from_slice(&key).unwrap();
+    let answer = coset::CoseEncrypt0Builder::new()
+        .protected(
+            coset::HeaderBuilder::new()
+                .algorithm(iana::Algorithm::AES_CCM_16_128_256)
+                .build(),
+        )
+        .unprotected(coset::HeaderBuilder::new().iv(iv.to_vec()).build())
+        .create_ciphertext(&now, &nonce, |msg, aad| {
+            cipher
+                .encrypt(GenericArray::from_slice(&iv), Payload { msg, aad })
+                .unwrap()
+        })
+        .build()
+        .to_vec()
+        .unwrap();
+
+    // Made-up answers do not use the nonce up.
+    let mut forged = answer.clone();
+    *forged.last_mut().unwrap() ^= 1;
+    let response = connection.exchange(&sync(PUT, &forged));
+    assert_eq!(response[0], BAD_REQUEST);
+
+    let response = connection.exchange(&sync(PUT, &answer));
+    assert_eq!(response[0], CHANGED);
+    let now = coap_ace_poc_firmware::devicetime::unixtime().unwrap();
+    assert!((1_700_000_000..1_700_000_002).contains(&now));
+
+    // The answer can not be replayed.
+    let response = connection.exchange(&sync(PUT, &answer));
+    assert_eq!(response[0], BAD_REQUEST);
+}
+
+#[test]
+fn safe_mode_serves_info_only() {
+    use coap_ace_poc_firmware::{coap, coap_gatt};
+
+    let mut resources = coap::create_safe_mode_handler();
+    let response = coap_gatt::write_unprotected(&mut resources, &mut request(GET, "info", &[]));
+    assert_eq!(response[0], CONTENT);
+    let response = coap_gatt::write_unprotected(&mut resources, &mut request(GET, "temp", &[]));
+    assert_eq!(response[0], NOT_FOUND);
+}
+
+#[test]
+fn protected_resources_require_token() {
+    let _shared = exclusive();
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    for path in ["temp", "leds", "identify"] {
+        let response = connection.exchange(&request(GET, path, &[]));
+        assert_eq!(response[0], UNAUTHORIZED, "Unauthorized access to {path}");
+    }
+    assert_eq!(device.leds.identify_count(), 0);
+}
+
+#[test]
+fn wkc_is_gated() {
+    let _shared = exclusive();
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    let mut wkc = vec![GET, 0xbb];
+    wkc.extend_from_slice(b".well-known");
+    wkc.push(0x04);
+    wkc.extend_from_slice(b"core");
+    let response = connection.exchange(&wkc);
+    assert_eq!(response[0], UNAUTHORIZED);
+}
+
+#[test]
+fn failed_token_posts_are_delayed() {
+    use coap_ace_poc_firmware::lockout;
+
+    let _shared = exclusive();
+    let device = Device::from_build_config();
+    let address = [0x42, 0, 0, 0, 0, 0x01];
+
+    // Counting continues across connections from the same address.
+    for attempt in 1..=lockout::FREE_FAILURES + 2 {
+        let mut connection = device.connect_from(address);
+        let response = connection.exchange(&request(POST, "authz-info", &[0x40]));
+        assert!(response[0] >> 5 >= 4, "Garbage token was accepted");
+        let expected = match attempt.checked_sub(lockout::FREE_FAILURES + 1) {
+            None => embassy_time::Duration::from_ticks(0),
+            Some(beyond) => lockout::FIRST_DELAY * (1 << beyond),
+        };
+        assert_eq!(
+            connection.delay(),
+            expected,
+            "Delay after attempt {attempt}"
+        );
+    }
+
+    // Other peers are not affected.
+    let mut connection = device.connect_from([0x42, 0, 0, 0, 0, 0x02]);
+    connection.exchange(&request(POST, "authz-info", &[0x40]));
+    assert_eq!(connection.delay(), embassy_time::Duration::from_ticks(0));
+}
+
+/// Build the payload of a token POST in the ACE OSCORE profile, with a token granting GET on
+/// `/temp` that is encrypted with `key` and issued for `audience`
+fn token_post(key: &[u8; 32], audience: ciborium::value::Value) -> Vec<u8> {
+    use ccm::aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+    use ciborium::value::Value;
+    use coset::{iana, CborSerializable};
+    type Cipher = ccm::Ccm<aes::Aes256, ccm::consts::U16, ccm::consts::U13>;
+
+    // [["/temp", GET]]
+    let scope = b"\x81\x82\x65/temp\x01".to_vec();
+    // OSCORE input material with an ID and a master secret
+    let cnf = Value::Map(vec![(
+        Value::Integer(4.into()),
+        Value::Map(vec![
+            (Value::Integer(0.into()), Value::Bytes(vec![0x01])),
+            (Value::Integer(2.into()), Value::Bytes(vec![0x55; 16])),
+        ]),
+    )]);
+    // {aud, exp, iat, cnf, scope}
+    let claims = Value::Map(vec![
+        (Value::Integer(3.into()), audience),
+        (
+            Value::Integer(4.into()),
+            Value::Integer(1_700_003_600.into()),
+        ),
+        (
+            Value::Integer(6.into()),
+            Value::Integer(1_700_000_000.into()),
+        ),
+        (Value::Integer(8.into()), cnf),
+        (Value::Integer(9.into()), Value::Bytes(scope)),
+    ]);
+    let mut plaintext = vec![];
+    ciborium::ser::into_writer(&claims, &mut plaintext).unwrap();
+
+    let iv = [0x24; 13];
+    let cipher = Cipher::new_from_slice(key).unwrap();
+    let token = coset::CoseEncrypt0Builder::new()
+        .protected(
+            coset::HeaderBuilder::new()
+                .algorithm(iana::Algorithm::AES_CCM_16_128_256)
+                .build(),
+        )
+        .unprotected(coset::HeaderBuilder::new().iv(iv.to_vec()).build())
+        .create_ciphertext(&plaintext, &[], |msg, aad| {
+            cipher
+                .encrypt(GenericArray::from_slice(&iv), Payload { msg, aad })
+                .unwrap()
+        })
+        .build()
+        .to_vec()
+        .unwrap();
+
+    // {access_token: token, nonce1: ..., ace_client_recipientid: ...}
+    let mut payload = [0; 256];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut payload[..]);
+    minicbor::Encoder::new(&mut cursor)
+        .map(3)
+        .and_then(|e| {
+            e.u8(1)?
+                .bytes(&token)?
+                .u8(40)?
+                .bytes(&[0x11; 8])?
+                .u8(43)?
+                .bytes(&[0x02])
+        })
+        .unwrap();
+    let length = cursor.position();
+    payload[..length].to_vec()
+}
+
+#[test]
+fn tokens_of_the_previous_as_key_are_accepted() {
+    use coap_ace_poc_firmware::security;
+
+    let _clock = set_clock(1_700_000_000);
+    let device = Device::from_build_config();
+    let mut connection = device.connect();
+
+    let old_key = security::keys().unwrap().as_symmetric.unwrap();
+    security::rotate_as_key([0x42; 32], embassy_time::Duration::from_secs(60));
+
+    let payload = token_post(&old_key, "d00".into());
+    let response = connection.exchange(&request(POST, "authz-info", &payload));
+    assert_eq!(response[0], CREATED);
+
+    // Recording the token needs decrypting it once more, with the previous key.
+    let mut report = [0; 256];
+    let mut cursor = minicbor::encode::write::Cursor::new(&mut report[..]);
+    minicbor::encode(coap_ace_poc_firmware::tokens::report(), &mut cursor).unwrap();
+    assert_eq!(report[0], 0x81, "Token was not recorded");
+}
+
+#[test]
+fn tokens_for_group_audiences_are_accepted() {
+    use ciborium::value::Value;
+    use coap_ace_poc_firmware::CoapcoreConfig;
+
+    let _clock = set_clock(1_700_000_000);
+    // ["lab"]
+    let group_audiences = b"\x81\x63lab";
+    let device = Device::new(CoapcoreConfig {
+        group_audiences,
+        ..include!(concat!(env!("OUT_DIR"), "/rs_as_association.rs"))
+    });
+    let key = coap_ace_poc_firmware::security::keys()
+        .unwrap()
+        .as_symmetric
+        .unwrap();
+
+    for audience in [
+        Value::Text("lab".into()),
+        Value::Array(vec!["elsewhere".into(), "d00".into()]),
+    ] {
+        let mut connection = device.connect();
+        let payload = token_post(&key, audience);
+        let response = connection.exchange(&request(POST, "authz-info", &payload));
+        assert_eq!(response[0], CREATED);
+    }
+
+    let mut connection = device.connect();
+    let payload = token_post(&key, Value::Array(vec!["elsewhere".into()]));
+    let response = connection.exchange(&request(POST, "authz-info", &payload));
+    assert_ne!(response[0], CREATED);
+
+    // Leave no recorded tokens behind for the other tests; they go with the next rebuild.
+    coap_ace_poc_firmware::security::revoke_all();
+    connection.exchange(&request(GET, "time", &[]));
+}
+
+/// Replay all traces recorded with the `gatt-trace` feature that are kept in `tests/traces/`
+#[test]
+fn recorded_traces_replay() {
+    // The traces set the clock to 1700000000
+    let _clock = set_clock(1_600_000_000);
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/traces");
+    for entry in std::fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("trace".as_ref()) {
+            continue;
+        }
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let divergences = coap_ace_poc_firmware::sim::replay(&trace);
+        assert!(
+            divergences.is_empty(),
+            "Replay of {} diverged: {divergences:?}",
+            path.display()
+        );
+    }
+}